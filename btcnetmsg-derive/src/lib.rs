@@ -0,0 +1,107 @@
+// btcnetmsg-derive
+//
+// Derive macros for the `Encode` and `Decode` traits of btcnetmsg.
+//
+// Fields are encoded/decoded in declaration order, each field using its own
+// `Encode`/`Decode` implementation. This matches how network message structures
+// are laid out on the wire.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input,
+    Data,
+    DeriveInput,
+    Fields,
+    Index
+};
+
+/// Derive `Encode` for a struct by encoding each field in order.
+#[proc_macro_derive(Encode)]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let fields = match struct_fields(&input) {
+        Ok(f) => f,
+        Err(e) => return e.to_compile_error().into()
+    };
+
+    // Access expressions for each field, in declaration order
+    let accessors: Vec<TokenStream2> = match fields {
+        Fields::Named(named) => named.named
+            .iter()
+            .map(|f| {
+                let ident = &f.ident;
+                quote! { self.#ident }
+            })
+            .collect(),
+        Fields::Unnamed(unnamed) => (0..unnamed.unnamed.len())
+            .map(|i| {
+                let index = Index::from(i);
+                quote! { self.#index }
+            })
+            .collect(),
+        Fields::Unit => Vec::new()
+    };
+
+    quote! {
+        impl #impl_generics ::btcnetmsg::encode::Encode for #name #ty_generics #where_clause {
+            #[allow(unused_mut, unused_variables)]
+            fn net_encode<W>(&self, mut w: W) -> usize
+            where W: ::std::io::Write {
+                0 #( + ::btcnetmsg::encode::Encode::net_encode(&#accessors, &mut w) )*
+            }
+
+            fn encoded_size(&self) -> usize {
+                0 #( + ::btcnetmsg::encode::Encode::encoded_size(&#accessors) )*
+            }
+        }
+    }.into()
+}
+
+/// Derive `Decode` for a struct by decoding each field in order.
+#[proc_macro_derive(Decode)]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let fields = match struct_fields(&input) {
+        Ok(f) => f,
+        Err(e) => return e.to_compile_error().into()
+    };
+
+    let decode = quote! { ::btcnetmsg::encode::Decode::net_decode(&mut r)? };
+    let construct = match fields {
+        Fields::Named(named) => {
+            let idents = named.named.iter().map(|f| &f.ident);
+            quote! { Self { #( #idents: #decode ),* } }
+        },
+        Fields::Unnamed(unnamed) => {
+            let decodes = unnamed.unnamed.iter().map(|_| &decode);
+            quote! { Self( #( #decodes ),* ) }
+        },
+        Fields::Unit => quote! { Self }
+    };
+
+    quote! {
+        impl #impl_generics ::btcnetmsg::encode::Decode for #name #ty_generics #where_clause {
+            #[allow(unused_mut, unused_variables)]
+            fn net_decode<R>(mut r: R) -> ::std::result::Result<Self, ::btcnetmsg::encode::Error>
+            where R: ::std::io::Read {
+                Ok(#construct)
+            }
+        }
+    }.into()
+}
+
+/// Get the fields of a struct, or an error if the input is not a struct.
+fn struct_fields(input: &DeriveInput) -> Result<&Fields, syn::Error> {
+    match &input.data {
+        Data::Struct(s) => Ok(&s.fields),
+        _ => Err(syn::Error::new_spanned(&input.ident, "Encode/Decode can only be derived for structs"))
+    }
+}
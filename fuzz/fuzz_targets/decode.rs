@@ -0,0 +1,42 @@
+// Decoding arbitrary bytes never panics, and messages that decode are encoded into bytes
+// that decode to the same message. Bytes after the payload fields are ignored when decoding,
+// like Bitcoin Core does, so only messages without them are encoded again.
+//
+// Checksums are not verified, otherwise almost no input would get past the header.
+#![no_main]
+
+use btcnetmsg::{
+    encode::DecodeConfig,
+    Decode,
+    Encode,
+    Message,
+    MessageRef,
+    RawMessage
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let config = DecodeConfig { verify_checksum: false, ..DecodeConfig::default() };
+
+    if let Ok(msg) = Message::net_decode_with_config(data, &config) {
+        let _ = msg.network_message();
+        if msg.payload().encoded_size() == msg.header().length as usize {
+            let mut enc = Vec::new();
+            assert_eq!(msg.net_encode(&mut enc), msg.encoded_size());
+            assert_eq!(Message::net_decode_with_config(&enc[..], &config).expect("Failed to decode"), msg);
+        }
+    }
+
+    if let Ok(raw) = RawMessage::net_decode(data) {
+        let _ = raw.decode_payload_with_config(&config);
+    }
+
+    if let Ok((msg, used)) = MessageRef::net_decode_ref_with_config(data, &config) {
+        assert!(used <= data.len());
+        let _ = msg.into_owned();
+    }
+
+    if let Ok(json) = std::str::from_utf8(data) {
+        let _ = Message::from_json(json);
+    }
+});
@@ -0,0 +1,33 @@
+// Encoding arbitrary values and decoding them gives back the same values, in as many bytes
+// as `encoded_size` reports.
+#![no_main]
+
+use btcnetmsg::{
+    blockdata::BlockHeader,
+    msg::VariableInteger,
+    Decode,
+    Encode,
+    Inventory,
+    Message,
+    MessageHeader,
+    VersionMessage
+};
+use libfuzzer_sys::fuzz_target;
+use std::fmt::Debug;
+
+fn roundtrip<T: Encode + Decode + PartialEq + Debug>(value: T) {
+    let mut enc = Vec::new();
+    assert_eq!(value.net_encode(&mut enc), value.encoded_size());
+    assert_eq!(T::net_decode(&enc[..]).expect("Failed to decode"), value);
+}
+
+fuzz_target!(|input: (Message, MessageHeader, VersionMessage, Inventory, VariableInteger, BlockHeader)| {
+    let (msg, header, version, inv, varint, block_header) = input;
+    assert_eq!(Message::from_json(&msg.to_json()).expect("Failed to parse json"), msg);
+    roundtrip(msg);
+    roundtrip(header);
+    roundtrip(version);
+    roundtrip(inv);
+    roundtrip(varint);
+    roundtrip(block_header);
+});
@@ -0,0 +1,144 @@
+/* C API of btcnetmsg, generated with cbindgen from src/ffi.rs */
+
+#ifndef BTCNETMSG_H
+#define BTCNETMSG_H
+
+#include <stdint.h>
+#include <stddef.h>
+
+// Status returned by the functions of the C API
+typedef enum BtcStatus {
+  BTC_STATUS_OK = 0,
+  BTC_STATUS_NULL_POINTER = 1,
+  // The buffer does not hold a valid message
+  BTC_STATUS_DECODE = 2,
+  // The output buffer is too small, the required length was written
+  BTC_STATUS_BUFFER_TOO_SMALL = 3,
+  // The message does not have the requested payload
+  BTC_STATUS_WRONG_COMMAND = 4,
+  BTC_STATUS_INVALID_ARGUMENT = 5,
+} BtcStatus;
+
+// Opaque handle to a decoded message
+typedef struct BtcMessage BtcMessage;
+
+// Fields of a version message
+typedef struct BtcVersion {
+  int32_t version;
+  // Service flags as sent on the wire
+  uint64_t services;
+  // Unix time in seconds
+  int64_t timestamp;
+  uint64_t nonce;
+  int32_t start_height;
+  // 1 or 0, or -1 if the relay flag is not sent
+  int8_t relay;
+  // IPv6 address of the receiving node, IPv4 addresses are mapped into IPv6
+  uint8_t addr_recv_ip[16];
+  uint16_t addr_recv_port;
+} BtcVersion;
+
+// Decode a message from the start of a buffer.
+// On success `*out` is a handle to release with `btc_message_free` and `*consumed` is the
+// number of bytes read.
+//
+// # Safety
+// `buf` must be valid for reads of `len` bytes, `out` and `consumed` must be valid for writes.
+enum BtcStatus btc_message_decode(const uint8_t *buf,
+                                  size_t len,
+                                  struct BtcMessage **out,
+                                  size_t *consumed);
+
+// Release a message handle. Null handles are ignored.
+//
+// # Safety
+// `msg` must be null or a handle from this API that was not already released.
+void btc_message_free(struct BtcMessage *msg);
+
+// Network magic of the message, as the little endian integer of its wire bytes
+//
+// # Safety
+// `msg` must be a live handle from this API and `out` valid for writes.
+enum BtcStatus btc_message_magic(const struct BtcMessage *msg, uint32_t *out);
+
+// Length of the payload of the message in bytes
+//
+// # Safety
+// `msg` must be a live handle from this API and `out` valid for writes.
+enum BtcStatus btc_message_payload_length(const struct BtcMessage *msg, uint32_t *out);
+
+// Copy the command of the message into `buf` as a nul terminated string
+//
+// # Safety
+// `msg` must be a live handle from this API, `buf` valid for writes of `cap` bytes and
+// `len` valid for writes.
+enum BtcStatus btc_message_command(const struct BtcMessage *msg,
+                                   char *buf,
+                                   size_t cap,
+                                   size_t *len);
+
+// Nonce of a `ping` or `pong` message
+//
+// # Safety
+// `msg` must be a live handle from this API and `out` valid for writes.
+enum BtcStatus btc_message_ping_nonce(const struct BtcMessage *msg, uint64_t *out);
+
+// Fields of a `version` message
+//
+// # Safety
+// `msg` must be a live handle from this API and `out` valid for writes.
+enum BtcStatus btc_message_version(const struct BtcMessage *msg, struct BtcVersion *out);
+
+// Copy the user agent of a `version` message into `buf` as a nul terminated string
+//
+// # Safety
+// `msg` must be a live handle from this API, `buf` valid for writes of `cap` bytes and
+// `len` valid for writes.
+enum BtcStatus btc_message_user_agent(const struct BtcMessage *msg,
+                                      char *buf,
+                                      size_t cap,
+                                      size_t *len);
+
+// Encode a message back into `buf`
+//
+// # Safety
+// `msg` must be a live handle from this API, `buf` valid for writes of `cap` bytes and
+// `written` valid for writes.
+enum BtcStatus btc_message_encode(const struct BtcMessage *msg,
+                                  uint8_t *buf,
+                                  size_t cap,
+                                  size_t *written);
+
+// Encode a `version` message with the given fields and user agent
+//
+// # Safety
+// `version` must be valid for reads, `agent` a nul terminated string, `buf` valid for
+// writes of `cap` bytes and `written` valid for writes.
+enum BtcStatus btc_encode_version(uint32_t magic,
+                                  const struct BtcVersion *version,
+                                  const char *agent,
+                                  uint8_t *buf,
+                                  size_t cap,
+                                  size_t *written);
+
+// Encode a `ping` message with the given nonce
+//
+// # Safety
+// `buf` must be valid for writes of `cap` bytes and `written` valid for writes.
+enum BtcStatus btc_encode_ping(uint32_t magic,
+                               uint64_t nonce,
+                               uint8_t *buf,
+                               size_t cap,
+                               size_t *written);
+
+// Encode a `pong` message answering the ping with the given nonce
+//
+// # Safety
+// `buf` must be valid for writes of `cap` bytes and `written` valid for writes.
+enum BtcStatus btc_encode_pong(uint32_t magic,
+                               uint64_t nonce,
+                               uint8_t *buf,
+                               size_t cap,
+                               size_t *written);
+
+#endif /* BTCNETMSG_H */
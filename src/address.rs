@@ -1,45 +1,418 @@
+// address.rs
+//
+// Module for peer addresses.
+//
+// Addresses are made of a network specific address and a port. Peers on the IPv4 and IPv6
+// networks can be dialled directly while other networks (ie Tor) need a proxy. Addresses on
+// the I2P and CJDNS networks are kept so that gossip about them can be relayed and reported
+// but cannot be dialled.
+//
+
 use std::net::{
     SocketAddr,
     IpAddr,
     Ipv4Addr,
     Ipv6Addr
 };
+use sha3::{
+    Sha3_256,
+    Digest
+};
+use crate::encode::Error;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-/// Structure representing an ip address + port combination
-pub struct Address(pub SocketAddr);
+/// Version byte of Tor v3 onion addresses
+const ONION_V3_VERSION: u8 = 0x03;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+/// Network specific part of an address, as defined in BIP-155
+pub enum AddressNetwork {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+
+    // Tor v3 onion service, stored as the ed25519 public key of the service
+    TorV3([u8; 32]),
+
+    // I2P destination, stored as the SHA256 hash of the destination
+    I2p([u8; 32]),
+
+    // CJDNS address, which is an IPv6 address in fc00::/8
+    Cjdns(Ipv6Addr)
+}
+
+impl AddressNetwork {
+    /// Get the BIP-155 network id
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Ipv4(_) => 0x01,
+            Self::Ipv6(_) => 0x02,
+            Self::TorV3(_) => 0x04,
+            Self::I2p(_) => 0x05,
+            Self::Cjdns(_) => 0x06
+        }
+    }
+
+    /// Get the address bytes as encoded in addrv2 messages
+    pub fn bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Ipv4(ip) => ip.octets().to_vec(),
+            Self::Ipv6(ip) => ip.octets().to_vec(),
+            Self::TorV3(key) |
+            Self::I2p(key) => key.to_vec(),
+            Self::Cjdns(ip) => ip.octets().to_vec()
+        }
+    }
+
+    /// Create self from a BIP-155 network id and address bytes.
+    /// Returns `None` for unsupported networks or addresses of the wrong length.
+    pub fn from_id_and_bytes(id: u8, bytes: &[u8]) -> Option<Self> {
+        match (id, bytes.len()) {
+            (0x01, 4) => Some(Self::Ipv4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))),
+            (0x02, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(bytes);
+                Some(Self::Ipv6(Ipv6Addr::from(octets)))
+            },
+            (0x04, 32) | (0x05, 32) => {
+                let mut key = [0; 32];
+                key.copy_from_slice(bytes);
+                match id {
+                    0x04 => Some(Self::TorV3(key)),
+                    _ => Some(Self::I2p(key))
+                }
+            },
+            (0x06, 16) if bytes[0] == 0xFC => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(bytes);
+                Some(Self::Cjdns(Ipv6Addr::from(octets)))
+            },
+            _ => None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+/// Structure representing a network address + port combination
+pub struct Address {
+    pub network: AddressNetwork,
+    pub port: u16
+}
 
 impl Address {
     /// Create a new instance of self from an IP and port
     pub fn new(ip: IpAddr, port: u16) -> Self {
-        Self(SocketAddr::new(ip, port))
+        Self::from(SocketAddr::new(ip, port))
+    }
+
+    /// Create a new instance of self from a Tor v3 public key and port
+    pub fn onion(pubkey: [u8; 32], port: u16) -> Self {
+        Self {
+            network: AddressNetwork::TorV3(pubkey),
+            port
+        }
+    }
+
+    /// Create a new instance of self from the hash of an I2P destination.
+    /// I2P does not use ports, so the port is zero.
+    pub fn i2p(hash: [u8; 32]) -> Self {
+        Self {
+            network: AddressNetwork::I2p(hash),
+            port: 0
+        }
+    }
+
+    /// Create a new instance of self from a CJDNS address and port.
+    /// Returns `None` if the address is not in fc00::/8.
+    pub fn cjdns(ip: Ipv6Addr, port: u16) -> Option<Self> {
+        if ip.octets()[0] != 0xFC { return None }
+        Some(Self {
+            network: AddressNetwork::Cjdns(ip),
+            port
+        })
     }
 
     /// Get the local IP representation of this machine
     pub fn me() -> Self {
-        Address(
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
-        )
+        Self::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
     }
 
-    /// Get the IP stored in self
-    pub fn ip(&self) -> IpAddr {
-        self.0.ip()
+    /// Get the IP stored in self, if it is an IP address
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self.network {
+            AddressNetwork::Ipv4(ip) => Some(IpAddr::V4(ip)),
+            AddressNetwork::Ipv6(ip) => Some(IpAddr::V6(ip)),
+            _ => None
+        }
     }
 
     /// Get the port stoted in self
     pub fn port(&self) -> u16 {
-        self.0.port()
+        self.port
+    }
+
+    /// Get the port stored in self as big endian bytes
+    pub fn port_bytes(&self) -> Port {
+        Port::from(self.port())
     }
 
-    /// Return the underlying SocketAddr structure
-    pub fn inner(&self) -> SocketAddr {
-        self.0
+    /// Return self as a SocketAddr, if it is an IP address
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.ip().map(|ip| SocketAddr::new(ip, self.port))
+    }
+
+    /// Get the host name of self if it is an onion address
+    pub fn onion_host(&self) -> Option<String> {
+        match &self.network {
+            AddressNetwork::TorV3(key) => Some(onion_v3_host(key)),
+            _ => None
+        }
+    }
+
+    /// Check if self can be reached from the public internet or an overlay network.
+    /// Private, loopback, link local and documentation ranges are not routable.
+    pub fn is_routable(&self) -> bool {
+        match &self.network {
+            AddressNetwork::Ipv4(ip) => !(
+                ip.is_unspecified() ||
+                ip.is_private() ||
+                ip.is_loopback() ||
+                ip.is_link_local() ||
+                ip.is_broadcast() ||
+                ip.is_documentation() ||
+                // Shared address space (RFC 6598)
+                (ip.octets()[0] == 100 && ip.octets()[1] & 0xC0 == 64)
+            ),
+            AddressNetwork::Ipv6(ip) => {
+                let seg = ip.segments();
+                !(
+                    ip.is_unspecified() ||
+                    ip.is_loopback() ||
+                    seg[0] & 0xFE00 == 0xFC00 ||            // Unique local (RFC 4193)
+                    seg[0] & 0xFFC0 == 0xFE80 ||            // Link local (RFC 4862)
+                    (seg[0] == 0x2001 && seg[1] == 0x0DB8)  // Documentation (RFC 3849)
+                )
+            },
+            AddressNetwork::TorV3(_) |
+            AddressNetwork::I2p(_) |
+            AddressNetwork::Cjdns(_) => true
+        }
     }
 }
 
 impl From<SocketAddr> for Address {
     fn from(addr: SocketAddr) -> Self {
-        Self(addr)
+        let network = match addr.ip() {
+            IpAddr::V4(ip) => AddressNetwork::Ipv4(ip),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => AddressNetwork::Ipv4(ip),
+                None => AddressNetwork::Ipv6(ip)
+            }
+        };
+
+        Self {
+            network,
+            port: addr.port()
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    /// CJDNS addresses are prefixed with "cjdns:" so they can be told apart from IPv6 addresses
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.network {
+            AddressNetwork::Ipv4(ip) => write!(f, "{}:{}", ip, self.port),
+            AddressNetwork::Ipv6(ip) => write!(f, "[{}]:{}", ip, self.port),
+            AddressNetwork::TorV3(key) => write!(f, "{}:{}", onion_v3_host(key), self.port),
+            AddressNetwork::I2p(hash) => write!(f, "{}:{}", i2p_host(hash), self.port),
+            AddressNetwork::Cjdns(ip) => write!(f, "cjdns:[{}]:{}", ip, self.port)
+        }
     }
-}
\ No newline at end of file
+}
+
+impl std::str::FromStr for Address {
+    type Err = Error;
+
+    /// Parse an address from an "ip:port", "host.onion:port", "host.b32.i2p:port" or
+    /// "cjdns:[ip]:port" string
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("cjdns:") {
+            let addr = addr.parse::<SocketAddr>().map_err(|_| Error::InvalidData)?;
+            return match addr.ip() {
+                IpAddr::V6(ip) => Self::cjdns(ip, addr.port()).ok_or(Error::InvalidData),
+                IpAddr::V4(_) => Err(Error::InvalidData)
+            }
+        }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self::from(addr))
+        }
+
+        let (host, port) = s.rsplit_once(':').ok_or(Error::InvalidData)?;
+        let port = port.parse::<u16>().map_err(|_| Error::InvalidData)?;
+        if let Some(hash) = parse_i2p_host(host) {
+            return Ok(Self { network: AddressNetwork::I2p(hash), port })
+        }
+        let key = parse_onion_v3_host(host).ok_or(Error::InvalidData)?;
+        Ok(Self::onion(key, port))
+    }
+}
+
+/// Checksum of a Tor v3 onion address
+fn onion_v3_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(pubkey);
+    hasher.update([ONION_V3_VERSION]);
+    let hash = hasher.finalize();
+    [hash[0], hash[1]]
+}
+
+/// Get the onion host name of a Tor v3 public key
+pub fn onion_v3_host(pubkey: &[u8; 32]) -> String {
+    let mut data = pubkey.to_vec();
+    data.extend_from_slice(&onion_v3_checksum(pubkey));
+    data.push(ONION_V3_VERSION);
+    format!("{}.onion", base32_encode(&data))
+}
+
+/// Get the Tor v3 public key of an onion host name.
+/// Returns `None` if the host name is not a valid v3 onion address.
+pub fn parse_onion_v3_host(host: &str) -> Option<[u8; 32]> {
+    let data = base32_decode(host.strip_suffix(".onion")?)?;
+    if data.len() != 35 || data[34] != ONION_V3_VERSION { return None }
+
+    let mut key = [0; 32];
+    key.copy_from_slice(&data[..32]);
+    if data[32..34] != onion_v3_checksum(&key) { return None }
+    Some(key)
+}
+
+/// Get the host name of an I2P destination hash
+pub fn i2p_host(hash: &[u8; 32]) -> String {
+    format!("{}.b32.i2p", base32_encode(hash))
+}
+
+/// Get the destination hash of an I2P host name.
+/// Returns `None` if the host name is not a valid b32 I2P address.
+pub fn parse_i2p_host(host: &str) -> Option<[u8; 32]> {
+    let data = base32_decode(host.strip_suffix(".b32.i2p")?)?;
+    if data.len() != 32 { return None }
+
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&data);
+    Some(hash)
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Lowercase RFC 4648 base32 without padding
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buf, mut bits) = (0u32, 0);
+    for byte in data {
+        buf = (buf << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buf >> bits) & 0x1F) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buf << (5 - bits)) & 0x1F) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut buf, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let val = BASE32_ALPHABET.iter().position(|x| *x == c.to_ascii_lowercase())? as u32;
+        buf = (buf << 5) | val;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// TCP/IP Port stored as big endian bytes
+//  Hence the use of [u8; 2] instead of u16.
+pub struct Port(pub [u8; 2]);
+
+impl From<u16> for Port {
+    fn from(port: u16) -> Port {
+        Port(port.to_be_bytes())
+    }
+}
+
+impl From<[u8; 2]> for Port {
+    fn from(port: [u8; 2]) -> Port {
+        Port(port)
+    }
+}
+
+impl From<Port> for u16 {
+    fn from(port: Port) -> u16 {
+        port.to_u16()
+    }
+}
+
+impl Port {
+    pub fn to_u16(&self) -> u16 {
+        u16::from_be_bytes(self.0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn onion_v3_roundtrip() {
+        // Onion address of the Tor Project website
+        let host = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+        let key = parse_onion_v3_host(host).expect("Invalid onion address");
+        assert_eq!(onion_v3_host(&key), host);
+
+        let addr: Address = format!("{}:8333", host).parse().unwrap();
+        assert_eq!(addr, Address::onion(key, 8333));
+        assert_eq!(addr.to_string(), format!("{}:8333", host));
+        assert!(addr.socket_addr().is_none());
+
+        // Corrupting a character breaks the checksum
+        assert!(parse_onion_v3_host(&host.replacen('2', "3", 1)).is_none());
+    }
+
+    #[test]
+    fn i2p_and_cjdns_strings() {
+        let i2p = Address::i2p([0xAB; 32]);
+        assert!(i2p.to_string().ends_with(".b32.i2p:0"));
+        assert_eq!(i2p.to_string().parse::<Address>().unwrap(), i2p);
+
+        let cjdns = Address::cjdns("fc32:17ea:e415:c3bf:9808:149d:b5a2:c9aa".parse().unwrap(), 8333).unwrap();
+        assert_eq!(cjdns.to_string(), "cjdns:[fc32:17ea:e415:c3bf:9808:149d:b5a2:c9aa]:8333");
+        assert_eq!(cjdns.to_string().parse::<Address>().unwrap(), cjdns);
+        assert!(cjdns.socket_addr().is_none());
+        assert!(Address::cjdns("fd00::1".parse().unwrap(), 8333).is_none());
+    }
+
+    #[test]
+    fn routable() {
+        for addr in ["20.1.2.3:8333", "[2600::1]:8333"] {
+            assert!(addr.parse::<Address>().unwrap().is_routable());
+        }
+        for addr in ["127.0.0.1:8333", "10.0.0.1:8333", "100.64.0.1:8333", "[::1]:8333", "[fd00::1]:8333", "[2001:db8::1]:8333"] {
+            assert!(!addr.parse::<Address>().unwrap().is_routable());
+        }
+        assert!(Address::onion([1; 32], 8333).is_routable());
+    }
+
+    #[test]
+    fn mapped_ipv4() {
+        let addr = Address::from("[::ffff:1.2.3.4]:8333".parse::<SocketAddr>().unwrap());
+        assert_eq!(addr.network, AddressNetwork::Ipv4(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+}
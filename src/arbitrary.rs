@@ -0,0 +1,347 @@
+// arbitrary.rs
+//
+// Implementations of `arbitrary::Arbitrary` for fuzzing, enabled with the `arbitrary` feature.
+//
+// Generated values are canonical: encoding and decoding them gives back the same value. So
+// only known network magics are generated, unknown commands never spell a known one and
+// transactions have at least one input, as a transaction without inputs is read back as a
+// witness transaction.
+//
+// Addresses in `version` and `addr` messages use the legacy encoding, which only holds IP
+// addresses, while `addrv2` messages hold addresses of any network. Lists are capped at the
+// limits of the default decode config.
+//
+
+use crate::{
+    address::{
+        Address,
+        AddressNetwork
+    },
+    blockdata::{
+        Block,
+        BlockHeader,
+        Tx
+    },
+    encode::DecodeConfig,
+    msg::{
+        data::{
+            Message,
+            MessagePayload,
+            NetworkMessage
+        },
+        header::{
+            Command,
+            Magic,
+            MessageHeader,
+            COMMANDS
+        },
+        inventory::{
+            BlockdataLocatorInfo,
+            Inventory,
+            MAX_INV_SIZE,
+            MAX_LOCATOR_SIZE
+        },
+        network::{
+            NetAddress,
+            ServicesList,
+            TimestampedNetAddress,
+            VersionMessage
+        },
+        VarString,
+        VariableInteger
+    },
+    bitcoin::{
+        hashes::Hash,
+        BlockHash,
+        OutPoint,
+        Script,
+        Transaction,
+        TxIn,
+        TxMerkleNode,
+        TxOut,
+        Txid
+    }
+};
+use arbitrary::{
+    Arbitrary,
+    Result,
+    Unstructured
+};
+use std::{
+    net::{
+        IpAddr,
+        Ipv6Addr
+    },
+    time::Duration
+};
+
+/// Generate a list of at most `max` values, taking at least eight bytes of data each
+fn list<'a, T>(u: &mut Unstructured<'a>, max: usize, f: impl Fn(&mut Unstructured<'a>) -> Result<T>) -> Result<Vec<T>> {
+    let len = u.arbitrary_len::<u64>()?.min(max);
+    (0..len).map(|_| f(u)).collect()
+}
+
+/// Generate a list of values with their `Arbitrary` implementation
+fn list_of<'a, T: Arbitrary<'a>>(u: &mut Unstructured<'a>, max: usize) -> Result<Vec<T>> {
+    list(u, max, T::arbitrary)
+}
+
+/// Address that can be held by the legacy encoding
+fn ip_address(u: &mut Unstructured) -> Result<Address> {
+    Ok(Address::new(IpAddr::arbitrary(u)?, u.arbitrary()?))
+}
+
+/// Entry of an `addrv2` message, on any network
+fn addrv2_entry(u: &mut Unstructured) -> Result<TimestampedNetAddress> {
+    Ok(TimestampedNetAddress::new(
+        Duration::from_secs(u32::arbitrary(u)? as u64),
+        NetAddress::new(u.arbitrary()?, u.arbitrary()?)
+    ))
+}
+
+impl<'a> Arbitrary<'a> for VariableInteger {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self(u.arbitrary()?))
+    }
+}
+
+impl<'a, const MAX: usize> Arbitrary<'a> for VarString<MAX> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut s = String::arbitrary(u)?;
+        while s.len() > MAX {
+            s.pop();
+        }
+        Ok(Self::new(s).expect("String within the maximum length"))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Magic {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // Messages with an unknown magic are rejected when decoding
+        Ok(u.choose(&[Magic::Main, Magic::Test, Magic::Testnet, Magic::Signet])?.clone())
+    }
+}
+
+impl<'a> Arbitrary<'a> for Command {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        if u.arbitrary()? {
+            return Ok(u.choose(&COMMANDS)?.0.clone())
+        }
+
+        // Unknown commands are up to 12 single byte characters, without trailing nulls
+        let mut bytes = list_of::<u8>(u, 12)?;
+        while bytes.last() == Some(&0) {
+            bytes.pop();
+        }
+        let command = bytes.into_iter().map(|b| b as char).collect::<String>();
+        Ok(Command::from_str(command.clone()).unwrap_or(Command::Unknown(command)))
+    }
+}
+
+impl<'a> Arbitrary<'a> for MessageHeader {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self::new(u.arbitrary()?, u.arbitrary()?, u32::arbitrary(u)? as usize, u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for ServicesList {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self::from_bits(u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for AddressNetwork {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=4)? {
+            0 => Self::Ipv4(u.arbitrary()?),
+            1 => Self::Ipv6(u.arbitrary()?),
+            2 => Self::TorV3(u.arbitrary()?),
+            3 => Self::I2p(u.arbitrary()?),
+            _ => {
+                // CJDNS addresses are in fc00::/8
+                let mut octets: [u8; 16] = u.arbitrary()?;
+                octets[0] = 0xFC;
+                Self::Cjdns(Ipv6Addr::from(octets))
+            }
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Address {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self { network: u.arbitrary()?, port: u.arbitrary()? })
+    }
+}
+
+impl<'a> Arbitrary<'a> for NetAddress {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self::new(u.arbitrary()?, ip_address(u)?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for TimestampedNetAddress {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self::new(Duration::from_secs(u32::arbitrary(u)? as u64), u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for VersionMessage {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self::new(
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?
+        ))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Inventory {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self::from_id_and_hash(u.arbitrary()?, u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for BlockdataLocatorInfo {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let hashes = list(u, MAX_LOCATOR_SIZE, |u| Ok(BlockHash::from_inner(u.arbitrary()?)))?;
+        Ok(Self::new(u.arbitrary()?, hashes, BlockHash::from_inner(u.arbitrary()?)))
+    }
+}
+
+impl<'a> Arbitrary<'a> for BlockHeader {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self::new(
+            u.arbitrary()?,
+            BlockHash::from_inner(u.arbitrary()?),
+            TxMerkleNode::from_inner(u.arbitrary()?),
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?
+        ))
+    }
+}
+
+/// Transaction with at least one input, with or without witnesses
+fn transaction(u: &mut Unstructured) -> Result<Transaction> {
+    let txin = |u: &mut Unstructured| -> Result<TxIn> {
+        Ok(TxIn {
+            previous_output: OutPoint::new(Txid::from_inner(u.arbitrary()?), u.arbitrary()?),
+            script_sig: Script::from(Vec::<u8>::arbitrary(u)?),
+            sequence: u.arbitrary()?,
+            witness: u.arbitrary()?
+        })
+    };
+    let mut input = vec![txin(u)?];
+    input.extend(list(u, usize::MAX, txin)?);
+    let output = list(u, usize::MAX, |u| Ok(TxOut { value: u.arbitrary()?, script_pubkey: Script::from(Vec::<u8>::arbitrary(u)?) }))?;
+
+    Ok(Transaction { version: u.arbitrary()?, lock_time: u.arbitrary()?, input, output })
+}
+
+impl<'a> Arbitrary<'a> for Tx {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self::new(transaction(u)?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Block {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Self::new(u.arbitrary()?, list(u, usize::MAX, transaction)?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for NetworkMessage {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let DecodeConfig { max_addrs, max_headers, .. } = DecodeConfig::default();
+        Ok(match u.int_in_range(0..=21)? {
+            0 => Self::Version(u.arbitrary()?),
+            1 => Self::Verack,
+            2 => Self::SendHeaders,
+            3 => Self::WTxIdRelay,
+            4 => Self::Ping(u.arbitrary()?),
+            5 => Self::Pong(u.arbitrary()?),
+            6 => Self::Addr(list_of(u, max_addrs)?),
+            7 => Self::GetAddr,
+            8 => Self::Inv(list_of(u, MAX_INV_SIZE)?),
+            9 => Self::GetData(list_of(u, MAX_INV_SIZE)?),
+            10 => Self::NotFound(list_of(u, MAX_INV_SIZE)?),
+            11 => Self::Tx(transaction(u)?),
+            12 => Self::GetBlocks(u.arbitrary()?),
+            13 => Self::GetHeaders(u.arbitrary()?),
+            14 => Self::Block(u.arbitrary()?),
+            15 => Self::Headers(list_of(u, max_headers)?),
+            16 => Self::MemPool,
+            17 => Self::FilterClear,
+            18 => Self::SendAddrV2,
+            19 => Self::AddrV2(list(u, max_addrs, addrv2_entry)?),
+            20 => {
+                let raw = COMMANDS.iter().map(|(c, _, _)| c).filter(|c| c.has_raw_payload()).collect::<Vec<&Command>>();
+                Self::Raw { command: (*u.choose(&raw)?).clone(), payload: u.arbitrary()? }
+            },
+            _ => match Command::arbitrary(u)? {
+                Command::Unknown(command) => Self::Unknown { command, payload: u.arbitrary()? },
+                _ => Self::Unknown { command: String::from("unknown"), payload: u.arbitrary()? }
+            }
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for MessagePayload {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(NetworkMessage::arbitrary(u)?.into_payload())
+    }
+}
+
+impl<'a> Arbitrary<'a> for Message {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let magic = u.arbitrary()?;
+        Ok(Self::from_payload(u.arbitrary()?, magic))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{
+        Encode,
+        Decode
+    };
+    use rand::RngCore;
+
+    fn roundtrip<T>(bytes: &[u8])
+    where T: for<'a> Arbitrary<'a> + Encode + Decode + PartialEq + std::fmt::Debug {
+        let value = match T::arbitrary(&mut Unstructured::new(bytes)) {
+            Ok(value) => value,
+            Err(_) => return
+        };
+        let mut enc = Vec::new();
+        assert_eq!(value.net_encode(&mut enc), value.encoded_size());
+        assert_eq!(T::net_decode(&enc[..]).expect("Failed to decode"), value);
+    }
+
+    #[test]
+    fn arbitrary_roundtrips() {
+        let mut rng = rand::thread_rng();
+        let mut bytes = vec![0; 4096];
+        for i in 0..500 {
+            rng.fill_bytes(&mut bytes);
+            let bytes = &bytes[..i % bytes.len() + 1];
+            roundtrip::<Message>(bytes);
+            roundtrip::<MessageHeader>(bytes);
+            roundtrip::<VersionMessage>(bytes);
+            roundtrip::<Inventory>(bytes);
+            roundtrip::<VariableInteger>(bytes);
+            roundtrip::<TimestampedNetAddress>(bytes);
+            roundtrip::<BlockHeader>(bytes);
+            roundtrip::<Block>(bytes);
+            roundtrip::<Tx>(bytes);
+        }
+    }
+}
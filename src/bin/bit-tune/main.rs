@@ -0,0 +1,372 @@
+// main.rs
+//
+// Command line tool tuning in to the bitcoin network.
+//
+// Subcommands:
+//  - listen: keep outbound connections to peers and print the messages they send until
+//    interrupted. SIGINT and SIGTERM shut the connection manager down, which saves the known
+//    peers and anchors and prints the statistics of the session.
+//  - handshake <ADDR>: do the handshake with a peer and print its version message
+//  - crawl: crawl the network with `getaddr` from the DNS seeds and print a census of the nodes
+//  - headers-sync: sync the block headers from a peer
+//
+// The network is chosen with `--network` (main, test, signet or regtest), which sets the magic
+// of the messages, the default port of peers, the DNS seeds and the genesis header.
+//
+// Peers are found through the DNS seeds unless given with `--connect`, as host names or IP
+// addresses with an optional port. `listen` also takes `--addnode` for peers connected to in
+// addition to the ones found by discovery.
+//
+// With the tui feature, `listen --tui` shows a dashboard of the connected peers, the message
+// rates per command and the recent announcements instead of printing every message.
+//
+// Invalid arguments are reported with the usage, and failures with a non-zero exit code.
+//
+
+use btcnetmsg::{
+    address::Address,
+    blockdata::{
+        chain::HeaderChain,
+        params::ChainParams,
+        store::{
+            FileStore,
+            HeaderStore
+        }
+    },
+    msg::network::{
+        VersionConfig,
+        VersionMessage
+    },
+    net::{
+        crawl::{
+            self,
+            CrawlConfig
+        },
+        eventloop::Event,
+        handshake,
+        manager::{
+            ConnectionManager,
+            ManagerConfig,
+            PeerTarget
+        },
+        stream::PeerStream,
+        Error
+    },
+    seeds
+};
+use clap::{
+    error::ErrorKind,
+    Args,
+    CommandFactory,
+    Parser,
+    Subcommand
+};
+use std::{
+    net::{
+        SocketAddr,
+        TcpStream,
+        ToSocketAddrs
+    },
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering
+        },
+        Arc
+    },
+    time::Duration
+};
+
+#[cfg(feature = "tui")]
+mod tui;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Parser)]
+#[command(name = "bit-tune", version, about = "Tune in to the bitcoin network")]
+struct Cli {
+    /// Network to connect to: main, test, signet or regtest
+    #[arg(long, global = true, value_name = "NAME", default_value = "main", value_parser = network)]
+    network: ChainParams,
+
+    #[command(subcommand)]
+    command: Commands
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Keep outbound connections to peers and print the messages they send until interrupted
+    Listen(ListenArgs),
+
+    /// Do the handshake with a peer and print its version message
+    Handshake(HandshakeArgs),
+
+    /// Crawl the network with getaddr from the DNS seeds and print a census of the nodes
+    Crawl(CrawlArgs),
+
+    /// Sync the block headers from a peer
+    HeadersSync(HeadersSyncArgs)
+}
+
+#[derive(Args)]
+struct ListenArgs {
+    /// File the known peers are loaded from and saved to
+    #[arg(long, value_name = "FILE")]
+    peers_file: Option<PathBuf>,
+
+    /// File the anchor peers are saved to on shutdown and connected to on startup
+    #[arg(long, value_name = "FILE")]
+    anchors_file: Option<PathBuf>,
+
+    /// Address to accept inbound connections on
+    #[arg(long, value_name = "ADDR")]
+    bind: Option<SocketAddr>,
+
+    /// Number of outbound peers to keep connected
+    #[arg(long, value_name = "COUNT", default_value_t = 8, value_parser = positive)]
+    outbound: u64,
+
+    /// Only connect to this peer instead of finding peers through the DNS seeds and the known
+    /// peers. Can be given several times.
+    #[arg(long, value_name = "PEER")]
+    connect: Vec<String>,
+
+    /// Connect to this peer in addition to the peers that are found. Can be given several times.
+    #[arg(long, value_name = "PEER")]
+    addnode: Vec<String>,
+
+    /// Show a dashboard of the peers, message rates and announcements instead of printing every
+    /// message
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    tui: bool
+}
+
+#[derive(Args)]
+struct HandshakeArgs {
+    /// Peer to connect to, as host, host:port or [ipv6]:port
+    addr: String,
+
+    /// Seconds to wait for the connection and the handshake
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = positive)]
+    timeout: u64
+}
+
+#[derive(Args)]
+struct CrawlArgs {
+    /// Maximum number of nodes connected to
+    #[arg(long, value_name = "COUNT", default_value_t = 100, value_parser = positive)]
+    max_nodes: u64,
+
+    /// Seconds each node has to complete the handshake and answer with addresses
+    #[arg(long, value_name = "SECS", default_value_t = 30, value_parser = positive)]
+    timeout: u64,
+
+    /// Node to start the crawl from instead of the DNS seeds. Can be given several times.
+    #[arg(long, value_name = "PEER")]
+    connect: Vec<String>,
+
+    /// File the snapshot of the crawl is saved to, as JSON
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>
+}
+
+#[derive(Args)]
+struct HeadersSyncArgs {
+    /// File the headers are stored in, so later syncs continue from its tip
+    #[arg(long, value_name = "FILE")]
+    store: Option<PathBuf>,
+
+    /// Seconds to wait for the handshake and each batch of headers
+    #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = positive)]
+    timeout: u64,
+
+    /// Peer to sync from instead of one from the DNS seeds. Several peers are tried in order.
+    #[arg(long, value_name = "PEER")]
+    connect: Vec<String>
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let params = cli.network;
+    let res = match cli.command {
+        Commands::Listen(args) => listen(&params, args),
+        Commands::Handshake(args) => handshake(&params, args),
+        Commands::Crawl(args) => crawl(&params, args),
+        Commands::HeadersSync(args) => headers_sync(&params, args)
+    };
+    if let Err(e) = res {
+        eprintln!("error: {}", e);
+        process::exit(1)
+    }
+}
+
+/// Parse the name of a network
+fn network(s: &str) -> std::result::Result<ChainParams, String> {
+    ChainParams::from_name(s).ok_or_else(|| String::from("expected main, test, signet or regtest"))
+}
+
+/// Parse a count or duration that cannot be zero
+fn positive(s: &str) -> std::result::Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err(String::from("must be at least 1")),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+/// Parse a peer given on the command line, using the default port of the network.
+/// Exits with the usage if the peer is invalid.
+fn target(s: &str, params: &ChainParams) -> PeerTarget {
+    PeerTarget::parse(s, params.default_port).unwrap_or_else(|_| {
+        Cli::command()
+            .error(ErrorKind::ValueValidation, format!("invalid peer '{}', expected host, host:port or [ipv6]:port", s))
+            .exit()
+    })
+}
+
+/// Resolve the host name of a peer
+fn resolve(target: &PeerTarget) -> Result<SocketAddr> {
+    (target.host.as_str(), target.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("{} did not resolve to an address", target).into())
+}
+
+/// Resolve the peers given with `--connect`, or the DNS seeds of the network if there are none
+fn peers(connect: &[String], params: &ChainParams) -> Result<Vec<Address>> {
+    if connect.is_empty() {
+        let seeds = seeds::resolve(params);
+        if seeds.is_empty() {
+            return Err("no seed nodes resolved".into())
+        }
+        return Ok(seeds)
+    }
+
+    // Every peer is checked before any is resolved, so a typo is reported with the usage
+    let targets = connect.iter().map(|s| target(s, params)).collect::<Vec<PeerTarget>>();
+    targets.iter().map(|t| resolve(t).map(Address::from)).collect()
+}
+
+/// Connect to a peer and do the handshake
+fn open(addr: SocketAddr, params: &ChainParams, timeout: Duration) -> Result<(PeerStream, VersionMessage)> {
+    let socket = TcpStream::connect_timeout(&addr, timeout).map_err(|_| Error::FailedToConnect(addr.to_string()))?;
+    let mut stream = PeerStream::new(socket, params.magic.clone())?;
+    let version = handshake::initiate(&mut stream, VersionConfig::default().message(Address::from(addr)), timeout)?;
+    Ok((stream, version))
+}
+
+/// Connect to the first of the peers that completes the handshake
+fn open_any(addrs: &[Address], params: &ChainParams, timeout: Duration) -> Result<PeerStream> {
+    for addr in addrs.iter().filter_map(|a| a.socket_addr()) {
+        if let Ok((stream, _)) = open(addr, params, timeout) {
+            return Ok(stream)
+        }
+    }
+    Err("no peer completed the handshake".into())
+}
+
+fn listen(params: &ChainParams, args: ListenArgs) -> Result<()> {
+    // Host names are resolved by the manager when it connects
+    let connect_only = !args.connect.is_empty();
+    let config = ManagerConfig {
+        peers_file: args.peers_file,
+        anchors_file: args.anchors_file,
+        listen: args.bind,
+        connect: args.connect.iter().chain(args.addnode.iter()).map(|s| target(s, params)).collect(),
+        connect_only,
+        ..ManagerConfig::for_network(params)
+    };
+    let mut manager = ConnectionManager::new(config)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))?;
+
+    // Seeds are only needed until the address manager has learnt other peers, and never with
+    // --connect, where the manager does not select peers from the address manager either
+    let mut seeds = match connect_only || !manager.addrman().is_empty() {
+        true => Vec::new(),
+        false => seeds::resolve(params)
+    };
+    #[cfg(feature = "tui")]
+    let mut dashboard = args.tui.then(tui::Dashboard::new);
+    while !stop.load(Ordering::SeqCst) {
+        // One connection attempt per round, so a signal does not wait on many connect timeouts
+        if (manager.outbound_count() as u64) < args.outbound {
+            if let Some(addr) = seeds.pop().or_else(|| manager.select_outbound()) {
+                let _ = manager.connect_outbound(&addr);
+            }
+        }
+        for event in manager.poll() {
+            if let Event::Message(id, msg) = event {
+                if let Some(peer) = manager.peer(id) {
+                    #[cfg(feature = "tui")]
+                    if let Some(dashboard) = dashboard.as_mut() {
+                        dashboard.record(peer.addr, &msg);
+                        continue
+                    }
+                    println!("{} {} ({} bytes)", peer.addr, msg.header().command.to_str(), msg.header().length);
+                }
+            }
+        }
+        #[cfg(feature = "tui")]
+        if let Some(dashboard) = dashboard.as_mut() {
+            dashboard.draw(&manager)?;
+        }
+        if let Err(e) = manager.tick() {
+            eprintln!("Failed to save the peers: {}", e);
+        }
+    }
+
+    let stats = manager.shutdown()?;
+    println!("{}", stats);
+    Ok(())
+}
+
+fn handshake(params: &ChainParams, args: HandshakeArgs) -> Result<()> {
+    let target = target(&args.addr, params);
+    let (stream, version) = open(resolve(&target)?, params, Duration::from_secs(args.timeout))?;
+    println!("{}", version);
+    let _ = stream.shutdown();
+    Ok(())
+}
+
+fn crawl(params: &ChainParams, args: CrawlArgs) -> Result<()> {
+    let config = CrawlConfig {
+        magic: params.magic.clone(),
+        max_nodes: args.max_nodes as usize,
+        timeout: Duration::from_secs(args.timeout),
+        ..CrawlConfig::default()
+    };
+    let start = peers(&args.connect, params)?;
+    let snapshot = crawl::crawl(&start, &config);
+    println!("{} reachable, {} unreachable, {} addresses", snapshot.reachable.len(), snapshot.unreachable.len(), snapshot.addrs.len());
+    println!("{}", snapshot.census());
+    if let Some(path) = args.output {
+        snapshot.save(path)?;
+    }
+    Ok(())
+}
+
+fn headers_sync(params: &ChainParams, args: HeadersSyncArgs) -> Result<()> {
+    let timeout = Duration::from_secs(args.timeout);
+    let peers = peers(&args.connect, params)?;
+    match args.store {
+        Some(path) => sync(HeaderChain::with_store(params.clone(), FileStore::open(path)?)?, &peers, timeout),
+        None => sync(HeaderChain::new(params.clone()), &peers, timeout)
+    }
+}
+
+fn sync<S: HeaderStore>(mut chain: HeaderChain<S>, peers: &[Address], timeout: Duration) -> Result<()> {
+    let params = chain.params().clone();
+    let mut stream = open_any(peers, &params, timeout)?;
+    let res = chain.sync(&mut stream, timeout);
+    let _ = stream.shutdown();
+
+    let added = res?;
+    println!("{} headers added, height {} tip {}", added, chain.best_height(), chain.tip());
+    Ok(())
+}
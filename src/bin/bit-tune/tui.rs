@@ -0,0 +1,164 @@
+// tui.rs
+//
+// Terminal dashboard of the listen subcommand.
+//
+// The screen is redrawn with ANSI escape codes about once a second. It shows the connected
+// peers with their user agent, start height and ping, the rate of the messages received per
+// command over the last seconds, and the most recent inv, tx and block announcements.
+//
+
+use btcnetmsg::{
+    address::Address,
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        inventory::Inventory
+    },
+    net::manager::ConnectionManager
+};
+use std::{
+    collections::{
+        HashMap,
+        VecDeque
+    },
+    fmt::Write as _,
+    io::{
+        self,
+        Write
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Time between redraws
+const REDRAW: Duration = Duration::from_secs(1);
+
+/// Window the message rates are averaged over
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Number of announcements shown
+const ANNOUNCEMENTS: usize = 15;
+
+/// Clear the screen and move the cursor to the top left corner
+const CLEAR: &str = "\x1b[2J\x1b[H";
+
+/// Announcement of an inventory item, transaction or block by a peer
+struct Announcement {
+    at: Instant,
+    addr: Address,
+    what: String
+}
+
+/// Dashboard of the messages received by a connection manager
+pub struct Dashboard {
+    /// Receive times of the recent messages of each command
+    received: HashMap<String, VecDeque<Instant>>,
+    announcements: VecDeque<Announcement>,
+    last_draw: Option<Instant>
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Self {
+            received: HashMap::new(),
+            announcements: VecDeque::new(),
+            last_draw: None
+        }
+    }
+
+    /// Record a message received from a peer
+    pub fn record(&mut self, addr: Address, msg: &Message) {
+        let now = Instant::now();
+        self.received.entry(msg.header().command.to_str().to_string()).or_default().push_back(now);
+
+        let announced = match msg.payload() {
+            MessagePayload::InvVect(inv) if msg.header().command.to_str() == "inv" => {
+                inv.iter().map(inventory).collect::<Vec<String>>()
+            },
+            MessagePayload::Transction(tx) => vec![format!("tx    {}", tx.txid())],
+            MessagePayload::Block(block) => vec![format!("block {}", block.hash())],
+            _ => Vec::new()
+        };
+        for what in announced {
+            self.announcements.push_front(Announcement { at: now, addr, what });
+        }
+        self.announcements.truncate(ANNOUNCEMENTS);
+    }
+
+    /// Redraw the screen if it was last drawn more than a second ago
+    pub fn draw(&mut self, manager: &ConnectionManager) -> io::Result<()> {
+        if matches!(self.last_draw, Some(at) if at.elapsed() < REDRAW) { return Ok(()) }
+        self.last_draw = Some(Instant::now());
+        self.expire();
+
+        let mut screen = String::from(CLEAR);
+        self.render(manager, &mut screen).expect("Writing to a string does not fail");
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()
+    }
+
+    /// Forget the messages received before the rate window
+    fn expire(&mut self) {
+        for times in self.received.values_mut() {
+            while matches!(times.front(), Some(at) if at.elapsed() > RATE_WINDOW) {
+                times.pop_front();
+            }
+        }
+        self.received.retain(|_, times| !times.is_empty());
+    }
+
+    fn render(&self, manager: &ConnectionManager, out: &mut String) -> std::fmt::Result {
+        let stats = manager.session_stats();
+        writeln!(out, "bit-tune on {}, up {}s, {} peers, {} known addresses\n",
+            manager.config().network.magic,
+            stats.uptime.as_secs(),
+            stats.connected,
+            stats.known_addrs
+        )?;
+
+        writeln!(out, "{:<46} {:<30} {:>8} {:>8}", "PEER", "USER AGENT", "HEIGHT", "PING")?;
+        for peer in manager.peer_infos() {
+            let addr = format!("{}{}", peer.addr, if peer.inbound { " (in)" } else { "" });
+            let ping = peer.ping.map_or(String::from("-"), |p| format!("{}ms", p.as_millis()));
+            writeln!(out, "{:<46} {:<30} {:>8} {:>8}", addr, truncate(&peer.user_agent, 30), peer.start_height, ping)?;
+        }
+
+        writeln!(out, "\nMESSAGES/S (LAST {}S)", RATE_WINDOW.as_secs())?;
+        let mut rates = self.received
+            .iter()
+            .map(|(command, times)| (command.as_str(), times.len() as f64 / RATE_WINDOW.as_secs_f64()))
+            .collect::<Vec<(&str, f64)>>();
+        rates.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+        for (command, rate) in rates {
+            writeln!(out, "{:<12} {:>8.1}", command, rate)?;
+        }
+
+        writeln!(out, "\nANNOUNCEMENTS")?;
+        for a in self.announcements.iter() {
+            writeln!(out, "{:>4}s ago  {:<46} {}", a.at.elapsed().as_secs(), a.addr.to_string(), a.what)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describe an announced inventory item
+fn inventory(inv: &Inventory) -> String {
+    match inv {
+        Inventory::Tx(txid) |
+        Inventory::WitnessTx(txid) => format!("inv   tx {}", txid),
+        Inventory::Block(hash) |
+        Inventory::WitnessBlock(hash) |
+        Inventory::CompactBlock(hash) => format!("inv   block {}", hash),
+        other => format!("inv   type {}", other.identifier())
+    }
+}
+
+/// Cut a string to a number of characters
+fn truncate(s: &str, len: usize) -> String {
+    s.chars().take(len).collect()
+}
@@ -0,0 +1,266 @@
+// block.rs
+//
+// Block and block header structures as sent in `block` and `headers` messages.
+//
+// The header is 80 bytes on the wire and its double SHA256 is the hash of the block.
+// Transactions are parsed with rust-bitcoin, including their witnesses. With the
+// `consensus` feature, headers and blocks are also encoded by rust-bitcoin.
+//
+
+use crate::{
+    bitcoin::hash_types::{
+        BlockHash,
+        Txid,
+        TxMerkleNode
+    },
+    bitcoin::hashes::Hash,
+    bitcoin::Transaction,
+    blockdata::{
+        merkle,
+        params::ChainParams,
+        pow::{
+            self,
+            Uint256
+        }
+    },
+    encode::Encode,
+    msg::header::sha256d
+};
+#[cfg(not(feature = "consensus"))]
+use btcnetmsg_derive::{
+    Encode,
+    Decode
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(not(feature = "consensus"), derive(Encode, Decode))]
+/// Header of a block
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_blockhash: BlockHash,
+    pub merkle_root: TxMerkleNode,
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32
+}
+
+impl BlockHeader {
+    /// Size of an encoded header in bytes
+    pub const SIZE: usize = 80;
+
+    pub fn new(version: i32, prev_blockhash: BlockHash, merkle_root: TxMerkleNode, time: u32, bits: u32, nonce: u32) -> Self {
+        Self {
+            version,
+            prev_blockhash,
+            merkle_root,
+            time,
+            bits,
+            nonce
+        }
+    }
+
+    /// Header of the genesis block of the main network
+    pub fn genesis() -> Self {
+        ChainParams::main().genesis
+    }
+
+    /// Hash of the block, the double SHA256 of the encoded header
+    pub fn hash(&self) -> BlockHash {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        self.net_encode(&mut buf);
+        BlockHash::from_inner(sha256d(buf))
+    }
+
+    /// Target the hash of the header has to meet, decoded from `bits`.
+    /// Returns `None` if the target is invalid.
+    pub fn target(&self) -> Option<Uint256> {
+        pow::target_from_compact(self.bits)
+    }
+
+    /// Expected number of hashes needed to mine the header, zero if its target is invalid
+    pub fn work(&self) -> Uint256 {
+        self.target().map_or(Uint256::default(), |t| pow::work(&t))
+    }
+
+    /// Check that the hash of the header meets the target it claims
+    pub fn check_pow(&self) -> bool {
+        match self.target() {
+            Some(target) => pow::hash_to_u256(&self.hash()) <= target,
+            None => false
+        }
+    }
+}
+
+impl From<crate::bitcoin::BlockHeader> for BlockHeader {
+    fn from(h: crate::bitcoin::BlockHeader) -> Self {
+        Self::new(h.version, h.prev_blockhash, h.merkle_root, h.time, h.bits, h.nonce)
+    }
+}
+
+impl From<BlockHeader> for crate::bitcoin::BlockHeader {
+    fn from(h: BlockHeader) -> Self {
+        Self {
+            version: h.version,
+            prev_blockhash: h.prev_blockhash,
+            merkle_root: h.merkle_root,
+            time: h.time,
+            bits: h.bits,
+            nonce: h.nonce
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Block with its transactions
+pub struct Block {
+    pub header: BlockHeader,
+    pub txs: Vec<Transaction>
+}
+
+impl Block {
+    pub fn new(header: BlockHeader, txs: Vec<Transaction>) -> Self {
+        Self {
+            header,
+            txs
+        }
+    }
+
+    /// Hash of the block header
+    pub fn hash(&self) -> BlockHash {
+        self.header.hash()
+    }
+
+    /// Check that the merkle root in the header matches the transactions, and that the
+    /// transactions were not mutated by repeating the last ones.
+    pub fn check_merkle_root(&self) -> bool {
+        let txids = self.txs.iter().map(|tx| tx.txid()).collect::<Vec<Txid>>();
+        match merkle::merkle_root_mutated(&txids) {
+            (root, false) => !txids.is_empty() && root == self.header.merkle_root,
+            (_, true) => false
+        }
+    }
+
+    /// Iterate over the transactions of the block, the coinbase first
+    pub fn iter(&self) -> std::slice::Iter<'_, Transaction> {
+        self.txs.iter()
+    }
+}
+
+impl IntoIterator for Block {
+    type Item = Transaction;
+    type IntoIter = std::vec::IntoIter<Transaction>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.txs.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Block {
+    type Item = &'a Transaction;
+    type IntoIter = std::slice::Iter<'a, Transaction>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl From<crate::bitcoin::Block> for Block {
+    fn from(b: crate::bitcoin::Block) -> Self {
+        Self::new(b.header.into(), b.txdata)
+    }
+}
+
+impl From<Block> for crate::bitcoin::Block {
+    fn from(b: Block) -> Self {
+        Self {
+            header: b.header.into(),
+            txdata: b.txs
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitcoin::hashes::hex::FromHex,
+        encode::Decode
+    };
+
+    // Header of the genesis block as sent on the wire
+    const GENESIS_HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+
+    #[test]
+    fn genesis_header() {
+        let bytes = Vec::<u8>::from_hex(GENESIS_HEADER).unwrap();
+        let header = BlockHeader::net_decode(&bytes[..]).unwrap();
+        assert_eq!(header, BlockHeader::genesis());
+
+        let mut enc = Vec::new();
+        assert_eq!(header.net_encode(&mut enc), BlockHeader::SIZE);
+        assert_eq!(header.encoded_size(), BlockHeader::SIZE);
+        assert_eq!(enc, bytes);
+
+        assert_eq!(header.hash().to_string(), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        assert_eq!(BlockHeader::from(genesis.header), header);
+        assert_eq!(header.hash(), genesis.block_hash());
+    }
+
+    #[test]
+    fn segwit_block() {
+        // Block with a coinbase and a transaction spending a witness output
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        let mut spend = genesis.txdata[0].clone();
+        spend.input[0].witness = vec![vec![1; 72], vec![2; 33]];
+        let block = Block::new(BlockHeader::genesis(), vec![genesis.txdata[0].clone(), spend.clone()]);
+
+        let mut enc = Vec::new();
+        assert_eq!(block.net_encode(&mut enc), block.encoded_size());
+        assert_eq!(enc, crate::bitcoin::consensus::serialize(&crate::bitcoin::Block::from(block.clone())));
+
+        assert!(!block.check_merkle_root());
+        let genesis = Block::from(genesis);
+        assert!(genesis.check_merkle_root());
+
+        let dec = Block::net_decode(&enc[..]).unwrap();
+        assert_eq!(dec, block);
+        assert_eq!(dec.iter().map(|tx| tx.wtxid()).collect::<Vec<_>>(), vec![genesis.txs[0].wtxid(), spend.wtxid()]);
+        assert!(Block::net_decode(&enc[..enc.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn matches_consensus_encoding() {
+        // Runs against both backends, with and without the `consensus` feature
+        use crate::bitcoin::consensus::{deserialize, serialize};
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        let mut spend = genesis.txdata[0].clone();
+        spend.input[0].witness = vec![vec![3; 64]];
+        let blocks = vec![
+            Block::new(BlockHeader::genesis(), vec![]),
+            Block::from(genesis.clone()),
+            Block::new(BlockHeader::new(-1, genesis.block_hash(), genesis.header.merkle_root, u32::MAX, 0x207fffff, 7), vec![genesis.txdata[0].clone(), spend])
+        ];
+
+        for block in blocks {
+            let theirs = crate::bitcoin::Block::from(block.clone());
+            let mut enc = Vec::new();
+            assert_eq!(block.net_encode(&mut enc), block.encoded_size());
+            assert_eq!(enc, serialize(&theirs));
+            assert_eq!(Block::net_decode(&enc[..]).unwrap(), block);
+
+            let mut header = Vec::new();
+            block.header.net_encode(&mut header);
+            assert_eq!(header, serialize(&theirs.header));
+            assert_eq!(BlockHeader::net_decode(&header[..]).unwrap(), block.header);
+
+            // Both reject every truncation of the block
+            for len in 0..enc.len() {
+                assert!(Block::net_decode(&enc[..len]).is_err());
+                assert!(deserialize::<crate::bitcoin::Block>(&enc[..len]).is_err());
+            }
+        }
+    }
+}
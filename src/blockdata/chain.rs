@@ -0,0 +1,725 @@
+// chain.rs
+//
+// Chain of block headers synced from peers.
+//
+// Headers are linked to the chain by their previous block hash. Syncing is headers first:
+// the peer is sent a `getheaders` from the tip and answers with up to 2000 headers
+// following it, which is repeated until the peer answers with a smaller batch.
+//
+// The block locator sent with `getheaders` lists the hashes of the last 10 blocks, then
+// of blocks exponentially further back, and finally of the genesis block, so the peer can
+// find the fork point in few hashes even if the chains diverged long ago.
+//
+// Header timestamps have to be after the median time of the previous 11 headers and at
+// most two hours in the future. The current time comes from the system clock unless
+// another clock is set, ie on targets without one.
+//
+// Headers contradicting a checkpoint of the network are rejected, so a peer cannot make
+// the chain follow a branch of cheap low difficulty headers below the checkpoint.
+//
+// Headers are kept in a [`HeaderStore`], in memory by default.
+//
+// The active chain is the branch with the most cumulative work, which is the expected
+// number of hashes needed to mine all of its headers. Headers of competing branches are
+// validated against their own ancestors and kept aside. When a branch gets more work than
+// the active chain, the headers above the fork point are disconnected and the branch is
+// connected instead, which is reported as a reorg event.
+//
+
+use crate::{
+    bitcoin::{
+        hash_types::BlockHash,
+        hashes::Hash
+    },
+    blockdata::{
+        params::ChainParams,
+        pow::{
+            self,
+            Uint256
+        },
+        store::{
+            HeaderStore,
+            MemoryStore
+        },
+        BlockHeader
+    },
+    msg::{
+        data::NetworkMessage,
+        inventory::BlockdataLocatorInfo
+    }
+};
+#[cfg(feature = "net")]
+use crate::net::stream::PeerStream;
+use std::{
+    collections::{
+        HashMap,
+        HashSet
+    },
+    time::{
+        Duration,
+        SystemTime
+    }
+};
+
+/// Maximum number of headers in a `headers` message
+pub const MAX_HEADERS: usize = 2000;
+
+/// Number of previous headers the median time past is computed over
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far in the future the timestamp of a header may be
+pub const MAX_FUTURE_BLOCK_TIME: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Protocol version sent in `getheaders` messages
+const LOCATOR_VERSION: u32 = 70015;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Reasons for headers to be rejected by the chain
+pub enum Error {
+    /// The previous block of the header is not known
+    Orphan(BlockHash),
+
+    /// The hash of the header does not meet its target, or the target is easier than the
+    /// network allows
+    InvalidPow(BlockHash),
+
+    /// The header forks off the chain below the last checkpoint
+    Fork(BlockHash),
+
+    /// The target of the header is not the one required at its height
+    Difficulty {
+        expected: u32,
+        hash: BlockHash
+    },
+
+    /// The timestamp of the header is not after the median time past
+    TimeTooOld(BlockHash),
+
+    /// The timestamp of the header is too far in the future
+    TimeTooNew(BlockHash),
+
+    /// The header is at a checkpointed height but does not match the checkpoint
+    Checkpoint {
+        height: usize,
+        hash: BlockHash
+    },
+
+    /// The header store failed
+    Store(String)
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Orphan(h) => write!(f, "previous block of {} is unknown", h),
+            Self::InvalidPow(h) => write!(f, "{} does not meet its target", h),
+            Self::Fork(h) => write!(f, "{} forks off the chain below the last checkpoint", h),
+            Self::Difficulty { expected, hash } => write!(f, "{} does not have the expected target {:#010x}", hash, expected),
+            Self::TimeTooOld(h) => write!(f, "{} is not after the median time past", h),
+            Self::TimeTooNew(h) => write!(f, "{} is too far in the future", h),
+            Self::Checkpoint { height, hash } => write!(f, "{} contradicts the checkpoint at height {}", hash, height),
+            Self::Store(e) => write!(f, "header store error: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Changes of the chain that consumers may want to react to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The active chain switched to a branch with more work
+    Reorg {
+        old_tip: BlockHash,
+        new_tip: BlockHash,
+
+        /// Number of headers of the old chain that were disconnected
+        depth: usize
+    }
+}
+
+/// Header of a branch that is not part of the active chain
+#[derive(Clone, Debug)]
+struct ForkHeader {
+    header: BlockHeader,
+    work: Uint256
+}
+
+/// Chain of headers from the genesis block to the tip with the most work.
+/// Headers of competing branches are kept, so the chain can switch to them if they end up
+/// with more work.
+#[derive(Clone, Debug)]
+pub struct HeaderChain<S = MemoryStore> {
+    params: ChainParams,
+    store: S,
+
+    /// Cumulative work of the active chain at each height
+    work: Vec<Uint256>,
+    forks: HashMap<BlockHash, ForkHeader>,
+    events: Vec<Event>,
+
+    /// Current unix time in seconds
+    clock: fn() -> u64
+}
+
+impl Default for HeaderChain {
+    fn default() -> Self {
+        Self::new(ChainParams::main())
+    }
+}
+
+impl HeaderChain {
+    /// Create a chain in memory holding only the genesis header of a network
+    pub fn new(params: ChainParams) -> Self {
+        Self::with_store(params, MemoryStore::new()).expect("Memory store does not fail")
+    }
+}
+
+impl<S: HeaderStore> HeaderChain<S> {
+    /// Create a chain from the headers of a store.
+    /// The genesis header is added to empty stores. Returns an error if the store holds
+    /// the headers of another network.
+    pub fn with_store(params: ChainParams, mut store: S) -> Result<Self, Error> {
+        match store.get_at(0) {
+            Some(genesis) if genesis != params.genesis => return Err(Error::Store(String::from("store is of another network"))),
+            Some(_) => {},
+            None => store.append(params.genesis).map_err(|e| Error::Store(e.to_string()))?
+        }
+
+        let mut work: Vec<Uint256> = Vec::with_capacity(store.len());
+        for height in 0..store.len() {
+            let header = store.get_at(height).expect("Height in the store");
+            work.push(work.last().copied().unwrap_or_default() + header.work());
+        }
+        Ok(Self { params, store, work, forks: HashMap::new(), events: Vec::new(), clock: unix_now })
+    }
+
+    /// Replace the clock giving the current unix time in seconds, which headers too far in
+    /// the future are rejected against
+    pub fn set_clock(&mut self, clock: fn() -> u64) {
+        self.clock = clock;
+    }
+
+    /// Parameters of the network of the chain
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Store holding the headers of the active chain
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Height of the tip, the genesis block being at height 0
+    pub fn best_height(&self) -> usize {
+        self.store.len() - 1
+    }
+
+    /// Hash of the tip
+    pub fn tip(&self) -> BlockHash {
+        self.header(self.best_height()).hash()
+    }
+
+    /// Cumulative work of the active chain
+    pub fn chainwork(&self) -> Uint256 {
+        self.work[self.best_height()]
+    }
+
+    /// Cumulative work of the active chain up to a height
+    pub fn work_at(&self, height: usize) -> Option<Uint256> {
+        self.work.get(height).copied()
+    }
+
+    /// Hashes of the tips of all known branches, starting with the active tip
+    pub fn tips(&self) -> Vec<BlockHash> {
+        let parents: HashSet<BlockHash> = self.forks.values().map(|f| f.header.prev_blockhash).collect();
+        let mut tips = vec![self.tip()];
+        tips.extend(self.forks.keys().filter(|h| !parents.contains(*h)));
+        tips
+    }
+
+    /// Take the events that happened since the last call
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Get the header at a height of the active chain
+    pub fn header_at(&self, height: usize) -> Option<BlockHeader> {
+        self.store.get_at(height)
+    }
+
+    /// Get the height of a header in the active chain
+    pub fn height_of(&self, hash: &BlockHash) -> Option<usize> {
+        self.store.height_of(hash)
+    }
+
+    /// Get a header of the active chain by its hash
+    pub fn get(&self, hash: &BlockHash) -> Option<BlockHeader> {
+        self.store.get(hash)
+    }
+
+    /// Get a header at a height of the active chain
+    fn header(&self, height: usize) -> BlockHeader {
+        self.store.get_at(height).expect("Height in the chain")
+    }
+
+    /// Add the headers of a `headers` message, in order.
+    /// Headers already known are skipped, and headers building on a competing branch are
+    /// kept with it. The active chain switches to a branch once it has more work.
+    /// Returns the number of headers added, or the reason the first invalid header was
+    /// rejected; the headers before it are kept.
+    pub fn extend(&mut self, headers: &[BlockHeader]) -> Result<usize, Error> {
+        let mut added = 0;
+        for header in headers {
+            let hash = header.hash();
+            if self.height_of(&hash).is_some() || self.forks.contains_key(&hash) { continue }
+
+            let (fork, branch) = self.branch(&header.prev_blockhash).ok_or(Error::Orphan(hash))?;
+            self.check(header, fork, &branch)?;
+
+            let work = header.work() + match branch.last() {
+                Some(prev) => self.forks[&prev.hash()].work,
+                None => self.work[fork]
+            };
+            if fork == self.best_height() {
+                self.store.append(*header).map_err(|e| Error::Store(e.to_string()))?;
+                self.work.push(work);
+            } else {
+                self.forks.insert(hash, ForkHeader { header: *header, work });
+                if work > self.chainwork() {
+                    let mut branch = branch;
+                    branch.push(*header);
+                    self.reorg(fork, &branch)?;
+                }
+            }
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Median timestamp of the last headers of the chain, up to the tip
+    pub fn median_time_past(&self) -> u32 {
+        self.median_time_past_of(self.best_height(), &[])
+    }
+
+    /// Get the branch of fork headers ending at a header, and the height of the header of the
+    /// active chain it forks off. The branch is empty for headers of the active chain.
+    /// Returns `None` if the header is not known.
+    fn branch(&self, hash: &BlockHash) -> Option<(usize, Vec<BlockHeader>)> {
+        let mut branch = Vec::new();
+        let mut hash = *hash;
+        loop {
+            if let Some(height) = self.height_of(&hash) {
+                branch.reverse();
+                return Some((height, branch))
+            }
+            let fork = self.forks.get(&hash)?;
+            branch.push(fork.header);
+            hash = fork.header.prev_blockhash;
+        }
+    }
+
+    /// Get the header at a height of a branch forking off the active chain
+    fn ancestor(&self, fork: usize, branch: &[BlockHeader], height: usize) -> BlockHeader {
+        match height.checked_sub(fork + 1) {
+            Some(i) => branch[i],
+            None => self.header(height)
+        }
+    }
+
+    /// Check a header following a branch forking off the active chain
+    fn check(&self, header: &BlockHeader, fork: usize, branch: &[BlockHeader]) -> Result<(), Error> {
+        let hash = header.hash();
+        let height = fork + branch.len() + 1;
+
+        // Branches forking below the last checkpoint would be cheap to mine
+        let last_checkpoint = self.params.checkpoints.iter()
+            .map(|(h, _)| *h)
+            .filter(|h| *h <= self.best_height())
+            .max()
+            .unwrap_or(0);
+        if fork < self.best_height() && fork < last_checkpoint {
+            return Err(Error::Fork(hash))
+        }
+
+        if !header.check_pow() || !matches!(header.target(), Some(t) if t <= self.params.max_target) {
+            return Err(Error::InvalidPow(hash))
+        }
+        let expected = self.next_bits(header, fork, branch);
+        if header.bits != expected {
+            return Err(Error::Difficulty { expected, hash })
+        }
+        if header.time <= self.median_time_past_of(fork, branch) {
+            return Err(Error::TimeTooOld(hash))
+        }
+        if header.time as u64 > (self.clock)() + MAX_FUTURE_BLOCK_TIME.as_secs() {
+            return Err(Error::TimeTooNew(hash))
+        }
+        if matches!(self.params.checkpoint(height), Some(c) if c != hash) {
+            return Err(Error::Checkpoint { height, hash })
+        }
+        Ok(())
+    }
+
+    /// Switch the active chain to a branch forking off it
+    fn reorg(&mut self, fork: usize, branch: &[BlockHeader]) -> Result<(), Error> {
+        let old_tip = self.tip();
+        let depth = self.best_height() - fork;
+
+        // The disconnected headers become a competing branch
+        for height in fork + 1..=self.best_height() {
+            let header = self.header(height);
+            self.forks.insert(header.hash(), ForkHeader { header, work: self.work[height] });
+        }
+        self.store.truncate(fork + 1).map_err(|e| Error::Store(e.to_string()))?;
+        self.work.truncate(fork + 1);
+
+        for header in branch {
+            let fork = self.forks.remove(&header.hash()).expect("Branch headers are forks");
+            self.store.append(fork.header).map_err(|e| Error::Store(e.to_string()))?;
+            self.work.push(fork.work);
+        }
+
+        self.events.push(Event::Reorg { old_tip, new_tip: self.tip(), depth });
+        Ok(())
+    }
+
+    /// Median timestamp of the last headers of a branch
+    fn median_time_past_of(&self, fork: usize, branch: &[BlockHeader]) -> u32 {
+        let tip = fork + branch.len();
+        let start = (tip + 1).saturating_sub(MEDIAN_TIME_SPAN);
+        let mut times = (start..=tip).map(|h| self.ancestor(fork, branch, h).time).collect::<Vec<u32>>();
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    /// Target a header following a branch is required to have
+    fn next_bits(&self, header: &BlockHeader, fork: usize, branch: &[BlockHeader]) -> u32 {
+        let height = fork + branch.len() + 1;
+        let interval = self.params.retarget_interval;
+        let last = self.ancestor(fork, branch, height - 1);
+        if self.params.no_retarget { return last.bits }
+
+        if height.is_multiple_of(interval) {
+            let first = self.ancestor(fork, branch, height - interval);
+            return pow::retarget(&self.params, last.bits, first.time, last.time)
+        }
+        if !self.params.min_difficulty_blocks { return last.bits }
+
+        // Blocks found long after the previous one may be mined at the maximum target, the
+        // others have the target of the last block that did not
+        let max_bits = pow::compact_from_target(&self.params.max_target);
+        let spacing = self.params.target_spacing.as_secs() as u32;
+        if header.time > last.time.saturating_add(2 * spacing) { return max_bits }
+        (0..height)
+            .rev()
+            .map(|h| (h, self.ancestor(fork, branch, h)))
+            .find(|(h, b)| h.is_multiple_of(interval) || b.bits != max_bits)
+            .map(|(_, b)| b.bits)
+            .expect("Genesis is at a retarget height")
+    }
+
+    /// Block locator of the chain, from the tip back to the genesis block
+    pub fn locator(&self) -> Vec<BlockHash> {
+        let mut hashes = Vec::new();
+        let mut height = self.best_height();
+        let mut step = 1;
+        loop {
+            hashes.push(self.header(height).hash());
+            if height == 0 { break }
+
+            height = height.saturating_sub(step);
+            if hashes.len() > 10 {
+                step *= 2;
+            }
+        }
+        hashes
+    }
+
+    /// Message asking for the headers following the tip
+    pub fn getheaders(&self) -> NetworkMessage {
+        NetworkMessage::GetHeaders(BlockdataLocatorInfo::new(LOCATOR_VERSION, self.locator(), BlockHash::from_inner([0; 32])))
+    }
+
+    /// Sync headers from a peer the handshake was done with, until the peer has no more
+    /// headers to send. Returns the number of headers added.
+    #[cfg(feature = "net")]
+    pub fn sync(&mut self, stream: &mut PeerStream, timeout: Duration) -> Result<usize, crate::net::Error> {
+        stream.set_read_timeout(Some(timeout))?;
+        let mut added = 0;
+        loop {
+            stream.send(self.getheaders())?;
+            let headers = loop {
+                match stream.recv_network_message()? {
+                    NetworkMessage::Headers(headers) => break headers,
+                    NetworkMessage::Ping(nonce) => stream.send(NetworkMessage::Pong(nonce))?,
+                    _ => continue
+                }
+            };
+
+            added += self.extend(&headers)?;
+            if headers.len() < MAX_HEADERS { break }
+        }
+        stream.set_read_timeout(None)?;
+        Ok(added)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitcoin::hash_types::TxMerkleNode,
+        blockdata::store::FileStore
+    };
+    #[cfg(feature = "net")]
+    use crate::msg::header::Magic;
+    #[cfg(feature = "net")]
+    use std::net::{
+        TcpListener,
+        TcpStream
+    };
+
+    /// Mine a header with the easiest target on top of another one
+    fn mine(prev: &BlockHeader) -> BlockHeader {
+        mine_with(prev, prev.time + 600, 0x207fffff)
+    }
+
+    fn mine_with(prev: &BlockHeader, time: u32, bits: u32) -> BlockHeader {
+        let mut header = BlockHeader::new(1, prev.hash(), TxMerkleNode::from_inner([0; 32]), time, bits, 0);
+        while !header.check_pow() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn headers(from: &BlockHeader, n: usize) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = Vec::with_capacity(n);
+        for _ in 0..n {
+            let next = mine(headers.last().unwrap_or(from));
+            headers.push(next);
+        }
+        headers
+    }
+
+    #[test]
+    fn extend() {
+        let mut chain = HeaderChain::new(ChainParams::regtest());
+        let genesis = chain.params().genesis;
+        let batch = headers(&genesis, 3);
+        assert_eq!(chain.extend(&batch), Ok(3));
+        assert_eq!(chain.extend(&batch), Ok(0));
+        assert_eq!(chain.best_height(), 3);
+        assert_eq!(chain.tip(), batch[2].hash());
+        assert_eq!(chain.height_of(&batch[0].hash()), Some(1));
+
+        // Competing branches are kept without switching to them
+        let fork = mine_with(&batch[0], batch[0].time + 601, 0x207fffff);
+        assert_eq!(chain.extend(&[fork]), Ok(1));
+        assert_eq!(chain.extend(&[fork]), Ok(0));
+        assert_eq!(chain.tip(), batch[2].hash());
+        assert_eq!(chain.tips().len(), 2);
+        let orphan = mine(&mine(&batch[2]));
+        assert_eq!(chain.extend(&[orphan]), Err(Error::Orphan(orphan.hash())));
+
+        let mut weak = mine(&batch[2]);
+        while weak.check_pow() {
+            weak.nonce += 1;
+        }
+        assert_eq!(chain.extend(&[weak]), Err(Error::InvalidPow(weak.hash())));
+
+        // Main network headers claiming the regtest target
+        let mut main = HeaderChain::default();
+        let easy = mine(&main.params().genesis);
+        assert_eq!(main.extend(&[easy]), Err(Error::InvalidPow(easy.hash())));
+        assert_eq!(chain.best_height(), 3);
+    }
+
+    #[test]
+    fn restart_from_file() {
+        let path = std::env::temp_dir().join(format!("btcnetmsg-chain-{}.dat", rand::random::<u32>()));
+        let params = ChainParams::regtest();
+        let batch = headers(&params.genesis, 5);
+
+        let mut chain = HeaderChain::with_store(params.clone(), FileStore::open(&path).unwrap()).unwrap();
+        assert_eq!(chain.extend(&batch), Ok(5));
+        drop(chain);
+
+        let chain = HeaderChain::with_store(params, FileStore::open(&path).unwrap()).unwrap();
+        assert_eq!(chain.best_height(), 5);
+        assert_eq!(chain.tip(), batch[4].hash());
+        assert_eq!(chain.store().len(), 6);
+        assert!(matches!(HeaderChain::with_store(ChainParams::main(), FileStore::open(&path).unwrap()), Err(Error::Store(_))));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reorgs() {
+        let mut chain = HeaderChain::new(ChainParams::regtest());
+        let genesis = chain.params().genesis;
+        let a = headers(&genesis, 3);
+        chain.extend(&a).unwrap();
+        let work = chain.chainwork();
+        assert_eq!(work, chain.work_at(2).unwrap() + a[2].work());
+
+        // A branch with as much work does not replace the active chain
+        let b1 = mine_with(&a[0], a[0].time + 601, 0x207fffff);
+        let b = [b1, mine(&b1), mine(&mine(&b1))];
+        assert_eq!(chain.extend(&b[..2]), Ok(2));
+        assert_eq!(chain.tip(), a[2].hash());
+        assert!(chain.take_events().is_empty());
+
+        assert_eq!(chain.extend(&b[2..]), Ok(1));
+        assert_eq!(chain.tip(), b[2].hash());
+        assert_eq!(chain.best_height(), 4);
+        assert!(chain.chainwork() > work);
+        assert_eq!(chain.height_of(&a[1].hash()), None);
+        assert_eq!(chain.height_of(&b[0].hash()), Some(2));
+        assert_eq!(chain.take_events(), vec![Event::Reorg { old_tip: a[2].hash(), new_tip: b[2].hash(), depth: 2 }]);
+
+        // The disconnected branch can take over again
+        let a4 = mine(&a[2]);
+        let a5 = mine(&a4);
+        assert_eq!(chain.extend(&[a4, a5]), Ok(2));
+        assert_eq!(chain.tip(), a5.hash());
+        assert_eq!(chain.header_at(2), Some(a[1]));
+        assert_eq!(chain.take_events(), vec![Event::Reorg { old_tip: b[2].hash(), new_tip: a5.hash(), depth: 3 }]);
+        assert_eq!(chain.tips(), vec![a5.hash(), b[2].hash()]);
+        assert_eq!(chain.chainwork(), (0..=5).fold(Uint256::default(), |w, h| w + chain.header_at(h).unwrap().work()));
+    }
+
+    #[test]
+    fn checkpoints() {
+        let mut params = ChainParams::regtest();
+        let good = headers(&params.genesis, 3);
+        params.checkpoints = vec![(2, good[1].hash())];
+
+        let mut chain = HeaderChain::new(params.clone());
+        assert_eq!(chain.extend(&good), Ok(3));
+
+        // Branches forking below the checkpoint are rejected
+        let fork = mine_with(&good[0], good[0].time + 601, 0x207fffff);
+        assert_eq!(chain.extend(&[fork]), Err(Error::Fork(fork.hash())));
+
+        // A branch with a different block at the checkpoint is rejected at that height
+        let mut bad = mine(&good[0]);
+        while bad == good[1] || !bad.check_pow() {
+            bad.nonce += 1;
+        }
+        let mut chain = HeaderChain::new(params);
+        assert_eq!(chain.extend(&[good[0], bad]), Err(Error::Checkpoint { height: 2, hash: bad.hash() }));
+        assert_eq!(chain.best_height(), 1);
+    }
+
+    #[test]
+    fn difficulty_transitions() {
+        let mut params = ChainParams::regtest();
+        params.no_retarget = false;
+        params.min_difficulty_blocks = false;
+        params.retarget_interval = 4;
+        let mut chain = HeaderChain::new(params.clone());
+
+        // Blocks found a second apart make the target four times harder
+        let mut tip = params.genesis;
+        for _ in 0..3 {
+            tip = mine_with(&tip, tip.time + 1, 0x207fffff);
+            chain.extend(&[tip]).unwrap();
+        }
+        let harder = mine_with(&tip, tip.time + 1, 0x207fffff);
+        assert_eq!(chain.extend(&[harder]), Err(Error::Difficulty { expected: 0x201fffff, hash: harder.hash() }));
+        let harder = mine_with(&tip, tip.time + 1, 0x201fffff);
+        assert_eq!(chain.extend(&[harder]), Ok(1));
+
+        let easier = mine_with(&harder, harder.time + 1, 0x207fffff);
+        assert_eq!(chain.extend(&[easier]), Err(Error::Difficulty { expected: 0x201fffff, hash: easier.hash() }));
+
+        // Unless blocks may be mined at the maximum target when found late
+        chain.params.min_difficulty_blocks = true;
+        let late = mine_with(&harder, harder.time + 1201, 0x207fffff);
+        assert_eq!(chain.extend(&[late]), Ok(1));
+        let next = mine_with(&late, late.time + 1, 0x201fffff);
+        assert_eq!(chain.extend(&[next]), Ok(1));
+    }
+
+    #[test]
+    fn timestamps() {
+        let mut chain = HeaderChain::new(ChainParams::regtest());
+        let mut tip = chain.params().genesis;
+        let mut times = vec![tip.time];
+        for t in [1, 3, 2, 5, 4, 7, 6, 9, 8, 10] {
+            tip = mine_with(&tip, chain.params().genesis.time + t * 100, 0x207fffff);
+            chain.extend(&[tip]).unwrap();
+            times.push(tip.time);
+        }
+        times.sort_unstable();
+        assert_eq!(chain.median_time_past(), times[5]);
+
+        let old = mine_with(&tip, chain.median_time_past(), 0x207fffff);
+        assert_eq!(chain.extend(&[old]), Err(Error::TimeTooOld(old.hash())));
+        let future = mine_with(&tip, (unix_now() + 3 * 60 * 60) as u32, 0x207fffff);
+        assert_eq!(chain.extend(&[future]), Err(Error::TimeTooNew(future.hash())));
+
+        // The header is accepted once the clock catches up with it
+        let mut later = chain.clone();
+        later.set_clock(|| unix_now() + 2 * 60 * 60);
+        assert_eq!(later.extend(&[future]), Ok(1));
+        let next = mine_with(&tip, chain.median_time_past() + 1, 0x207fffff);
+        assert_eq!(chain.extend(&[next]), Ok(1));
+    }
+
+    #[test]
+    fn locator() {
+        let mut chain = HeaderChain::new(ChainParams::regtest());
+        let genesis = chain.params().genesis;
+        assert_eq!(chain.locator(), vec![genesis.hash()]);
+
+        chain.extend(&headers(&genesis, 100)).unwrap();
+        let heights = chain.locator().iter().map(|h| chain.height_of(h).unwrap()).collect::<Vec<usize>>();
+        let mut expected = (90..=100).rev().collect::<Vec<usize>>();
+        expected.extend(&[89, 87, 83, 75, 59, 27, 0]);
+        assert_eq!(heights, expected);
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn sync_batches() {
+        let genesis = ChainParams::regtest().genesis;
+        let remote = headers(&genesis, MAX_HEADERS + 500);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = PeerStream::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap(), Magic::Main).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let mut peer = PeerStream::new(listener.accept().unwrap().0, Magic::Main).unwrap();
+            let mut requests = 0;
+            while let Ok(msg) = peer.recv_network_message() {
+                let locator = match msg {
+                    NetworkMessage::GetHeaders(l) => l,
+                    _ => continue
+                };
+                requests += 1;
+                let start = match remote.iter().position(|h| h.hash() == locator.hashes[0]) {
+                    Some(i) => i + 1,
+                    None => 0
+                };
+                let batch = remote[start..].iter().take(MAX_HEADERS).copied().collect();
+                peer.send(NetworkMessage::Headers(batch)).unwrap();
+            }
+            requests
+        });
+
+        let mut chain = HeaderChain::new(ChainParams::regtest());
+        assert_eq!(chain.sync(&mut stream, Duration::from_secs(5)).unwrap(), MAX_HEADERS + 500);
+        assert_eq!(chain.best_height(), MAX_HEADERS + 500);
+        drop(stream);
+        assert_eq!(server.join().unwrap(), 2);
+    }
+}
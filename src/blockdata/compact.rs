@@ -0,0 +1,385 @@
+// compact.rs
+//
+// Compact blocks of BIP152.
+//
+// A compact block is a block header with short ids of its transactions, which are the
+// SipHash-2-4 of the txid (version 1) or wtxid (version 2) truncated to 6 bytes. The
+// SipHash keys are taken from the SHA256 of the header and a nonce chosen by the sender, so
+// collisions can not be crafted for every peer at once.
+//
+// Blocks are reconstructed by matching the short ids against the transactions already
+// received, and the missing transactions are requested with `getblocktxn` by their index
+// in the block. Transaction indexes are sent differentially encoded, but are stored as
+// absolute indexes here.
+//
+
+use crate::{
+    bitcoin::{
+        hash_types::BlockHash,
+        hashes::{
+            sha256,
+            siphash24,
+            Hash
+        },
+        Transaction
+    },
+    blockdata::{
+        Block,
+        BlockHeader,
+        Tx
+    },
+    encode::{
+        Encode,
+        Error
+    },
+    msg::{
+        data::NetworkMessage,
+        header::Command
+    }
+};
+use std::collections::HashMap;
+
+/// Short transaction id of a compact block
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ShortId(pub [u8; 6]);
+
+impl ShortId {
+    /// Compute the short id of a transaction hash using SipHash keys
+    pub fn new(keys: (u64, u64), hash: &[u8; 32]) -> Self {
+        let sip = siphash24::Hash::hash_to_u64_with_keys(keys.0, keys.1, hash);
+        let mut id = [0; 6];
+        id.copy_from_slice(&sip.to_le_bytes()[..6]);
+        Self(id)
+    }
+}
+
+/// Transaction sent in full in a compact block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefilledTx {
+    /// Index of the transaction in the block
+    pub index: usize,
+    pub tx: Transaction
+}
+
+/// Payload of a `cmpctblock` message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub nonce: u64,
+    pub short_ids: Vec<ShortId>,
+
+    /// Prefilled transactions, in increasing index order
+    prefilled: Vec<PrefilledTx>
+}
+
+impl CompactBlock {
+    /// Create a compact block.
+    /// Returns an error if the prefilled transactions are not in strictly increasing index order.
+    pub fn new(header: BlockHeader, nonce: u64, short_ids: Vec<ShortId>, prefilled: Vec<PrefilledTx>) -> Result<Self, Error> {
+        if !is_increasing(prefilled.iter().map(|p| p.index)) {
+            return Err(Error::InvalidData)
+        }
+        Ok(Self { header, nonce, short_ids, prefilled })
+    }
+
+    /// Create a compact block of a block, with only the coinbase prefilled
+    pub fn from_block(block: &Block, nonce: u64, version: u64) -> Self {
+        let mut cmpct = Self {
+            header: block.header,
+            nonce,
+            short_ids: Vec::new(),
+            prefilled: Vec::new()
+        };
+
+        let keys = cmpct.keys();
+        for (index, tx) in block.txs.iter().enumerate() {
+            match index {
+                0 => cmpct.prefilled.push(PrefilledTx { index, tx: tx.clone() }),
+                _ => cmpct.short_ids.push(ShortId::new(keys, &short_id_hash(&Tx::new(tx.clone()), version)))
+            }
+        }
+        cmpct
+    }
+
+    /// SipHash keys of the short ids, from the SHA256 of the header and nonce
+    pub fn keys(&self) -> (u64, u64) {
+        let mut data = Vec::with_capacity(BlockHeader::SIZE + 8);
+        self.header.net_encode(&mut data);
+        self.nonce.net_encode(&mut data);
+
+        let hash = sha256::Hash::hash(&data).into_inner();
+        let mut k0 = [0; 8];
+        let mut k1 = [0; 8];
+        k0.copy_from_slice(&hash[0..8]);
+        k1.copy_from_slice(&hash[8..16]);
+        (u64::from_le_bytes(k0), u64::from_le_bytes(k1))
+    }
+
+    /// Prefilled transactions, in increasing index order
+    pub fn prefilled(&self) -> &[PrefilledTx] {
+        &self.prefilled
+    }
+
+    /// Short id of a transaction in this compact block
+    pub fn short_id(&self, tx: &Tx, version: u64) -> ShortId {
+        ShortId::new(self.keys(), &short_id_hash(tx, version))
+    }
+
+    /// Number of transactions in the block
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// Start the reconstruction of the block from the known transactions.
+    /// Short ids matching several transactions are left missing.
+    /// Returns an error if the prefilled indexes are out of the block.
+    pub fn reconstruct<'a, I>(&self, txs: I, version: u64) -> Result<PartialBlock, Error>
+    where I: IntoIterator<Item = &'a Tx> {
+        let count = self.tx_count();
+        let mut slots: Vec<Option<Transaction>> = vec![None; count];
+        let mut prefilled = vec![false; count];
+        for p in self.prefilled.iter() {
+            if p.index >= count || prefilled[p.index] { return Err(Error::InvalidData) }
+            slots[p.index] = Some(p.tx.clone());
+            prefilled[p.index] = true;
+        }
+
+        // Indexes of the transactions identified by short ids, skipping prefilled ones
+        let mut indexes: HashMap<ShortId, usize> = HashMap::new();
+        let mut free = (0..count).filter(|i| !prefilled[*i]);
+        for id in self.short_ids.iter() {
+            let index = free.next().ok_or(Error::InvalidData)?;
+            if indexes.insert(*id, index).is_some() {
+                // Duplicate short ids in the block, which can not be told apart
+                return Err(Error::InvalidData)
+            }
+        }
+
+        let keys = self.keys();
+        let mut matched: HashMap<usize, Option<&Tx>> = HashMap::new();
+        for tx in txs {
+            if let Some(index) = indexes.get(&ShortId::new(keys, &short_id_hash(tx, version))) {
+                matched.entry(*index)
+                    .and_modify(|m| *m = None)
+                    .or_insert(Some(tx));
+            }
+        }
+        for (index, tx) in matched {
+            slots[index] = tx.map(|tx| tx.transaction().clone());
+        }
+
+        Ok(PartialBlock { header: self.header, txs: slots })
+    }
+}
+
+/// Block being reconstructed from a compact block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartialBlock {
+    pub header: BlockHeader,
+    pub txs: Vec<Option<Transaction>>
+}
+
+impl PartialBlock {
+    /// Indexes of the transactions that are missing
+    pub fn missing(&self) -> Vec<usize> {
+        self.txs.iter().enumerate().filter(|(_, tx)| tx.is_none()).map(|(i, _)| i).collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.txs.iter().all(|tx| tx.is_some())
+    }
+
+    /// Request for the missing transactions
+    pub fn request(&self) -> BlockTxnRequest {
+        BlockTxnRequest {
+            block_hash: self.header.hash(),
+            indexes: self.missing()
+        }
+    }
+
+    /// Fill the missing transactions with the response to the request.
+    /// Returns an error if the response does not match the missing transactions.
+    pub fn fill(&mut self, response: BlockTxn) -> Result<(), Error> {
+        let missing = self.missing();
+        if response.block_hash != self.header.hash() || response.txs.len() != missing.len() {
+            return Err(Error::InvalidData)
+        }
+        for (index, tx) in missing.into_iter().zip(response.txs) {
+            self.txs[index] = Some(tx);
+        }
+        Ok(())
+    }
+
+    /// Get the reconstructed block.
+    /// Returns `None` if transactions are missing or the transactions do not match the
+    /// merkle root, in which case the full block should be requested.
+    pub fn block(&self) -> Option<Block> {
+        let txs = self.txs.iter().cloned().collect::<Option<Vec<Transaction>>>()?;
+        let block = Block::new(self.header, txs);
+        match block.check_merkle_root() {
+            true => Some(block),
+            false => None
+        }
+    }
+}
+
+/// Payload of a `getblocktxn` message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTxnRequest {
+    pub block_hash: BlockHash,
+
+    /// Indexes of the requested transactions, in increasing order
+    indexes: Vec<usize>
+}
+
+impl BlockTxnRequest {
+    /// Create a request for the transactions of a block.
+    /// Returns an error if the indexes are not strictly increasing.
+    pub fn new(block_hash: BlockHash, indexes: Vec<usize>) -> Result<Self, Error> {
+        if !is_increasing(indexes.iter().copied()) {
+            return Err(Error::InvalidData)
+        }
+        Ok(Self { block_hash, indexes })
+    }
+
+    /// Indexes of the requested transactions, in increasing order
+    pub fn indexes(&self) -> &[usize] {
+        &self.indexes
+    }
+
+    /// Create the `getblocktxn` message of the request
+    pub fn into_message(self) -> NetworkMessage {
+        NetworkMessage::Raw {
+            command: Command::GetBlockTxn,
+            payload: self.encode_to_vec()
+        }
+    }
+
+    /// Answer the request using the full block.
+    /// Returns `None` if an index is out of the block.
+    pub fn respond(&self, block: &Block) -> Option<BlockTxn> {
+        Some(BlockTxn {
+            block_hash: self.block_hash,
+            txs: self.indexes.iter().map(|i| block.txs.get(*i).cloned()).collect::<Option<Vec<Transaction>>>()?
+        })
+    }
+
+    fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.net_encode(&mut buf);
+        buf
+    }
+}
+
+/// Payload of a `blocktxn` message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTxn {
+    pub block_hash: BlockHash,
+    pub txs: Vec<Transaction>
+}
+
+/// Check that indexes are strictly increasing, so each index appears once
+fn is_increasing<I: IntoIterator<Item = usize>>(indexes: I) -> bool {
+    let mut next = 0;
+    indexes.into_iter().all(|i| {
+        let ok = i >= next;
+        next = i + 1;
+        ok
+    })
+}
+
+/// Hash used for the short id of a transaction
+fn short_id_hash(tx: &Tx, version: u64) -> [u8; 32] {
+    match version {
+        1 => tx.txid().into_inner(),
+        _ => tx.wtxid().into_inner()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::Decode;
+
+    fn block() -> Block {
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        let txs: Vec<Transaction> = (0..4).map(|n| {
+            let mut tx = genesis.txdata[0].clone();
+            tx.lock_time = n;
+            tx
+        }).collect();
+        let txids: Vec<_> = txs.iter().map(|tx| tx.txid()).collect();
+
+        let mut header = BlockHeader::genesis();
+        header.merkle_root = crate::blockdata::merkle::merkle_root(&txids);
+        Block::new(header, txs)
+    }
+
+    #[test]
+    fn reconstruct() {
+        let block = block();
+        let cmpct = CompactBlock::from_block(&block, 42, 2);
+        assert_eq!(cmpct.tx_count(), 4);
+        assert_eq!(cmpct.short_ids[0], cmpct.short_id(&Tx::new(block.txs[1].clone()), 2));
+        assert_ne!(cmpct.short_ids[0], CompactBlock::from_block(&block, 43, 2).short_ids[0]);
+
+        let mut enc = Vec::new();
+        assert_eq!(cmpct.net_encode(&mut enc), cmpct.encoded_size());
+        assert_eq!(enc.len(), 80 + 8 + 1 + 3 * 6 + 1 + 1 + block.txs[0].get_size());
+        assert_eq!(CompactBlock::net_decode(&enc[..]).unwrap(), cmpct);
+
+        // Only the second transaction is known
+        let known = [Tx::new(block.txs[2].clone())];
+        let mut partial = cmpct.reconstruct(known.iter(), 2).unwrap();
+        assert_eq!(partial.missing(), vec![1, 3]);
+        assert_eq!(partial.block(), None);
+
+        let request = partial.request();
+        let mut enc = Vec::new();
+        request.net_encode(&mut enc);
+        assert_eq!(enc.len(), request.encoded_size());
+        assert_eq!(BlockTxnRequest::net_decode(&enc[..]).unwrap(), request);
+
+        let response = request.respond(&block).unwrap();
+        let mut enc = Vec::new();
+        response.net_encode(&mut enc);
+        let response = BlockTxn::net_decode(&enc[..]).unwrap();
+        assert!(partial.fill(BlockTxn { block_hash: response.block_hash, txs: vec![] }).is_err());
+        partial.fill(response).unwrap();
+        assert_eq!(partial.block(), Some(block.clone()));
+
+        // A wrong transaction is detected by the merkle root
+        let mut wrong = cmpct.reconstruct(&[], 2).unwrap();
+        wrong.txs = block.txs.iter().rev().cloned().map(Some).collect();
+        assert_eq!(wrong.block(), None);
+
+        let prefilled = vec![PrefilledTx { index: 4, tx: block.txs[0].clone() }];
+        let invalid = CompactBlock::new(cmpct.header, cmpct.nonce, cmpct.short_ids.clone(), prefilled).unwrap();
+        assert!(invalid.reconstruct(&[], 2).is_err());
+    }
+
+    #[test]
+    fn unordered_indexes() {
+        let block = block();
+        let tx = |index: usize| PrefilledTx { index, tx: block.txs[index].clone() };
+        let cmpct = CompactBlock::from_block(&block, 42, 2);
+
+        // Indexes out of order or repeated are rejected when the value is built
+        let short_ids = cmpct.short_ids[..1].to_vec();
+        assert!(CompactBlock::new(cmpct.header, 42, short_ids.clone(), vec![tx(3), tx(0), tx(2)]).is_err());
+        assert!(CompactBlock::new(cmpct.header, 42, short_ids.clone(), vec![tx(0), tx(2), tx(2)]).is_err());
+        let sorted = CompactBlock::new(cmpct.header, 42, short_ids, vec![tx(0), tx(2), tx(3)]).unwrap();
+        let mut enc = Vec::new();
+        assert_eq!(sorted.net_encode(&mut enc), sorted.encoded_size());
+        assert_eq!(CompactBlock::net_decode(&enc[..]).unwrap(), sorted);
+
+        assert!(BlockTxnRequest::new(block.hash(), vec![5, 1, 0]).is_err());
+        assert!(BlockTxnRequest::new(block.hash(), vec![0, 1, 1]).is_err());
+        let request = BlockTxnRequest::new(block.hash(), vec![0, 1, 5]).unwrap();
+        let mut enc = Vec::new();
+        assert_eq!(request.net_encode(&mut enc), request.encoded_size());
+        assert_eq!(BlockTxnRequest::net_decode(&enc[..]).unwrap(), request);
+    }
+
+}
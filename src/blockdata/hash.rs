@@ -0,0 +1,86 @@
+// hash.rs
+//
+// Display and parsing of hashes as hex strings.
+//
+// Hashes are sent over the wire in little endian, but are conventionally displayed as big
+// endian hex strings, which is how block explorers and bitcoin core show them.
+//
+
+use crate::{
+    bitcoin::{
+        hash_types::{
+            BlockHash,
+            Txid,
+            TxMerkleNode,
+            Wtxid
+        },
+        hashes::Hash
+    },
+    encode::Error
+};
+
+/// Hash displayed as a big endian hex string
+pub trait HexHash: Sized {
+    /// Format the hash as a big endian hex string
+    fn to_be_hex(&self) -> String;
+
+    /// Parse a hash from a big endian hex string
+    fn from_be_hex(s: &str) -> Result<Self, Error>;
+}
+
+impl HexHash for [u8; 32] {
+    fn to_be_hex(&self) -> String {
+        self.iter().rev().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn from_be_hex(s: &str) -> Result<Self, Error> {
+        if s.len() != 64 || !s.is_ascii() { return Err(Error::InvalidData) }
+
+        let mut hash = [0; 32];
+        for (i, byte) in hash.iter_mut().rev().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| Error::InvalidData)?;
+        }
+        Ok(hash)
+    }
+}
+
+/// Macro to implement hex display for the hashes of rust-bitcoin
+macro_rules! bitcoin_hash_hex {
+    ($hash: ty) => {
+        impl HexHash for $hash {
+            fn to_be_hex(&self) -> String {
+                self.into_inner().to_be_hex()
+            }
+
+            fn from_be_hex(s: &str) -> Result<Self, Error> {
+                Ok(Self::from_inner(<[u8; 32]>::from_be_hex(s)?))
+            }
+        }
+    };
+}
+
+bitcoin_hash_hex!(BlockHash);
+bitcoin_hash_hex!(Txid);
+bitcoin_hash_hex!(Wtxid);
+bitcoin_hash_hex!(TxMerkleNode);
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdata::BlockHeader;
+
+    #[test]
+    fn big_endian_hex() {
+        let genesis = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+        let hash = BlockHeader::genesis().hash();
+        assert_eq!(hash.to_be_hex(), genesis);
+        assert_eq!(hash.to_be_hex(), hash.to_string());
+        assert_eq!(BlockHash::from_be_hex(genesis).unwrap(), hash);
+        assert_eq!(hash.into_inner()[31], 0);
+
+        assert!(BlockHash::from_be_hex(&genesis[2..]).is_err());
+        assert!(BlockHash::from_be_hex(&genesis.replace('a', "g")).is_err());
+        assert!(<[u8; 32]>::from_be_hex(&"é".repeat(32)).is_err());
+    }
+}
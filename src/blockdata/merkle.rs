@@ -0,0 +1,86 @@
+// merkle.rs
+//
+// Merkle root of the transactions of a block.
+//
+// Pairs of hashes are concatenated and hashed with double SHA256 until a single hash is
+// left. A level with an odd number of hashes pairs the last hash with itself.
+//
+
+use crate::{
+    bitcoin::{
+        hash_types::{
+            Txid,
+            TxMerkleNode
+        },
+        hashes::Hash
+    },
+    msg::header::sha256d
+};
+
+/// Compute the merkle root of a list of txids, in block order.
+/// The root of an empty list is all zeros.
+pub fn merkle_root(txids: &[Txid]) -> TxMerkleNode {
+    compute(txids).0
+}
+
+/// Compute the merkle root of a list of txids, and whether two identical hashes were paired
+/// on any level.
+///
+/// Because of the duplicate last node rule, repeating the last transactions of a block can
+/// give the same root as the original block (CVE-2012-2459). Such a mutated block has to be
+/// rejected even though its merkle root matches.
+pub fn merkle_root_mutated(txids: &[Txid]) -> (TxMerkleNode, bool) {
+    compute(txids)
+}
+
+fn compute(txids: &[Txid]) -> (TxMerkleNode, bool) {
+    if txids.is_empty() { return (TxMerkleNode::from_inner([0; 32]), false) }
+
+    let mut level = txids.iter().map(|txid| txid.into_inner()).collect::<Vec<[u8; 32]>>();
+    let mut mutated = false;
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| {
+                let (left, right) = match pair {
+                    [left, right] => {
+                        mutated |= left == right;
+                        (left, right)
+                    },
+                    [last] => (last, last),
+                    _ => unreachable!("Chunks have one or two hashes")
+                };
+
+                let mut buf = [0; 64];
+                buf[..32].copy_from_slice(left);
+                buf[32..].copy_from_slice(right);
+                sha256d(buf)
+            })
+            .collect();
+    }
+
+    (TxMerkleNode::from_inner(level[0]), mutated)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::util::hash::bitcoin_merkle_root;
+
+    #[test]
+    fn odd_levels() {
+        let txids = (0..7u8).map(|i| Txid::from_inner([i; 32])).collect::<Vec<Txid>>();
+        for n in 1..=txids.len() {
+            let expected: TxMerkleNode = bitcoin_merkle_root(txids[..n].iter().map(|t| t.as_hash().into()));
+            assert_eq!(merkle_root(&txids[..n]), expected);
+        }
+        assert_eq!(merkle_root(&txids[..1]).into_inner(), txids[0].into_inner());
+
+        // Repeating the odd last transaction gives the same root
+        let mut mutated = txids[..3].to_vec();
+        mutated.push(txids[2]);
+        assert_eq!(merkle_root_mutated(&mutated), (merkle_root(&txids[..3]), true));
+        assert!(!merkle_root_mutated(&txids[..3]).1);
+    }
+}
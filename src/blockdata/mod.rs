@@ -4,11 +4,24 @@
 //
 //
 
+pub mod block;
+pub mod chain;
+pub mod compact;
+pub mod hash;
+pub mod merkle;
+pub mod params;
+pub mod pow;
+pub mod store;
+pub mod tx;
+
 pub use crate::bitcoin::{
     hash_types::BlockHash,
     hashes::Hash,
     Transaction
 };
-
-// Bitcoin genesis hash
-pub const GENESIS_HASH: [u8; 32] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x19, 0xd6, 0x68, 0x9c, 0x08, 0x5a, 0xe1, 0x65, 0x83, 0x1e, 0x93, 0x4f, 0xf7, 0x63, 0xae, 0x46, 0xa2, 0xa6, 0xc1, 0x72, 0xb3, 0xf1, 0xb6, 0x0a, 0x8c, 0xe2, 0x6f];
\ No newline at end of file
+pub use block::{
+    Block,
+    BlockHeader
+};
+pub use tx::Tx;
+pub use hash::HexHash;
@@ -0,0 +1,223 @@
+// params.rs
+//
+// Consensus parameters of each network.
+//
+// Values follow `chainparams.cpp` in bitcoin core.
+//
+
+use crate::{
+    bitcoin::{
+        hash_types::{
+            BlockHash,
+            TxMerkleNode
+        },
+        hashes::Hash
+    },
+    blockdata::{
+        pow::{
+            self,
+            Uint256
+        },
+        BlockHeader
+    },
+    msg::header::Magic,
+    seeds::MAIN_SEEDS
+};
+use std::time::Duration;
+
+/// Merkle root of the genesis block, which is the same on every network
+const GENESIS_MERKLE_ROOT: [u8; 32] = [
+    0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2, 0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f, 0x61,
+    0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32, 0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e, 0x5e, 0x4a
+];
+
+/// Parameters of a network
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainParams {
+    pub magic: Magic,
+    pub genesis: BlockHeader,
+    pub default_port: u16,
+
+    /// Host names resolving to peers of the network
+    pub dns_seeds: &'static [&'static str],
+
+    /// Addresses of peers used if the DNS seeds do not answer, in the format of `seeds`
+    pub fixed_seeds: &'static [[u8; 6]],
+
+    /// Easiest target headers may claim
+    pub max_target: Uint256,
+
+    /// Number of blocks between difficulty adjustments
+    pub retarget_interval: usize,
+
+    /// Expected time between blocks
+    pub target_spacing: Duration,
+
+    /// The target never changes
+    pub no_retarget: bool,
+
+    /// Blocks may use the maximum target if they are found more than twice the target
+    /// spacing after the previous block
+    pub min_difficulty_blocks: bool,
+
+    /// Known hashes of the chain at some heights, in increasing height order.
+    /// Headers contradicting a checkpoint are rejected.
+    pub checkpoints: Vec<(usize, BlockHash)>
+}
+
+impl ChainParams {
+    fn new(magic: Magic, genesis: BlockHeader, default_port: u16, max_bits: u32) -> Self {
+        Self {
+            magic,
+            genesis,
+            default_port,
+            dns_seeds: &[],
+            fixed_seeds: &[],
+            max_target: pow::target_from_compact(max_bits).expect("Valid target"),
+            retarget_interval: 2016,
+            target_spacing: Duration::from_secs(10 * 60),
+            no_retarget: false,
+            min_difficulty_blocks: false,
+            checkpoints: Vec::new()
+        }
+    }
+
+    pub fn main() -> Self {
+        let mut params = Self::new(Magic::Main, genesis(1231006505, 0x1d00ffff, 2083236893), 8333, 0x1d00ffff);
+        params.checkpoints = checkpoints(&[
+            (11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+            (33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+            (74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+            (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+            (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+            (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+            (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+            (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+            (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+            (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+            (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+            (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+            (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983")
+        ]);
+        params.dns_seeds = &[
+            "seed.bitcoin.sipa.be",
+            "dnsseed.bluematt.me",
+            "dnsseed.bitcoin.dashjr.org",
+            "seed.bitcoinstats.com",
+            "seed.bitcoin.jonasschnelli.ch",
+            "seed.btc.petertodd.org",
+            "seed.bitcoin.sprovoost.nl",
+            "dnsseed.emzy.de",
+            "seed.bitcoin.wiz.biz"
+        ];
+        params.fixed_seeds = &MAIN_SEEDS;
+        params
+    }
+
+    pub fn testnet() -> Self {
+        let mut params = Self::new(Magic::Testnet, genesis(1296688602, 0x1d00ffff, 414098458), 18333, 0x1d00ffff);
+        params.checkpoints = checkpoints(&[
+            (546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70")
+        ]);
+        params.dns_seeds = &[
+            "testnet-seed.bitcoin.jonasschnelli.ch",
+            "seed.tbtc.petertodd.org",
+            "seed.testnet.bitcoin.sprovoost.nl",
+            "testnet-seed.bluematt.me"
+        ];
+        params.min_difficulty_blocks = true;
+        params
+    }
+
+    pub fn signet() -> Self {
+        let mut params = Self::new(Magic::Signet, genesis(1598918400, 0x1e0377ae, 52613770), 38333, 0x1e0377ae);
+        params.dns_seeds = &["seed.signet.bitcoin.sprovoost.nl"];
+        params
+    }
+
+    pub fn regtest() -> Self {
+        let mut params = Self::new(Magic::Test, genesis(1296688602, 0x207fffff, 2), 18444, 0x207fffff);
+        params.no_retarget = true;
+        params.min_difficulty_blocks = true;
+        params
+    }
+
+    /// Get the parameters of the network using a magic.
+    /// Returns `None` for unknown networks.
+    pub fn from_magic(magic: &Magic) -> Option<Self> {
+        match magic {
+            Magic::Main => Some(Self::main()),
+            Magic::Testnet => Some(Self::testnet()),
+            Magic::Signet => Some(Self::signet()),
+            Magic::Test => Some(Self::regtest()),
+            Magic::Unknown(_) => None
+        }
+    }
+
+    /// Get the parameters of a network by name, ie from a command line flag.
+    /// Accepts the names used by bitcoin core: main, test, signet and regtest.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "main" | "mainnet" | "bitcoin" => Some(Self::main()),
+            "test" | "testnet" | "testnet3" => Some(Self::testnet()),
+            "signet" => Some(Self::signet()),
+            "regtest" => Some(Self::regtest()),
+            _ => None
+        }
+    }
+
+    /// Hash of the genesis block
+    pub fn genesis_hash(&self) -> BlockHash {
+        self.genesis.hash()
+    }
+
+    /// Expected time between difficulty adjustments
+    pub fn target_timespan(&self) -> Duration {
+        self.target_spacing * self.retarget_interval as u32
+    }
+
+    /// Get the checkpointed hash at a height
+    pub fn checkpoint(&self, height: usize) -> Option<BlockHash> {
+        self.checkpoints.iter().find(|(h, _)| *h == height).map(|(_, hash)| *hash)
+    }
+}
+
+fn checkpoints(list: &[(usize, &str)]) -> Vec<(usize, BlockHash)> {
+    list.iter().map(|(height, hash)| (*height, hash.parse().expect("Valid hash"))).collect()
+}
+
+fn genesis(time: u32, bits: u32, nonce: u32) -> BlockHeader {
+    BlockHeader::new(1, BlockHash::from_inner([0; 32]), TxMerkleNode::from_inner(GENESIS_MERKLE_ROOT), time, bits, nonce)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesis_hashes() {
+        let expected = [
+            (ChainParams::main(), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"),
+            (ChainParams::testnet(), "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"),
+            (ChainParams::signet(), "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"),
+            (ChainParams::regtest(), "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+        ];
+        for (params, hash) in expected.iter() {
+            assert_eq!(params.genesis_hash().to_string(), *hash);
+            assert!(params.genesis.check_pow());
+            assert_eq!(ChainParams::from_magic(&params.magic).as_ref(), Some(params));
+        }
+        assert_eq!(ChainParams::from_magic(&Magic::Unknown(1)), None);
+        assert_eq!(ChainParams::from_name("test"), Some(ChainParams::testnet()));
+        assert_eq!(ChainParams::from_name("regtest").unwrap().default_port, 18444);
+        assert_eq!(ChainParams::from_name("litecoin"), None);
+        #[cfg(feature = "net")]
+        assert!(crate::seeds::resolve(&ChainParams::regtest()).is_empty());
+
+        let main = ChainParams::main();
+        assert!(main.checkpoints.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(main.checkpoint(11111).unwrap().to_string(), "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d");
+        assert_eq!(main.checkpoint(11112), None);
+    }
+}
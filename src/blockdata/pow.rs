@@ -0,0 +1,140 @@
+// pow.rs
+//
+// Proof of work targets.
+//
+// Headers store their target in the compact "bits" format, a base 256 floating point
+// number with a one byte exponent and a signed three byte mantissa:
+//      target = mantissa * 256^(exponent - 3)
+// A header meets its target if its hash, read as a little endian number, is not above it.
+//
+// Every 2016 blocks the target is scaled by the time the last blocks took compared to the
+// expected two weeks, which is clamped between a quarter and four times the expected time.
+//
+
+pub use crate::bitcoin::util::uint::Uint256;
+use crate::{
+    bitcoin::{
+        hash_types::BlockHash,
+        hashes::Hash
+    },
+    blockdata::params::ChainParams
+};
+
+/// Decode a compact target.
+/// Returns `None` for targets that are zero, negative or do not fit in 256 bits, as no hash
+/// can meet them.
+pub fn target_from_compact(bits: u32) -> Option<Uint256> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007fffff;
+    if mantissa == 0 || bits & 0x00800000 != 0 { return None }
+
+    let target = match exponent {
+        0..=3 => Uint256::from_u64((mantissa >> (8 * (3 - exponent))) as u64).expect("Fits in 64 bits"),
+        _ => {
+            // The mantissa is shifted out of the 256 bits
+            let size = 32 - mantissa.leading_zeros() as usize;
+            if size + 8 * (exponent - 3) > 256 { return None }
+            Uint256::from_u64(mantissa as u64).expect("Fits in 64 bits") << (8 * (exponent - 3))
+        }
+    };
+    match target == Uint256::default() {
+        true => None,
+        false => Some(target)
+    }
+}
+
+/// Encode a target in the compact format, rounding it down to three bytes of precision
+pub fn compact_from_target(target: &Uint256) -> u32 {
+    let mut size = target.bits().div_ceil(8);
+    let mut mantissa = match size {
+        0..=3 => (target.low_u64() << (8 * (3 - size))) as u32,
+        _ => (*target >> (8 * (size - 3))).low_u32()
+    };
+
+    // The mantissa would be read as negative
+    if mantissa & 0x00800000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    mantissa | (size as u32) << 24
+}
+
+/// Compute the target of a retarget block, given the target of the last block of the
+/// period and the times of the first and last blocks of the period.
+pub fn retarget(params: &ChainParams, last_bits: u32, first_time: u32, last_time: u32) -> u32 {
+    let expected = params.target_timespan().as_secs();
+    let timespan = (last_time as i64 - first_time as i64).clamp(expected as i64 / 4, expected as i64 * 4) as u64;
+
+    let target = match target_from_compact(last_bits) {
+        Some(t) => t,
+        None => return compact_from_target(&params.max_target)
+    };
+    // Targets close to 256 bits are divided first so that scaling them does not overflow
+    let expected = Uint256::from_u64(expected).expect("Fits in 64 bits");
+    let target = match target.bits() + 32 > 256 {
+        true => (target / expected).mul_u32(timespan as u32),
+        false => target.mul_u32(timespan as u32) / expected
+    };
+    compact_from_target(&target.min(params.max_target))
+}
+
+/// Expected number of hashes needed to meet a target, which is 2^256 / (target + 1).
+/// Computed as !target / (target + 1) + 1 like in bitcoin core, as 2^256 does not fit in
+/// 256 bits.
+pub fn work(target: &Uint256) -> Uint256 {
+    let mut divisor = *target;
+    divisor.increment();
+    let mut work = !*target / divisor;
+    work.increment();
+    work
+}
+
+/// Read a hash as the little endian number it is compared to targets as
+pub fn hash_to_u256(hash: &BlockHash) -> Uint256 {
+    let mut bytes = hash.into_inner();
+    bytes.reverse();
+    Uint256::from_be_bytes(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdata::BlockHeader;
+
+    #[test]
+    fn compact_targets() {
+        let max = target_from_compact(0x1d00ffff).unwrap();
+        assert_eq!(max, Uint256::from_u64(0xffff).unwrap() << 208);
+        assert_eq!(compact_from_target(&max), 0x1d00ffff);
+        assert_eq!(target_from_compact(0x03123456), Uint256::from_u64(0x123456));
+        assert_eq!(target_from_compact(0x02123456), Uint256::from_u64(0x1234));
+        assert_eq!(compact_from_target(&Uint256::from_u64(0x80).unwrap()), 0x02008000);
+
+        // Zero, negative and overflowing targets
+        assert_eq!(target_from_compact(0x1d000000), None);
+        assert_eq!(target_from_compact(0x01003456), None);
+        assert_eq!(target_from_compact(0x1d800001), None);
+        assert_eq!(target_from_compact(0x21010000), None);
+        assert!(target_from_compact(0x207fffff).is_some());
+
+        let mut header = BlockHeader::genesis();
+        assert!(header.check_pow());
+        header.nonce += 1;
+        assert!(!header.check_pow());
+
+        // Chainwork of the genesis block in bitcoin core
+        assert_eq!(work(&max), Uint256::from_u64(0x100010001).unwrap());
+        assert_eq!(work(&(max >> 1)), Uint256::from_u64(0x200020002).unwrap());
+    }
+
+    #[test]
+    fn retargets() {
+        // Vectors from the pow tests of bitcoin core
+        let params = ChainParams::main();
+        assert_eq!(retarget(&params, 0x1d00ffff, 1261130161, 1262152739), 0x1d00d86a);
+        assert_eq!(retarget(&params, 0x1d00ffff, 1231006505, 1233061996), 0x1d00ffff);
+        assert_eq!(retarget(&params, 0x1c05a3f4, 1279008237, 1279297671), 0x1c0168fd);
+        assert_eq!(retarget(&params, 0x1c387f6f, 1263163443, 1269211443), 0x1d00e1fd);
+    }
+}
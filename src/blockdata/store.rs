@@ -0,0 +1,201 @@
+// store.rs
+//
+// Storage of the headers of the chain.
+//
+// Stores hold the headers of the active chain from the genesis block, indexed by height and
+// hash. The file store appends each header to a file of 80 byte encoded headers, and reads
+// them back when opened so synced headers are kept across restarts. Since headers are only
+// appended, a crash can at most leave a partial header at the end of the file, which is
+// dropped when the file is opened. The file is only truncated when a reorg disconnects the
+// headers at its end.
+//
+
+use crate::{
+    bitcoin::hash_types::BlockHash,
+    blockdata::BlockHeader,
+    encode::{
+        Decode,
+        Encode,
+        Error
+    }
+};
+use std::{
+    collections::HashMap,
+    fs::{
+        File,
+        OpenOptions
+    },
+    io::{
+        Read,
+        Write
+    },
+    path::Path
+};
+
+/// Storage backend of a header chain
+pub trait HeaderStore {
+    /// Number of headers in the store
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the header at a height
+    fn get_at(&self, height: usize) -> Option<BlockHeader>;
+
+    /// Get the height of a header by its hash
+    fn height_of(&self, hash: &BlockHash) -> Option<usize>;
+
+    /// Get a header by its hash
+    fn get(&self, hash: &BlockHash) -> Option<BlockHeader> {
+        self.get_at(self.height_of(hash)?)
+    }
+
+    /// Add a header after the tip
+    fn append(&mut self, header: BlockHeader) -> Result<(), Error>;
+
+    /// Remove the headers from a height to the tip
+    fn truncate(&mut self, height: usize) -> Result<(), Error>;
+
+    /// Get the last header and its height
+    fn tip(&self) -> Option<(usize, BlockHeader)> {
+        let height = self.len().checked_sub(1)?;
+        Some((height, self.get_at(height)?))
+    }
+}
+
+/// Store keeping the headers in memory
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    headers: Vec<BlockHeader>,
+    heights: HashMap<BlockHash, usize>
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl HeaderStore for MemoryStore {
+    fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    fn get_at(&self, height: usize) -> Option<BlockHeader> {
+        self.headers.get(height).copied()
+    }
+
+    fn height_of(&self, hash: &BlockHash) -> Option<usize> {
+        self.heights.get(hash).copied()
+    }
+
+    fn append(&mut self, header: BlockHeader) -> Result<(), Error> {
+        self.heights.insert(header.hash(), self.headers.len());
+        self.headers.push(header);
+        Ok(())
+    }
+
+    fn truncate(&mut self, height: usize) -> Result<(), Error> {
+        for header in self.headers.iter().skip(height) {
+            self.heights.remove(&header.hash());
+        }
+        self.headers.truncate(height);
+        Ok(())
+    }
+}
+
+/// Store appending the headers to a file, with an index in memory
+#[derive(Debug)]
+pub struct FileStore {
+    file: File,
+    index: MemoryStore
+}
+
+impl FileStore {
+    /// Open a store file, creating it if it does not exist.
+    /// Headers that do not follow the previous header of the file, and everything after
+    /// them, are dropped.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut index = MemoryStore::new();
+        for chunk in data.chunks_exact(BlockHeader::SIZE) {
+            let header = BlockHeader::net_decode(chunk)?;
+            if matches!(index.tip(), Some((_, tip)) if tip.hash() != header.prev_blockhash) { break }
+            index.append(header)?;
+        }
+
+        let valid = (index.len() * BlockHeader::SIZE) as u64;
+        if valid != data.len() as u64 {
+            file.set_len(valid)?;
+        }
+        Ok(Self { file, index })
+    }
+}
+
+impl HeaderStore for FileStore {
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn get_at(&self, height: usize) -> Option<BlockHeader> {
+        self.index.get_at(height)
+    }
+
+    fn height_of(&self, hash: &BlockHash) -> Option<usize> {
+        self.index.height_of(hash)
+    }
+
+    fn append(&mut self, header: BlockHeader) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(BlockHeader::SIZE);
+        header.net_encode(&mut buf);
+        self.file.write_all(&buf)?;
+        self.index.append(header)
+    }
+
+    fn truncate(&mut self, height: usize) -> Result<(), Error> {
+        if height >= self.len() { return Ok(()) }
+        self.file.set_len((height * BlockHeader::SIZE) as u64)?;
+        self.index.truncate(height)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdata::params::ChainParams;
+
+    #[test]
+    fn file_store_reopen() {
+        let path = std::env::temp_dir().join(format!("btcnetmsg-headers-{}.dat", rand::random::<u32>()));
+        let genesis = ChainParams::regtest().genesis;
+        let mut next = genesis;
+        next.prev_blockhash = genesis.hash();
+
+        let mut store = FileStore::open(&path).unwrap();
+        assert!(store.is_empty());
+        store.append(genesis).unwrap();
+        store.append(next).unwrap();
+        drop(store);
+
+        // A partial header left by a crash is dropped
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[1; 40]).unwrap();
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.tip(), Some((1, next)));
+        assert_eq!(store.get(&genesis.hash()), Some(genesis));
+        assert_eq!(store.height_of(&next.hash()), Some(1));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * BlockHeader::SIZE as u64);
+
+        store.truncate(1).unwrap();
+        assert_eq!(store.height_of(&next.hash()), None);
+        assert_eq!(FileStore::open(&path).unwrap().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
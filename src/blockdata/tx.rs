@@ -0,0 +1,116 @@
+// tx.rs
+//
+// Transaction with its identifiers.
+//
+// Transactions are parsed with rust-bitcoin, which reads the segwit marker and witnesses.
+// The txid hashes the transaction without its witnesses and the wtxid hashes it with them,
+// so both are the same for transactions without witnesses. Both are computed once when the
+// transaction is created, as they are needed to match every inventory entry.
+//
+
+use crate::bitcoin::{
+    hash_types::{
+        Txid,
+        Wtxid
+    },
+    Transaction
+};
+
+/// Transaction with its txid and wtxid
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tx {
+    inner: Transaction,
+    txid: Txid,
+    wtxid: Wtxid
+}
+
+impl Tx {
+    pub fn new(tx: Transaction) -> Self {
+        Self {
+            txid: tx.txid(),
+            wtxid: tx.wtxid(),
+            inner: tx
+        }
+    }
+
+    /// Hash of the transaction without its witnesses
+    pub fn txid(&self) -> Txid {
+        self.txid
+    }
+
+    /// Hash of the transaction including its witnesses
+    pub fn wtxid(&self) -> Wtxid {
+        self.wtxid
+    }
+
+    /// Check if any input of the transaction has a witness
+    pub fn has_witness(&self) -> bool {
+        self.inner.input.iter().any(|i| !i.witness.is_empty())
+    }
+
+    pub fn transaction(&self) -> &Transaction {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> Transaction {
+        self.inner
+    }
+}
+
+impl From<Transaction> for Tx {
+    fn from(tx: Transaction) -> Self {
+        Self::new(tx)
+    }
+}
+
+impl From<Tx> for Transaction {
+    fn from(tx: Tx) -> Self {
+        tx.inner
+    }
+}
+
+impl std::ops::Deref for Tx {
+    type Target = Transaction;
+
+    fn deref(&self) -> &Transaction {
+        &self.inner
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitcoin::hashes::Hash,
+        encode::{
+            Decode,
+            Encode
+        },
+        msg::inventory::Inventory
+    };
+
+    #[test]
+    fn witness_ids() {
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        let legacy = Tx::new(genesis.txdata[0].clone());
+        assert!(!legacy.has_witness());
+        assert_eq!(legacy.txid().into_inner(), legacy.wtxid().into_inner());
+
+        let mut spend = genesis.txdata[0].clone();
+        spend.input[0].witness = vec![vec![1; 72], vec![2; 33]];
+        let segwit = Tx::new(spend);
+        assert!(segwit.has_witness());
+        assert_eq!(segwit.txid(), legacy.txid());
+        assert_ne!(segwit.wtxid().into_inner(), segwit.txid().into_inner());
+
+        let mut enc = Vec::new();
+        assert_eq!(segwit.net_encode(&mut enc), segwit.encoded_size());
+        let dec = Tx::net_decode(&enc[..]).unwrap();
+        assert_eq!(dec, segwit);
+
+        assert!(Inventory::WitnessTx(segwit.txid()).matches(&dec));
+        assert!(Inventory::Tx(segwit.txid()).matches(&dec));
+        assert!(!Inventory::WitnessTx(Txid::from_inner(segwit.wtxid().into_inner())).matches(&dec));
+    }
+}
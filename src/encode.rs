@@ -16,40 +16,60 @@ use crate::{
         data::{
             Message,
             MessagePayload,
+            MessageRef,
+            MessagePayloadRef,
+            RawMessage,
             EmptyPayload
         },
         header::{
             Magic,
             Command,
-            MessageHeader
+            MessageHeader,
+            COMMANDS,
+            sha256d
         },
         network::{
             ServicesList,
-            VersionMessage,
-            Service,
-            SERVICE_BITS,
             NetAddress,
             TimestampedNetAddress
         },
         inventory::{
             Inventory,
-            BlockdataLocatorInfo
+            BlockdataLocatorInfo,
+            INVENTORY_SIZE,
+            MAX_INV_SIZE,
+            MAX_LOCATOR_SIZE
         },
-        VariableInteger
+        VariableInteger,
+        VarString
+    },
+    address::{
+        Address,
+        AddressNetwork,
+        Port
     },
-    address::Address,
 
-    bitcoin::{
-        Transaction,
+    blockdata::{
+        compact::{
+            BlockTxn,
+            BlockTxnRequest,
+            CompactBlock,
+            PrefilledTx,
+            ShortId
+        },
+        Block,
         BlockHeader,
-        Block
-    }
+        Tx
+    },
+
+    bitcoin::Transaction
 };
 
 use crate::bitcoin::{
     hash_types::{
         Txid,
-        BlockHash
+        BlockHash,
+        TxMerkleNode
     },
     consensus::{
         Encodable,
@@ -61,6 +81,12 @@ use crate::bitcoin::{
 pub trait Encode {
     fn net_encode<W>(&self, w: W) -> usize
     where W: std::io::Write;
+
+    /// Return the length of self when encoded.
+    /// Defaults to encoding into a sink, types with a known size should override this.
+    fn encoded_size(&self) -> usize {
+        self.net_encode(std::io::sink())
+    }
 }
 
 pub trait Decode: Sized {
@@ -68,14 +94,97 @@ pub trait Decode: Sized {
     where R: std::io::Read;
 }
 
+/// Trait to decode self from a caller owned buffer, borrowing large data from the buffer
+/// instead of copying it.
+pub trait DecodeRef<'a>: Sized {
+    /// Returns the decoded object and the number of bytes consumed from the buffer.
+    fn net_decode_ref(buf: &'a [u8]) -> Result<(Self, usize), Error>;
+}
+
 #[derive(Debug)]
 pub enum Error {
     InvalidData,
     BadNetworkMagic(Magic),
     Io(std::io::Error),
-    UnknownCommand(String)
+    UnknownCommand(String),
+    InvalidJson(String),
+    BadChecksum {
+        expected: [u8; 4],
+        actual: [u8; 4]
+    },
+    PayloadTooLarge(u32),
+    TooManyItems {
+        count: u64,
+        max: usize
+    },
+    NonCanonicalVarint,
+    InvalidUtf8,
+
+    // Error that occured while decoding the payload of a message
+    Decode {
+        command: Command,    // Command of the message being decoded
+        offset: usize,       // Byte offset within the payload where decoding failed
+        kind: Box<Error>     // Underlying cause
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidData => write!(f, "invalid data"),
+            Self::BadNetworkMagic(m) => write!(f, "bad network magic {:#010x}", m.bytes()),
+            Self::Io(e) => write!(f, "io error: {}", e),
+            Self::UnknownCommand(c) => write!(f, "unknown command `{}`", c),
+            Self::InvalidJson(e) => write!(f, "invalid json: {}", e),
+            Self::BadChecksum { expected, actual } => write!(f, "bad checksum, expected {:02x?} got {:02x?}", expected, actual),
+            Self::PayloadTooLarge(len) => write!(f, "payload of {} bytes is too large", len),
+            Self::TooManyItems { count, max } => write!(f, "{} items exceeds the maximum of {}", count, max),
+            Self::NonCanonicalVarint => write!(f, "non canonical variable length integer"),
+            Self::InvalidUtf8 => write!(f, "invalid utf-8 string"),
+            Self::Decode { command, offset, kind } => write!(f, "failed to decode `{}` payload at offset {}: {}", command.to_str(), offset, kind)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Decode { kind, .. } => Some(kind.as_ref()),
+            _ => None
+        }
+    }
+}
+
+
+/// Limits and checks applied when decoding messages received from peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeConfig {
+    pub max_addrs: usize,         // Maximum number of addresses in an `addr` message
+    pub max_inv: usize,           // Maximum number of entries in `inv`, `getdata` and `notfound` messages, at most the protocol cap
+    pub max_headers: usize,       // Maximum number of headers in a `headers` message
+    pub max_locator: usize,       // Maximum number of hashes in a `getblocks` or `getheaders` locator, at most the protocol cap
+    pub max_payload: usize,       // Maximum payload length in bytes
+    pub strict_varints: bool,     // Reject varints that are not encoded in their shortest form
+    pub verify_checksum: bool,    // Reject messages with a checksum that does not match the payload
+    pub strict_utf8: bool         // Reject user agents that are not valid UTF-8 instead of replacing invalid sequences
 }
 
+impl Default for DecodeConfig {
+    /// Default limits match the ones used by Bitcoin Core
+    fn default() -> Self {
+        Self {
+            max_addrs: 1000,
+            max_inv: MAX_INV_SIZE,
+            max_headers: 2000,
+            max_locator: MAX_LOCATOR_SIZE,
+            max_payload: 4_000_000,
+            strict_varints: true,
+            verify_checksum: true,
+            strict_utf8: false
+        }
+    }
+}
 
 /// Utility function to decode a slice into an object without consuming the entire slice.
 /// Returns the decoded object and the position in the slice where the object decode ended.
@@ -93,6 +202,10 @@ macro_rules! integer_le_encode {
             where W: std::io::Write {
                 w.write(&self.to_le_bytes()).expect("Failed to write")
             }
+
+            fn encoded_size(&self) -> usize {
+                std::mem::size_of::<$int>()
+            }
         }
     };
 }
@@ -107,7 +220,7 @@ macro_rules! integer_le_decode {
                 Self: Sized
             {
                 let mut buf = [0; std::mem::size_of::<$int>()];
-                r.read_exact(&mut buf).expect("Failed to read");
+                r.read_exact(&mut buf)?;
                 
                 let mut ret: u64 = 0;
                 let mut i = buf.len() - 1;
@@ -115,7 +228,7 @@ macro_rules! integer_le_decode {
                     ret ^= buf[i] as u64;
                     if i == 0 { break }
                     i-=1;
-                    ret = ret << 8; 
+                    ret <<= 8;
                 }
                 
                 Ok(ret as $int)
@@ -129,12 +242,76 @@ integer_le_encode!(u16);
 integer_le_encode!(u32);
 integer_le_encode!(u64);
 integer_le_encode!(usize);
+integer_le_encode!(i8);
+integer_le_encode!(i16);
+integer_le_encode!(i32);
+integer_le_encode!(i64);
 
 integer_le_decode!(u8);
 integer_le_decode!(u16);
 integer_le_decode!(u32);
 integer_le_decode!(u64);
 integer_le_decode!(usize);
+integer_le_decode!(i8);
+integer_le_decode!(i16);
+integer_le_decode!(i32);
+integer_le_decode!(i64);
+
+/// Booleans are encoded as a single byte
+impl Encode for bool {
+    fn net_encode<W>(&self, w: W) -> usize
+    where W: std::io::Write {
+        (*self as u8).net_encode(w)
+    }
+
+    fn encoded_size(&self) -> usize {
+        1
+    }
+}
+
+impl Decode for bool {
+    fn net_decode<R>(r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Ok(u8::net_decode(r)? != 0)
+    }
+}
+
+
+/// Optional values are used for trailing fields that older peers may omit.
+/// `None` encodes to nothing.
+impl<T: Encode> Encode for Option<T> {
+    fn net_encode<W>(&self, w: W) -> usize
+    where W: std::io::Write {
+        match self {
+            Some(v) => v.net_encode(w),
+            None => 0
+        }
+    }
+
+    fn encoded_size(&self) -> usize {
+        self.as_ref().map_or(0, |v| v.encoded_size())
+    }
+}
+
+/// Decoding an optional value returns `None` if the reader is already at the end of the data.
+/// Values that end partway are still an error.
+/// Readers should therefore be bound to the payload length (see [`Message`] decoding).
+impl<T: Decode> Decode for Option<T> {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        // Peek the first byte to tell a missing value from a truncated one
+        let mut first = [0; 1];
+        loop {
+            match r.read(&mut first) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Io(e))
+            }
+        }
+        Ok(Some(T::net_decode(std::io::Read::chain(&first[..], r))?))
+    }
+}
 
 
 /// Macro to encode arrays
@@ -145,6 +322,10 @@ macro_rules! array_encode {
             where W: std::io::Write {
                 w.write(self).expect("Failed to write")
             }
+
+            fn encoded_size(&self) -> usize {
+                $len
+            }
         }
     };
 }
@@ -158,7 +339,7 @@ macro_rules! array_decode {
                 Self: Sized
             {
                 let mut buf: [u8; $len] = [0; $len];
-                r.read_exact(&mut buf).expect("Failed to read");
+                r.read_exact(&mut buf)?;
                 
                 Ok(buf)
             }
@@ -168,11 +349,13 @@ macro_rules! array_decode {
 
 array_encode!(4);
 array_encode!(2);
+array_encode!(6);
 array_encode!(16);
 array_encode!(32);
 
 array_decode!(4);
 array_decode!(2);
+array_decode!(6);
 array_decode!(16);
 array_decode!(32);
 
@@ -187,6 +370,10 @@ impl<T: Encode> Encode for Vec<T> {
         }
         size
     }
+
+    fn encoded_size(&self) -> usize {
+        self.iter().map(|elem| elem.encoded_size()).sum()
+    }
 }
 
 
@@ -198,22 +385,31 @@ impl Encode for VariableInteger {
                 (self.0 as u8).net_encode(w)
             },
             0xFD..=0xFFFF => {
-                w.write(&[0xFD]).expect("Failed to write");
+                w.write_all(&[0xFD]).expect("Failed to write");
                 (self.0 as u16).net_encode(w);
                 3
             },
             0x10000..=0xFFFF_FFFF => {
-                w.write(&[0xFE]).expect("Failed to write");
+                w.write_all(&[0xFE]).expect("Failed to write");
                 (self.0 as u32).net_encode(w);
                 5
             },
             _ => {
-                w.write(&[0xFF]).expect("Failed to write");
-                (self.0 as u64).net_encode(w);
+                w.write_all(&[0xFF]).expect("Failed to write");
+                self.0.net_encode(w);
                 9
             }
         }
     }
+
+    fn encoded_size(&self) -> usize {
+        match self.0 {
+            0..=0xFC => 1,
+            0xFD..=0xFFFF => 3,
+            0x10000..=0xFFFF_FFFF => 5,
+            _ => 9
+        }
+    }
 }
 
 impl Decode for VariableInteger {
@@ -221,7 +417,7 @@ impl Decode for VariableInteger {
         // Read the first byte as a length indicator and match it with protocol varint length indicators
         // to set the buffer length of the integer that follows
         let mut len_indic: [u8; 1] = [0; 1];
-        r.read_exact(&mut len_indic).expect("Failed to read");
+        r.read_exact(&mut len_indic)?;
         let mut buf: Vec<u8> = match len_indic[0] {
             0xFD => vec![0; 2],
             0xFE => vec![0; 4],
@@ -236,13 +432,29 @@ impl Decode for VariableInteger {
 
         // The varint did have a length indicating prefix.
         // Read the integer and append zeroes to cast it as a LE u64.
-        r.read_exact(&mut buf).expect("Failed to read");
+        r.read_exact(&mut buf)?;
         while buf.len() != 8 {
             buf.push(0x00);
         }
 
         // Return the LE u64 decoded as a Varint
-        return Ok(VariableInteger::from(u64::net_decode(&buf[..])?))
+        Ok(VariableInteger::from(u64::net_decode(&buf[..])?))
+    }
+}
+
+impl VariableInteger {
+    /// Decode a varint, rejecting values that are not encoded in their shortest form.
+    pub fn net_decode_canonical<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        let (value, min) = match u8::net_decode(&mut r)? {
+            0xFD => (u16::net_decode(&mut r)? as u64, 0xFD),
+            0xFE => (u32::net_decode(&mut r)? as u64, 0x10000),
+            0xFF => (u64::net_decode(&mut r)?, 0x1_0000_0000),
+            x => (x as u64, 0)
+        };
+
+        if value < min { return Err(Error::NonCanonicalVarint) }
+        Ok(VariableInteger(value))
     }
 }
 
@@ -251,18 +463,22 @@ impl Encode for Magic {
     where W: std::io::Write {
         self.bytes().net_encode(w)
     }
+
+    fn encoded_size(&self) -> usize {
+        4
+    }
 }
 
 impl Decode for Magic {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
         let mut buf = [0; 4];
-        r.read(&mut buf).expect("Failed to read");
+        r.read_exact(&mut buf)?;
         buf.reverse();
 
         // If the network magic is not known, return an error.
         match Magic::from(buf) {
-            Magic::Unknown(v) => return Err(Error::BadNetworkMagic(Magic::Unknown(v))),
+            Magic::Unknown(v) => Err(Error::BadNetworkMagic(Magic::Unknown(v))),
             x => Ok(x)
         }
     }
@@ -271,23 +487,40 @@ impl Decode for Magic {
 impl Encode for Command {
     fn net_encode<W>(&self, mut w: W) -> usize
     where W: std::io::Write {
+        if let Some((_, _, bytes)) = COMMANDS.iter().find(|(c, _, _)| c == self) {
+            return w.write(bytes).expect("Failed to write")
+        }
+
+        // Each character is written as a single byte so that unknown commands decoded
+        // from the wire are encoded back into the same bytes. Names built with
+        // `Command::unknown` have no characters above a byte.
         let mut buf: [u8; 12] = [0; 12];
-        let cmd_str = self.to_str().as_bytes();
-        buf[..cmd_str.len()].copy_from_slice(&cmd_str);
+        buf.iter_mut()
+            .zip(self.to_str().chars())
+            .for_each(|(b, c)| *b = c as u8);
         w.write(&buf).expect("Failed to write")
     }
+
+    fn encoded_size(&self) -> usize {
+        12
+    }
 }
 
 impl Decode for Command {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
         let mut buf = [0; 12];
-        r.read(&mut buf).expect("Failed to read");
+        r.read_exact(&mut buf)?;
+        if let Some((command, _, _)) = COMMANDS.iter().find(|(_, _, b)| *b == buf) {
+            return Ok(command.clone())
+        }
 
+        // Only the trailing null padding is stripped, any bytes in between are kept
+        // in unknown commands so they can be re-encoded byte for byte.
+        let end = buf.iter().rposition(|x| *x != 0x00).map_or(0, |i| i + 1);
         Self::from_str(
-        buf
+            buf[..end]
                 .iter()
-                .take_while(|x| **x != 0x00)
                 .map(|c| *c as char)
                 .collect::<String>()
         )
@@ -302,6 +535,10 @@ impl Encode for MessageHeader {
         self.length.net_encode(&mut w) +
         self.checksum.net_encode(&mut w)
     }
+
+    fn encoded_size(&self) -> usize {
+        Self::SIZE
+    }
 }
 
 impl Decode for MessageHeader {
@@ -328,83 +565,288 @@ impl Encode for Message {
     fn net_encode<W>(&self, mut w: W) -> usize
     where W: std::io::Write {
         // If the payload is empty, check that the heder has zero as the length.
-        if self.payload == MessagePayload::EmptyPayload {
-            assert_eq!(self.header.length, 0)
+        if *self.payload() == MessagePayload::EmptyPayload {
+            assert_eq!(self.header().length, 0)
         }
         
-        self.header.net_encode(&mut w) +
-        self.payload.net_encode(&mut w)
+        self.header().net_encode(&mut w) +
+        self.payload().net_encode(&mut w)
+    }
+
+    fn encoded_size(&self) -> usize {
+        self.header().encoded_size() + self.payload().encoded_size()
     }
 }
 
 impl Decode for Message {
-    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    fn net_decode<R>(r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Self::net_decode_with_config(r, &DecodeConfig::default())
+    }
+}
+
+impl Message {
+    /// Decode a message, applying the limits and checks in the config.
+    pub fn net_decode_with_config<R>(mut r: R, config: &DecodeConfig) -> Result<Self, Error>
     where R: std::io::Read {
         let header: MessageHeader = Decode::net_decode(&mut r)?;
+        let payload = MessagePayload::decode_with_header_config(&header, &mut r, config)?;
+        
+        Ok(Message::from_header(header, payload))
+    }
+}
 
-        // Message payload doesn't implement the [`Decode`] trait on it's own as
-        // it cannot be decoded without the header context.
-        // TODO: Read header.len bytes into a vector and then decode the vector to avoid comsuming more bytes than necessary.
-        let payload: MessagePayload = match header.command {
-            Command::Version => MessagePayload::Version(Decode::net_decode(&mut r)?),
-            Command::Verack => MessagePayload::EmptyPayload,
-            Command::SendHeaders => MessagePayload::EmptyPayload,
-            Command::WTxIdRelay => MessagePayload::EmptyPayload,
-            Command::Ping => MessagePayload::PingPong(Decode::net_decode(&mut r)?),
-            Command::Pong => MessagePayload::PingPong(Decode::net_decode(&mut r)?),
-            Command::Addr => { 
-                let count: VariableInteger = Decode::net_decode(&mut r)?;
-                assert!(count.inner() <= 100); // Max of 100 addresses
-                let mut addrs: Vec<TimestampedNetAddress> = Vec::new();
-                for _ in 0..count.inner() {
-                    addrs.push(Decode::net_decode(&mut r)?)
-                }
-                MessagePayload::AddrList(addrs)
-            },
-            Command::GetAddr => MessagePayload::EmptyPayload,
-            Command::Inv |
-            Command::GetData |
-            Command::NotFound => {
-                let count: VariableInteger = Decode::net_decode(&mut r)?;
-                let mut inv_items: Vec<Inventory> = Vec::new();
-                for _ in 0..count.inner() {
-                    inv_items.push(Decode::net_decode(&mut r)?)
-                }
+impl Encode for RawMessage {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        self.header.net_encode(&mut w) +
+        w.write(&self.payload_bytes).expect("Failed to write")
+    }
 
-                MessagePayload::InvVect(inv_items)
-            },
-            Command::Tx => MessagePayload::Transction(Transaction::consensus_decode(&mut r)?),
-            Command::GetBlocks |
-            Command::GetHeaders => MessagePayload::BlockLocator(Decode::net_decode(&mut r)?),
-            Command::Headers => {
-                let count = VariableInteger::net_decode(&mut r)?.inner();
-                let mut headers: Vec<BlockHeader> = Vec::new();
-                for _ in 0..count {
-                    headers.push(Decodable::consensus_decode(&mut r)?)
-                }
-                MessagePayload::Headers(headers)
-            },
-            Command::Block => MessagePayload::Block(Decodable::consensus_decode(&mut r)?),
+    fn encoded_size(&self) -> usize {
+        self.header.encoded_size() + self.payload_bytes.len()
+    }
+}
 
-            // Upon receiving an unknown/invalid command in the header...
-            Command::Unknown(_) => {
-                // Consume the payload and store it as a hex dump
-                let mut buf = vec![0; header.length as usize];
-                r.read_exact(&mut buf).expect("Failed to read");
+impl Decode for RawMessage {
+    /// Payload length is checked against the default config before the payload is read.
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        let header: MessageHeader = Decode::net_decode(&mut r)?;
+        check_header(&header, &DecodeConfig::default())?;
+
+        let mut payload_bytes = vec![0; header.length as usize];
+        r.read_exact(&mut payload_bytes)?;
 
-                MessagePayload::Dump(buf)
-            }
-        };
-        
         Ok(
-            Message {
+            RawMessage {
                 header,
-                payload
+                payload_bytes
             }
         )
     }
 }
 
+impl MessagePayload {
+    /// Decode the payload that follows an already decoded header.
+    /// Exactly `header.length` bytes are read from the reader.
+    pub fn decode_with_header<R>(header: &MessageHeader, r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Self::decode_with_header_config(header, r, &DecodeConfig::default())
+    }
+
+    /// Decode the payload that follows an already decoded header, applying the limits and checks in the config.
+    /// Exactly `header.length` bytes are read from the reader unless the header fails the config checks,
+    /// in which case nothing is read.
+    pub fn decode_with_header_config<R>(header: &MessageHeader, mut r: R, config: &DecodeConfig) -> Result<Self, Error>
+    where R: std::io::Read {
+        check_header(header, config)?;
+
+        // Read exactly the payload bytes so the payload decode cannot consume the next message
+        let mut buf = vec![0; header.length as usize];
+        r.read_exact(&mut buf)?;
+        check_checksum(header, &buf, config)?;
+        decode_payload_bytes(header, &buf[..], config)
+    }
+}
+
+impl MessageHeader {
+    /// Skip over the payload that follows self without decoding or buffering it.
+    pub fn skip_payload<R>(&self, r: R) -> Result<(), Error>
+    where R: std::io::Read {
+        let skipped = std::io::copy(&mut r.take(self.length as u64), &mut std::io::sink())?;
+        if skipped != self.length as u64 {
+            return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()))
+        }
+        Ok(())
+    }
+}
+
+impl<'a> DecodeRef<'a> for MessageRef<'a> {
+    fn net_decode_ref(buf: &'a [u8]) -> Result<(Self, usize), Error> {
+        Self::net_decode_ref_with_config(buf, &DecodeConfig::default())
+    }
+}
+
+impl<'a> MessageRef<'a> {
+    /// Decode a borrowed message, applying the limits and checks in the config.
+    pub fn net_decode_ref_with_config(buf: &'a [u8], config: &DecodeConfig) -> Result<(Self, usize), Error> {
+        // The header is always 24 bytes
+        if buf.len() < 24 {
+            return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()))
+        }
+        let header: MessageHeader = Decode::net_decode(&buf[..24])?;
+        check_header(&header, config)?;
+
+        let end = 24 + header.length as usize;
+        if buf.len() < end {
+            return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()))
+        }
+        let bytes = &buf[24..end];
+        check_checksum(&header, bytes, config)?;
+
+        // Large payloads are borrowed, everything else is decoded from the payload bytes.
+        let payload = match header.command {
+            Command::Tx => MessagePayloadRef::Transaction(bytes),
+            Command::Block => MessagePayloadRef::Block(bytes),
+            ref c if c.has_raw_payload() => MessagePayloadRef::Dump(bytes),
+            _ => MessagePayloadRef::Owned(decode_payload_bytes(&header, bytes, config)?)
+        };
+
+        Ok((MessageRef { header, payload }, end))
+    }
+}
+
+/// Check a decoded header against the config before its payload is read
+fn check_header(header: &MessageHeader, config: &DecodeConfig) -> Result<(), Error> {
+    if header.length as usize > config.max_payload {
+        return Err(Error::PayloadTooLarge(header.length))
+    }
+    Ok(())
+}
+
+/// Check the checksum in the header against the payload bytes, if enabled in the config
+fn check_checksum(header: &MessageHeader, payload: &[u8], config: &DecodeConfig) -> Result<(), Error> {
+    if !config.verify_checksum { return Ok(()) }
+
+    let mut actual: [u8; 4] = [0; 4];
+    actual.copy_from_slice(&sha256d(payload)[..4]);
+    if actual != header.checksum {
+        return Err(Error::BadChecksum { expected: header.checksum, actual })
+    }
+    Ok(())
+}
+
+/// Decode the item count of a list, checking it against the maximum
+fn decode_count<R>(r: R, max: usize, config: &DecodeConfig) -> Result<u64, Error>
+where R: std::io::Read {
+    let count = match config.strict_varints {
+        true => VariableInteger::net_decode_canonical(r)?,
+        false => VariableInteger::net_decode(r)?
+    }.inner();
+
+    if count > max as u64 {
+        return Err(Error::TooManyItems { count, max })
+    }
+    Ok(count)
+}
+
+/// Decode a message payload from its bytes, adding the command and the offset where
+/// decoding failed to any error.
+fn decode_payload_bytes(header: &MessageHeader, bytes: &[u8], config: &DecodeConfig) -> Result<MessagePayload, Error> {
+    if config.strict_utf8 && header.command == Command::Version {
+        check_user_agent(bytes).map_err(|(offset, kind)| Error::Decode {
+            command: header.command.clone(),
+            offset,
+            kind: Box::new(kind)
+        })?;
+    }
+
+    let mut cursor = std::io::Cursor::new(bytes);
+    decode_payload(header, &mut cursor, config).map_err(|kind| Error::Decode {
+        command: header.command.clone(),
+        offset: cursor.position() as usize,
+        kind: Box::new(kind)
+    })
+}
+
+/// Check that the user agent of a version payload is valid UTF-8.
+/// Returns the offset of the user agent with the error.
+fn check_user_agent(payload: &[u8]) -> Result<(), (usize, Error)> {
+    // The user agent follows the fixed size fields of the version message. Payloads too
+    // short to hold it fail when they are decoded.
+    const OFFSET: usize = 80;
+    let rest = match payload.get(OFFSET..) {
+        Some(rest) => rest,
+        None => return Ok(())
+    };
+    let (len, start) = match decode_partial::<VariableInteger>(rest) {
+        Ok(x) => x,
+        Err(_) => return Ok(())
+    };
+    match rest.get(start..).and_then(|r| r.get(..len.inner() as usize)) {
+        Some(agent) if std::str::from_utf8(agent).is_err() => Err((OFFSET + start, Error::InvalidUtf8)),
+        _ => Ok(())
+    }
+}
+
+/// Decode a message payload from a reader, given the header of the message it belongs to.
+fn decode_payload<R>(header: &MessageHeader, mut r: R, config: &DecodeConfig) -> Result<MessagePayload, Error>
+where R: std::io::Read {
+    // Message payload doesn't implement the [`Decode`] trait on it's own as
+    // it cannot be decoded without the header context.
+    let payload: MessagePayload = match &header.command {
+        Command::Version => MessagePayload::Version(Decode::net_decode(&mut r)?),
+        Command::Verack => MessagePayload::EmptyPayload,
+        Command::SendHeaders => MessagePayload::EmptyPayload,
+        Command::WTxIdRelay => MessagePayload::EmptyPayload,
+        Command::Ping => MessagePayload::PingPong(Decode::net_decode(&mut r)?),
+        Command::Pong => MessagePayload::PingPong(Decode::net_decode(&mut r)?),
+        Command::Addr => { 
+            let count = decode_count(&mut r, config.max_addrs, config)?;
+            let mut addrs: Vec<TimestampedNetAddress> = Vec::new();
+            for _ in 0..count {
+                addrs.push(Decode::net_decode(&mut r)?)
+            }
+            MessagePayload::AddrList(addrs)
+        },
+        Command::AddrV2 => {
+            let count = decode_count(&mut r, config.max_addrs, config)?;
+            let mut addrs: Vec<TimestampedNetAddress> = Vec::new();
+            for _ in 0..count {
+                addrs.extend(decode_addrv2(&mut r)?)
+            }
+            MessagePayload::AddrV2List(addrs)
+        },
+        Command::GetAddr => MessagePayload::EmptyPayload,
+        Command::Inv |
+        Command::GetData |
+        Command::NotFound => {
+            let count = decode_count(&mut r, config.max_inv.min(MAX_INV_SIZE), config)?;
+
+            // The count is checked against the payload so a short message can not reserve a lot
+            let capacity = (count as usize).min(header.length as usize / INVENTORY_SIZE);
+            let mut inv_items: Vec<Inventory> = Vec::with_capacity(capacity);
+            for _ in 0..count {
+                inv_items.push(Decode::net_decode(&mut r)?)
+            }
+
+            MessagePayload::InvVect(inv_items)
+        },
+        Command::Tx => MessagePayload::Transction(Transaction::consensus_decode(&mut r)?),
+        Command::GetBlocks |
+        Command::GetHeaders => MessagePayload::BlockLocator(decode_locator(&mut r, config)?),
+        Command::Headers => {
+            // Every header is followed by a transaction count, which is always zero
+            let count = decode_count(&mut r, config.max_headers, config)?;
+            let mut headers: Vec<BlockHeader> = Vec::new();
+            for _ in 0..count {
+                headers.push(Decode::net_decode(&mut r)?);
+                if VariableInteger::net_decode(&mut r)?.inner() != 0 {
+                    return Err(Error::InvalidData)
+                }
+            }
+            MessagePayload::Headers(headers)
+        },
+        Command::Block => MessagePayload::Block(Decode::net_decode(&mut r)?),
+        Command::MemPool |
+        Command::FilterClear |
+        Command::SendAddrV2 => MessagePayload::EmptyPayload,
+
+        // Upon receiving an unknown/invalid command in the header, or a command
+        // whose payload structure is not supported yet...
+        _ => {
+            // Consume the payload and store it as a hex dump
+            let mut buf = vec![0; header.length as usize];
+            r.read_exact(&mut buf)?;
+
+            MessagePayload::Dump(buf)
+        }
+    };
+
+    Ok(payload)
+}
+
 impl Encode for MessagePayload {
     fn net_encode<W>(&self, mut w: W) -> usize
     where W: std::io::Write {
@@ -413,52 +855,98 @@ impl Encode for MessagePayload {
             MessagePayload::PingPong(int) => int.net_encode(w),
             MessagePayload::EmptyPayload =>  EmptyPayload.net_encode(w),
             MessagePayload::AddrList(addrs) => VariableInteger::from(addrs.len()).net_encode(&mut w) + addrs.net_encode(&mut w),
+            MessagePayload::AddrV2List(addrs) => VariableInteger::from(addrs.len()).net_encode(&mut w) + addrs.iter().map(|a| encode_addrv2(a, &mut w)).sum::<usize>(),
             MessagePayload::InvVect(inv) => VariableInteger::from(inv.len()).net_encode(&mut w) + inv.net_encode(&mut w),
             MessagePayload::Transction(tx) => tx.consensus_encode(w).expect("Failed to write"),
             MessagePayload::BlockLocator(loc) => loc.net_encode(w),
-            MessagePayload::Block(block) => block.consensus_encode(w).expect("Failed to write"),
-            MessagePayload::Headers(hdrs) => VariableInteger::from(hdrs.len()).net_encode(&mut w) + hdrs.iter().fold(0, |acc, h| acc + h.consensus_encode(&mut w).expect("Failed to write")),
+            MessagePayload::Block(block) => block.net_encode(w),
+            MessagePayload::Headers(hdrs) => VariableInteger::from(hdrs.len()).net_encode(&mut w) + hdrs.iter().fold(0, |acc, h| acc + h.net_encode(&mut w) + VariableInteger::from(0usize).net_encode(&mut w)),
             MessagePayload::Dump(d) => d.net_encode(w)
         }
     }
-}   
+
+    fn encoded_size(&self) -> usize {
+        match self {
+            MessagePayload::Version(v) => v.encoded_size(),
+            MessagePayload::PingPong(int) => int.encoded_size(),
+            MessagePayload::EmptyPayload => 0,
+            MessagePayload::AddrList(addrs) => VariableInteger::from(addrs.len()).encoded_size() + addrs.encoded_size(),
+            MessagePayload::AddrV2List(addrs) => VariableInteger::from(addrs.len()).encoded_size() + addrs.iter().map(addrv2_size).sum::<usize>(),
+            MessagePayload::InvVect(inv) => VariableInteger::from(inv.len()).encoded_size() + inv.encoded_size(),
+            MessagePayload::Transction(tx) => tx.get_size(),
+            MessagePayload::BlockLocator(loc) => loc.encoded_size(),
+            MessagePayload::Block(block) => block.encoded_size(),
+            MessagePayload::Headers(hdrs) => VariableInteger::from(hdrs.len()).encoded_size() + hdrs.len() * (BlockHeader::SIZE + 1),
+            MessagePayload::Dump(d) => d.len()
+        }
+    }
+}
 
 /// Strings are encoded as var string which is the string bytes with a varint prefixed
-impl Encode for String {
+impl<const MAX: usize> Encode for VarString<MAX> {
     fn net_encode<W>(&self, mut w: W) -> usize
     where W: std::io::Write {
-        VariableInteger::from(self.len()).net_encode(&mut w) +
-        w.write(self.as_bytes()).expect("Failed to write")
+        VariableInteger::from(self.as_str().len()).net_encode(&mut w) +
+        w.write(self.as_str().as_bytes()).expect("Failed to write")
+    }
+
+    fn encoded_size(&self) -> usize {
+        VariableInteger::from(self.as_str().len()).encoded_size() + self.as_str().len()
     }
 }
 
-impl Decode for String {
+impl<const MAX: usize> Decode for VarString<MAX> {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
+        // Check the length before allocating so peers cannot make us allocate arbitrary amounts of memory
         let varint: VariableInteger = Decode::net_decode(&mut r)?;
+        if varint.inner() > MAX as u64 { return Err(Error::InvalidData) }
+
         let mut buf = vec![0; varint.inner() as usize];
-        r.read_exact(&mut buf).expect("Failed to read");
+        r.read_exact(&mut buf)?;
 
-        Ok(
-            buf
-                .iter()
-                .map(|x| *x as char)
-                .collect::<String>()
-        )
+        // Invalid UTF-8 sequences are replaced rather than rejected, like nodes do
+        Ok(Self::from_bytes_lossy(buf))
     }
 }
 
 impl Encode for Address {
     fn net_encode<W>(&self, mut w: W) -> usize
     where W: std::io::Write {
-        self.0.net_encode(&mut w)
+        // The legacy encoding can only hold IP addresses, other networks are written as
+        // the unspecified IPv6 address. Use addrv2 messages to gossip them.
+        self.socket_addr()
+            .unwrap_or_else(|| SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), self.port))
+            .net_encode(&mut w)
+    }
+
+    fn encoded_size(&self) -> usize {
+        18
     }
 }
 
 impl Decode for Address {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
-        Ok(Self(Decode::net_decode(&mut r)?))
+        Ok(Self::from(SocketAddr::net_decode(&mut r)?))
+    }
+}
+
+impl Encode for Port {
+    fn net_encode<W>(&self, w: W) -> usize
+    where W: std::io::Write {
+        self.0.net_encode(w)
+    }
+
+    fn encoded_size(&self) -> usize {
+        2
+    }
+}
+
+impl Decode for Port {
+    fn net_decode<R>(r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Ok(Port(Decode::net_decode(r)?))
     }
 }
 
@@ -466,7 +954,11 @@ impl Encode for SocketAddr {
     fn net_encode<W>(&self, mut w: W) -> usize
     where W: std::io::Write {
         self.ip().net_encode(&mut w) +
-        self.port().to_be_bytes().net_encode(&mut w)
+        Port::from(self.port()).net_encode(&mut w)
+    }
+
+    fn encoded_size(&self) -> usize {
+        18
     }
 }
 
@@ -474,8 +966,8 @@ impl Decode for SocketAddr {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
         let ip = Decode::net_decode(&mut r)?;
-        let portb: [u8; 2] = Decode::net_decode(&mut r)?;
-        Ok(SocketAddr::new(ip, portb[0] as u16 >> 8 | portb[1] as u16))
+        let port: Port = Decode::net_decode(&mut r)?;
+        Ok(SocketAddr::new(ip, port.to_u16()))
     }
 }
 
@@ -487,6 +979,10 @@ impl Encode for IpAddr {
             Self::V6(ip) => ip.net_encode(&mut w)
         }
     }
+
+    fn encoded_size(&self) -> usize {
+        16
+    }
 }
 
 impl Decode for IpAddr {
@@ -495,8 +991,8 @@ impl Decode for IpAddr {
         // Decode as ipv6...
         let ipv6: Ipv6Addr = Decode::net_decode(&mut r)?;
 
-        // attempt to convert to v4...
-        match ipv6.to_ipv4() {
+        // attempt to convert to v4, only IPv4 mapped addresses are IPv4 addresses...
+        match ipv6.to_ipv4_mapped() {
             Some(ipv4) => Ok(IpAddr::V4(ipv4)),
             None => Ok(IpAddr::V6(ipv6))
         }
@@ -511,6 +1007,10 @@ impl Encode for Ipv4Addr {
             .to_ipv6_mapped()
             .net_encode(&mut w)
     }
+
+    fn encoded_size(&self) -> usize {
+        16
+    }
 }
 
 impl Decode for Ipv4Addr {
@@ -531,6 +1031,10 @@ impl Encode for Ipv6Addr {
             .octets()
             .net_encode(&mut w)
     }
+
+    fn encoded_size(&self) -> usize {
+        16
+    }
 }
 
 impl Decode for Ipv6Addr {
@@ -544,18 +1048,11 @@ impl Decode for Ipv6Addr {
 impl Encode for ServicesList {
     fn net_encode<W>(&self, w: W) -> usize
     where W: std::io::Write {
-        // Collect all the service flags and XOR them up
-        let flag: u64 = 
-        self
-            .get_flags()
-            .iter()
-            .fold(
-                0,
-                |acc, num| 
-                acc ^ num.value()
-            );
+        self.bits().net_encode(w) //always 8 bytes
+    }
 
-        flag.net_encode(w) //always 8 bytes
+    fn encoded_size(&self) -> usize {
+        8
     }
 }
 
@@ -563,42 +1060,7 @@ impl Decode for ServicesList {
     fn net_decode<R>(mut r: R) -> Result<Self, Error>
     where R: std::io::Read {
         let flags: u64 = Decode::net_decode(&mut r)?;
-
-        // Early exit for flags with no bits set...
-        if flags == 0 {
-            return Ok(ServicesList::default());
-        }
-
-        // Iterate through all possible set flags and record any flags that are set.
-        let mut services = ServicesList::new();
-        for bitp in SERVICE_BITS {
-            if flags & (1<<bitp) == (1<<bitp) {
-                let flag = Service::try_from_bit(1<<bitp)?;
-                services.add_flag(flag);
-            }
-        }
-
-        Ok(services)
-    }
-}
-
-impl Encode for NetAddress {
-    fn net_encode<W>(&self, mut w: W) -> usize
-    where W: std::io::Write {
-        self.services.net_encode(&mut w) +
-        self.address.net_encode(&mut w)
-    }
-}
-
-impl Decode for NetAddress {
-    fn net_decode<R>(mut r: R) -> Result<Self, Error>
-    where R: std::io::Read {
-        Ok(
-            Self::new(
-                Decode::net_decode(&mut r)?,
-                Decode::net_decode(&mut r)?
-            )
-        )
+        Ok(ServicesList::from_bits(flags))
     }
 }
 
@@ -609,6 +1071,10 @@ impl Encode for TimestampedNetAddress {
         (self.timestamp.as_secs() as u32).net_encode(&mut w) +
         self.netaddress.net_encode(&mut w)
     }
+
+    fn encoded_size(&self) -> usize {
+        4 + self.netaddress.encoded_size()
+    }
 }
 
 impl Decode for TimestampedNetAddress {
@@ -624,6 +1090,50 @@ impl Decode for TimestampedNetAddress {
     }
 }
 
+/// Maximum length of an address in addrv2 messages (BIP-155)
+const MAX_ADDRV2_LENGTH: u64 = 512;
+
+/// Encode an address in the addrv2 format
+fn encode_addrv2<W>(addr: &TimestampedNetAddress, mut w: W) -> usize
+where W: std::io::Write {
+    let network = &addr.netaddress.address.network;
+    let bytes = network.bytes();
+
+    (addr.timestamp.as_secs() as u32).net_encode(&mut w) +
+    VariableInteger::from(addr.netaddress.services.bits()).net_encode(&mut w) +
+    network.id().net_encode(&mut w) +
+    VariableInteger::from(bytes.len()).net_encode(&mut w) +
+    bytes.net_encode(&mut w) +
+    addr.netaddress.address.port_bytes().net_encode(&mut w)
+}
+
+/// Get the size of an address in the addrv2 format
+fn addrv2_size(addr: &TimestampedNetAddress) -> usize {
+    let len = addr.netaddress.address.network.bytes().len();
+    4 + VariableInteger::from(addr.netaddress.services.bits()).encoded_size() + 1 + VariableInteger::from(len).encoded_size() + len + 2
+}
+
+/// Decode an address in the addrv2 format.
+/// Addresses on networks that are not supported are read and return `None`.
+fn decode_addrv2<R>(mut r: R) -> Result<Option<TimestampedNetAddress>, Error>
+where R: std::io::Read {
+    let secs: u32 = Decode::net_decode(&mut r)?;
+    let services = ServicesList::from_bits(VariableInteger::net_decode(&mut r)?.inner());
+    let id: u8 = Decode::net_decode(&mut r)?;
+    let len = VariableInteger::net_decode(&mut r)?.inner();
+    if len > MAX_ADDRV2_LENGTH { return Err(Error::InvalidData) }
+    let mut bytes = vec![0; len as usize];
+    r.read_exact(&mut bytes)?;
+    let port: Port = Decode::net_decode(&mut r)?;
+
+    Ok(
+        AddressNetwork::from_id_and_bytes(id, &bytes).map(|network| TimestampedNetAddress::new(
+            Duration::from_secs(secs as u64),
+            NetAddress::new(services, Address { network, port: port.to_u16() })
+        ))
+    )
+}
+
 impl Encode for Duration {
     fn net_encode<W>(&self, w: W) -> usize
     where W: std::io::Write {
@@ -632,6 +1142,10 @@ impl Encode for Duration {
             .as_secs()
             .net_encode(w)
     }
+
+    fn encoded_size(&self) -> usize {
+        8
+    }
 }
 
 impl Decode for Duration {
@@ -642,63 +1156,21 @@ impl Decode for Duration {
     }
 }
 
-impl Encode for VersionMessage {
-    fn net_encode<W>(&self, mut w: W) -> usize
+impl Encode for EmptyPayload {
+    fn net_encode<W>(&self, _w: W) -> usize
     where W: std::io::Write {
-        self.version.net_encode(&mut w) +
-        self.service.net_encode(&mut w) +
-        self.timestamp.net_encode(&mut w) +
-        self.addr_recv.net_encode(&mut w) +
-        self.addr_from.net_encode(&mut w) +
-        self.nonce.net_encode(&mut w) +
-        self.agent.net_encode(&mut w) +
-        self.start_height.net_encode(&mut w) +
-        (self.relay as u8).net_encode(&mut w)
+        0
+    }
+
+    fn encoded_size(&self) -> usize {
+        0
     }
 }
 
-impl Decode for VersionMessage {
-    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+impl Decode for EmptyPayload {
+    fn net_decode<R>(_r: R) -> Result<Self, Error>
     where R: std::io::Read {
-        let version: u32 = Decode::net_decode(&mut r)?;
-        let services: ServicesList = Decode::net_decode(&mut r)?;
-        let timestamp: Duration = Decode::net_decode(&mut r)?;
-        let addr_recv: NetAddress = Decode::net_decode(&mut r)?;
-        let addr_from: NetAddress = Decode::net_decode(&mut r)?;
-        let nonce: u64 = Decode::net_decode(&mut r)?;
-        let agent: String = Decode::net_decode(&mut r)?;
-        let start_height: u32 = Decode::net_decode(&mut r)?;
-        let relay = match u8::net_decode(&mut r)? {
-            0 => false,
-            _ => true
-        };
-        
-        
-        Ok(VersionMessage::new(
-            version,
-            services,
-            timestamp,
-            addr_recv,
-            addr_from,
-            nonce,
-            agent,
-            start_height,
-            relay
-        ))
-    }
-}
-
-impl Encode for EmptyPayload {
-    fn net_encode<W>(&self, _w: W) -> usize
-    where W: std::io::Write {
-        0
-    }
-}
-
-impl Decode for EmptyPayload {
-    fn net_decode<R>(_r: R) -> Result<Self, Error>
-    where R: std::io::Read {
-        Ok(Self::default())
+        Ok(Self)
     }
 }
 
@@ -719,6 +1191,10 @@ impl Encode for Inventory {
             Self::Unknown{inv_type: _, hash: h} => *h
         }.net_encode(&mut w)
     }
+
+    fn encoded_size(&self) -> usize {
+        36
+    }
 }
 
 impl Decode for Inventory {
@@ -742,28 +1218,266 @@ impl Encode for BlockdataLocatorInfo {
         self.hashes.iter().fold(0, |len, hash| len + hash.net_encode(&mut w)) +
         self.stop.net_encode(&mut w)
     }
+
+    fn encoded_size(&self) -> usize {
+        4 + VariableInteger::from(self.hashes.len()).encoded_size() + 32 * self.hashes.len() + 32
+    }
 }
 
 impl Decode for BlockdataLocatorInfo {
-    fn net_decode<R: std::io::Read>(mut r: R) -> Result<Self, Error> {
-        let version: u32 = Decode::net_decode(&mut r)?;
-        let count: u64 = VariableInteger::net_decode(&mut r)?.inner();
-        let mut hashes: Vec<BlockHash> = Vec::new();
-        for _ in 0..count+1 {
-            hashes.push(Decode::net_decode(&mut r)?);
-        }
-        let stop = hashes.pop().expect("Stop hash missing.");
+    fn net_decode<R: std::io::Read>(r: R) -> Result<Self, Error> {
+        decode_locator(r, &DecodeConfig::default())
+    }
+}
 
-        Ok(
-            Self::new(
-                version,
-                hashes,
-                stop
-            )
+/// Decode a block locator, checking the number of hashes against the config and the protocol cap
+fn decode_locator<R>(mut r: R, config: &DecodeConfig) -> Result<BlockdataLocatorInfo, Error>
+where R: std::io::Read {
+    let version: u32 = Decode::net_decode(&mut r)?;
+    let count = decode_count(&mut r, config.max_locator.min(MAX_LOCATOR_SIZE), config)?;
+    let mut hashes: Vec<BlockHash> = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        hashes.push(Decode::net_decode(&mut r)?);
+    }
+    let stop: BlockHash = Decode::net_decode(&mut r)?;
+
+    Ok(
+        BlockdataLocatorInfo::new(
+            version,
+            hashes,
+            stop
         )
+    )
+}
+
+/// Headers and blocks delegate to the consensus encoding of rust-bitcoin
+#[cfg(feature = "consensus")]
+impl Encode for BlockHeader {
+    fn net_encode<W>(&self, w: W) -> usize
+    where W: std::io::Write {
+        crate::bitcoin::BlockHeader::from(*self).consensus_encode(w).expect("Failed to write")
+    }
+
+    fn encoded_size(&self) -> usize {
+        Self::SIZE
     }
 }
 
+#[cfg(feature = "consensus")]
+impl Decode for BlockHeader {
+    fn net_decode<R>(r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Ok(crate::bitcoin::BlockHeader::consensus_decode(r)?.into())
+    }
+}
+
+#[cfg(feature = "consensus")]
+impl Encode for Block {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        // Same as encoding a rust-bitcoin block, without cloning the transactions into one
+        self.header.net_encode(&mut w) +
+        self.txs.consensus_encode(&mut w).expect("Failed to write")
+    }
+
+    fn encoded_size(&self) -> usize {
+        BlockHeader::SIZE + VariableInteger::from(self.txs.len()).encoded_size() + self.txs.iter().map(|tx| tx.get_size()).sum::<usize>()
+    }
+}
+
+#[cfg(feature = "consensus")]
+impl Decode for Block {
+    fn net_decode<R>(r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Ok(crate::bitcoin::Block::consensus_decode(r)?.into())
+    }
+}
+
+/// Blocks are the header followed by the transactions, prefixed with their count
+#[cfg(not(feature = "consensus"))]
+impl Encode for Block {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        self.header.net_encode(&mut w) +
+        VariableInteger::from(self.txs.len()).net_encode(&mut w) +
+        self.txs.iter().fold(0, |len, tx| len + tx.consensus_encode(&mut w).expect("Failed to write"))
+    }
+
+    fn encoded_size(&self) -> usize {
+        BlockHeader::SIZE + VariableInteger::from(self.txs.len()).encoded_size() + self.txs.iter().map(|tx| tx.get_size()).sum::<usize>()
+    }
+}
+
+#[cfg(not(feature = "consensus"))]
+impl Decode for Block {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        let header = BlockHeader::net_decode(&mut r)?;
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut txs: Vec<Transaction> = Vec::new();
+        for _ in 0..count {
+            txs.push(Transaction::consensus_decode(&mut r)?);
+        }
+
+        Ok(Self::new(header, txs))
+    }
+}
+
+/// Transactions use the consensus encoding of rust-bitcoin, with witnesses
+impl Encode for Tx {
+    fn net_encode<W>(&self, w: W) -> usize
+    where W: std::io::Write {
+        self.transaction().consensus_encode(w).expect("Failed to write")
+    }
+
+    fn encoded_size(&self) -> usize {
+        self.get_size()
+    }
+}
+
+impl Decode for Tx {
+    fn net_decode<R>(r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Ok(Self::new(Transaction::consensus_decode(r)?))
+    }
+}
+
+/// Short ids are 6 bytes
+impl Encode for ShortId {
+    fn net_encode<W>(&self, w: W) -> usize
+    where W: std::io::Write {
+        self.0.net_encode(w)
+    }
+
+    fn encoded_size(&self) -> usize {
+        6
+    }
+}
+
+impl Decode for ShortId {
+    fn net_decode<R>(r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        Ok(Self(Decode::net_decode(r)?))
+    }
+}
+
+/// Compact blocks are the header, nonce, short ids and the prefilled transactions, whose
+/// indexes are differentially encoded
+impl Encode for CompactBlock {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        let prefilled = self.prefilled();
+        let mut size = self.header.net_encode(&mut w) +
+            self.nonce.net_encode(&mut w) +
+            VariableInteger::from(self.short_ids.len()).net_encode(&mut w) +
+            self.short_ids.net_encode(&mut w) +
+            VariableInteger::from(prefilled.len()).net_encode(&mut w);
+
+        let indexes: Vec<usize> = prefilled.iter().map(|p| p.index).collect();
+        for (diff, p) in differential_indexes(&indexes).into_iter().zip(prefilled) {
+            size += VariableInteger::from(diff).net_encode(&mut w) + p.tx.consensus_encode(&mut w).expect("Failed to write");
+        }
+        size
+    }
+}
+
+impl Decode for CompactBlock {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        let header = BlockHeader::net_decode(&mut r)?;
+        let nonce = u64::net_decode(&mut r)?;
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut short_ids = Vec::new();
+        for _ in 0..count {
+            short_ids.push(ShortId::net_decode(&mut r)?);
+        }
+
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut prefilled = Vec::new();
+        let mut next = 0;
+        for _ in 0..count {
+            let index = decode_index(&mut r, next)?;
+            prefilled.push(PrefilledTx { index, tx: Transaction::consensus_decode(&mut r)? });
+            next = index + 1;
+        }
+
+        CompactBlock::new(header, nonce, short_ids, prefilled)
+    }
+}
+
+/// Requests for block transactions are the block hash and differentially encoded indexes
+impl Encode for BlockTxnRequest {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        let indexes = self.indexes();
+        self.block_hash.net_encode(&mut w) +
+        VariableInteger::from(indexes.len()).net_encode(&mut w) +
+        differential_indexes(indexes).into_iter().fold(0, |len, i| len + VariableInteger::from(i).net_encode(&mut w))
+    }
+}
+
+impl Decode for BlockTxnRequest {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        let block_hash = BlockHash::net_decode(&mut r)?;
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut indexes = Vec::new();
+        let mut next = 0;
+        for _ in 0..count {
+            let index = decode_index(&mut r, next)?;
+            indexes.push(index);
+            next = index + 1;
+        }
+
+        BlockTxnRequest::new(block_hash, indexes)
+    }
+}
+
+/// Block transactions are the block hash followed by the transactions, prefixed with their count
+impl Encode for BlockTxn {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        self.block_hash.net_encode(&mut w) +
+        VariableInteger::from(self.txs.len()).net_encode(&mut w) +
+        self.txs.iter().fold(0, |len, tx| len + tx.consensus_encode(&mut w).expect("Failed to write"))
+    }
+}
+
+impl Decode for BlockTxn {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        let block_hash = BlockHash::net_decode(&mut r)?;
+        let count = VariableInteger::net_decode(&mut r)?.inner();
+        let mut txs = Vec::new();
+        for _ in 0..count {
+            txs.push(Transaction::consensus_decode(&mut r)?);
+        }
+
+        Ok(Self { block_hash, txs })
+    }
+}
+
+/// Differentially encode strictly increasing indexes, where each index is sent as the
+/// difference with the previous index plus one
+fn differential_indexes(indexes: &[usize]) -> Vec<usize> {
+    let mut next = 0;
+    indexes.iter().map(|i| {
+        let diff = i - next;
+        next = i + 1;
+        diff
+    }).collect()
+}
+
+/// Decode a differentially encoded index following the previous index.
+/// Indexes must fit in 16 bits like in bitcoin core.
+fn decode_index<R>(r: R, next: usize) -> Result<usize, Error>
+where R: std::io::Read {
+    let index = VariableInteger::net_decode(r)?.inner().checked_add(next as u64).ok_or(Error::InvalidData)?;
+    if index > u16::MAX as u64 {
+        return Err(Error::InvalidData)
+    }
+    Ok(index as usize)
+}
 
 // Macro to implement hashing for the imported hash types from rust-bitcoin
 macro_rules! bitcoin_hash_encode {
@@ -773,6 +1487,10 @@ macro_rules! bitcoin_hash_encode {
             where W: std::io::Write {
                 self.consensus_encode(&mut w).expect("Failed to write")
             }
+
+            fn encoded_size(&self) -> usize {
+                32
+            }
         }
 
         impl Decode for $hash {
@@ -784,6 +1502,12 @@ macro_rules! bitcoin_hash_encode {
     };
 }
 
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
 // Conversion of encode::Error to Error
 impl From<crate::bitcoin::consensus::encode::Error> for Error {
     fn from(err: crate::bitcoin::consensus::encode::Error) -> Error {
@@ -797,6 +1521,7 @@ impl From<crate::bitcoin::consensus::encode::Error> for Error {
 // Implement encoding for Txid and Blockhashes imported from rust-bitcoin
 bitcoin_hash_encode!(Txid);
 bitcoin_hash_encode!(BlockHash);
+bitcoin_hash_encode!(TxMerkleNode);
 
 
 
@@ -808,10 +1533,21 @@ bitcoin_hash_encode!(BlockHash);
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::msg::network::Service;
+    use crate::msg::network::{
+        Service,
+        VersionConfig,
+        VersionMessage
+    };
+    use crate::msg::data::NetworkMessage;
+    use crate::msg::UserAgent;
     use bitcoin::hashes::Hash;
     use bitcoin::TxMerkleNode;
 
+    // Version message with a fixed time and nonce, so tests need no clock or RNG
+    fn version(address: crate::address::Address) -> VersionMessage {
+        VersionConfig::default().message_at(address, 1_640_995_200, 0x5eed)
+    }
+
     #[test]
     fn varint_test() {
         let ints: [u64; 9] = [0x01, 0xFC, 0xFD, 0x1000, 0xFFFF, 0x10000, 0x55555, 0xFFFF_FFFF, 0x1000_0000_0000];
@@ -824,6 +1560,66 @@ mod tests {
         }
     }
 
+    #[test]
+    fn derived_encode_decode() {
+        #[derive(Debug, PartialEq, btcnetmsg_derive::Encode, btcnetmsg_derive::Decode)]
+        struct Pair(u16, bool);
+
+        let pair = Pair(0x1234, true);
+        let mut enc = Vec::new();
+        assert_eq!(pair.net_encode(&mut enc), 3);
+        assert_eq!(enc, [0x34, 0x12, 0x01]);
+        assert_eq!(Pair::net_decode(&enc[..]).expect("Failed to decode"), pair);
+    }
+
+    #[test]
+    fn var_string_limit() {
+        let agent = VarString::<8>::new(String::from("/agent/")).unwrap();
+        let mut enc = Vec::new();
+        agent.net_encode(&mut enc);
+        assert_eq!(VarString::<8>::net_decode(&enc[..]).unwrap(), agent);
+        assert!(VarString::<4>::net_decode(&enc[..]).is_err());
+        assert!(VarString::<4>::new(String::from("/agent/")).is_err());
+
+        // Invalid UTF-8 is decoded lossily, and cut when the replacements are too long
+        let dec = VarString::<8>::net_decode(&[0x02, 0x41, 0xFF][..]).unwrap();
+        assert_eq!(dec.as_str(), "A\u{FFFD}");
+        let dec = VarString::<4>::net_decode(&[0x03, 0x41, 0xFF, 0xFF][..]).unwrap();
+        assert_eq!(dec.as_str(), "A\u{FFFD}");
+        assert!(matches!(VarString::<8>::from_bytes(vec![0x41, 0xFF]), Err(Error::InvalidUtf8)));
+
+        // Multi-byte characters are kept
+        let agent = UserAgent::new(String::from("/Satoshi:25.0(ünïcødé 🚀)/")).unwrap();
+        let mut enc = Vec::new();
+        agent.net_encode(&mut enc);
+        assert_eq!(UserAgent::net_decode(&enc[..]).unwrap(), agent);
+
+        // Lengths above the maximum are rejected without reading the string
+        assert!(UserAgent::net_decode(&[0xFE, 0xFF, 0xFF, 0xFF, 0xFF][..]).is_err());
+    }
+
+    #[test]
+    fn encoded_size_matches_encoding() {
+        let vm = version(crate::address::Address::me());
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        let payloads = vec![
+            MessagePayload::Version(vm),
+            MessagePayload::PingPong(1),
+            MessagePayload::EmptyPayload,
+            MessagePayload::InvVect(vec![Inventory::Error; 300]),
+            MessagePayload::BlockLocator(BlockdataLocatorInfo::new(70015, vec![BlockHash::from_inner([1; 32]); 3], BlockHash::from_inner([0; 32]))),
+            MessagePayload::Headers(vec![BlockHeader::from(genesis.header); 2]),
+            MessagePayload::Block(genesis.into()),
+            MessagePayload::Dump(vec![0; 10])
+        ];
+
+        for payload in payloads {
+            let mut enc = Vec::new();
+            assert_eq!(payload.net_encode(&mut enc), payload.encoded_size());
+            assert_eq!(enc.len(), payload.encoded_size());
+        }
+    }
+
     #[test]
     fn network_magic() {
         let mut main: Vec<u8> = Vec::new();
@@ -834,6 +1630,11 @@ mod tests {
 
         assert_eq!(main, [0xF9, 0xBE, 0xB4, 0xD9]);
         assert_eq!(test, [0xFA, 0xBF, 0xB5, 0xDA]);
+
+        let mut signet = Vec::new();
+        Magic::Signet.net_encode(&mut signet);
+        assert_eq!(signet, [0x0A, 0x03, 0xCF, 0x40]);
+        assert_eq!(Magic::net_decode(&[0x0B, 0x11, 0x09, 0x07][..]).unwrap(), Magic::Testnet);
     }
 
     #[test]
@@ -872,6 +1673,19 @@ mod tests {
         int.net_encode(&mut enc);
         let dec = u64::net_decode(&enc[..]).expect("Failed to decode");
         assert_eq!(int, dec);
+
+        let int: i32 = -1;
+        let mut enc: Vec<u8> = Vec::new();
+        int.net_encode(&mut enc);
+        assert_eq!(enc, [0xFF; 4]);
+        let dec = i32::net_decode(&enc[..]).expect("Failed to decode");
+        assert_eq!(int, dec);
+
+        let int: i64 = i64::MIN;
+        let mut enc: Vec<u8> = Vec::new();
+        int.net_encode(&mut enc);
+        let dec = i64::net_decode(&enc[..]).expect("Failed to decode");
+        assert_eq!(int, dec);
     }
 
     #[test]
@@ -886,7 +1700,7 @@ mod tests {
     #[test]
     fn version_encode_decode() {
         let peer = crate::address::Address::me();
-        let vm = VersionMessage::from(peer);
+        let vm = version(peer);
         let mut enc = Vec::new();
         vm.net_encode(&mut enc);
         let dec: VersionMessage = Decode::net_decode(&enc[..]).expect("Failed to decode");
@@ -894,9 +1708,179 @@ mod tests {
         assert_eq!(vm, dec);
     }
 
+    #[test]
+    fn version_without_relay() {
+        let mut vm = version(crate::address::Address::me());
+        vm.relay = None;
+        let msg = Message::from_payload(NetworkMessage::Version(vm), Magic::Main);
+        let mut enc = Vec::new();
+        msg.net_encode(&mut enc);
+        
+        // A following message must not be consumed as the relay flag
+        let ping = Message::from_payload(NetworkMessage::Ping(1), Magic::Main);
+        ping.net_encode(&mut enc);
+
+        let mut cursor = std::io::Cursor::new(enc);
+        let dec: Message = Decode::net_decode(&mut cursor).expect("Failed to decode");
+        assert_eq!(msg, dec);
+        let dec: Message = Decode::net_decode(&mut cursor).expect("Failed to decode");
+        assert_eq!(ping, dec);
+    }
+
+    #[test]
+    fn truncated_option() {
+        assert_eq!(Option::<u32>::net_decode(&[][..]).unwrap(), None);
+        assert_eq!(Option::<u32>::net_decode(&[1, 0, 0, 0][..]).unwrap(), Some(1));
+
+        // A value cut partway is an error, not a missing value
+        assert!(matches!(Option::<u32>::net_decode(&[1, 0][..]), Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn decode_error_context() {
+        // Ping message with a 4 byte payload instead of 8
+        let mut enc = Vec::new();
+        let payload = [0x01, 0x02, 0x03, 0x04];
+        let mut checksum = [0; 4];
+        checksum.copy_from_slice(&sha256d(payload)[..4]);
+        MessageHeader::new(Magic::Main, Command::Ping, 4, checksum).net_encode(&mut enc);
+        enc.extend_from_slice(&payload);
+
+        match Message::net_decode(&enc[..]) {
+            Err(Error::Decode { command, offset, kind }) => {
+                assert_eq!(command, Command::Ping);
+                assert_eq!(offset, 4);
+                assert!(matches!(*kind, Error::Io(_)));
+            },
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn decode_config_limits() {
+        let inv = vec![Inventory::Error; 3];
+        let msg = Message::from_payload(NetworkMessage::Inv(inv), Magic::Main);
+        let mut enc = Vec::new();
+        msg.net_encode(&mut enc);
+
+        let config = DecodeConfig { max_inv: 2, ..Default::default() };
+        match Message::net_decode_with_config(&enc[..], &config) {
+            Err(Error::Decode { kind, .. }) => assert!(matches!(*kind, Error::TooManyItems { count: 3, max: 2 })),
+            x => panic!("Unexpected result {:?}", x)
+        }
+
+        let config = DecodeConfig { max_payload: 16, ..Default::default() };
+        assert!(matches!(Message::net_decode_with_config(&enc[..], &config), Err(Error::PayloadTooLarge(_))));
+
+        // Headers and locators are limited too
+        let header = BlockHeader::new(1, BlockHash::from_inner([0; 32]), TxMerkleNode::from_inner([0; 32]), 0, 0, 0);
+        let locator = BlockdataLocatorInfo::new(70016, vec![BlockHash::from_inner([0; 32]); 3], BlockHash::from_inner([0; 32]));
+        let config = DecodeConfig { max_headers: 2, max_locator: 2, ..Default::default() };
+        for msg in [NetworkMessage::Headers(vec![header; 3]), NetworkMessage::GetHeaders(locator)] {
+            let mut enc = Vec::new();
+            Message::from_payload(msg, Magic::Main).net_encode(&mut enc);
+            assert!(Message::net_decode(&enc[..]).is_ok());
+            match Message::net_decode_with_config(&enc[..], &config) {
+                Err(Error::Decode { kind, .. }) => assert!(matches!(*kind, Error::TooManyItems { count: 3, max: 2 })),
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+
+        // Corrupt the checksum
+        enc[20] ^= 0xFF;
+        assert!(matches!(Message::net_decode(&enc[..]), Err(Error::BadChecksum { .. })));
+        let config = DecodeConfig { verify_checksum: false, ..Default::default() };
+        assert!(Message::net_decode_with_config(&enc[..], &config).is_ok());
+    }
+
+    #[test]
+    fn inv_protocol_cap() {
+        let decode = |count: usize, config: &DecodeConfig| {
+            let msg = Message::from_payload(NetworkMessage::Inv(vec![Inventory::Error; count]), Magic::Main);
+            let mut enc = Vec::new();
+            msg.net_encode(&mut enc);
+            Message::net_decode_with_config(&enc[..], config)
+        };
+
+        // Entries are decoded into a vector of the right size
+        match decode(3, &DecodeConfig::default()).unwrap().payload() {
+            MessagePayload::InvVect(inv) => assert_eq!((inv.len(), inv.capacity()), (3, 3)),
+            x => panic!("Unexpected payload {:?}", x)
+        }
+
+        // Raising the limit does not go past the protocol cap
+        let config = DecodeConfig { max_inv: 2 * MAX_INV_SIZE, ..Default::default() };
+        assert!(decode(MAX_INV_SIZE, &config).is_ok());
+        match decode(MAX_INV_SIZE + 1, &config) {
+            Err(Error::Decode { kind, .. }) => assert!(matches!(*kind, Error::TooManyItems { count: 50_001, max: MAX_INV_SIZE })),
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn strict_utf8_agents() {
+        let mut vm = version(crate::address::Address::me());
+        vm.agent = UserAgent::new(String::from("AB")).unwrap();
+        let mut enc = Vec::new();
+        Message::from_payload(NetworkMessage::Version(vm), Magic::Main).net_encode(&mut enc);
+        enc[24 + 81] = 0xFF;
+
+        let config = DecodeConfig { verify_checksum: false, ..Default::default() };
+        match Message::net_decode_with_config(&enc[..], &config).unwrap().payload() {
+            MessagePayload::Version(vm) => assert_eq!(vm.agent.as_str(), "\u{FFFD}B"),
+            x => panic!("Unexpected payload {:?}", x)
+        }
+
+        let config = DecodeConfig { verify_checksum: false, strict_utf8: true, ..Default::default() };
+        match Message::net_decode_with_config(&enc[..], &config) {
+            Err(Error::Decode { offset: 81, kind, .. }) => assert!(matches!(*kind, Error::InvalidUtf8)),
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn canonical_varint() {
+        assert_eq!(VariableInteger::net_decode_canonical(&[0xFD, 0xFD, 0x00][..]).unwrap().inner(), 0xFD);
+        assert!(matches!(VariableInteger::net_decode_canonical(&[0xFD, 0x01, 0x00][..]), Err(Error::NonCanonicalVarint)));
+        assert_eq!(VariableInteger::net_decode(&[0xFD, 0x01, 0x00][..]).unwrap().inner(), 1);
+    }
+
+    #[test]
+    fn split_header_payload_decode() {
+        let mut enc = Vec::new();
+        Message::from_payload(NetworkMessage::Block(crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin).into()), Magic::Main).net_encode(&mut enc);
+        Message::from_payload(NetworkMessage::Ping(9), Magic::Main).net_encode(&mut enc);
+
+        let mut r = &enc[..];
+        let header = MessageHeader::net_decode(&mut r).expect("Failed to decode");
+        assert_eq!(header.command, Command::Block);
+        header.skip_payload(&mut r).expect("Failed to skip");
+
+        let header = MessageHeader::net_decode(&mut r).expect("Failed to decode");
+        let payload = MessagePayload::decode_with_header(&header, &mut r).expect("Failed to decode");
+        assert_eq!(payload, MessagePayload::PingPong(9));
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn raw_message_lazy_decode() {
+        let msg = Message::from_payload(NetworkMessage::Pong(3), Magic::Main);
+        let mut enc = Vec::new();
+        msg.net_encode(&mut enc);
+
+        let raw = RawMessage::net_decode(&enc[..]).expect("Failed to decode");
+        assert_eq!(raw.header.command, Command::Pong);
+        assert_eq!(raw.payload_bytes, 3u64.to_le_bytes());
+
+        let mut reenc = Vec::new();
+        raw.net_encode(&mut reenc);
+        assert_eq!(reenc, enc);
+        assert_eq!(raw.decode().expect("Failed to decode"), msg);
+    }
+
     #[test]
     fn getaddr_encdec() {
-        let msg = Message::new(MessagePayload::EmptyPayload, Magic::Main, Command::GetAddr);
+        let msg = Message::from_payload(NetworkMessage::GetAddr, Magic::Main);
         let mut enc = Vec::new();
         msg.net_encode(&mut enc);
         let dec: Message = Decode::net_decode(&enc[..]).expect("Failed to decode");
@@ -915,7 +1899,7 @@ mod tests {
 
         let block_locator_obj = BlockdataLocatorInfo::new(70016, vec![h1, h2, h3, h4, h5], h6);
 
-        let msg = Message::new(MessagePayload::BlockLocator(block_locator_obj), Magic::Main, Command::GetHeaders);
+        let msg = Message::from_payload(NetworkMessage::GetHeaders(block_locator_obj), Magic::Main);
         let mut enc = Vec::new();
         msg.net_encode(&mut enc);
         let dec: Message = Decode::net_decode(&enc[..]).expect("Failed to decode");
@@ -923,6 +1907,167 @@ mod tests {
         assert_eq!(msg, dec);
     }
 
+    #[test]
+    fn locator_count_overflow() {
+        // getheaders frame with a locator count of u64::MAX and a valid checksum
+        let mut payload = 70016u32.to_le_bytes().to_vec();
+        payload.extend(&[0xFF; 9]);
+        let mut checksum = [0; 4];
+        checksum.copy_from_slice(&sha256d(&payload)[..4]);
+        let mut frame = Vec::new();
+        MessageHeader::new(Magic::Main, Command::GetHeaders, payload.len(), checksum).net_encode(&mut frame);
+        frame.extend(&payload);
+
+        match Message::net_decode(&frame[..]) {
+            Err(Error::Decode { kind, .. }) => assert!(matches!(*kind, Error::TooManyItems { count: u64::MAX, max: MAX_LOCATOR_SIZE })),
+            x => panic!("Unexpected result {:?}", x)
+        }
+        assert!(BlockdataLocatorInfo::net_decode(&payload[..]).is_err());
+
+        // A locator without its stop hash is an error too
+        let mut short = 70016u32.to_le_bytes().to_vec();
+        short.push(0);
+        assert!(BlockdataLocatorInfo::net_decode(&short[..]).is_err());
+    }
+
+    #[test]
+    fn typed_message_command() {
+        let msg = Message::from_payload(NetworkMessage::Pong(7), Magic::Main);
+        assert_eq!(msg.header().command, Command::Pong);
+        assert_eq!(msg.network_message().unwrap(), NetworkMessage::Pong(7));
+
+        // Mismatched command and payload pairs are rejected
+        assert!(NetworkMessage::from_parts(Command::Version, MessagePayload::PingPong(7)).is_err());
+    }
+
+    #[test]
+    fn known_raw_commands() {
+        let cmd = Command::from_str(String::from("feefilter")).expect("Unrecognised command");
+        assert_eq!(cmd, Command::FeeFilter);
+        assert!(cmd.has_raw_payload());
+
+        let msg = Message::from_payload(NetworkMessage::Raw { command: cmd, payload: 1000u64.to_le_bytes().to_vec() }, Magic::Main);
+        let mut enc = Vec::new();
+        msg.net_encode(&mut enc);
+        let dec = Message::net_decode(&enc[..]).expect("Failed to decode");
+        assert_eq!(dec, msg);
+        assert_eq!(
+            dec.network_message().unwrap(),
+            NetworkMessage::Raw { command: Command::FeeFilter, payload: 1000u64.to_le_bytes().to_vec() }
+        );
+
+        let mempool = Message::from_payload(NetworkMessage::MemPool, Magic::Main);
+        let mut enc = Vec::new();
+        mempool.net_encode(&mut enc);
+        assert_eq!(Message::net_decode(&enc[..]).expect("Failed to decode"), mempool);
+    }
+
+    #[test]
+    fn command_table() {
+        for (command, name, bytes) in COMMANDS.iter() {
+            assert_eq!(command.to_str(), *name);
+            assert_eq!(&Command::from_str(name.to_string()).unwrap(), command);
+            assert_eq!(&bytes[..name.len()], name.as_bytes());
+            assert!(bytes[name.len()..].iter().all(|b| *b == 0));
+
+            let mut enc = Vec::new();
+            command.net_encode(&mut enc);
+            assert_eq!(&enc[..], &bytes[..]);
+            assert_eq!(&Command::net_decode(&enc[..]).unwrap(), command);
+        }
+
+        // Commands outside the table still go through their string
+        let mut enc = Vec::new();
+        Command::Unknown(String::from("custom")).net_encode(&mut enc);
+        assert!(matches!(Command::net_decode(&enc[..]), Err(Error::UnknownCommand(c)) if c == "custom"));
+
+        // Names that do not fit a byte per character are rejected when they are built
+        assert_eq!(Command::unknown(String::from("caf\u{e9}")).unwrap(), Command::Unknown(String::from("caf\u{e9}")));
+        assert!(matches!(Command::unknown(String::from("\u{20ac}")), Err(Error::InvalidData)));
+        assert!(matches!(Command::unknown(String::from("thirteenchars")), Err(Error::InvalidData)));
+    }
+
+    #[test]
+    fn socket_addr_ports() {
+        for port in [0, 1, 255, 256, 8333, 18333, 65535] {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port);
+            let mut enc = Vec::new();
+            addr.net_encode(&mut enc);
+            assert_eq!(&enc[16..], &port.to_be_bytes());
+            assert_eq!(SocketAddr::net_decode(&enc[..]).expect("Failed to decode"), addr);
+        }
+
+        assert_eq!(Port::net_decode(&[0x20, 0x8D][..]).expect("Failed to decode").to_u16(), 8333);
+
+        // Only IPv4 mapped addresses are IPv4, not the deprecated IPv4 compatible ones
+        for ip in [Ipv6Addr::UNSPECIFIED, Ipv6Addr::LOCALHOST] {
+            let mut enc = Vec::new();
+            IpAddr::V6(ip).net_encode(&mut enc);
+            assert_eq!(IpAddr::net_decode(&enc[..]).expect("Failed to decode"), IpAddr::V6(ip));
+        }
+    }
+
+    #[test]
+    fn addrv2_roundtrip() {
+        let mut services = ServicesList::new();
+        services.add_flag(Service::Network);
+        let onion = TimestampedNetAddress::new(Duration::from_secs(1650000000), NetAddress::new(services.clone(), Address::onion([9; 32], 8333)));
+        let ip = TimestampedNetAddress::new(Duration::from_secs(1650000000), NetAddress::new(services, Address::from("1.2.3.4:8333".parse::<SocketAddr>().unwrap())));
+
+        let msg = Message::from_payload(NetworkMessage::AddrV2(vec![onion.clone(), ip.clone()]), Magic::Main);
+        let mut enc = Vec::new();
+        assert_eq!(msg.net_encode(&mut enc), msg.encoded_size());
+        // Onion entry: time, services, network id, length, key, port
+        assert_eq!(&enc[25..33], &[0x80, 0x00, 0x59, 0x62, 0x01, 0x04, 0x20, 0x09]);
+        assert_eq!(Message::net_decode(&enc[..]).expect("Failed to decode"), msg);
+
+        // Addresses on unsupported networks are skipped
+        let mut payload = vec![0x02];
+        payload.extend_from_slice(&[0, 0, 0, 0, 0, 0x63, 0x02, 0xAA, 0xBB, 0x20, 0x8D]);
+        encode_addrv2(&ip, &mut payload);
+        let unknown = Message::new(MessagePayload::Dump(payload), Magic::Main, Command::AddrV2);
+        let mut enc = Vec::new();
+        unknown.net_encode(&mut enc);
+        let dec = Message::net_decode(&enc[..]).expect("Failed to decode");
+        assert_eq!(*dec.payload(), MessagePayload::AddrV2List(vec![ip.clone()]));
+
+        // I2P and CJDNS addresses are kept
+        let i2p = TimestampedNetAddress::new(Duration::from_secs(1650000000), NetAddress::new(ServicesList::default(), Address::i2p([3; 32])));
+        let cjdns = TimestampedNetAddress::new(
+            Duration::from_secs(1650000000),
+            NetAddress::new(ServicesList::default(), Address::cjdns("fc00::1".parse().unwrap(), 8333).unwrap())
+        );
+        let msg = Message::from_payload(NetworkMessage::AddrV2(vec![i2p, cjdns, ip]), Magic::Main);
+        let mut enc = Vec::new();
+        msg.net_encode(&mut enc);
+        assert_eq!(Message::net_decode(&enc[..]).expect("Failed to decode"), msg);
+
+        // The legacy encoding has no room for onion addresses
+        let mut enc = Vec::new();
+        onion.net_encode(&mut enc);
+        assert_eq!(&enc[12..28], &[0; 16]);
+    }
+
+    #[test]
+    fn borrowed_decode() {
+        let msg = Message::from_payload(NetworkMessage::Unknown { command: String::from("foo"), payload: vec![0xAB; 64] }, Magic::Main);
+        let mut enc = Vec::new();
+        msg.net_encode(&mut enc);
+        enc.extend_from_slice(&[0; 8]); // Trailing bytes of the next message
+        
+        let (dec, used) = MessageRef::net_decode_ref(&enc[..]).expect("Failed to decode");
+        assert_eq!(used, enc.len() - 8);
+        assert_eq!(dec.payload.bytes(), Some(&enc[24..used]));
+        assert_eq!(dec.into_owned().expect("Failed to convert"), msg);
+
+        let ping = Message::from_payload(NetworkMessage::Ping(42), Magic::Main);
+        let mut enc = Vec::new();
+        ping.net_encode(&mut enc);
+        let (dec, _) = MessageRef::net_decode_ref(&enc[..]).expect("Failed to decode");
+        assert_eq!(dec.payload, MessagePayloadRef::Owned(MessagePayload::PingPong(42)));
+        assert!(MessageRef::net_decode_ref(&enc[..enc.len()-1]).is_err());
+    }
+
     #[test]
     fn header_decode_test() {
         // Test by creating a message with 3 block headers and encoding and decode the message
@@ -953,11 +2098,24 @@ mod tests {
             }
         ];
 
-        let msg = Message::new(MessagePayload::Headers(headers), Magic::Main, Command::Headers);
+        let msg = Message::from_payload(NetworkMessage::Headers(headers), Magic::Main);
         let mut enc = Vec::new();
         msg.net_encode(&mut enc);
         let dec: Message = Decode::net_decode(&enc[..]).expect("Failed to decode");
 
-        assert_eq!(msg, dec)
+        assert_eq!(msg, dec);
+
+        // Headers carry no transactions, so a transaction count is invalid
+        let mut payload = enc[MessageHeader::SIZE..].to_vec();
+        *payload.last_mut().unwrap() = 1;
+        let mut checksum = [0; 4];
+        checksum.copy_from_slice(&sha256d(&payload)[..4]);
+        let mut frame = Vec::new();
+        MessageHeader::new(Magic::Main, Command::Headers, payload.len(), checksum).net_encode(&mut frame);
+        frame.extend(&payload);
+        match Message::net_decode(&frame[..]) {
+            Err(Error::Decode { kind, .. }) => assert!(matches!(*kind, Error::InvalidData)),
+            x => panic!("Unexpected result {:?}", x)
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,394 @@
+// ffi.rs
+//
+// C API to the codec, enabled with the `ffi` feature.
+//
+// Decoded messages are handed out as opaque `BtcMessage` handles, which are queried for
+// their command, length and fields and must be released with `btc_message_free`.
+// Every function returns a `BtcStatus`. Strings and encoded messages are copied into
+// buffers owned by the caller; when a buffer is too small the required length is still
+// written, so the call can be repeated with a larger buffer.
+//
+// The header in include/btcnetmsg.h is generated from this module with
+// `cbindgen --config cbindgen.toml --output include/btcnetmsg.h src/ffi.rs`.
+//
+
+use crate::{
+    address::Address,
+    encode::{
+        Encode,
+        Decode
+    },
+    msg::{
+        data::{
+            Message,
+            NetworkMessage
+        },
+        header::Magic,
+        network::{
+            NetAddress,
+            ServicesList,
+            VersionMessage
+        },
+        UserAgent
+    }
+};
+use std::{
+    ffi::CStr,
+    net::{
+        IpAddr,
+        Ipv6Addr
+    },
+    os::raw::c_char
+};
+
+/// Status returned by the functions of the C API
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BtcStatus {
+    Ok = 0,
+    NullPointer = 1,
+    /// The buffer does not hold a valid message
+    Decode = 2,
+    /// The output buffer is too small, the required length was written
+    BufferTooSmall = 3,
+    /// The message does not have the requested payload
+    WrongCommand = 4,
+    InvalidArgument = 5
+}
+
+/// Opaque handle to a decoded message
+pub struct BtcMessage(Message);
+
+/// Fields of a version message
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BtcVersion {
+    pub version: i32,
+    /// Service flags as sent on the wire
+    pub services: u64,
+    /// Unix time in seconds
+    pub timestamp: i64,
+    pub nonce: u64,
+    pub start_height: i32,
+    /// 1 or 0, or -1 if the relay flag is not sent
+    pub relay: i8,
+    /// IPv6 address of the receiving node, IPv4 addresses are mapped into IPv6
+    pub addr_recv_ip: [u8; 16],
+    pub addr_recv_port: u16
+}
+
+impl BtcVersion {
+    fn from_message(v: &VersionMessage) -> Self {
+        let ip = match v.addr_recv.address.ip() {
+            Some(IpAddr::V4(ip)) => ip.to_ipv6_mapped(),
+            Some(IpAddr::V6(ip)) => ip,
+            None => Ipv6Addr::UNSPECIFIED
+        };
+        Self {
+            version: v.version,
+            services: v.service.bits(),
+            timestamp: v.timestamp,
+            nonce: v.nonce,
+            start_height: v.start_height,
+            relay: v.relay.map_or(-1, |r| r as i8),
+            addr_recv_ip: ip.octets(),
+            addr_recv_port: v.addr_recv.address.port()
+        }
+    }
+
+    fn to_message(self, agent: UserAgent) -> Result<VersionMessage, BtcStatus> {
+        let services = ServicesList::from_bits(self.services);
+        let relay = match self.relay {
+            -1 => None,
+            0 => Some(false),
+            1 => Some(true),
+            _ => return Err(BtcStatus::InvalidArgument)
+        };
+        let addr_recv = Address::new(IpAddr::V6(Ipv6Addr::from(self.addr_recv_ip)), self.addr_recv_port);
+        Ok(VersionMessage::new(
+            self.version,
+            services,
+            self.timestamp,
+            NetAddress::new(ServicesList::default(), addr_recv),
+            NetAddress::default(),
+            self.nonce,
+            agent,
+            self.start_height,
+            relay
+        ))
+    }
+}
+
+/// Decode a message from the start of a buffer.
+/// On success `*out` is a handle to release with `btc_message_free` and `*consumed` is the
+/// number of bytes read.
+///
+/// # Safety
+/// `buf` must be valid for reads of `len` bytes, `out` and `consumed` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_message_decode(buf: *const u8, len: usize, out: *mut *mut BtcMessage, consumed: *mut usize) -> BtcStatus {
+    if buf.is_null() || out.is_null() || consumed.is_null() { return BtcStatus::NullPointer }
+    let bytes = std::slice::from_raw_parts(buf, len);
+    let mut r = bytes;
+    match Message::net_decode(&mut r) {
+        Ok(msg) => {
+            *consumed = len - r.len();
+            *out = Box::into_raw(Box::new(BtcMessage(msg)));
+            BtcStatus::Ok
+        },
+        Err(_) => BtcStatus::Decode
+    }
+}
+
+/// Release a message handle. Null handles are ignored.
+///
+/// # Safety
+/// `msg` must be null or a handle from this API that was not already released.
+#[no_mangle]
+pub unsafe extern "C" fn btc_message_free(msg: *mut BtcMessage) {
+    if !msg.is_null() {
+        drop(Box::from_raw(msg));
+    }
+}
+
+/// Network magic of the message, as the little endian integer of its wire bytes
+///
+/// # Safety
+/// `msg` must be a live handle from this API and `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_message_magic(msg: *const BtcMessage, out: *mut u32) -> BtcStatus {
+    if msg.is_null() || out.is_null() { return BtcStatus::NullPointer }
+    *out = (*msg).0.header().magic.bytes();
+    BtcStatus::Ok
+}
+
+/// Length of the payload of the message in bytes
+///
+/// # Safety
+/// `msg` must be a live handle from this API and `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_message_payload_length(msg: *const BtcMessage, out: *mut u32) -> BtcStatus {
+    if msg.is_null() || out.is_null() { return BtcStatus::NullPointer }
+    *out = (*msg).0.header().length;
+    BtcStatus::Ok
+}
+
+/// Copy the command of the message into `buf` as a nul terminated string
+///
+/// # Safety
+/// `msg` must be a live handle from this API, `buf` valid for writes of `cap` bytes and
+/// `len` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_message_command(msg: *const BtcMessage, buf: *mut c_char, cap: usize, len: *mut usize) -> BtcStatus {
+    if msg.is_null() { return BtcStatus::NullPointer }
+    write_str((*msg).0.header().command.to_str(), buf, cap, len)
+}
+
+/// Nonce of a `ping` or `pong` message
+///
+/// # Safety
+/// `msg` must be a live handle from this API and `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_message_ping_nonce(msg: *const BtcMessage, out: *mut u64) -> BtcStatus {
+    if msg.is_null() || out.is_null() { return BtcStatus::NullPointer }
+    match (*msg).0.network_message() {
+        Ok(NetworkMessage::Ping(nonce)) |
+        Ok(NetworkMessage::Pong(nonce)) => {
+            *out = nonce;
+            BtcStatus::Ok
+        },
+        _ => BtcStatus::WrongCommand
+    }
+}
+
+/// Fields of a `version` message
+///
+/// # Safety
+/// `msg` must be a live handle from this API and `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_message_version(msg: *const BtcMessage, out: *mut BtcVersion) -> BtcStatus {
+    if msg.is_null() || out.is_null() { return BtcStatus::NullPointer }
+    match (*msg).0.network_message() {
+        Ok(NetworkMessage::Version(v)) => {
+            *out = BtcVersion::from_message(&v);
+            BtcStatus::Ok
+        },
+        _ => BtcStatus::WrongCommand
+    }
+}
+
+/// Copy the user agent of a `version` message into `buf` as a nul terminated string
+///
+/// # Safety
+/// `msg` must be a live handle from this API, `buf` valid for writes of `cap` bytes and
+/// `len` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_message_user_agent(msg: *const BtcMessage, buf: *mut c_char, cap: usize, len: *mut usize) -> BtcStatus {
+    if msg.is_null() { return BtcStatus::NullPointer }
+    match (*msg).0.network_message() {
+        Ok(NetworkMessage::Version(v)) => write_str(v.agent.as_str(), buf, cap, len),
+        _ => BtcStatus::WrongCommand
+    }
+}
+
+/// Encode a message back into `buf`
+///
+/// # Safety
+/// `msg` must be a live handle from this API, `buf` valid for writes of `cap` bytes and
+/// `written` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_message_encode(msg: *const BtcMessage, buf: *mut u8, cap: usize, written: *mut usize) -> BtcStatus {
+    if msg.is_null() { return BtcStatus::NullPointer }
+    write_message(&(*msg).0, buf, cap, written)
+}
+
+/// Encode a `version` message with the given fields and user agent
+///
+/// # Safety
+/// `version` must be valid for reads, `agent` a nul terminated string, `buf` valid for
+/// writes of `cap` bytes and `written` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_encode_version(magic: u32, version: *const BtcVersion, agent: *const c_char, buf: *mut u8, cap: usize, written: *mut usize) -> BtcStatus {
+    if version.is_null() || agent.is_null() { return BtcStatus::NullPointer }
+    let agent = match CStr::from_ptr(agent).to_str().ok().and_then(|s| UserAgent::new(s.to_string()).ok()) {
+        Some(agent) => agent,
+        None => return BtcStatus::InvalidArgument
+    };
+    match (*version).to_message(agent) {
+        Ok(v) => write_message(&Message::from_payload(NetworkMessage::Version(v), magic_of(magic)), buf, cap, written),
+        Err(status) => status
+    }
+}
+
+/// Encode a `ping` message with the given nonce
+///
+/// # Safety
+/// `buf` must be valid for writes of `cap` bytes and `written` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_encode_ping(magic: u32, nonce: u64, buf: *mut u8, cap: usize, written: *mut usize) -> BtcStatus {
+    write_message(&Message::from_payload(NetworkMessage::Ping(nonce), magic_of(magic)), buf, cap, written)
+}
+
+/// Encode a `pong` message answering the ping with the given nonce
+///
+/// # Safety
+/// `buf` must be valid for writes of `cap` bytes and `written` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn btc_encode_pong(magic: u32, nonce: u64, buf: *mut u8, cap: usize, written: *mut usize) -> BtcStatus {
+    write_message(&Message::from_payload(NetworkMessage::Pong(nonce), magic_of(magic)), buf, cap, written)
+}
+
+// The magic of the API is the integer read from the wire bytes, which Magic is built from
+fn magic_of(magic: u32) -> Magic {
+    Magic::from(magic.to_be_bytes())
+}
+
+// Copy a string and its nul terminator into a caller buffer, or only its length if the
+// buffer is too small
+unsafe fn write_str(s: &str, buf: *mut c_char, cap: usize, len: *mut usize) -> BtcStatus {
+    if len.is_null() { return BtcStatus::NullPointer }
+    *len = s.len();
+    if cap <= s.len() { return BtcStatus::BufferTooSmall }
+    if buf.is_null() { return BtcStatus::NullPointer }
+    std::ptr::copy_nonoverlapping(s.as_ptr() as *const c_char, buf, s.len());
+    *buf.add(s.len()) = 0;
+    BtcStatus::Ok
+}
+
+// Encode a message into a caller buffer, or only its length if the buffer is too small
+unsafe fn write_message(msg: &Message, buf: *mut u8, cap: usize, written: *mut usize) -> BtcStatus {
+    if written.is_null() { return BtcStatus::NullPointer }
+    let size = msg.encoded_size();
+    *written = size;
+    if cap < size { return BtcStatus::BufferTooSmall }
+    if buf.is_null() { return BtcStatus::NullPointer }
+    msg.net_encode(std::slice::from_raw_parts_mut(buf, size));
+    BtcStatus::Ok
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    #[test]
+    fn decode_and_query() {
+        let mut buf = [0u8; 64];
+        let mut written = 0;
+        unsafe {
+            assert_eq!(btc_encode_ping(Magic::Main.bytes(), 42, buf.as_mut_ptr(), 4, &mut written), BtcStatus::BufferTooSmall);
+            assert_eq!(written, 32);
+            assert_eq!(btc_encode_ping(Magic::Main.bytes(), 42, buf.as_mut_ptr(), buf.len(), &mut written), BtcStatus::Ok);
+        }
+        assert_eq!(&buf[..4], &[0xF9, 0xBE, 0xB4, 0xD9]);
+
+        let mut msg = ptr::null_mut();
+        let mut consumed = 0;
+        unsafe {
+            assert_eq!(btc_message_decode(buf.as_ptr(), written - 1, &mut msg, &mut consumed), BtcStatus::Decode);
+            assert_eq!(btc_message_decode(buf.as_ptr(), buf.len(), &mut msg, &mut consumed), BtcStatus::Ok);
+        }
+        assert_eq!(consumed, written);
+
+        let mut command = [0 as c_char; 16];
+        let (mut len, mut magic, mut length, mut nonce) = (0, 0, 0, 0);
+        let mut version = BtcVersion::default();
+        unsafe {
+            assert_eq!(btc_message_command(msg, command.as_mut_ptr(), command.len(), &mut len), BtcStatus::Ok);
+            assert_eq!(CStr::from_ptr(command.as_ptr()).to_str(), Ok("ping"));
+            assert_eq!(btc_message_command(msg, command.as_mut_ptr(), 4, &mut len), BtcStatus::BufferTooSmall);
+            assert_eq!(len, 4);
+            assert_eq!(btc_message_magic(msg, &mut magic), BtcStatus::Ok);
+            assert_eq!(magic, 0xD9B4BEF9);
+            assert_eq!(btc_message_payload_length(msg, &mut length), BtcStatus::Ok);
+            assert_eq!(length, 8);
+            assert_eq!(btc_message_ping_nonce(msg, &mut nonce), BtcStatus::Ok);
+            assert_eq!(nonce, 42);
+            assert_eq!(btc_message_version(msg, &mut version), BtcStatus::WrongCommand);
+
+            let mut reenc = [0u8; 64];
+            assert_eq!(btc_message_encode(msg, reenc.as_mut_ptr(), reenc.len(), &mut written), BtcStatus::Ok);
+            assert_eq!(reenc[..written], buf[..written]);
+            btc_message_free(msg);
+            btc_message_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn version_roundtrip() {
+        let fields = BtcVersion {
+            version: 70016,
+            services: 1 | 8 | 1024,
+            timestamp: 1_640_995_200,
+            nonce: 0x5eed,
+            start_height: 720_000,
+            relay: 1,
+            addr_recv_ip: Ipv6Addr::from([0, 0, 0, 0, 0, 0xFFFF, 0x0A00, 0x0001]).octets(),
+            addr_recv_port: 8333
+        };
+        let agent = b"/Satoshi:22.0.0/\0";
+        let mut buf = vec![0u8; 256];
+        let (mut written, mut consumed) = (0, 0);
+        let mut msg = ptr::null_mut();
+        let mut decoded = BtcVersion::default();
+        let mut text = [0 as c_char; 32];
+        let mut len = 0;
+        unsafe {
+            assert_eq!(btc_encode_version(Magic::Test.bytes(), &fields, agent.as_ptr() as *const c_char, buf.as_mut_ptr(), buf.len(), &mut written), BtcStatus::Ok);
+            assert_eq!(btc_message_decode(buf.as_ptr(), written, &mut msg, &mut consumed), BtcStatus::Ok);
+            assert_eq!(btc_message_version(msg, &mut decoded), BtcStatus::Ok);
+            assert_eq!(btc_message_user_agent(msg, text.as_mut_ptr(), text.len(), &mut len), BtcStatus::Ok);
+            assert_eq!(btc_message_ping_nonce(msg, &mut 0), BtcStatus::WrongCommand);
+            btc_message_free(msg);
+        }
+        assert_eq!(decoded, fields);
+        assert_eq!(unsafe { CStr::from_ptr(text.as_ptr()) }.to_bytes(), &agent[..agent.len() - 1]);
+
+        let bad = BtcVersion { relay: 2, ..fields };
+        unsafe {
+            assert_eq!(btc_encode_version(0, &bad, agent.as_ptr() as *const c_char, buf.as_mut_ptr(), buf.len(), &mut written), BtcStatus::InvalidArgument);
+            assert_eq!(btc_encode_version(0, ptr::null(), agent.as_ptr() as *const c_char, buf.as_mut_ptr(), buf.len(), &mut written), BtcStatus::NullPointer);
+        }
+    }
+}
@@ -0,0 +1,776 @@
+// json.rs
+//
+// Module implementing a canonical JSON representation of network messages.
+//
+// The schema is hand written rather than derived so that it stays stable and readable
+// for external tools (ie jq):
+//      - Commands are represented by their command string
+//      - Hashes are represented as big endian hex strings
+//      - Service flags are represented as a list of their names
+//      - Addresses are represented as "ip:port" strings
+//      - Timestamps are represented as unix seconds
+//      - Round trip times are represented as microseconds
+//      - Transactions and blocks are represented as consensus encoded hex along with their hash
+
+use std::time::Duration;
+use serde_json::{
+    json,
+    Value
+};
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload,
+            NetworkMessage
+        },
+        header::{
+            Magic,
+            Command
+        },
+        network::{
+            ServicesList,
+            Service,
+            VersionMessage,
+            NetAddress,
+            TimestampedNetAddress
+        },
+        inventory::{
+            Inventory,
+            BlockdataLocatorInfo
+        },
+        UserAgent
+    },
+    address::Address,
+    encode::{
+        Encode,
+        Decode,
+        Error
+    },
+    blockdata::{
+        BlockHeader,
+        HexHash
+    },
+
+    bitcoin::{
+        hash_types::{
+            BlockHash,
+            TxMerkleNode
+        },
+        hashes::hex::{
+            ToHex,
+            FromHex
+        },
+        consensus::encode::{
+            serialize_hex,
+            deserialize
+        }
+    }
+};
+
+#[cfg(feature = "net")]
+use crate::net::{
+    addrman::{
+        AddrMan,
+        AddrInfo
+    },
+    banman::BanMan,
+    anchors::Anchors,
+    crawl::{
+        Census,
+        CrawledNode,
+        Snapshot
+    },
+    eventloop::{
+        DisconnectReason,
+        Event
+    },
+    manager::{
+        PeerInfo,
+        SessionStats
+    },
+    stats::{
+        Counter,
+        PeerMetrics,
+        Stats
+    }
+};
+
+/// Trait to convert self to and from a JSON value using the canonical schema.
+pub(crate) trait JsonValue: Sized {
+    fn to_value(&self) -> Value;
+
+    fn from_value(value: &Value) -> Result<Self, Error>;
+}
+
+impl Message {
+    /// Serialize the message into its canonical JSON representation.
+    pub fn to_json(&self) -> String {
+        self.to_value().to_string()
+    }
+
+    /// Deserialize a message from its canonical JSON representation.
+    /// The header length and checksum are recomputed from the payload.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let value: Value = serde_json::from_str(json).map_err(|e| Error::InvalidJson(e.to_string()))?;
+        Self::from_value(&value)
+    }
+}
+
+#[cfg(feature = "net")]
+impl AddrMan {
+    /// Serialize the known addresses and the bucketing key into JSON.
+    pub fn to_json(&self) -> String {
+        json!({
+            "key": self.key().to_hex(),
+            "addrs": self.iter().map(|a| a.to_value()).collect::<Vec<Value>>()
+        }).to_string()
+    }
+
+    /// Deserialize an address manager from JSON created with [`AddrMan::to_json`].
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let value: Value = serde_json::from_str(json).map_err(|e| Error::InvalidJson(e.to_string()))?;
+
+        let mut key = [0; 32];
+        let bytes = field_hex(&value, "key")?;
+        if bytes.len() != key.len() { return Err(bad_field("key")) }
+        key.copy_from_slice(&bytes);
+
+        let mut addrman = AddrMan::with_key(key);
+        for addr in field_array(&value, "addrs")? {
+            addrman.restore(AddrInfo::from_value(addr)?);
+        }
+        Ok(addrman)
+    }
+}
+
+#[cfg(feature = "net")]
+impl BanMan {
+    /// Serialize the ban list into JSON.
+    pub fn to_json(&self) -> String {
+        json!({
+            "threshold": self.threshold(),
+            "ban_time": self.ban_time().as_secs(),
+            "bans": self.banned()
+                .iter()
+                .map(|(ip, until)| json!({ "ip": ip.to_string(), "until": until.as_secs() }))
+                .collect::<Vec<Value>>()
+        }).to_string()
+    }
+
+    /// Deserialize a ban list from JSON created with [`BanMan::to_json`].
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let value: Value = serde_json::from_str(json).map_err(|e| Error::InvalidJson(e.to_string()))?;
+
+        let mut banman = BanMan::new(field_u32(&value, "threshold")?, Duration::from_secs(field_u64(&value, "ban_time")?));
+        for ban in field_array(&value, "bans")? {
+            banman.restore(
+                parse_hash(field(ban, "ip")?, "ip")?,
+                Duration::from_secs(field_u64(ban, "until")?)
+            );
+        }
+        Ok(banman)
+    }
+}
+
+#[cfg(feature = "net")]
+impl Anchors {
+    /// Serialize the anchors into a JSON list of addresses.
+    pub fn to_json(&self) -> String {
+        Value::from(self.0.iter().map(|a| a.to_string()).collect::<Vec<String>>()).to_string()
+    }
+
+    /// Deserialize anchors from JSON created with [`Anchors::to_json`].
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let value: Value = serde_json::from_str(json).map_err(|e| Error::InvalidJson(e.to_string()))?;
+        let addrs = value.as_array().ok_or_else(|| bad_field("anchors"))?;
+        Ok(Anchors(addrs.iter().map(|a| parse_hash(a, "anchors")).collect::<Result<Vec<Address>, Error>>()?))
+    }
+}
+
+#[cfg(feature = "net")]
+impl Snapshot {
+    /// Serialize the snapshot of a crawl into JSON, along with its census.
+    pub fn to_json(&self) -> String {
+        json!({
+            "census": self.census().to_value(),
+            "reachable": self.reachable.iter().map(|n| n.to_value()).collect::<Vec<Value>>(),
+            "unreachable": self.unreachable.iter().map(|a| a.to_value()).collect::<Vec<Value>>(),
+            "addrs": self.addrs.iter().map(|a| a.to_value()).collect::<Vec<Value>>()
+        }).to_string()
+    }
+
+    /// Deserialize a snapshot from JSON created with [`Snapshot::to_json`].
+    /// The census is not read, as it is computed from the nodes.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let value: Value = serde_json::from_str(json).map_err(|e| Error::InvalidJson(e.to_string()))?;
+        Ok(Snapshot {
+            reachable: field_array(&value, "reachable")?
+                .iter()
+                .map(CrawledNode::from_value)
+                .collect::<Result<Vec<_>, Error>>()?,
+            unreachable: field_array(&value, "unreachable")?
+                .iter()
+                .map(Address::from_value)
+                .collect::<Result<Vec<_>, Error>>()?,
+            addrs: field_array(&value, "addrs")?
+                .iter()
+                .map(TimestampedNetAddress::from_value)
+                .collect::<Result<Vec<_>, Error>>()?
+        })
+    }
+}
+
+#[cfg(feature = "net")]
+impl Census {
+    fn to_value(&self) -> Value {
+        json!({
+            "reachable": self.reachable,
+            "unreachable": self.unreachable,
+            "user_agents": self.user_agents.iter().map(|(a, n)| json!({ "agent": a, "count": n })).collect::<Vec<Value>>(),
+            "versions": self.versions.iter().map(|(v, n)| json!({ "version": v, "count": n })).collect::<Vec<Value>>(),
+            "services": self.services.iter().map(|(s, n)| json!({ "service": s.name(), "count": n })).collect::<Vec<Value>>()
+        })
+    }
+}
+
+#[cfg(feature = "net")]
+impl Event {
+    /// Serialize an event of the event loop into JSON, with messages in their canonical
+    /// representation.
+    pub fn to_json(&self) -> String {
+        self.to_value().to_string()
+    }
+
+    pub(crate) fn to_value(&self) -> Value {
+        match self {
+            Event::Message(peer, msg) => json!({ "event": "message", "peer": peer.0, "message": msg.to_value() }),
+            Event::InvalidMessage(peer, e) => json!({ "event": "invalid_message", "peer": peer.0, "error": e.to_string() }),
+            Event::RateLimited(peer, command) => json!({ "event": "rate_limited", "peer": peer.0, "command": command.to_str() }),
+            Event::ProtocolViolation(peer, v) => json!({ "event": "protocol_violation", "peer": peer.0, "violation": v.to_string() }),
+            Event::Disconnected(peer, reason) => json!({
+                "event": "disconnected",
+                "peer": peer.0,
+                "reason": match reason {
+                    DisconnectReason::Closed(_) => "closed",
+                    DisconnectReason::Inactive => "inactive",
+                    DisconnectReason::PingTimeout => "ping_timeout",
+                    DisconnectReason::Banned => "banned",
+                    DisconnectReason::Requested => "requested"
+                }
+            })
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+impl PeerInfo {
+    pub(crate) fn to_value(&self) -> Value {
+        json!({
+            "id": self.id.0,
+            "addr": self.addr.to_value(),
+            "addr_local": self.addr_local.to_value(),
+            "inbound": self.inbound,
+            "version": self.version,
+            "services": self.services.to_value(),
+            "user_agent": self.user_agent,
+            "start_height": self.start_height,
+            "relay": self.relay,
+            "block_relay_only": self.block_relay_only,
+            "connected_at": self.connected_at.as_secs(),
+            "ping": self.ping.map(|p| p.as_micros() as u64),
+            "metrics": self.metrics.to_value()
+        })
+    }
+}
+
+#[cfg(feature = "net")]
+impl PeerMetrics {
+    pub(crate) fn to_value(self) -> Value {
+        let micros = |d: Duration| d.as_micros() as u64;
+        json!({
+            "received": { "messages": self.received.messages, "bytes": self.received.bytes },
+            "sent": { "messages": self.sent.messages, "bytes": self.sent.bytes },
+            "last_recv": self.last_recv.map(|t| t.as_secs()),
+            "last_send": self.last_send.map(|t| t.as_secs()),
+            "ping": self.ping.map(|p| json!({
+                "min": micros(p.min),
+                "avg": micros(p.avg),
+                "p95": micros(p.p95),
+                "samples": p.samples
+            })),
+            "misbehavior": self.misbehavior
+        })
+    }
+}
+
+#[cfg(feature = "net")]
+impl SessionStats {
+    pub(crate) fn to_value(&self) -> Value {
+        json!({
+            "uptime": self.uptime.as_secs(),
+            "connected": self.connected,
+            "known_addrs": self.known_addrs,
+            "census": self.census.to_value()
+        })
+    }
+}
+
+#[cfg(feature = "net")]
+impl Stats {
+    pub(crate) fn to_value(&self) -> Value {
+        let counter = |c: Counter| json!({ "messages": c.messages, "bytes": c.bytes });
+        json!({
+            "received": counter(self.total_received()),
+            "sent": counter(self.total_sent()),
+            "commands": self.by_command()
+                .into_iter()
+                .map(|(command, received, sent)| json!({
+                    "command": command.to_str(),
+                    "received": counter(received),
+                    "sent": counter(sent)
+                }))
+                .collect::<Vec<Value>>()
+        })
+    }
+}
+
+/// Utility function to build an error for a missing or mistyped field.
+fn bad_field(field: &str) -> Error {
+    Error::InvalidJson(format!("missing or invalid field `{}`", field))
+}
+
+/// Utility function to get a field from a JSON object
+fn field<'a>(value: &'a Value, field: &str) -> Result<&'a Value, Error> {
+    value.get(field).ok_or_else(|| bad_field(field))
+}
+
+fn field_u64(value: &Value, name: &str) -> Result<u64, Error> {
+    field(value, name)?.as_u64().ok_or_else(|| bad_field(name))
+}
+
+fn field_u32(value: &Value, name: &str) -> Result<u32, Error> {
+    let int = field_u64(value, name)?;
+    if int > u32::MAX as u64 { return Err(bad_field(name)) }
+    Ok(int as u32)
+}
+
+fn field_i64(value: &Value, name: &str) -> Result<i64, Error> {
+    field(value, name)?.as_i64().ok_or_else(|| bad_field(name))
+}
+
+fn field_i32(value: &Value, name: &str) -> Result<i32, Error> {
+    let int = field_i64(value, name)?;
+    if int < i32::MIN as i64 || int > i32::MAX as i64 { return Err(bad_field(name)) }
+    Ok(int as i32)
+}
+
+fn field_str<'a>(value: &'a Value, name: &str) -> Result<&'a str, Error> {
+    field(value, name)?.as_str().ok_or_else(|| bad_field(name))
+}
+
+fn field_array<'a>(value: &'a Value, name: &str) -> Result<&'a Vec<Value>, Error> {
+    field(value, name)?.as_array().ok_or_else(|| bad_field(name))
+}
+
+fn field_hex(value: &Value, name: &str) -> Result<Vec<u8>, Error> {
+    Vec::<u8>::from_hex(field_str(value, name)?).map_err(|_| bad_field(name))
+}
+
+/// Utility function to get an optional unix timestamp field
+#[cfg(feature = "net")]
+fn field_opt_time(value: &Value, name: &str) -> Result<Option<Duration>, Error> {
+    match field(value, name)? {
+        Value::Null => Ok(None),
+        v => Ok(Some(Duration::from_secs(v.as_u64().ok_or_else(|| bad_field(name))?)))
+    }
+}
+
+/// Utility function to parse a big endian hex hash string
+fn parse_hash<T: std::str::FromStr>(value: &Value, name: &str) -> Result<T, Error> {
+    value.as_str().ok_or_else(|| bad_field(name))?.parse().map_err(|_| bad_field(name))
+}
+
+impl JsonValue for Message {
+    fn to_value(&self) -> Value {
+        json!({
+            "magic": self.header().magic.to_value(),
+            "command": self.header().command.to_str(),
+            "length": self.header().length,
+            "checksum": self.header().checksum.to_hex(),
+            "payload": self.payload().to_value()
+        })
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        let magic = Magic::from_value(field(value, "magic")?)?;
+        let command = match Command::from_str(field_str(value, "command")?.to_string()) {
+            Ok(x) => x,
+            Err(Error::UnknownCommand(x)) => Command::unknown(x)?,
+            Err(x) => return Err(x)
+        };
+        let payload = field(value, "payload")?;
+
+        // The payload cannot be interpreted without the command context.
+        let payload = match command {
+            Command::Version => MessagePayload::Version(VersionMessage::from_value(payload)?),
+            Command::Verack |
+            Command::SendHeaders |
+            Command::WTxIdRelay |
+            Command::GetAddr |
+            Command::MemPool |
+            Command::FilterClear |
+            Command::SendAddrV2 => MessagePayload::EmptyPayload,
+            Command::Ping |
+            Command::Pong => MessagePayload::PingPong(field_u64(payload, "nonce")?),
+            Command::Addr => MessagePayload::AddrList(
+                field_array(payload, "addrs")?
+                    .iter()
+                    .map(TimestampedNetAddress::from_value)
+                    .collect::<Result<Vec<_>, Error>>()?
+            ),
+            Command::AddrV2 => MessagePayload::AddrV2List(
+                field_array(payload, "addrs")?
+                    .iter()
+                    .map(TimestampedNetAddress::from_value)
+                    .collect::<Result<Vec<_>, Error>>()?
+            ),
+            Command::Inv |
+            Command::GetData |
+            Command::NotFound => MessagePayload::InvVect(
+                field_array(payload, "inventory")?
+                    .iter()
+                    .map(Inventory::from_value)
+                    .collect::<Result<Vec<_>, Error>>()?
+            ),
+            Command::Tx => MessagePayload::Transction(deserialize(&field_hex(payload, "raw")?)?),
+            Command::GetBlocks |
+            Command::GetHeaders => MessagePayload::BlockLocator(BlockdataLocatorInfo::from_value(payload)?),
+            Command::Headers => MessagePayload::Headers(
+                field_array(payload, "headers")?
+                    .iter()
+                    .map(BlockHeader::from_value)
+                    .collect::<Result<Vec<_>, Error>>()?
+            ),
+            Command::Block => MessagePayload::Block(Decode::net_decode(&field_hex(payload, "raw")?[..])?),
+            _ => MessagePayload::Dump(field_hex(payload, "hex")?)
+        };
+
+        // Going through the typed message rejects payloads that do not match the command
+        Ok(Message::from_payload(NetworkMessage::from_parts(command, payload)?, magic))
+    }
+}
+
+impl MessagePayload {
+    fn to_value(&self) -> Value {
+        match self {
+            Self::Version(v) => v.to_value(),
+            Self::PingPong(nonce) => json!({ "nonce": nonce }),
+            Self::AddrList(addrs) |
+            Self::AddrV2List(addrs) => json!({ "addrs": addrs.iter().map(|a| a.to_value()).collect::<Vec<Value>>() }),
+            Self::InvVect(inv) => json!({ "inventory": inv.iter().map(|i| i.to_value()).collect::<Vec<Value>>() }),
+            Self::Transction(tx) => json!({ "txid": tx.txid().to_string(), "raw": serialize_hex(tx) }),
+            Self::BlockLocator(loc) => loc.to_value(),
+            Self::Headers(hdrs) => json!({ "headers": hdrs.iter().map(|h| h.to_value()).collect::<Vec<Value>>() }),
+            Self::Block(block) => {
+                let mut raw = Vec::new();
+                block.net_encode(&mut raw);
+                json!({ "hash": block.hash().to_string(), "raw": raw.to_hex() })
+            },
+            Self::EmptyPayload => Value::Null,
+            Self::Dump(d) => json!({ "hex": d.to_hex() })
+        }
+    }
+}
+
+impl JsonValue for Magic {
+    fn to_value(&self) -> Value {
+        match self {
+            Self::Main => json!("main"),
+            Self::Test => json!("test"),
+            Self::Testnet => json!("testnet"),
+            Self::Signet => json!("signet"),
+            Self::Unknown(v) => json!(v)
+        }
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::String(s) if s == "main" => Ok(Self::Main),
+            Value::String(s) if s == "test" => Ok(Self::Test),
+            Value::String(s) if s == "testnet" => Ok(Self::Testnet),
+            Value::String(s) if s == "signet" => Ok(Self::Signet),
+            Value::Number(n) => match n.as_u64() {
+                Some(v) if v <= u32::MAX as u64 => Ok(Self::from((v as u32).to_be_bytes())),
+                _ => Err(bad_field("magic"))
+            },
+            _ => Err(bad_field("magic"))
+        }
+    }
+}
+
+impl JsonValue for ServicesList {
+    fn to_value(&self) -> Value {
+        json!(self.names())
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        let names = value.as_array().ok_or_else(|| bad_field("services"))?;
+
+        let mut bits = 0;
+        for name in names {
+            let name = name.as_str().ok_or_else(|| bad_field("services"))?;
+            // Unknown bits are named after their position
+            let unknown = name
+                .strip_prefix("UNKNOWN[2^")
+                .and_then(|bit| bit.strip_suffix(']'))
+                .and_then(|bit| bit.parse::<u32>().ok())
+                .filter(|bit| *bit < 64);
+            bits |= match unknown {
+                Some(bit) => 1 << bit,
+                None => Service::try_from_name(name)?.value()
+            };
+        }
+        Ok(ServicesList::from_bits(bits))
+    }
+}
+
+impl JsonValue for Address {
+    fn to_value(&self) -> Value {
+        json!(self.to_string())
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        parse_hash::<Address>(value, "address")
+    }
+}
+
+impl JsonValue for NetAddress {
+    fn to_value(&self) -> Value {
+        json!({
+            "services": self.services.to_value(),
+            "address": self.address.to_value()
+        })
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(Self::new(
+            ServicesList::from_value(field(value, "services")?)?,
+            Address::from_value(field(value, "address")?)?
+        ))
+    }
+}
+
+impl JsonValue for TimestampedNetAddress {
+    fn to_value(&self) -> Value {
+        json!({
+            "timestamp": self.timestamp.as_secs(),
+            "services": self.netaddress.services.to_value(),
+            "address": self.netaddress.address.to_value()
+        })
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(Self::new(
+            Duration::from_secs(field_u64(value, "timestamp")?),
+            NetAddress::from_value(value)?
+        ))
+    }
+}
+
+#[cfg(feature = "net")]
+impl JsonValue for AddrInfo {
+    fn to_value(&self) -> Value {
+        json!({
+            "services": self.addr.services.to_value(),
+            "address": self.addr.address.to_value(),
+            "timestamp": self.timestamp.as_secs(),
+            "source": self.source.to_string(),
+            "last_attempt": self.last_attempt.map(|t| t.as_secs()),
+            "last_success": self.last_success.map(|t| t.as_secs()),
+            "attempts": self.attempts,
+            "tried": self.tried
+        })
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(Self {
+            addr: NetAddress::from_value(value)?,
+            timestamp: Duration::from_secs(field_u64(value, "timestamp")?),
+            source: parse_hash(field(value, "source")?, "source")?,
+            last_attempt: field_opt_time(value, "last_attempt")?,
+            last_success: field_opt_time(value, "last_success")?,
+            attempts: field_u32(value, "attempts")?,
+            tried: field(value, "tried")?.as_bool().ok_or_else(|| bad_field("tried"))?
+        })
+    }
+}
+
+impl JsonValue for VersionMessage {
+    fn to_value(&self) -> Value {
+        json!({
+            "version": self.version,
+            "services": self.service.to_value(),
+            "timestamp": self.timestamp,
+            "addr_recv": self.addr_recv.to_value(),
+            "addr_from": self.addr_from.to_value(),
+            "nonce": self.nonce,
+            "agent": self.agent.as_str(),
+            "start_height": self.start_height,
+            "relay": self.relay
+        })
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(Self::new(
+            field_i32(value, "version")?,
+            ServicesList::from_value(field(value, "services")?)?,
+            field_i64(value, "timestamp")?,
+            NetAddress::from_value(field(value, "addr_recv")?)?,
+            NetAddress::from_value(field(value, "addr_from")?)?,
+            field_u64(value, "nonce")?,
+            UserAgent::new(field_str(value, "agent")?.to_string())?,
+            field_i32(value, "start_height")?,
+            match field(value, "relay")? {
+                Value::Null => None,
+                v => Some(v.as_bool().ok_or_else(|| bad_field("relay"))?)
+            }
+        ))
+    }
+}
+
+#[cfg(feature = "net")]
+impl JsonValue for CrawledNode {
+    fn to_value(&self) -> Value {
+        json!({
+            "address": self.addr.to_value(),
+            "version": self.version.to_value(),
+            "addrs": self.addrs
+        })
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(Self {
+            addr: Address::from_value(field(value, "address")?)?,
+            version: VersionMessage::from_value(field(value, "version")?)?,
+            addrs: field_u64(value, "addrs")? as usize
+        })
+    }
+}
+
+impl JsonValue for Inventory {
+    fn to_value(&self) -> Value {
+        let inv_type = match self {
+            Self::Error => "error",
+            Self::Tx(_) => "tx",
+            Self::Block(_) => "block",
+            Self::FilteredBlock(_) => "filtered_block",
+            Self::CompactBlock(_) => "compact_block",
+            Self::WitnessTx(_) => "witness_tx",
+            Self::WitnessBlock(_) => "witness_block",
+            Self::FilteredWitnessBlock(_) => "filtered_witness_block",
+            Self::Unknown{..} => "unknown"
+        };
+
+        json!({
+            "type": inv_type,
+            "id": self.identifier(),
+            "hash": self.inner().to_be_hex()
+        })
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        let hash = <[u8; 32]>::from_be_hex(field_str(value, "hash")?).map_err(|_| bad_field("hash"))?;
+        Ok(Self::from_id_and_hash(field_u32(value, "id")?, hash))
+    }
+}
+
+impl JsonValue for BlockdataLocatorInfo {
+    fn to_value(&self) -> Value {
+        json!({
+            "version": self.version,
+            "hashes": self.hashes.iter().map(|h| h.to_string()).collect::<Vec<String>>(),
+            "stop": self.stop.to_string()
+        })
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(Self::new(
+            field_u32(value, "version")?,
+            field_array(value, "hashes")?
+                .iter()
+                .map(|h| parse_hash(h, "hashes"))
+                .collect::<Result<Vec<BlockHash>, Error>>()?,
+            parse_hash(field(value, "stop")?, "stop")?
+        ))
+    }
+}
+
+impl JsonValue for BlockHeader {
+    fn to_value(&self) -> Value {
+        json!({
+            "hash": self.hash().to_string(),
+            "version": self.version,
+            "prev_blockhash": self.prev_blockhash.to_string(),
+            "merkle_root": self.merkle_root.to_string(),
+            "time": self.time,
+            "bits": self.bits,
+            "nonce": self.nonce
+        })
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(BlockHeader {
+            version: field_i32(value, "version")?,
+            prev_blockhash: parse_hash(field(value, "prev_blockhash")?, "prev_blockhash")?,
+            merkle_root: parse_hash::<TxMerkleNode>(field(value, "merkle_root")?, "merkle_root")?,
+            time: field_u32(value, "time")?,
+            bits: field_u32(value, "bits")?,
+            nonce: field_u32(value, "nonce")?
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_json_roundtrip() {
+        let vm = crate::msg::network::VersionConfig::default().message_at(Address::me(), 1_640_995_200, 0x5eed);
+        let msg = Message::from_payload(NetworkMessage::Version(vm), Magic::Main);
+        let json = msg.to_json();
+        let dec = Message::from_json(&json).expect("Failed to parse");
+
+        assert_eq!(msg, dec);
+        assert!(json.contains("\"command\":\"version\""));
+    }
+
+    #[test]
+    fn inventory_json_is_big_endian() {
+        let mut hash = [0; 32];
+        hash[0] = 0xAB;
+        let msg = Message::from_payload(NetworkMessage::Inv(vec![Inventory::from_id_and_hash(1, hash)]), Magic::Main);
+        let value = msg.to_value();
+
+        assert_eq!(value["payload"]["inventory"][0]["hash"].as_str().unwrap(), format!("{}ab", "00".repeat(31)));
+        assert_eq!(Message::from_json(&msg.to_json()).expect("Failed to parse"), msg);
+    }
+
+    #[test]
+    fn service_flag_names() {
+        let mut flags = ServicesList::new();
+        flags.add_flag(Service::Witness);
+        flags.add_flag(Service::Network);
+
+        assert_eq!(flags.to_value(), json!(["NETWORK", "WITNESS"]));
+        assert_eq!(ServicesList::from_value(&flags.to_value()).unwrap(), flags);
+
+        // Unknown bits are kept
+        let flags = ServicesList::from_bits(1 << 11 | 1 << 24);
+        assert_eq!(flags.to_value(), json!(["P2P_V2", "UNKNOWN[2^24]"]));
+        assert_eq!(ServicesList::from_value(&flags.to_value()).unwrap(), flags);
+    }
+}
@@ -2,28 +2,42 @@
 //
 // A small library for encoding/decoding bitcoin P2P messages
 //
-//
-//  Todos:
-//  - Implement other common network messages (block, headers, etc...)
-//
-//  - Use rust-bitcoin crate for blockdata
-//
-//  - Message structures refactor to enforce payload restrictions based on command type.
 
 
 
+// Allow the derive macros to refer to this crate by name from within the crate
+extern crate self as btcnetmsg;
+
 // Modules
 pub mod msg;
 pub mod encode;
 pub mod blockdata;
 pub mod address;
+pub mod json;
+#[cfg(feature = "net")]
+pub mod net;
+#[cfg(feature = "net")]
+pub mod mempool;
+pub mod seeds;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "arbitrary")]
+mod arbitrary;
+#[cfg(test)]
+mod proptests;
 
 // Re-exports
 pub use bitcoin as bitcoin;
 pub use msg::{
     data::{
         Message,
-        MessagePayload
+        MessagePayload,
+        MessageRef,
+        MessagePayloadRef,
+        RawMessage,
+        NetworkMessage
     },
     header::{
         MessageHeader,
@@ -36,11 +50,22 @@ pub use msg::{
         ServicesList,
         Service
     },
-    inventory::Inventory
+    inventory::Inventory,
+    builder::MessageBuilder,
+    stream::MessageStream
 };
 pub use encode::{
     Encode,
     Decode,
+    DecodeRef,
+    DecodeConfig,
     Error
 };
-pub use address::Address;
\ No newline at end of file
+pub use btcnetmsg_derive::{
+    Encode,
+    Decode
+};
+pub use address::{
+    Address,
+    Port
+};
\ No newline at end of file
@@ -0,0 +1,180 @@
+// fee.rs
+//
+// Fee rates of the unconfirmed transactions.
+//
+// The fee of a transaction is the value of its inputs minus the value of its outputs, which
+// needs the outputs spent by each input. Only transactions whose parents were also received
+// can have their fee computed, as the outputs of confirmed transactions are not known.
+//
+// Fee rates are recorded in a rolling window to give a live estimate of the fee rate needed
+// to be confirmed.
+//
+
+use crate::{
+    bitcoin::blockdata::transaction::OutPoint,
+    blockdata::Tx
+};
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::Duration
+};
+
+/// Time during which fee rates are kept in the histogram
+pub const FEE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum number of fee rates kept in the histogram
+const MAX_SAMPLES: usize = 100_000;
+
+/// Fee rate in satoshis per 1000 virtual bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct FeeRate(pub u64);
+
+impl FeeRate {
+    /// Fee rate of a fee paid for a transaction of the given virtual size
+    pub fn new(fee: u64, vsize: usize) -> Self {
+        Self(fee.saturating_mul(1000) / vsize.max(1) as u64)
+    }
+
+    /// Fee rate of a transaction with a known fee
+    pub fn of(tx: &Tx, fee: u64) -> Self {
+        Self::new(fee, vsize(tx))
+    }
+
+    /// Fee rate in satoshis per virtual byte
+    pub fn sat_per_vb(&self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.3} sat/vB", self.sat_per_vb())
+    }
+}
+
+/// Virtual size of a transaction, which is its weight divided by four rounded up
+pub fn vsize(tx: &Tx) -> usize {
+    tx.get_weight().div_ceil(4)
+}
+
+/// Compute the fee of a transaction using a function giving the value of spent outputs.
+/// Returns `None` if an output is unknown or the outputs are worth more than the inputs.
+pub fn fee<F>(tx: &Tx, prevout: F) -> Option<u64>
+where F: Fn(&OutPoint) -> Option<u64> {
+    if tx.is_coin_base() { return None }
+
+    let inputs = tx.input.iter().try_fold(0u64, |sum, i| sum.checked_add(prevout(&i.previous_output)?))?;
+    let outputs = tx.output.iter().try_fold(0u64, |sum, o| sum.checked_add(o.value))?;
+    inputs.checked_sub(outputs)
+}
+
+/// Rolling histogram of the fee rates of recently seen transactions.
+/// Fee rates are weighted by the virtual size of the transactions.
+#[derive(Clone, Debug)]
+pub struct FeeHistogram {
+    window: Duration,
+    samples: VecDeque<(Duration, FeeRate, usize)>
+}
+
+impl Default for FeeHistogram {
+    fn default() -> Self {
+        Self::new(FEE_WINDOW)
+    }
+}
+
+impl FeeHistogram {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new()
+        }
+    }
+
+    /// Number of fee rates in the window
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Record the fee rate of a transaction seen at a time since the unix epoch
+    pub fn record(&mut self, time: Duration, rate: FeeRate, vsize: usize) {
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((time, rate, vsize));
+        self.expire(time);
+    }
+
+    /// Forget fee rates recorded before the window ending at a time
+    pub fn expire(&mut self, now: Duration) {
+        while let Some((time, _, _)) = self.samples.front() {
+            if *time + self.window > now { break }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Fee rate paid by the given percentage of the virtual bytes in the window,
+    /// starting from the highest fee rates.
+    /// Returns `None` if no fee rate was recorded.
+    pub fn percentile(&self, percent: f64) -> Option<FeeRate> {
+        let mut sorted: Vec<(FeeRate, usize)> = self.samples.iter().map(|(_, r, s)| (*r, *s)).collect();
+        sorted.sort_unstable_by(|a, b| b.cmp(a));
+
+        let total: usize = sorted.iter().map(|(_, s)| s).sum();
+        let goal = (total as f64 * percent.clamp(0.0, 100.0) / 100.0).ceil() as usize;
+        let mut acc = 0;
+        for (rate, size) in sorted.iter() {
+            acc += size;
+            if acc >= goal { return Some(*rate) }
+        }
+        sorted.last().map(|(r, _)| *r)
+    }
+
+    /// Sum of the virtual sizes in the window for each bucket of fee rates.
+    /// Bucket `i` contains the fee rates from `bounds[i]` up to `bounds[i + 1]`, and fee
+    /// rates lower than the first bound are not counted.
+    pub fn histogram(&self, bounds: &[FeeRate]) -> Vec<(FeeRate, usize)> {
+        let mut buckets: Vec<(FeeRate, usize)> = bounds.iter().map(|b| (*b, 0)).collect();
+        for (_, rate, size) in self.samples.iter() {
+            if let Some(bucket) = buckets.iter_mut().rev().find(|(b, _)| b <= rate) {
+                bucket.1 += size;
+            }
+        }
+        buckets
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut hist = FeeHistogram::new(Duration::from_secs(10));
+        assert_eq!(hist.percentile(50.0), None);
+
+        hist.record(Duration::from_secs(1), FeeRate(1000), 100);
+        hist.record(Duration::from_secs(2), FeeRate(5000), 200);
+        hist.record(Duration::from_secs(3), FeeRate(20000), 100);
+        assert_eq!(hist.percentile(0.0), Some(FeeRate(20000)));
+        assert_eq!(hist.percentile(25.0), Some(FeeRate(20000)));
+        assert_eq!(hist.percentile(50.0), Some(FeeRate(5000)));
+        assert_eq!(hist.percentile(100.0), Some(FeeRate(1000)));
+        assert_eq!(
+            hist.histogram(&[FeeRate(2000), FeeRate(10000)]),
+            vec![(FeeRate(2000), 200), (FeeRate(10000), 100)]
+        );
+
+        // The first fee rate leaves the window
+        hist.record(Duration::from_secs(11), FeeRate(3000), 100);
+        assert_eq!(hist.len(), 3);
+        assert_eq!(hist.percentile(100.0), Some(FeeRate(3000)));
+
+        assert_eq!(FeeRate::new(226, 141).to_string(), "1.602 sat/vB");
+    }
+}
@@ -0,0 +1,12 @@
+// mempool/mod.rs
+//
+// Bundling module for tracking the unconfirmed transactions relayed on the network
+//
+
+pub mod fee;
+pub mod orphan;
+pub mod tracker;
+
+pub use fee::FeeRate;
+pub use orphan::OrphanPool;
+pub use tracker::Tracker;
@@ -0,0 +1,244 @@
+// orphan.rs
+//
+// Pool of orphan transactions.
+//
+// Orphans are transactions spending outputs of parents that were not received. Like in
+// bitcoin core, they are held in a bounded pool keyed by their missing parents, which are
+// requested from the peer that sent the orphan. Orphans are resolved once all of their
+// parents arrive, and are dropped if a parent is not found or after some time.
+//
+
+use crate::{
+    bitcoin::hash_types::Txid,
+    blockdata::Tx,
+    msg::inventory::Inventory,
+    net::eventloop::PeerId
+};
+use std::{
+    collections::{
+        HashMap,
+        HashSet
+    },
+    time::Duration
+};
+
+/// Default maximum number of orphans, as in bitcoin core
+pub const DEFAULT_MAX_ORPHANS: usize = 100;
+
+/// Time after which orphans are dropped, as in bitcoin core
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+/// Transaction waiting for its parents
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Orphan {
+    pub tx: Tx,
+
+    /// Peer that sent the orphan, and that is asked for its parents
+    pub peer: PeerId,
+
+    /// Time the orphan was added, since the unix epoch
+    pub added: Duration,
+
+    /// Parents that were not received yet
+    pub missing: HashSet<Txid>
+}
+
+/// Bounded pool of orphan transactions
+#[derive(Clone, Debug)]
+pub struct OrphanPool {
+    max: usize,
+    orphans: HashMap<Txid, Orphan>,
+    by_parent: HashMap<Txid, HashSet<Txid>>,
+
+    /// Parents to request with `getdata`
+    requests: Vec<(PeerId, Txid)>
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ORPHANS)
+    }
+}
+
+impl OrphanPool {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
+            requests: Vec::new()
+        }
+    }
+
+    /// Number of orphans in the pool
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.orphans.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<&Orphan> {
+        self.orphans.get(txid)
+    }
+
+    /// Add an orphan with its missing parents, at a time since the unix epoch.
+    /// The oldest orphan is dropped if the pool is full.
+    /// Returns false if the orphan was already in the pool or has no missing parents.
+    pub fn insert(&mut self, peer: PeerId, tx: Tx, missing: HashSet<Txid>, now: Duration) -> bool {
+        let txid = tx.txid();
+        if missing.is_empty() || self.orphans.contains_key(&txid) { return false }
+
+        if self.orphans.len() >= self.max {
+            let oldest = self.orphans.iter().min_by_key(|(_, o)| o.added).map(|(txid, _)| *txid);
+            if let Some(oldest) = oldest {
+                self.remove(&oldest);
+            }
+        }
+
+        for parent in missing.iter() {
+            let children = self.by_parent.entry(*parent).or_default();
+            if children.is_empty() {
+                self.requests.push((peer, *parent));
+            }
+            children.insert(txid);
+        }
+        self.orphans.insert(txid, Orphan { tx, peer, added: now, missing });
+        true
+    }
+
+    /// Remove an orphan from the pool
+    pub fn remove(&mut self, txid: &Txid) -> Option<Orphan> {
+        let orphan = self.orphans.remove(txid)?;
+        for parent in orphan.missing.iter() {
+            if let Some(children) = self.by_parent.get_mut(parent) {
+                children.remove(txid);
+                if children.is_empty() {
+                    self.by_parent.remove(parent);
+                }
+            }
+        }
+        Some(orphan)
+    }
+
+    /// Handle the arrival of a parent.
+    /// Returns the orphans that are no longer missing any parent, removed from the pool.
+    pub fn resolve(&mut self, parent: &Txid) -> Vec<Orphan> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+        self.requests.retain(|(_, p)| p != parent);
+
+        let mut resolved = Vec::new();
+        for child in children {
+            let complete = match self.orphans.get_mut(&child) {
+                Some(orphan) => {
+                    orphan.missing.remove(parent);
+                    orphan.missing.is_empty()
+                },
+                None => false
+            };
+            if complete {
+                resolved.extend(self.orphans.remove(&child));
+            }
+        }
+        resolved
+    }
+
+    /// Drop the orphans spending a parent that will not arrive, because it was not found
+    /// or is already confirmed.
+    /// Returns the dropped orphans.
+    pub fn not_found(&mut self, parent: &Txid) -> Vec<Orphan> {
+        let children = self.by_parent.get(parent).cloned().unwrap_or_default();
+        self.requests.retain(|(_, p)| p != parent);
+        children.iter().filter_map(|c| self.remove(c)).collect()
+    }
+
+    /// Drop the orphans added before the expiry.
+    /// Returns the number of orphans dropped.
+    pub fn expire(&mut self, now: Duration) -> usize {
+        let expired: Vec<Txid> = self.orphans.iter()
+            .filter(|(_, o)| o.added + ORPHAN_EXPIRY <= now)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in expired.iter() {
+            self.remove(txid);
+        }
+        expired.len()
+    }
+
+    /// Take the parents that should be requested, grouped by peer
+    pub fn take_requests(&mut self) -> Vec<(PeerId, Vec<Inventory>)> {
+        let mut requests: Vec<(PeerId, Vec<Inventory>)> = Vec::new();
+        for (peer, parent) in self.requests.drain(..) {
+            if !self.by_parent.contains_key(&parent) { continue }
+
+            let inv = Inventory::WitnessTx(parent);
+            match requests.iter_mut().find(|(p, _)| *p == peer) {
+                Some((_, list)) => list.push(inv),
+                None => requests.push((peer, vec![inv]))
+            }
+        }
+        requests
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::hashes::Hash;
+
+    fn orphan(n: u32) -> Tx {
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        let mut tx = genesis.txdata[0].clone();
+        tx.lock_time = n;
+        Tx::new(tx)
+    }
+
+    fn txids(list: &[u8]) -> HashSet<Txid> {
+        list.iter().map(|n| Txid::from_inner([*n; 32])).collect()
+    }
+
+    #[test]
+    fn resolve_parents() {
+        let mut pool = OrphanPool::new(2);
+        let (a, b, c) = (orphan(1), orphan(2), orphan(3));
+        let (p1, p2) = (Txid::from_inner([1; 32]), Txid::from_inner([2; 32]));
+
+        assert!(!pool.insert(PeerId(1), a.clone(), HashSet::new(), Duration::from_secs(0)));
+        assert!(pool.insert(PeerId(1), a.clone(), txids(&[1, 2]), Duration::from_secs(0)));
+        assert!(!pool.insert(PeerId(1), a.clone(), txids(&[1, 2]), Duration::from_secs(0)));
+        assert!(pool.insert(PeerId(2), b.clone(), txids(&[2]), Duration::from_secs(1)));
+
+        // Each parent is requested once, from the first peer that sent a child
+        let requests = pool.take_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, PeerId(1));
+        assert_eq!(requests[0].1.len(), 2);
+        assert!(pool.take_requests().is_empty());
+
+        let resolved = pool.resolve(&p2);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].tx, b);
+        assert_eq!(pool.get(&a.txid()).unwrap().missing, txids(&[1]));
+        assert_eq!(pool.resolve(&p1)[0].tx, a);
+        assert!(pool.is_empty());
+
+        // The oldest orphan is dropped when the pool is full
+        pool.insert(PeerId(1), a.clone(), txids(&[1]), Duration::from_secs(0));
+        pool.insert(PeerId(1), b.clone(), txids(&[2]), Duration::from_secs(1));
+        pool.insert(PeerId(1), c.clone(), txids(&[2]), Duration::from_secs(2));
+        assert!(!pool.contains(&a.txid()));
+        assert_eq!(pool.not_found(&p2).len(), 2);
+        assert!(pool.is_empty());
+
+        pool.insert(PeerId(1), a.clone(), txids(&[1]), Duration::from_secs(0));
+        assert_eq!(pool.expire(ORPHAN_EXPIRY - Duration::from_secs(1)), 0);
+        assert_eq!(pool.expire(ORPHAN_EXPIRY), 1);
+        assert!(pool.take_requests().is_empty());
+    }
+}
@@ -0,0 +1,463 @@
+// tracker.rs
+//
+// Tracker of the unconfirmed transactions announced by peers.
+//
+// Transactions are tracked from their first `inv` or `tx` message, along with the peers
+// that announced them. They are removed once they are included in a block, or expire if
+// they were not confirmed within two weeks like in the mempool of bitcoin core.
+//
+// The fee of a received transaction is computed once all of its parents are received, and
+// its fee rate is then recorded in the fee histogram. Transactions with missing parents are
+// also held in the orphan pool, which requests the parents from the peer that sent them.
+//
+// When fetching is enabled, announced transactions are also requested from the first peer
+// that announced them, so every transaction propagating through the peers is received.
+//
+
+use crate::{
+    bitcoin::hash_types::Txid,
+    blockdata::{
+        compact::{
+            CompactBlock,
+            PartialBlock
+        },
+        Block,
+        Tx
+    },
+    encode::Error,
+    mempool::{
+        fee::{
+            self,
+            FeeHistogram,
+            FeeRate
+        },
+        orphan::OrphanPool
+    },
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::Command,
+        inventory::Inventory
+    },
+    net::{
+        addrman::now,
+        eventloop::PeerId
+    }
+};
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+        VecDeque
+    },
+    time::Duration
+};
+
+/// Time after which unconfirmed transactions are forgotten
+pub const MEMPOOL_EXPIRY: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Number of confirmed txids remembered so that late announcements are ignored
+const MAX_CONFIRMED: usize = 50_000;
+
+/// Time during which the arrivals of new transactions are kept to compute arrival rates
+pub const ARRIVAL_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum number of arrivals kept
+const MAX_ARRIVALS: usize = 100_000;
+
+/// Unconfirmed transaction known to the tracker
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxEntry {
+    /// Time the transaction was first announced, since the unix epoch
+    pub first_seen: Duration,
+
+    /// Peers that announced or sent the transaction
+    pub peers: HashSet<PeerId>,
+
+    /// The transaction, if it was received
+    pub tx: Option<Tx>,
+
+    /// Fee paid by the transaction, if it and its parents were received
+    pub fee: Option<u64>
+}
+
+impl TxEntry {
+    /// Fee rate of the transaction, if its fee is known
+    pub fn fee_rate(&self) -> Option<FeeRate> {
+        Some(FeeRate::of(self.tx.as_ref()?, self.fee?))
+    }
+}
+
+/// Set of unconfirmed transactions seen across all peers
+#[derive(Clone, Debug, Default)]
+pub struct Tracker {
+    entries: HashMap<Txid, TxEntry>,
+    confirmed: HashSet<Txid>,
+
+    /// Times new transactions were first seen, since the unix epoch
+    arrivals: VecDeque<Duration>,
+
+    /// Whether announced transactions are requested, and the ones to request
+    fetch: bool,
+    announced: Vec<(PeerId, Txid)>,
+
+    orphans: OrphanPool,
+    fees: FeeHistogram
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of tracked transactions
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<&TxEntry> {
+        self.entries.get(txid)
+    }
+
+    /// Iterate over the tracked transactions
+    pub fn iter(&self) -> impl Iterator<Item = (&Txid, &TxEntry)> {
+        self.entries.iter()
+    }
+
+    /// Fee rates of the transactions received recently
+    pub fn fees(&self) -> &FeeHistogram {
+        &self.fees
+    }
+
+    /// Received transactions waiting for their parents to compute their fee
+    pub fn orphans(&self) -> &OrphanPool {
+        &self.orphans
+    }
+
+    /// Set whether announced transactions that were not received are requested
+    pub fn set_fetch(&mut self, fetch: bool) {
+        self.fetch = fetch;
+        if !fetch {
+            self.announced.clear();
+        }
+    }
+
+    /// Take the transactions to request with `getdata`, by the peer to request them from.
+    /// These are the missing parents of the orphans, and the announced transactions if
+    /// fetching is enabled.
+    pub fn take_requests(&mut self) -> Vec<(PeerId, Vec<Inventory>)> {
+        let mut requests = self.orphans.take_requests();
+        for (peer, txid) in self.announced.drain(..) {
+            if self.entries.get(&txid).is_none_or(|e| e.tx.is_some()) { continue }
+
+            let inv = Inventory::WitnessTx(txid);
+            match requests.iter_mut().find(|(p, _)| *p == peer) {
+                Some((_, list)) => list.push(inv),
+                None => requests.push((peer, vec![inv]))
+            }
+        }
+        requests
+    }
+
+    /// Number of new transactions seen per second, in a window ending now of at most
+    /// [`ARRIVAL_WINDOW`]
+    pub fn arrival_rate(&self, window: Duration) -> f64 {
+        let window = window.min(ARRIVAL_WINDOW);
+        if window.is_zero() { return 0.0 }
+
+        let now = now();
+        let count = self.arrivals.iter().rev().take_while(|t| **t + window > now).count();
+        count as f64 / window.as_secs_f64()
+    }
+
+    /// Get the parents of a received transaction that were not received yet, which can be
+    /// requested with `getdata` to compute its fee.
+    /// Parents that are known to be confirmed are not included.
+    pub fn missing_parents(&self, txid: &Txid) -> Vec<Txid> {
+        let tx = match self.entries.get(txid).and_then(|e| e.tx.as_ref()) {
+            Some(tx) => tx,
+            None => return Vec::new()
+        };
+
+        let mut parents: Vec<Txid> = tx.input.iter()
+            .map(|i| i.previous_output.txid)
+            .filter(|p| !self.confirmed.contains(p) && self.entries.get(p).is_none_or(|e| e.tx.is_none()))
+            .collect();
+        parents.sort_unstable();
+        parents.dedup();
+        parents
+    }
+
+    /// Start the reconstruction of a compact block from the received transactions.
+    /// The missing transactions can be requested with [`PartialBlock::request`].
+    pub fn reconstruct(&self, cmpct: &CompactBlock, version: u64) -> Result<PartialBlock, Error> {
+        cmpct.reconstruct(self.entries.values().filter_map(|e| e.tx.as_ref()), version)
+    }
+
+    /// Handle a message received from a peer.
+    /// Returns the txids that were not known before.
+    pub fn on_message(&mut self, peer: PeerId, msg: &Message) -> Vec<Txid> {
+        match (&msg.header().command, msg.payload()) {
+            (Command::Inv, MessagePayload::InvVect(inv)) => self.on_inv(peer, inv),
+            (Command::NotFound, MessagePayload::InvVect(inv)) => {
+                self.on_not_found(inv);
+                Vec::new()
+            },
+            (_, MessagePayload::Transction(tx)) => self.on_tx(peer, Tx::new(tx.clone())).into_iter().collect(),
+            (_, MessagePayload::Block(block)) => {
+                self.on_block(block);
+                Vec::new()
+            },
+            _ => Vec::new()
+        }
+    }
+
+    /// Record the transactions announced in an `inv` message.
+    /// Returns the txids that were not known before.
+    pub fn on_inv(&mut self, peer: PeerId, inv: &[Inventory]) -> Vec<Txid> {
+        inv.iter()
+            .filter_map(|i| match i {
+                Inventory::Tx(txid) |
+                Inventory::WitnessTx(txid) => Some(*txid),
+                _ => None
+            })
+            .filter(|txid| {
+                let new = self.announce(peer, *txid);
+                if new && self.fetch {
+                    self.announced.push((peer, *txid));
+                }
+                new
+            })
+            .collect()
+    }
+
+    /// Drop the orphans whose parents were not found by a peer.
+    /// Their fee can not be computed, but they are still tracked.
+    pub fn on_not_found(&mut self, inv: &[Inventory]) {
+        for i in inv {
+            if let Inventory::Tx(txid) | Inventory::WitnessTx(txid) = i {
+                self.orphans.not_found(txid);
+            }
+        }
+    }
+
+    /// Record a transaction received from a peer.
+    /// Returns its txid if it was not known before.
+    pub fn on_tx(&mut self, peer: PeerId, tx: Tx) -> Option<Txid> {
+        let txid = tx.txid();
+        let new = self.announce(peer, txid);
+        match self.entries.get_mut(&txid) {
+            Some(entry) if entry.tx.is_none() => entry.tx = Some(tx),
+            _ => return None
+        }
+
+        // The transaction may be the last missing parent of orphans
+        self.compute_fee(peer, txid);
+        for orphan in self.orphans.resolve(&txid) {
+            self.compute_fee(orphan.peer, orphan.tx.txid());
+        }
+
+        match new {
+            true => Some(txid),
+            false => None
+        }
+    }
+
+    /// Remove the transactions included in a block.
+    /// Returns the number of tracked transactions that were removed.
+    pub fn on_block(&mut self, block: &Block) -> usize {
+        if self.confirmed.len() + block.txs.len() > MAX_CONFIRMED {
+            self.confirmed.clear();
+        }
+
+        let mut removed = 0;
+        for tx in block {
+            let txid = tx.txid();
+            if self.entries.remove(&txid).is_some() {
+                removed += 1;
+            }
+            self.orphans.not_found(&txid);
+            self.confirmed.insert(txid);
+        }
+        removed
+    }
+
+    /// Forget transactions that were first seen longer than the expiry ago.
+    /// Returns the number of transactions removed.
+    pub fn expire(&mut self) -> usize {
+        let before = self.entries.len();
+        let now = now();
+        self.entries.retain(|_, e| e.first_seen + MEMPOOL_EXPIRY > now);
+        self.orphans.expire(now);
+        self.fees.expire(now);
+        while self.arrivals.front().is_some_and(|t| *t + ARRIVAL_WINDOW <= now) {
+            self.arrivals.pop_front();
+        }
+        before - self.entries.len()
+    }
+
+    /// Compute the fee of a received transaction if its parents were received, or add it
+    /// to the orphan pool
+    fn compute_fee(&mut self, peer: PeerId, txid: Txid) {
+        let tx = match self.entries.get(&txid) {
+            Some(TxEntry { tx: Some(tx), fee: None, .. }) => tx,
+            _ => return
+        };
+
+        let missing = self.missing_parents(&txid);
+        if !missing.is_empty() {
+            self.orphans.insert(peer, tx.clone(), missing.into_iter().collect(), now());
+            return
+        }
+
+        let entries = &self.entries;
+        let fee = fee::fee(tx, |out| {
+            entries.get(&out.txid)?.tx.as_ref()?.output.get(out.vout as usize).map(|o| o.value)
+        });
+        if let Some(fee) = fee {
+            self.fees.record(now(), FeeRate::of(tx, fee), fee::vsize(tx));
+            if let Some(entry) = self.entries.get_mut(&txid) {
+                entry.fee = Some(fee);
+            }
+        }
+    }
+
+    /// Add a peer to the announcers of a txid. Returns true if the txid was not known.
+    fn announce(&mut self, peer: PeerId, txid: Txid) -> bool {
+        if self.confirmed.contains(&txid) { return false }
+
+        let mut new = false;
+        let entry = self.entries.entry(txid).or_insert_with(|| {
+            new = true;
+            TxEntry { first_seen: now(), peers: HashSet::new(), tx: None, fee: None }
+        });
+        entry.peers.insert(peer);
+
+        if new {
+            if self.arrivals.len() >= MAX_ARRIVALS {
+                self.arrivals.pop_front();
+            }
+            self.arrivals.push_back(entry.first_seen);
+        }
+        new
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitcoin::blockdata::transaction::OutPoint,
+        blockdata::BlockHeader,
+        msg::{
+            data::NetworkMessage,
+            header::Magic
+        }
+    };
+
+    #[test]
+    fn track_until_confirmed() {
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        let tx = Tx::new(genesis.txdata[0].clone());
+        let mut tracker = Tracker::new();
+
+        let inv = Message::from_payload(NetworkMessage::Inv(vec![Inventory::WitnessTx(tx.txid())]), Magic::Main);
+        assert_eq!(tracker.on_message(PeerId(1), &inv), vec![tx.txid()]);
+        assert_eq!(tracker.on_message(PeerId(2), &inv), vec![]);
+        assert_eq!(tracker.on_tx(PeerId(3), tx.clone()), None);
+
+        let entry = tracker.get(&tx.txid()).unwrap();
+        assert_eq!(entry.peers, [PeerId(1), PeerId(2), PeerId(3)].iter().copied().collect());
+        assert_eq!(entry.tx.as_ref(), Some(&tx));
+        assert_eq!(tracker.expire(), 0);
+        assert_eq!(tracker.arrival_rate(Duration::from_secs(10)), 0.1);
+
+        // Transactions of compact blocks are taken from the tracker
+        let mut coinbase = tx.transaction().clone();
+        coinbase.lock_time = 1;
+        let mut header = BlockHeader::genesis();
+        header.merkle_root = crate::blockdata::merkle::merkle_root(&[coinbase.txid(), tx.txid()]);
+        let block = Block::new(header, vec![coinbase, tx.clone().into_inner()]);
+        let partial = tracker.reconstruct(&CompactBlock::from_block(&block, 1, 2), 2).unwrap();
+        assert_eq!(partial.block(), Some(block.clone()));
+
+        assert_eq!(tracker.on_message(PeerId(1), &Message::from_payload(NetworkMessage::Block(block), Magic::Main)), vec![]);
+        assert!(tracker.is_empty());
+
+        // Late announcements of confirmed transactions are ignored
+        assert_eq!(tracker.on_tx(PeerId(4), tx), None);
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn fees_with_parents() {
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        let mut parent = genesis.txdata[0].clone();
+        parent.input[0].previous_output = OutPoint::new(Txid::default(), 0);
+        let parent = Tx::new(parent);
+
+        let mut child = parent.transaction().clone();
+        child.input[0].previous_output = OutPoint::new(parent.txid(), 0);
+        child.output[0].value = parent.output[0].value - 10_000;
+        let child = Tx::new(child);
+
+        let mut tracker = Tracker::new();
+        tracker.on_tx(PeerId(1), child.clone());
+        assert_eq!(tracker.missing_parents(&child.txid()), vec![parent.txid()]);
+        assert_eq!(tracker.get(&child.txid()).unwrap().fee, None);
+        assert!(tracker.fees().is_empty());
+        assert!(tracker.orphans().contains(&child.txid()));
+        assert_eq!(tracker.take_requests(), vec![(PeerId(1), vec![Inventory::WitnessTx(parent.txid())])]);
+
+        // The parent spends an unknown output so its fee stays unknown
+        tracker.on_tx(PeerId(1), parent.clone());
+        assert_eq!(tracker.missing_parents(&child.txid()), vec![]);
+        assert_eq!(tracker.missing_parents(&parent.txid()), vec![Txid::default()]);
+        assert_eq!(tracker.get(&parent.txid()).unwrap().fee, None);
+        assert!(!tracker.orphans().contains(&child.txid()));
+
+        let entry = tracker.get(&child.txid()).unwrap();
+        assert_eq!(entry.fee, Some(10_000));
+        assert_eq!(entry.fee_rate(), Some(FeeRate::of(&child, 10_000)));
+        assert_eq!(tracker.fees().percentile(50.0), entry.fee_rate());
+
+        // The parent of the parent will not arrive
+        tracker.on_message(PeerId(1), &Message::from_payload(NetworkMessage::NotFound(vec![Inventory::Tx(Txid::default())]), Magic::Main));
+        assert!(tracker.orphans().is_empty());
+    }
+
+    #[test]
+    fn fetch_announced() {
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        let tx = Tx::new(genesis.txdata[0].clone());
+        let mut tracker = Tracker::new();
+
+        tracker.on_inv(PeerId(1), &[Inventory::Tx(tx.txid())]);
+        assert!(tracker.take_requests().is_empty());
+
+        // Only the first announcement is requested, and only until the transaction arrives
+        let mut tracker = Tracker::new();
+        tracker.set_fetch(true);
+        tracker.on_inv(PeerId(1), &[Inventory::Tx(tx.txid())]);
+        tracker.on_inv(PeerId(2), &[Inventory::Tx(tx.txid())]);
+        assert_eq!(tracker.take_requests(), vec![(PeerId(1), vec![Inventory::WitnessTx(tx.txid())])]);
+        assert!(tracker.take_requests().is_empty());
+
+        let mut tracker = Tracker::new();
+        tracker.set_fetch(true);
+        tracker.on_inv(PeerId(1), &[Inventory::Tx(tx.txid())]);
+        tracker.on_tx(PeerId(2), tx.clone());
+        assert!(tracker.take_requests().iter().all(|(_, inv)| !inv.contains(&Inventory::WitnessTx(tx.txid()))));
+    }
+}
@@ -0,0 +1,127 @@
+// builder.rs
+//
+// Fluent builder API for network messages
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            NetworkMessage
+        },
+        header::Magic,
+        network::{
+            VersionMessage,
+            TimestampedNetAddress
+        },
+        inventory::{
+            Inventory,
+            BlockdataLocatorInfo
+        }
+    },
+    encode::{
+        Encode,
+        Error
+    },
+
+    blockdata::{
+        Block,
+        BlockHeader
+    },
+
+    bitcoin::Transaction
+};
+
+/// Builder for network messages.
+/// The header length and checksum are only computed once the message is built.
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    magic: Magic,
+    message: Option<NetworkMessage>
+}
+
+/// Macro to create a builder method for a message which holds data
+macro_rules! builder_method {
+    ($name: ident, $var: ident, $data: ty) => {
+        pub fn $name(self, data: $data) -> Self {
+            self.message(NetworkMessage::$var(data))
+        }
+    };
+    ($name: ident, $var: ident) => {
+        pub fn $name(self) -> Self {
+            self.message(NetworkMessage::$var)
+        }
+    };
+}
+
+impl MessageBuilder {
+    pub fn new(magic: Magic) -> Self {
+        Self {
+            magic,
+            message: None
+        }
+    }
+
+    /// Set the message to build. Replaces any previously set message.
+    pub fn message(mut self, message: NetworkMessage) -> Self {
+        self.message = Some(message);
+        self
+    }
+
+    builder_method!(version, Version, VersionMessage);
+    builder_method!(verack, Verack);
+    builder_method!(sendheaders, SendHeaders);
+    builder_method!(wtxidrelay, WTxIdRelay);
+    builder_method!(ping, Ping, u64);
+    builder_method!(pong, Pong, u64);
+    builder_method!(addr, Addr, Vec<TimestampedNetAddress>);
+    builder_method!(getaddr, GetAddr);
+    builder_method!(inv, Inv, Vec<Inventory>);
+    builder_method!(getdata, GetData, Vec<Inventory>);
+    builder_method!(notfound, NotFound, Vec<Inventory>);
+    builder_method!(tx, Tx, Transaction);
+    builder_method!(getblocks, GetBlocks, BlockdataLocatorInfo);
+    builder_method!(getheaders, GetHeaders, BlockdataLocatorInfo);
+    builder_method!(block, Block, Block);
+    builder_method!(headers, Headers, Vec<BlockHeader>);
+    builder_method!(mempool, MemPool);
+    builder_method!(filterclear, FilterClear);
+    builder_method!(sendaddrv2, SendAddrV2);
+    builder_method!(addrv2, AddrV2, Vec<TimestampedNetAddress>);
+
+    /// Build the message.
+    /// Returns an error if no message was set.
+    pub fn build(self) -> Result<Message, Error> {
+        match self.message {
+            Some(msg) => Ok(Message::from_payload(msg, self.magic)),
+            None => Err(Error::InvalidData)
+        }
+    }
+
+    /// Build the message and encode it, ready to be written to a peer.
+    pub fn to_bytes(self) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        self.build()?.net_encode(&mut buf);
+        Ok(buf)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::header::Command;
+
+    #[test]
+    fn build_messages() {
+        let ping = MessageBuilder::new(Magic::Main).ping(5).build().expect("Failed to build");
+        assert_eq!(ping.header().command, Command::Ping);
+        assert_eq!(ping.header().length, 8);
+
+        let verack = MessageBuilder::new(Magic::Test).verack().to_bytes().expect("Failed to build");
+        assert_eq!(verack.len(), 24);
+        assert_eq!(verack[20..], [0x5D, 0xF6, 0xE0, 0xE2]);
+
+        assert!(MessageBuilder::new(Magic::Main).build().is_err());
+    }
+}
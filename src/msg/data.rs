@@ -14,8 +14,11 @@
 //      - Varint struct to create and parse variable length integers.
 //
 //    Message creation:
-//      - Messages can be created via the `Message::new()` API which is written to
-//        to take in a `Payload` enum value.
+//      - Messages can be created via the `Message::from_payload()` API which takes in a
+//        `NetworkMessage` enum value. The command is derived from the enum variant so a
+//        command can never be paired with the wrong payload.
+//      - Raw and unknown messages are framed through the `Raw` and `Unknown` variants of
+//        `NetworkMessage`. The header and payload of a message can only be read.
 //      - Once the `Message` struct is created, the message will be encoded through a
 //        encoding trait which will be implemented for the various data structures.
 //
@@ -33,7 +36,8 @@ use crate::{
         MessageHeader,
         Magic,
         Command,
-        Checksum
+        HashWriter,
+        sha256d
     },
     msg::network::{
         VersionMessage,
@@ -43,26 +47,317 @@ use crate::{
         Inventory,
         BlockdataLocatorInfo
     },
-    encode::Encode,
+    msg::fmt_timestamp,
+    encode::{
+        Encode,
+        Decode,
+        DecodeConfig,
+        Error
+    },
 
-    bitcoin::Transaction
+    bitcoin::{
+        Transaction,
+        consensus::encode::{
+            deserialize,
+            serialize
+        },
+        network::message as bitcoin_message
+    },
+    blockdata::{
+        Block,
+        BlockHeader
+    }
+};
+use std::io::{
+    ErrorKind,
+    IoSlice,
+    Write
 };
 
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-/// Network message structure
+/// Network message structure.
+/// The command in the header always matches the payload, so the fields can only be read.
 pub struct Message {
-    pub header: MessageHeader,
-    pub payload: MessagePayload
+    header: MessageHeader,
+    payload: MessagePayload
 }
 
 impl Message {
-    pub fn new(payload: MessagePayload, magic: Magic, command: Command) -> Message {
+    /// Create a message from a payload and command.
+    /// Does not check that the payload matches the command, messages are created with
+    /// [`Message::from_payload`] outside of the crate.
+    pub(crate) fn new(payload: MessagePayload, magic: Magic, command: Command) -> Message {
+        // The length and checksum are both taken from a single encoding of the payload
+        let mut w = HashWriter::new();
+        payload.net_encode(&mut w);
         Self {
-            header: MessageHeader::new(magic, command, payload.len(), payload.checksum()),
+            header: MessageHeader::new(magic, command, w.len(), w.checksum()),
             payload
         }
     }
+
+    /// Create a message where the command is derived from the network message.
+    pub fn from_payload(msg: NetworkMessage, magic: Magic) -> Message {
+        let command = msg.command();
+        Self::new(msg.into_payload(), magic, command)
+    }
+
+    /// Create a message from a decoded header and the payload decoded with it
+    pub(crate) fn from_header(header: MessageHeader, payload: MessagePayload) -> Message {
+        Self { header, payload }
+    }
+
+    /// Get the header of the message
+    pub fn header(&self) -> &MessageHeader {
+        &self.header
+    }
+
+    /// Get the payload of the message
+    pub fn payload(&self) -> &MessagePayload {
+        &self.payload
+    }
+
+    /// Convert self into its payload
+    pub fn into_payload(self) -> MessagePayload {
+        self.payload
+    }
+
+    /// Encode the message into a buffer, replacing its contents.
+    /// Reusing a buffer for many messages saves allocating a frame for each of them.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) -> usize {
+        buf.clear();
+        buf.reserve(self.encoded_size());
+        self.net_encode(&mut *buf)
+    }
+
+    /// Write the message with vectored writes of the header and the payload, so the payload
+    /// is not copied behind the header into a single frame. The payload is encoded into the
+    /// given buffer, which can be reused for many messages.
+    pub fn write_vectored<W: Write>(&self, w: &mut W, payload: &mut Vec<u8>) -> std::io::Result<usize> {
+        payload.clear();
+        payload.reserve(self.payload.encoded_size());
+        self.payload.net_encode(&mut *payload);
+
+        let header = self.header.to_bytes();
+        let mut bufs = [IoSlice::new(&header), IoSlice::new(payload)];
+        let mut slices = &mut bufs[..];
+        while !slices.is_empty() {
+            match w.write_vectored(slices) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut slices, n),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(header.len() + payload.len())
+    }
+
+    /// Get the typed network message of self.
+    /// Returns an error if the payload does not match the command in the header.
+    pub fn network_message(&self) -> Result<NetworkMessage, Error> {
+        NetworkMessage::from_parts(self.header.command.clone(), self.payload.clone())
+    }
+}
+
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.header, self.payload)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Typed network messages, one variant per command.
+#[allow(clippy::large_enum_variant)]
+pub enum NetworkMessage {
+    Version(VersionMessage),
+    Verack,
+    SendHeaders,
+    WTxIdRelay,
+    Ping(u64),
+    Pong(u64),
+    Addr(Vec<TimestampedNetAddress>),
+    GetAddr,
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    NotFound(Vec<Inventory>),
+    Tx(Transaction),
+    GetBlocks(BlockdataLocatorInfo),
+    GetHeaders(BlockdataLocatorInfo),
+    Block(Block),
+    Headers(Vec<BlockHeader>),
+    MemPool,
+    FilterClear,
+    SendAddrV2,
+    AddrV2(Vec<TimestampedNetAddress>),
+
+    // Messages with a known command whose payload is not decoded into a structure
+    Raw {
+        command: Command,
+        payload: Vec<u8>
+    },
+
+    // Messages with an unknown command and unknown structure payload
+    Unknown {
+        command: String,
+        payload: Vec<u8>
+    }
+}
+
+impl NetworkMessage {
+    /// Return the command associated with self
+    pub fn command(&self) -> Command {
+        match self {
+            Self::Version(_) => Command::Version,
+            Self::Verack => Command::Verack,
+            Self::SendHeaders => Command::SendHeaders,
+            Self::WTxIdRelay => Command::WTxIdRelay,
+            Self::Ping(_) => Command::Ping,
+            Self::Pong(_) => Command::Pong,
+            Self::Addr(_) => Command::Addr,
+            Self::GetAddr => Command::GetAddr,
+            Self::Inv(_) => Command::Inv,
+            Self::GetData(_) => Command::GetData,
+            Self::NotFound(_) => Command::NotFound,
+            Self::Tx(_) => Command::Tx,
+            Self::GetBlocks(_) => Command::GetBlocks,
+            Self::GetHeaders(_) => Command::GetHeaders,
+            Self::Block(_) => Command::Block,
+            Self::Headers(_) => Command::Headers,
+            Self::MemPool => Command::MemPool,
+            Self::FilterClear => Command::FilterClear,
+            Self::SendAddrV2 => Command::SendAddrV2,
+            Self::AddrV2(_) => Command::AddrV2,
+            Self::Raw{command, ..} => command.clone(),
+            Self::Unknown{command, ..} => Command::Unknown(command.clone())
+        }
+    }
+
+    /// Convert self into the payload that is sent over the wire
+    pub fn into_payload(self) -> MessagePayload {
+        match self {
+            Self::Version(v) => MessagePayload::Version(v),
+            Self::Verack |
+            Self::SendHeaders |
+            Self::WTxIdRelay |
+            Self::GetAddr |
+            Self::MemPool |
+            Self::FilterClear |
+            Self::SendAddrV2 => MessagePayload::EmptyPayload,
+            Self::Ping(n) |
+            Self::Pong(n) => MessagePayload::PingPong(n),
+            Self::Addr(a) => MessagePayload::AddrList(a),
+            Self::AddrV2(a) => MessagePayload::AddrV2List(a),
+            Self::Inv(i) |
+            Self::GetData(i) |
+            Self::NotFound(i) => MessagePayload::InvVect(i),
+            Self::Tx(tx) => MessagePayload::Transction(tx),
+            Self::GetBlocks(l) |
+            Self::GetHeaders(l) => MessagePayload::BlockLocator(l),
+            Self::Block(b) => MessagePayload::Block(b),
+            Self::Headers(h) => MessagePayload::Headers(h),
+            Self::Raw{payload, ..} |
+            Self::Unknown{payload, ..} => MessagePayload::Dump(payload)
+        }
+    }
+
+    /// Create self from a command and payload.
+    /// Returns an error if the payload cannot be sent with the command.
+    pub fn from_parts(command: Command, payload: MessagePayload) -> Result<Self, Error> {
+        Ok(match (command, payload) {
+            (Command::Version, MessagePayload::Version(v)) => Self::Version(v),
+            (Command::Verack, MessagePayload::EmptyPayload) => Self::Verack,
+            (Command::SendHeaders, MessagePayload::EmptyPayload) => Self::SendHeaders,
+            (Command::WTxIdRelay, MessagePayload::EmptyPayload) => Self::WTxIdRelay,
+            (Command::Ping, MessagePayload::PingPong(n)) => Self::Ping(n),
+            (Command::Pong, MessagePayload::PingPong(n)) => Self::Pong(n),
+            (Command::Addr, MessagePayload::AddrList(a)) => Self::Addr(a),
+            (Command::GetAddr, MessagePayload::EmptyPayload) => Self::GetAddr,
+            (Command::Inv, MessagePayload::InvVect(i)) => Self::Inv(i),
+            (Command::GetData, MessagePayload::InvVect(i)) => Self::GetData(i),
+            (Command::NotFound, MessagePayload::InvVect(i)) => Self::NotFound(i),
+            (Command::Tx, MessagePayload::Transction(tx)) => Self::Tx(tx),
+            (Command::GetBlocks, MessagePayload::BlockLocator(l)) => Self::GetBlocks(l),
+            (Command::GetHeaders, MessagePayload::BlockLocator(l)) => Self::GetHeaders(l),
+            (Command::Block, MessagePayload::Block(b)) => Self::Block(b),
+            (Command::Headers, MessagePayload::Headers(h)) => Self::Headers(h),
+            (Command::MemPool, MessagePayload::EmptyPayload) => Self::MemPool,
+            (Command::FilterClear, MessagePayload::EmptyPayload) => Self::FilterClear,
+            (Command::SendAddrV2, MessagePayload::EmptyPayload) => Self::SendAddrV2,
+            (Command::AddrV2, MessagePayload::AddrV2List(a)) => Self::AddrV2(a),
+            (Command::Unknown(command), MessagePayload::Dump(payload)) => Self::Unknown { command, payload },
+            (command, MessagePayload::Dump(payload)) if command.has_raw_payload() => Self::Raw { command, payload },
+            _ => return Err(Error::InvalidData)
+        })
+    }
+}
+
+impl std::convert::TryFrom<Message> for NetworkMessage {
+    type Error = Error;
+
+    fn try_from(msg: Message) -> Result<Self, Self::Error> {
+        Self::from_parts(msg.header.command, msg.payload)
+    }
+}
+
+// Conversions with the messages of rust-bitcoin go through the wire encoding, which both
+// crates share, so they fail only when the payload is invalid to the other crate.
+impl std::convert::TryFrom<Message> for bitcoin_message::RawNetworkMessage {
+    type Error = Error;
+
+    fn try_from(msg: Message) -> Result<Self, Self::Error> {
+        let mut buf = Vec::with_capacity(msg.encoded_size());
+        msg.net_encode(&mut buf);
+        Ok(deserialize(&buf)?)
+    }
+}
+
+impl std::convert::TryFrom<bitcoin_message::RawNetworkMessage> for Message {
+    type Error = Error;
+
+    fn try_from(msg: bitcoin_message::RawNetworkMessage) -> Result<Self, Self::Error> {
+        let bytes = match &msg.payload {
+            // rust-bitcoin 0.27 prefixes the payload of unknown messages with its length, so
+            // they are framed here instead
+            bitcoin_message::NetworkMessage::Unknown { command, payload } => {
+                let mut buf = serialize(&msg.magic);
+                buf.extend(serialize(command));
+                buf.extend(serialize(&(payload.len() as u32)));
+                buf.extend(&sha256d(payload)[..4]);
+                buf.extend(payload);
+                buf
+            },
+            _ => serialize(&msg)
+        };
+        Message::net_decode(&bytes[..])
+    }
+}
+
+impl std::convert::TryFrom<NetworkMessage> for bitcoin_message::NetworkMessage {
+    type Error = Error;
+
+    fn try_from(msg: NetworkMessage) -> Result<Self, Self::Error> {
+        // The magic is not part of the payload, any network will do
+        let raw = bitcoin_message::RawNetworkMessage::try_from(Message::from_payload(msg, Magic::Main))?;
+        Ok(raw.payload)
+    }
+}
+
+impl std::convert::TryFrom<bitcoin_message::NetworkMessage> for NetworkMessage {
+    type Error = Error;
+
+    fn try_from(msg: bitcoin_message::NetworkMessage) -> Result<Self, Self::Error> {
+        let raw = bitcoin_message::RawNetworkMessage { magic: Magic::Main.bytes(), payload: msg };
+        Message::try_from(raw)?.network_message()
+    }
+}
+
+impl std::convert::TryFrom<bitcoin_message::NetworkMessage> for MessagePayload {
+    type Error = Error;
+
+    fn try_from(msg: bitcoin_message::NetworkMessage) -> Result<Self, Self::Error> {
+        Ok(NetworkMessage::try_from(msg)?.into_payload())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -71,28 +366,138 @@ pub enum MessagePayload {
     Version(VersionMessage),
     PingPong(u64),
     AddrList(Vec<TimestampedNetAddress>),
+    AddrV2List(Vec<TimestampedNetAddress>),
     InvVect(Vec<Inventory>),
     Transction(Transaction),
     BlockLocator(BlockdataLocatorInfo),
-    Headers(Vec<crate::bitcoin::BlockHeader>),
-    Block(crate::bitcoin::Block),
+    Headers(Vec<BlockHeader>),
+    Block(Block),
     
     // Generic payloads for:
     EmptyPayload,   // Payloads with no data
-    Dump(Vec<u8>)   // Unknown structure payloads
+    Dump(Vec<u8>)   // Unknown or not yet supported structure payloads
 }
 
 impl MessagePayload {
-    /// Get the length of the encoded payload by encoding the
-    /// message and returning the length of the encoded message.
+    /// Get the length of the encoded payload
     pub fn len(&self) -> usize {
+        self.encoded_size()
+    }
+
+    /// Check if the encoded payload is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+impl std::fmt::Display for MessagePayload {
+    /// Lists are displayed with one entry per line
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Version(v) => write!(f, "{}", v),
+            Self::PingPong(nonce) => write!(f, "nonce {}", nonce),
+            Self::AddrList(addrs) |
+            Self::AddrV2List(addrs) => {
+                write!(f, "{} addresses", addrs.len())?;
+                addrs.iter().try_for_each(|a| write!(f, "\n  {}", a))
+            },
+            Self::InvVect(inv) => {
+                write!(f, "{} inventory items", inv.len())?;
+                inv.iter().try_for_each(|i| write!(f, "\n  {}", i))
+            },
+            Self::Transction(tx) => write!(f, "tx {} ({} inputs, {} outputs, {} bytes)", tx.txid(), tx.input.len(), tx.output.len(), tx.get_size()),
+            Self::BlockLocator(loc) => write!(f, "locator with {} hashes, stop {}", loc.hashes.len(), loc.stop),
+            Self::Headers(hdrs) => {
+                write!(f, "{} headers", hdrs.len())?;
+                hdrs.iter().try_for_each(|h| write!(f, "\n  {} time {}", h.hash(), fmt_timestamp(h.time as i64)))
+            },
+            Self::Block(block) => write!(f, "block {} ({} txs, {} bytes)", block.hash(), block.txs.len(), block.encoded_size()),
+            Self::EmptyPayload => write!(f, "empty"),
+            Self::Dump(d) => write!(f, "{} raw bytes", d.len())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Network message structure where the payload is kept as raw bytes and only decoded on demand.
+pub struct RawMessage {
+    pub header: MessageHeader,
+    pub payload_bytes: Vec<u8>
+}
+
+impl RawMessage {
+    /// Decode the payload bytes
+    pub fn decode_payload(&self) -> Result<MessagePayload, Error> {
+        MessagePayload::decode_with_header(&self.header, &self.payload_bytes[..])
+    }
+
+    /// Decode the payload bytes, applying the limits and checks in the config.
+    pub fn decode_payload_with_config(&self, config: &DecodeConfig) -> Result<MessagePayload, Error> {
+        MessagePayload::decode_with_header_config(&self.header, &self.payload_bytes[..], config)
+    }
+
+    /// Convert self into a fully decoded message
+    pub fn decode(self) -> Result<Message, Error> {
+        let payload = self.decode_payload()?;
+        Ok(
+            Message {
+                header: self.header,
+                payload
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Network message structure that borrows large payloads from a caller owned buffer
+/// instead of allocating them. Created through the [`crate::encode::DecodeRef`] trait.
+pub struct MessageRef<'a> {
+    pub header: MessageHeader,
+    pub payload: MessagePayloadRef<'a>
+}
+
+impl<'a> MessageRef<'a> {
+    /// Convert self into an owned message, decoding any borrowed payload.
+    pub fn into_owned(self) -> Result<Message, Error> {
+        Ok(
+            Message {
+                header: self.header,
+                payload: self.payload.into_owned()?
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Message payload where transactions, blocks and unknown payloads are kept as
+/// borrowed raw bytes. Other payloads are small and are decoded as usual.
+#[allow(clippy::large_enum_variant)]
+pub enum MessagePayloadRef<'a> {
+    Transaction(&'a [u8]),
+    Block(&'a [u8]),
+    Dump(&'a [u8]),
+    Owned(MessagePayload)
+}
+
+impl<'a> MessagePayloadRef<'a> {
+    /// Return the raw payload bytes if the payload is borrowed
+    pub fn bytes(&self) -> Option<&'a [u8]> {
         match self {
-            // Payloads with a known fixed size:
-            Self::EmptyPayload => 0,
-            Self::PingPong(_) => 8,
+            Self::Transaction(b) |
+            Self::Block(b) |
+            Self::Dump(b) => Some(b),
+            Self::Owned(_) => None
+        }
+    }
 
-            // Payloads with a variable size:
-            _ => self.net_encode(Vec::new())
+    /// Convert self into an owned payload, decoding any borrowed bytes.
+    pub fn into_owned(self) -> Result<MessagePayload, Error> {
+        match self {
+            Self::Transaction(b) => Ok(MessagePayload::Transction(deserialize(b)?)),
+            Self::Block(b) => Ok(MessagePayload::Block(Decode::net_decode(b)?)),
+            Self::Dump(b) => Ok(MessagePayload::Dump(b.to_vec())),
+            Self::Owned(p) => Ok(p)
         }
     }
 }
@@ -119,4 +524,114 @@ macro_rules! payload_from_struct {
     };
 }
 
-payload_from_struct!(VersionMessage, Version);
\ No newline at end of file
+payload_from_struct!(VersionMessage, Version);
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        bitcoin::network::message::{
+            NetworkMessage as BitcoinMessage,
+            RawNetworkMessage
+        },
+        msg::network::VersionConfig
+    };
+    use std::convert::TryFrom;
+
+    #[test]
+    fn encode_into_buffer() {
+        let mut buf = Vec::new();
+        let inv = Message::from_payload(NetworkMessage::Inv(vec![Inventory::Tx(Default::default()); 10]), Magic::Main);
+        assert_eq!(inv.encode_into(&mut buf), inv.encoded_size());
+        assert_eq!(inv.header().checksum[..], sha256d(&buf[24..])[..4]);
+
+        // The buffer is reused for smaller messages
+        let capacity = buf.capacity();
+        let ping = Message::from_payload(NetworkMessage::Ping(1), Magic::Main);
+        ping.encode_into(&mut buf);
+        assert_eq!(Message::net_decode(&buf[..]).unwrap(), ping);
+        assert_eq!(buf.capacity(), capacity);
+    }
+
+    #[test]
+    fn vectored_write() {
+        // Writer taking at most a few bytes per call
+        struct Slow(Vec<u8>);
+        impl std::io::Write for Slow {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(7);
+                self.0.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut payload = Vec::new();
+        for msg in [NetworkMessage::Ping(3), NetworkMessage::Verack] {
+            let msg = Message::from_payload(msg, Magic::Main);
+            let mut expected = Vec::new();
+            msg.net_encode(&mut expected);
+
+            let mut out = Slow(Vec::new());
+            assert_eq!(msg.write_vectored(&mut out, &mut payload).unwrap(), expected.len());
+            assert_eq!(out.0, expected);
+            assert_eq!(&msg.header().to_bytes()[..], &expected[..MessageHeader::SIZE]);
+        }
+    }
+
+    #[test]
+    fn unknown_message_roundtrip() {
+        // Command with bytes after a null and a non-ascii byte
+        let mut enc = vec![0xF9, 0xBE, 0xB4, 0xD9];
+        enc.extend_from_slice(&[b'f', b'o', b'o', 0x00, b'b', 0xE9, 0, 0, 0, 0, 0, 0]);
+        enc.extend_from_slice(&3u32.to_le_bytes());
+        enc.extend_from_slice(&sha256d([1, 2, 3])[..4]);
+        enc.extend_from_slice(&[1, 2, 3]);
+
+        let dec = Message::net_decode(&enc[..]).expect("Failed to decode");
+        assert_eq!(dec.header().command, Command::Unknown(String::from("foo\0b\u{e9}")));
+        assert_eq!(*dec.payload(), MessagePayload::Dump(vec![1, 2, 3]));
+
+        let mut reenc = Vec::new();
+        assert_eq!(dec.net_encode(&mut reenc), enc.len());
+        assert_eq!(reenc, enc);
+    }
+
+    #[test]
+    fn rust_bitcoin_conversions() {
+        let genesis = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin);
+        let messages = vec![
+            NetworkMessage::Version(VersionConfig::default().message_at(Address::me(), 1_640_995_200, 0x5eed)),
+            NetworkMessage::Verack,
+            NetworkMessage::Ping(7),
+            NetworkMessage::Inv(vec![Inventory::Block(genesis.block_hash())]),
+            NetworkMessage::Headers(vec![genesis.header.into()]),
+            NetworkMessage::Block(genesis.clone().into()),
+            NetworkMessage::Unknown { command: String::from("foo"), payload: vec![1, 2, 3] }
+        ];
+        for msg in messages {
+            let theirs = BitcoinMessage::try_from(msg.clone()).expect("Failed to convert");
+            assert_eq!(NetworkMessage::try_from(theirs).expect("Failed to convert"), msg);
+        }
+
+        // Messages rust-bitcoin decodes come back as raw payloads of the known command
+        let fee = NetworkMessage::try_from(BitcoinMessage::FeeFilter(1000)).expect("Failed to convert");
+        assert_eq!(fee, NetworkMessage::Raw { command: Command::FeeFilter, payload: 1000i64.to_le_bytes().to_vec() });
+        assert_eq!(MessagePayload::try_from(BitcoinMessage::Pong(9)).expect("Failed to convert"), MessagePayload::PingPong(9));
+
+        let msg = Message::from_payload(NetworkMessage::GetAddr, Magic::Testnet);
+        let raw = RawNetworkMessage::try_from(msg.clone()).expect("Failed to convert");
+        assert_eq!(raw.magic, crate::bitcoin::Network::Testnet.magic());
+        assert_eq!(raw.payload, BitcoinMessage::GetAddr);
+        assert_eq!(Message::try_from(raw).expect("Failed to convert"), msg);
+
+        // Payloads rust-bitcoin cannot decode fail to convert
+        let bad = NetworkMessage::Raw { command: Command::FeeFilter, payload: vec![1] };
+        assert!(BitcoinMessage::try_from(bad).is_err());
+    }
+}
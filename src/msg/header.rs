@@ -19,6 +19,9 @@ pub struct MessageHeader {
 }
 
 impl MessageHeader {
+    /// Size of an encoded header in bytes
+    pub const SIZE: usize = 24;
+
     pub fn new(magic: Magic, command: Command, pylen: usize, checksum: [u8; 4]) -> MessageHeader {
         Self {
             magic,
@@ -27,13 +30,24 @@ impl MessageHeader {
             checksum
         }
     }
+
+    /// Encode the header into an array
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0; Self::SIZE];
+        self.net_encode(&mut buf[..]);
+        buf
+    }
 }
 
 /// Network magic enum
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Magic {
     Main,
+
+    // Regression test network
     Test,
+    Testnet,
+    Signet,
     Unknown(u32)
 }
 
@@ -43,30 +57,48 @@ impl Magic {
         match self {
             Magic::Main => 0xD9B4BEF9,
             Magic::Test => 0xDAB5BFFA,
+            Magic::Testnet => 0x0709110B,
+            Magic::Signet => 0x40CF030A,
             Magic::Unknown(v)=> *v
         }
     }
 }
 
+impl std::fmt::Display for MessageHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {} ({} bytes, checksum {})", self.magic, self.command, self.length, self.checksum.iter().map(|x| format!("{:02x}", x)).collect::<String>())
+    }
+}
+
+impl std::fmt::Display for Magic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Magic::Main => write!(f, "main"),
+            Magic::Test => write!(f, "test"),
+            Magic::Testnet => write!(f, "testnet"),
+            Magic::Signet => write!(f, "signet"),
+            Magic::Unknown(v) => write!(f, "unknown {:#010x}", v)
+        }
+    }
+}
+
 impl From<[u8; 4]> for Magic {
     fn from(bytes: [u8; 4]) -> Self {
-        if bytes == Magic::Main.bytes().to_be_bytes() { return Magic::Main }
-        else if bytes == Magic::Test.bytes().to_be_bytes() { return Magic::Test }
-        else { return Magic::Unknown(
-            bytes[0] as u32 >> 24 |
-            bytes[1] as u32 >> 16 |
-            bytes[2] as u32 >> 8 |
-            bytes[3] as u32
-        ) }
+        if bytes == Magic::Main.bytes().to_be_bytes() { Magic::Main }
+        else if bytes == Magic::Test.bytes().to_be_bytes() { Magic::Test }
+        else if bytes == Magic::Testnet.bytes().to_be_bytes() { Magic::Testnet }
+        else if bytes == Magic::Signet.bytes().to_be_bytes() { Magic::Signet }
+        else { Magic::Unknown(u32::from_be_bytes(bytes)) }
     }
 }
 
 
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 #[allow(dead_code)]
 /// Network command enum
 //  Adding a new command requires:
+//     - An entry in the command table with its name
 //     - A new entry in the payload enum (Or reuse the same payload for a different command)
 //     - Associated match statements modified to support the new command/payload.
 pub enum Command {
@@ -86,57 +118,154 @@ pub enum Command {
     GetHeaders,
     Block,
     Headers,
-    //More to come...
+    MemPool,
+    MerkleBlock,
+    CmpctBlock,
+    SendCmpct,
+    GetBlockTxn,
+    BlockTxn,
+    FeeFilter,
+    FilterLoad,
+    FilterAdd,
+    FilterClear,
+    GetCFilters,
+    CFilter,
+    GetCFHeaders,
+    CFHeaders,
+    GetCFCheckpt,
+    CFCheckpt,
+    SendAddrV2,
+    AddrV2,
+    Reject,
+    Alert,
 
-    // Command enum option for unknonwn/invalid command strings
+    // Command enum option for unknonwn/invalid command strings.
+    // Each character represents a single byte of the command, up to 12 bytes.
+    // Names from outside the wire should be checked with `Command::unknown`.
     Unknown(String)
     
 }
 
+/// Build the table of known commands, so the name of each command is only written once
+macro_rules! commands {
+    ($($command:ident => $name:literal),*) => {
+        /// Known commands with their name and null padded encoding, so that they are encoded
+        /// and decoded without building strings
+        pub(crate) static COMMANDS: [(Command, &str, [u8; 12]); 36] = [
+            $((Command::$command, $name, pad($name.as_bytes()))),*
+        ];
+    }
+}
+
+commands! {
+    Version => "version",
+    Verack => "verack",
+    SendHeaders => "sendheaders",
+    WTxIdRelay => "wtxidrelay",
+    Ping => "ping",
+    Pong => "pong",
+    Addr => "addr",
+    GetAddr => "getaddr",
+    Inv => "inv",
+    GetData => "getdata",
+    NotFound => "notfound",
+    Tx => "tx",
+    GetBlocks => "getblocks",
+    GetHeaders => "getheaders",
+    Block => "block",
+    Headers => "headers",
+    MemPool => "mempool",
+    MerkleBlock => "merkleblock",
+    CmpctBlock => "cmpctblock",
+    SendCmpct => "sendcmpct",
+    GetBlockTxn => "getblocktxn",
+    BlockTxn => "blocktxn",
+    FeeFilter => "feefilter",
+    FilterLoad => "filterload",
+    FilterAdd => "filteradd",
+    FilterClear => "filterclear",
+    GetCFilters => "getcfilters",
+    CFilter => "cfilter",
+    GetCFHeaders => "getcfheaders",
+    CFHeaders => "cfheaders",
+    GetCFCheckpt => "getcfcheckpt",
+    CFCheckpt => "cfcheckpt",
+    SendAddrV2 => "sendaddrv2",
+    AddrV2 => "addrv2",
+    Reject => "reject",
+    Alert => "alert"
+}
+
+/// Pad a command string with nulls to 12 bytes
+const fn pad(cmd: &[u8]) -> [u8; 12] {
+    let mut buf = [0; 12];
+    let mut i = 0;
+    while i < cmd.len() {
+        buf[i] = cmd[i];
+        i += 1;
+    }
+    buf
+}
+
 impl Command {
     pub fn to_str(&self) -> &str {
         match self {
-            Self::Version => "version",
-            Self::Verack =>  "verack",
-            Self::SendHeaders => "sendheaders",
-            Self::WTxIdRelay => "wtxidrelay",
-            Self::Ping => "ping",
-            Self::Pong => "pong",
-            Self::Addr => "addr",
-            Self::GetAddr => "getaddr",
-            Self::Inv => "inv",
-            Self::GetData => "getdata",
-            Self::NotFound => "notfound",
-            Self::Tx => "tx",
-            Self::GetBlocks => "getblocks",
-            Self::GetHeaders => "getheaders",
-            Self::Block => "block",
-            Self::Headers => "headers",
-            Self::Unknown(s) => &s
+            Self::Unknown(s) => s,
+            known => COMMANDS
+                .iter()
+                .find(|(c, _, _)| c == known)
+                .map(|(_, name, _)| *name)
+                .expect("Known commands are in the command table")
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(cmd: String) -> Result<Self, Error> {
-        match &cmd[..] {
-            "version" => Ok(Self::Version),
-            "verack" => Ok(Self::Verack),
-            "sendheaders" => Ok(Self::SendHeaders),
-            "wtxidrelay" => Ok(Self::WTxIdRelay),
-            "ping" => Ok(Self::Ping),
-            "pong" => Ok(Self::Pong),
-            "addr" => Ok(Self::Addr),
-            "getaddr" => Ok(Self::GetAddr),
-            "inv" => Ok(Self::Inv),
-            "getdata" => Ok(Self::GetData),
-            "notfound" => Ok(Self::NotFound),
-            "tx" => Ok(Self::Tx),
-            "getblocks" => Ok(Self::GetBlocks),
-            "getheaders" => Ok(Self::GetHeaders),
-            "block" => Ok(Self::Block),
-            "headers" => Ok(Self::Headers),
-            _ => Err(Error::UnknownCommand(cmd))
+        match COMMANDS.iter().find(|(_, name, _)| *name == cmd) {
+            Some((command, _, _)) => Ok(command.clone()),
+            None => Err(Error::UnknownCommand(cmd))
         }
     }
+
+    /// Build the command of a name that is not a known command.
+    /// Returns an error if the name is longer than 12 characters or has characters above
+    /// U+00FF, since each character is sent as a single byte.
+    pub fn unknown(name: String) -> Result<Self, Error> {
+        if name.chars().count() > 12 || name.chars().any(|c| c as u32 > 0xFF) {
+            return Err(Error::InvalidData)
+        }
+        Ok(Self::Unknown(name))
+    }
+
+    /// Check if the payload of messages with this command is kept as raw bytes.
+    /// This is true for unknown commands and known commands without a structured payload yet.
+    pub fn has_raw_payload(&self) -> bool {
+        matches!(self,
+            Self::MerkleBlock |
+            Self::CmpctBlock |
+            Self::SendCmpct |
+            Self::GetBlockTxn |
+            Self::BlockTxn |
+            Self::FeeFilter |
+            Self::FilterLoad |
+            Self::FilterAdd |
+            Self::GetCFilters |
+            Self::CFilter |
+            Self::GetCFHeaders |
+            Self::CFHeaders |
+            Self::GetCFCheckpt |
+            Self::CFCheckpt |
+            Self::Reject |
+            Self::Alert |
+            Self::Unknown(_)
+        )
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_str())
+    }
 }
 
 
@@ -159,11 +288,74 @@ pub fn sha256d<T: AsRef<[u8]>>(data: T) -> [u8; 32] {
 
 impl<T: Encode> Checksum for T {
     fn checksum(&self) -> [u8; 4] {
+        // The payload is encoded straight into the hasher rather than into a buffer
+        let mut w = HashWriter::new();
+        self.net_encode(&mut w);
+        w.checksum()
+    }
+}
+
+/// Writer counting and hashing the bytes written to it, so that a single encoding of a
+/// payload gives both its length and its checksum
+#[derive(Clone, Default)]
+pub struct HashWriter {
+    hasher: Sha256,
+    len: usize
+}
+
+impl HashWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of bytes written
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// First four bytes of the sha256d of the bytes written
+    pub fn checksum(self) -> [u8; 4] {
         let mut ret: [u8; 4] = [0; 4];
+        ret.copy_from_slice(&Sha256::digest(self.hasher.finalize())[..4]);
+        ret
+    }
+}
+
+impl std::io::Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hasher.update(buf);
+        self.len += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::data::{
+        Message,
+        NetworkMessage
+    };
+
+    #[test]
+    fn hash_writer() {
+        let msg = Message::from_payload(NetworkMessage::Ping(5), Magic::Main);
         let mut payload = Vec::new();
-        self.net_encode(&mut payload);
+        msg.payload().net_encode(&mut payload);
 
-        ret.copy_from_slice(&sha256d(payload)[..4]);
-        ret
+        let mut w = HashWriter::new();
+        msg.payload().net_encode(&mut w);
+        assert_eq!((w.len(), msg.header().length), (payload.len(), 8));
+        assert_eq!(w.checksum()[..], sha256d(&payload)[..4]);
+        assert_eq!(msg.header().checksum, msg.payload().checksum());
     }
-}
\ No newline at end of file
+}
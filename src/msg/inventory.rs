@@ -11,8 +11,16 @@ pub use crate::bitcoin::{
     },
     hashes::Hash
 };
+use crate::blockdata::HexHash;
 
-use crate::msg::VariableInteger;
+/// Maximum number of entries in an `inv`, `getdata` or `notfound` message, as enforced by Bitcoin Core
+pub const MAX_INV_SIZE: usize = 50_000;
+
+/// Maximum number of hashes in the locator of a `getblocks` or `getheaders` message, as enforced by Bitcoin Core
+pub const MAX_LOCATOR_SIZE: usize = 101;
+
+/// Size of an encoded entry: a four byte type followed by a hash
+pub const INVENTORY_SIZE: usize = 36;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Inventory {
@@ -71,6 +79,17 @@ impl Inventory {
         
     }
 
+    /// Check if self refers to a transaction.
+    /// Witness entries also refer to transactions by txid, they only ask for the witnesses
+    /// to be included.
+    pub fn matches(&self, tx: &crate::blockdata::Tx) -> bool {
+        match self {
+            Self::Tx(txid) |
+            Self::WitnessTx(txid) => *txid == tx.txid(),
+            _ => false
+        }
+    }
+
     /// Return the inner hash stored in Self.
     /// Returns [0; 32] for error variant.
     pub fn inner(&self) -> [u8; 32] {
@@ -102,7 +121,7 @@ impl std::fmt::Display for Inventory {
             Self::Unknown{inv_type: _, hash: _} => "Unknown"
         };
 
-        write!(f, "INV: [{}] {}", obj_type, self.inner().iter().rev().map(|x| format!("{:02x}", x)).collect::<String>())
+        write!(f, "INV: [{}] {}", obj_type, self.inner().to_be_hex())
     }
 }
 
@@ -113,13 +132,13 @@ pub struct BlockdataLocatorInfo {
     // Protocol version
     pub version: u32,
     // Locator hashes, newest back to the genesis block
-    pub hashes: Vec<crate::blockdata::BlockHash>,
+    pub hashes: Vec<BlockHash>,
     // Hash of the last desired block. Set to zero for maximum
-    pub stop: crate::blockdata::BlockHash
+    pub stop: BlockHash
 }
 
 impl BlockdataLocatorInfo {
-    pub fn new(version: u32, hashes: Vec<crate::blockdata::BlockHash>, stop: crate::blockdata::BlockHash) -> Self {
+    pub fn new(version: u32, hashes: Vec<BlockHash>, stop: BlockHash) -> Self {
         Self {
             version,
             hashes,
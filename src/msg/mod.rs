@@ -7,6 +7,12 @@ pub mod data;
 pub mod header;
 pub mod network;
 pub mod inventory;
+pub mod builder;
+pub mod stream;
+#[cfg(test)]
+mod vectors;
+
+use crate::encode::Error;
 
 // Variable length integer structure
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -32,4 +38,90 @@ varint_from!(u8);
 varint_from!(u16);
 varint_from!(u32);
 varint_from!(u64);
-varint_from!(usize);
\ No newline at end of file
+varint_from!(usize);
+
+/// Maximum length of a user agent string, as enforced by Bitcoin Core
+pub const MAX_USER_AGENT_LENGTH: usize = 256;
+
+// Variable length string structure with a maximum length in bytes.
+// Decoding a string longer than `MAX` fails before the string is allocated.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct VarString<const MAX: usize>(String);
+
+/// Variable length string used for user agents in version messages
+pub type UserAgent = VarString<MAX_USER_AGENT_LENGTH>;
+
+impl<const MAX: usize> VarString<MAX> {
+    /// Create a new var string, returning an error if it is longer than the maximum length.
+    pub fn new(s: String) -> Result<Self, Error> {
+        if s.len() > MAX { return Err(Error::InvalidData) }
+        Ok(Self(s))
+    }
+
+    /// Create a var string from bytes, returning an error if they are not valid UTF-8 or
+    /// are longer than the maximum length.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, Error> {
+        Self::new(String::from_utf8(bytes).map_err(|_| Error::InvalidUtf8)?)
+    }
+
+    /// Create a var string from bytes, replacing invalid UTF-8 sequences. Since replacements
+    /// can be longer than the bytes they replace, the string is cut at the maximum length.
+    pub fn from_bytes_lossy(bytes: Vec<u8>) -> Self {
+        let mut s = match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned()
+        };
+        if s.len() > MAX {
+            let end = (0..=MAX).rev().find(|i| s.is_char_boundary(*i)).unwrap_or(0);
+            s.truncate(end);
+        }
+        Self(s)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl<const MAX: usize> std::fmt::Display for VarString<MAX> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+
+/// Format a unix timestamp as a UTC date and time.
+//  Civil date conversion from http://howardhinnant.github.io/date_algorithms.html
+pub(crate) fn fmt_timestamp(secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let day = doy - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era*400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp_format() {
+        assert_eq!(fmt_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(fmt_timestamp(1231006505), "2009-01-03 18:15:05 UTC");
+        assert_eq!(fmt_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+    }
+}
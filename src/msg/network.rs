@@ -6,13 +6,20 @@
 
 use crate::{
     encode::Error,
-    address::Address
+    address::Address,
+    msg::{
+        UserAgent,
+        fmt_timestamp
+    }
 };
-use std::collections::HashSet;
-use std::time::{
-    SystemTime,
-    Duration
+use btcnetmsg_derive::{
+    Encode,
+    Decode
 };
+use std::time::Duration;
+#[cfg(feature = "net")]
+use std::time::SystemTime;
+#[cfg(feature = "net")]
 use rand::Rng;
 
 
@@ -26,17 +33,19 @@ pub enum Service {
     Bloom,
     Witness,
     CompactFilters,
-    NetworkLimited
+    NetworkLimited,
+    P2pV2
 }
 
 // Constant array containing the right shift amount for each service flag.
-pub const SERVICE_BITS: [usize; 6] = [
-    0, // Network
-    1, // GetUTXO
-    2, // Bloom
-    3, // Witness
-    6, // CompactFilters
-    10 // NetworkLimited
+pub const SERVICE_BITS: [usize; 7] = [
+    0,  // Network
+    1,  // GetUTXO
+    2,  // Bloom
+    3,  // Witness
+    6,  // CompactFilters
+    10, // NetworkLimited
+    11  // P2pV2
 ];
 
 impl Service {
@@ -44,12 +53,13 @@ impl Service {
         match self {
             // Each service is a bit flag
             Self::None => 0,                              // No service available
-            Self::Network =>        (1<<SERVICE_BITS[0]), // Full chain history available
-            Self::GetUTXO =>        (1<<SERVICE_BITS[1]), // Can be queried for UTXOs
-            Self::Bloom =>          (1<<SERVICE_BITS[2]), // Capable of handling bloom filtered connections
-            Self::Witness =>        (1<<SERVICE_BITS[3]), // Witness data available
-            Self::CompactFilters => (1<<SERVICE_BITS[4]), // Can serve basic block filte requests
-            Self::NetworkLimited => (1<<SERVICE_BITS[5])  // Can serve blocks from the last 2 days
+            Self::Network =>        1<<SERVICE_BITS[0],  // Full chain history available
+            Self::GetUTXO =>        1<<SERVICE_BITS[1],  // Can be queried for UTXOs
+            Self::Bloom =>          1<<SERVICE_BITS[2],  // Capable of handling bloom filtered connections
+            Self::Witness =>        1<<SERVICE_BITS[3],  // Witness data available
+            Self::CompactFilters => 1<<SERVICE_BITS[4],  // Can serve basic block filte requests
+            Self::NetworkLimited => 1<<SERVICE_BITS[5],  // Can serve blocks from the last 2 days
+            Self::P2pV2 =>          1<<SERVICE_BITS[6]   // Supports the BIP324 v2 transport
         }
     }
 
@@ -62,63 +72,124 @@ impl Service {
             8 => Ok(Self::Witness),
             64 => Ok(Self::CompactFilters),
             1024 => Ok(Self::NetworkLimited),
+            2048 => Ok(Self::P2pV2),
+            _ => Err(Error::InvalidData)
+        }
+    }
+
+    /// Return the human readable name of the service flag, as used by Bitcoin Core.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "NONE",
+            Self::Network => "NETWORK",
+            Self::GetUTXO => "GETUTXO",
+            Self::Bloom => "BLOOM",
+            Self::Witness => "WITNESS",
+            Self::CompactFilters => "COMPACT_FILTERS",
+            Self::NetworkLimited => "NETWORK_LIMITED",
+            Self::P2pV2 => "P2P_V2"
+        }
+    }
+
+    pub fn try_from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "NONE" => Ok(Self::None),
+            "NETWORK" => Ok(Self::Network),
+            "GETUTXO" => Ok(Self::GetUTXO),
+            "BLOOM" => Ok(Self::Bloom),
+            "WITNESS" => Ok(Self::Witness),
+            "COMPACT_FILTERS" => Ok(Self::CompactFilters),
+            "NETWORK_LIMITED" => Ok(Self::NetworkLimited),
+            "P2P_V2" => Ok(Self::P2pV2),
             _ => Err(Error::InvalidData)
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-/// A list of service flags in a hash set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// Service flags of a node, kept as the bit field sent on the wire.
+/// Bits of services unknown to this crate are kept so they are encoded back unchanged.
 /// DOES NOT ENFORCE CONFLICTING FLAGS
-pub struct ServicesList(std::collections::HashSet<Service>);
+pub struct ServicesList(u64);
 
 impl ServicesList {
     pub fn new() -> Self {
-        ServicesList(HashSet::new())
+        ServicesList(0)
+    }
+
+    /// Create a list of services from a bit field
+    pub fn from_bits(bits: u64) -> Self {
+        ServicesList(bits)
+    }
+
+    /// Get the service flags as a bit field, including unknown bits
+    pub fn bits(&self) -> u64 {
+        self.0
     }
 
     pub fn add_flag(&mut self, flag: Service) {
-        self.0.insert(flag);
+        self.0 |= flag.value();
     }
 
+    /// Get the known service flags that are set, ordered by their bit
     pub fn get_flags(&self) -> Vec<Service> {
-        self.0.iter().map(|flag| *flag).collect()
+        SERVICE_BITS
+            .iter()
+            .filter(|bit| self.0 & 1 << *bit != 0)
+            .map(|bit| Service::try_from_bit(1 << bit).expect("Known service bit"))
+            .collect()
+    }
+
+    /// Get the names of the set flags ordered by their bit, as used by Bitcoin Core.
+    /// Unknown bits are named `UNKNOWN[2^bit]`.
+    pub fn names(&self) -> Vec<String> {
+        (0..64)
+            .filter(|bit| self.0 & 1 << bit != 0)
+            .map(|bit| match Service::try_from_bit(1 << bit) {
+                Ok(flag) => flag.name().to_string(),
+                Err(_) => format!("UNKNOWN[2^{}]", bit)
+            })
+            .collect()
     }
 }
 
-impl Default for ServicesList {
-    fn default() -> Self {
-        let mut flags = Self::new();
-        flags.add_flag(Service::None);
-        flags
+impl std::fmt::Display for ServicesList {
+    /// Services are displayed by name, ordered by their bit
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self.names();
+        if names.is_empty() { return write!(f, "{}", Service::None.name()) }
+        write!(f, "{}", names.join(" | "))
     }
 }
 
-#[derive(Debug, Clone, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
 /// The message payload for version commands.
+//  Field types follow the signed integers used by Bitcoin Core on the wire.
 pub struct VersionMessage {
-    pub version: u32,
+    pub version: i32,
     pub service: ServicesList,
-    pub timestamp: Duration,
+    pub timestamp: i64,
     pub addr_recv: NetAddress,
     pub addr_from: NetAddress,
     pub nonce: u64,
-    pub agent: String,
-    pub start_height: u32,
-    pub relay: bool
+    pub agent: UserAgent,
+    pub start_height: i32,
+    // Not sent by peers older than protocol version 70001
+    pub relay: Option<bool>
 }
 
 impl VersionMessage {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        version: u32,
+        version: i32,
         service: ServicesList,
-        timestamp: Duration,
+        timestamp: i64,
         addr_recv: NetAddress,
         addr_from: NetAddress,
         nonce: u64,
-        agent: String,
-        start_height: u32,
-        relay: bool
+        agent: UserAgent,
+        start_height: i32,
+        relay: Option<bool>
     ) -> VersionMessage {
         Self {
             version,
@@ -134,45 +205,88 @@ impl VersionMessage {
     }
 }
 
+/// Fields of the version messages sent to peers, controlling how the node presents itself
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionConfig {
+    pub version: i32,
+    pub services: ServicesList,
+    pub agent: UserAgent,
+    pub start_height: i32,
+    pub relay: Option<bool>
+}
+
+impl Default for VersionConfig {
+    fn default() -> Self {
+        Self {
+            version: 70015,
+            services: ServicesList::default(),
+            agent: UserAgent::new(String::from("bit-tune-v0.0.1")).expect("User agent too long"),
+            start_height: 0,
+            // Ask peers to announce transactions, as Bitcoin Core does. Block relay only
+            // connections turn this off.
+            relay: Some(true)
+        }
+    }
+}
+
+impl VersionConfig {
+    /// Create the version message to send to a peer, with the current time and a random nonce
+    #[cfg(feature = "net")]
+    pub fn message(&self, address: Address) -> VersionMessage {
+        self.message_at(
+            address,
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Failed to get time").as_secs() as i64,
+            rand::thread_rng().gen_range(0..u64::MAX)
+        )
+    }
+
+    /// Create the version message to send to a peer, with the given unix time and nonce
+    pub fn message_at(&self, address: Address, timestamp: i64, nonce: u64) -> VersionMessage {
+        VersionMessage::new(
+            self.version,
+            self.services.clone(),
+            timestamp,
+            NetAddress::new(ServicesList::default(), address),
+            NetAddress::default(),
+            nonce,
+            self.agent.clone(),
+            self.start_height,
+            self.relay
+        )
+    }
+}
+
+#[cfg(feature = "net")]
 impl From<Address> for VersionMessage {
     /// Create a default VersionMessage struct from a peer with:
-    /// * Protocol version 70016 (line 12: https://github.com/bitcoin/bitcoin/blob/master/src/version.h)
+    /// * Protocol version 70015
     /// * No service flags
     /// * Current time at fuction evoke
     /// * Default net address structs
     /// * Random nonce capped at u64 ceiling
     /// * Agent "bit-tune-v0.0.1"
-    /// * Relay flag set to false
+    /// * Relay flag set to true
     fn from(address: Address) -> Self {
-        VersionMessage::new(
-            70015, 
-            ServicesList::default(), 
-            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Failed to get time"), 
-            NetAddress::new(ServicesList::default(), address),
-            NetAddress::default(),
-            rand::thread_rng().gen_range(0..u64::MAX), 
-            String::from("bit-tune-v0.0.1"), 
-            0u32,
-            false // Setting this option to true will get the other node to broadcast transaction regardless of bloom filter status
-        )
+        VersionConfig::default().message(address)
     }
 }
 
-impl PartialEq for VersionMessage {
-    fn eq(&self, other: &Self) -> bool { 
-        self.version == other.version &&
-        self.service == other.service &&
-        self.timestamp.as_secs() == other.timestamp.as_secs() && // Need to call as_secs() or else the code will be comparing floating points
-        self.addr_from == other.addr_from &&
-        self.addr_recv == other.addr_recv &&
-        self.nonce == other.nonce &&
-        self.agent == other.agent &&
-        self.start_height == other.start_height &&
-        self.relay == other.relay
+impl std::fmt::Display for VersionMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "version {} agent \"{}\" height {} services [{}] relay {} time {} from {} to {}",
+            self.version,
+            self.agent,
+            self.start_height,
+            self.service,
+            self.relay.map_or(String::from("unset"), |r| r.to_string()),
+            fmt_timestamp(self.timestamp),
+            self.addr_from,
+            self.addr_recv
+        )
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
 /// Data structure to pass around network addresses and related meta data in the bitcoin network
 pub struct NetAddress {
     pub services: ServicesList,
@@ -188,6 +302,12 @@ impl NetAddress {
     }
 }
 
+impl std::fmt::Display for NetAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} [{}]", self.address, self.services)
+    }
+}
+
 impl Default for NetAddress {
     fn default() -> Self {
         Self {
@@ -213,6 +333,12 @@ impl TimestampedNetAddress {
     }
 }
 
+impl std::fmt::Display for TimestampedNetAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} seen {}", self.netaddress, fmt_timestamp(self.timestamp.as_secs() as i64))
+    }
+}
+
 impl From<TimestampedNetAddress> for NetAddress {
     fn from(tsna: TimestampedNetAddress) -> Self {
         tsna.netaddress
@@ -0,0 +1,157 @@
+// stream.rs
+//
+// Iterator adapter to decode a stream of network messages from a reader.
+//
+
+#[cfg(feature = "net")]
+use std::time::Instant;
+use std::io::Read;
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::MessageHeader
+    },
+    encode::{
+        Decode,
+        DecodeConfig,
+        Error
+    }
+};
+
+/// Iterator over the messages read from a reader.
+///
+/// Errors in a single message (malformed payloads, bad checksums, oversized payloads) are
+/// returned and the stream moves on to the next message. Errors that leave the stream out
+/// of sync (IO errors, bad network magic) are returned once and end the iteration.
+pub struct MessageStream<R: Read> {
+    reader: R,
+    config: DecodeConfig,
+    done: bool
+}
+
+impl<R: Read> MessageStream<R> {
+    pub fn new(reader: R, config: DecodeConfig) -> Self {
+        Self {
+            reader,
+            config,
+            done: false
+        }
+    }
+
+    /// Return the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Read the 24 byte header of the next message.
+    /// Returns `None` if the reader ended cleanly before the header.
+    fn read_header(&mut self) -> Option<Result<[u8; 24], Error>> {
+        let mut buf: [u8; 24] = [0; 24];
+        let mut read = 0;
+        while read < buf.len() {
+            match self.reader.read(&mut buf[read..]) {
+                Ok(0) if read == 0 => return None,
+                Ok(0) => return Some(Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()))),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(Error::Io(e)))
+            }
+        }
+        Some(Ok(buf))
+    }
+}
+
+impl<R: Read> Iterator for MessageStream<R> {
+    type Item = Result<Message, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done { return None }
+
+        let header = match self.read_header() {
+            Some(Ok(h)) => h,
+            Some(Err(e)) => {
+                self.done = true;
+                return Some(Err(e))
+            },
+            None => {
+                self.done = true;
+                return None
+            }
+        };
+
+        let header = match MessageHeader::net_decode(&header[..]) {
+            Ok(h) => h,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e))
+            }
+        };
+
+        // Time from the header to the decoded payload, which shows peers stalling mid message.
+        // Targets without the `net` feature may not have a clock.
+        #[cfg(feature = "net")]
+        let start = Instant::now();
+        let result = MessagePayload::decode_with_header_config(&header, &mut self.reader, &self.config);
+        #[cfg(feature = "net")]
+        tracing::trace!(
+            command = header.command.to_str(),
+            length = header.length,
+            elapsed_us = start.elapsed().as_micros() as u64,
+            ok = result.is_ok(),
+            "decoded payload"
+        );
+        match &result {
+            // The payload of an oversized message was not read, skip over it
+            Err(Error::PayloadTooLarge(_)) if header.skip_payload(&mut self.reader).is_err() => self.done = true,
+            Err(Error::Io(_)) => self.done = true,
+            _ => {}
+        }
+
+        Some(result.map(|payload| Message::from_header(header, payload)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encode::Encode,
+        msg::{
+            data::NetworkMessage,
+            header::Magic
+        }
+    };
+
+    #[test]
+    fn stream_recovers_from_bad_message() {
+        let mut enc = Vec::new();
+        Message::from_payload(NetworkMessage::Ping(1), Magic::Main).net_encode(&mut enc);
+        let mut bad = Vec::new();
+        Message::from_payload(NetworkMessage::Ping(2), Magic::Main).net_encode(&mut bad);
+        bad[20] ^= 0xFF; // Corrupt checksum
+        enc.extend_from_slice(&bad);
+        Message::from_payload(NetworkMessage::Verack, Magic::Main).net_encode(&mut enc);
+
+        let results: Vec<Result<Message, Error>> = MessageStream::new(&enc[..], DecodeConfig::default()).collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().network_message().unwrap(), NetworkMessage::Ping(1));
+        assert!(matches!(results[1], Err(Error::BadChecksum { .. })));
+        assert_eq!(results[2].as_ref().unwrap().network_message().unwrap(), NetworkMessage::Verack);
+    }
+
+    #[test]
+    fn stream_ends_on_truncated_message() {
+        let mut enc = Vec::new();
+        Message::from_payload(NetworkMessage::Ping(1), Magic::Main).net_encode(&mut enc);
+        enc.extend_from_slice(&[0xF9, 0xBE]);
+
+        let mut stream = MessageStream::new(&enc[..], DecodeConfig::default());
+        assert!(stream.next().unwrap().is_ok());
+        assert!(matches!(stream.next(), Some(Err(Error::Io(_)))));
+        assert!(stream.next().is_none());
+    }
+}
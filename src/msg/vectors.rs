@@ -0,0 +1,292 @@
+// msg/vectors.rs
+//
+// Corpus of real message frames, decoded and encoded back byte for byte.
+//
+// The `version`, `verack`, `ping` and `alert` frames were captured by Wireshark from a
+// Bitcoin Core 0.17.1 mainnet node, and are also used by the tests of rust-bitcoin. The
+// `addr`, `inv`, `headers` and `cmpctblock` frames are built from mainnet data: the headers
+// of blocks 1 and 2, block 00000000b0c5a240b2a61d2e75692224efd4cbecdf6eaf4cc2cf477ca7c270e7
+// and its transactions, and the address of the peer of the captured `version` message. Their
+// checksums and the short id of the compact block were computed by a script independent of
+// this crate, so they do not just repeat the encoder.
+//
+// The `addr` and `addrv2` payloads and the BIP155 addresses are the test vectors of Bitcoin
+// Core (src/test/netbase_tests.cpp and src/test/net_tests.cpp), framed by the tests.
+//
+// A frame only passes if it decodes into the expected typed message and if both the decoded
+// message and the message rebuilt from the typed payload encode to the same bytes. Swapped
+// fields or a wrong byte order in any of them shows up as a mismatch.
+//
+
+use crate::{
+    bitcoin::hashes::hex::{
+        FromHex,
+        ToHex
+    },
+    blockdata::{
+        compact::CompactBlock,
+        Tx
+    },
+    encode::{
+        Decode,
+        Encode
+    },
+    msg::{
+        data::{
+            Message,
+            MessagePayload,
+            NetworkMessage
+        },
+        header::{
+            Command,
+            Magic
+        },
+        inventory::Inventory,
+        network::{
+            Service,
+            TimestampedNetAddress,
+            VersionMessage
+        }
+    }
+};
+
+const VERSION: &str = "f9beb4d976657273696f6e000000000066000000be61b8277f1101000d04000000000000f00f4d5c00000000000000000000000000000000000000000000ffff5bf08c80b4bd0d04000000000000000000000000000000000000000000000000faa99559cc68a1c1102f5361746f7368693a302e31372e312f938c080001";
+const VERACK: &str = "f9beb4d976657261636b000000000000000000005df6e0e2";
+const PING: &str = "f9beb4d970696e670000000000000000080000002467f11d6400000000000000";
+const ALERT: &str = "f9beb4d9616c65727400000000000000a80000001bf9aaea60010000000000000000000000ffffff7f00000000ffffff7ffeffff7f01ffffff7f00000000ffffff7f00ffffff7f002f555247454e543a20416c657274206b657920636f6d70726f6d697365642c2075706772616465207265717569726564004630440220653febd6410f470f6bae11cad19c48413becb1ac2c17f908fd0fd53bdc3abd5202206d0e9c96fe88d4a0f01ed9dedae2b6f9e00da94cad0fecaae66ecf689bf71b50";
+
+// Version payload of a Bitcoin Core 0.9.99 node, without the frame
+const VERSION_PAYLOAD: &str = "721101000100000000000000e6e0845300000000010000000000000000000000000000000000ffff0000000000000100000000000000fd87d87eeb4364f22cf54dca59412db7208d47d920cffce83ee8102f5361746f7368693a302e392e39392f2c9f040001";
+
+const ADDR: &str = "f9beb4d96164647200000000000000003d00000000b2107302f00f4d5c0d0400000000000000000000000000000000ffff5bf08c80208df00f4d5c090400000000000020010db8000000000000000000000001479d";
+const INV: &str = "f9beb4d9696e7600000000000000000049000000f876a4740202000000e770c2a77c47cfc24caf6edfeccbd4ef242269752e1da6b240a2c5b00000000001000000339d9a371e2b5a26147ddfd87228b900ff75762a18a40f2778bedbcde7e9b0a3";
+const HEADERS: &str = "f9beb4d9686561646572730000000000a300000044b0ec7802010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e3629900010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd6100";
+const CMPCTBLOCK: &str = "f9beb4d9636d706374626c6f636b0000e800000086f5e8a6010000004ddccd549d28f385ab457e98d1b11ce80bfea2c5ab93015ade4973e400000000bf4473e53794beae34e64fccc471dace6ae544180816f89591894e0f417a914cd74d6e49ffff001d323b3a7b7001ed5e0000000001b89449799bf3010001000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0804ffff001d026e04ffffffff0100f2052a0100000043410446ef0102d1ec5240f0d061a4246c1bdef63fc3dbab7733052fbbf0ecd8f41fc26bf049ebb4f9527f374280259e7cfa99c48b0e3f39c51347a19a5819651503a5ac00000000";
+
+// `addr` payload of Bitcoin Core's netbase tests, with services NONE, NETWORK and
+// WITNESS | COMPACT_FILTERS | NETWORK_LIMITED
+const CORE_ADDR_PAYLOAD: &str = concat!(
+    "03",
+    "61bc6649", "0000000000000000", "00000000000000000000000000000001", "0000",
+    "79627683", "0100000000000000", "00000000000000000000000000000001", "00f1",
+    "ffffffff", "4804000000000000", "00000000000000000000000000000001", "f1f2"
+);
+
+// The same addresses in the `addrv2` payload of Bitcoin Core's netbase tests
+const CORE_ADDRV2_PAYLOAD: &str = concat!(
+    "03",
+    "61bc6649", "00", "02", "10", "00000000000000000000000000000001", "0000",
+    "79627683", "01", "02", "10", "00000000000000000000000000000001", "00f1",
+    "ffffffff", "fd4804", "02", "10", "00000000000000000000000000000001", "f1f2"
+);
+
+// Valid BIP155 addresses of Bitcoin Core's net tests: network id, length and address
+const CORE_BIP155: [(&str, &str); 5] = [
+    ("010401020304", "1.2.3.4:0"),
+    ("02100102030405060708090a0b0c0d0e0f10", "[102:304:506:708:90a:b0c:d0e:f10]:0"),
+    ("042079bcc625184b05194975c28b66b66b0469f7f6556fb1ac3189a79b40dda32f1f", "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion:0"),
+    ("0520a2894dabaec08c0051a481a6dac88b64f98232ae42d4b6fd2fa81952dfe36a87", "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:0"),
+    ("0610fc000001000200030004000500060007", "cjdns:[fc00:1:2:3:4:5:6:7]:0")
+];
+
+// Frame a payload given in hex, returning the frame in hex
+fn frame(command: Command, payload: &str) -> String {
+    let payload = Vec::<u8>::from_hex(payload).unwrap();
+    let mut enc = Vec::new();
+    Message::new(MessagePayload::Dump(payload), Magic::Main, command).net_encode(&mut enc);
+    enc.to_hex()
+}
+
+// Decode a frame, check that it encodes back to the same bytes both as decoded and when
+// rebuilt from its typed payload, and return the typed payload.
+fn roundtrip(hex: &str) -> NetworkMessage {
+    let bytes = Vec::<u8>::from_hex(hex).unwrap();
+    let msg = Message::net_decode(&bytes[..]).unwrap();
+    assert_eq!(msg.header().magic, Magic::Main);
+    assert_eq!(msg.header().length as usize, bytes.len() - 24);
+
+    let mut enc = Vec::new();
+    assert_eq!(msg.net_encode(&mut enc), bytes.len());
+    assert_eq!(msg.encoded_size(), bytes.len());
+    assert_eq!(enc, bytes, "{} frame", msg.header().command.to_str());
+
+    let typed = msg.network_message().unwrap();
+    let mut enc = Vec::new();
+    Message::from_payload(typed.clone(), Magic::Main).net_encode(&mut enc);
+    assert_eq!(enc, bytes, "rebuilt {} frame", msg.header().command.to_str());
+
+    // Every truncation is rejected
+    for len in 0..bytes.len() {
+        assert!(Message::net_decode(&bytes[..len]).is_err());
+    }
+    typed
+}
+
+#[test]
+fn version() {
+    let version = match roundtrip(VERSION) {
+        NetworkMessage::Version(v) => v,
+        msg => panic!("{:?}", msg)
+    };
+    assert_eq!(version.version, 70015);
+    assert_eq!(version.service.bits(), 0x40d);
+    assert_eq!(version.timestamp, 1548554224);
+    assert_eq!(version.addr_recv.address.to_string(), "91.240.140.128:46269");
+    assert_eq!(version.addr_from.address.to_string(), "[::]:0");
+    assert_eq!(version.nonce, 13952548347456104954);
+    assert_eq!(version.agent.as_str(), "/Satoshi:0.17.1/");
+    assert_eq!(version.start_height, 560275);
+    assert_eq!(version.relay, Some(true));
+
+    let bytes = Vec::<u8>::from_hex(VERSION_PAYLOAD).unwrap();
+    let version = VersionMessage::net_decode(&bytes[..]).unwrap();
+    assert_eq!(version.agent.as_str(), "/Satoshi:0.9.99/");
+    assert_eq!(version.start_height, 302892);
+    assert_eq!(version.addr_recv.address.to_string(), "0.0.0.0:0");
+    let mut enc = Vec::new();
+    version.net_encode(&mut enc);
+    assert_eq!(enc, bytes);
+}
+
+#[test]
+fn unknown_service_bits() {
+    // The captured version frame with NETWORK | WITNESS | NETWORK_LIMITED | P2P_V2 and an
+    // unassigned bit set, as sent by current nodes
+    let mut payload = Vec::<u8>::from_hex(VERSION).unwrap().split_off(24);
+    payload[4..12].copy_from_slice(&0x0001_0c09u64.to_le_bytes());
+    let version = match roundtrip(&frame(Command::Version, &payload.to_hex())) {
+        NetworkMessage::Version(v) => v,
+        msg => panic!("{:?}", msg)
+    };
+    assert_eq!(version.service.bits(), 0x0001_0c09);
+    assert_eq!(version.service.get_flags(), vec![Service::Network, Service::Witness, Service::NetworkLimited, Service::P2pV2]);
+    assert_eq!(version.service.to_string(), "NETWORK | WITNESS | NETWORK_LIMITED | P2P_V2 | UNKNOWN[2^16]");
+}
+
+#[test]
+fn control() {
+    assert_eq!(roundtrip(VERACK), NetworkMessage::Verack);
+    assert_eq!(roundtrip(PING), NetworkMessage::Ping(100));
+    match roundtrip(ALERT) {
+        NetworkMessage::Raw { command: Command::Alert, payload } => assert_eq!(payload.len(), 168),
+        msg => panic!("{:?}", msg)
+    }
+}
+
+#[test]
+fn addr() {
+    let addrs = match roundtrip(ADDR) {
+        NetworkMessage::Addr(addrs) => addrs,
+        msg => panic!("{:?}", msg)
+    };
+    let addrs = addrs.iter()
+        .map(|a| (a.timestamp.as_secs(), a.netaddress.services.bits(), a.netaddress.address.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(addrs, vec![
+        (1548554224, 0x40d, "91.240.140.128:8333".to_string()),
+        (1548554224, 0x409, "[2001:db8::1]:18333".to_string())
+    ]);
+}
+
+#[test]
+fn core_addr() {
+    let entries = |addrs: Vec<TimestampedNetAddress>| addrs.iter()
+        .map(|a| (a.timestamp.as_secs(), a.netaddress.services.bits(), a.netaddress.address.to_string()))
+        .collect::<Vec<_>>();
+    let expected = vec![
+        (0x4966bc61, 0, "[::1]:0".to_string()),
+        (0x83766279, 0x001, "[::1]:241".to_string()),
+        (0xffffffff, 0x448, "[::1]:61938".to_string())
+    ];
+
+    match roundtrip(&frame(Command::Addr, CORE_ADDR_PAYLOAD)) {
+        NetworkMessage::Addr(addrs) => assert_eq!(entries(addrs), expected),
+        msg => panic!("{:?}", msg)
+    }
+    match roundtrip(&frame(Command::AddrV2, CORE_ADDRV2_PAYLOAD)) {
+        NetworkMessage::AddrV2(addrs) => assert_eq!(entries(addrs), expected),
+        msg => panic!("{:?}", msg)
+    }
+}
+
+#[test]
+fn core_bip155() {
+    // Each address in an addrv2 entry with no time, services or port
+    let entry = |address: &str| format!("00000000{}{}{}", "00", address, "0000");
+
+    let payload = format!("{:02x}{}", CORE_BIP155.len(), CORE_BIP155.iter().map(|(a, _)| entry(a)).collect::<String>());
+    match roundtrip(&frame(Command::AddrV2, &payload)) {
+        NetworkMessage::AddrV2(addrs) => {
+            let addrs = addrs.iter().map(|a| a.netaddress.address.to_string()).collect::<Vec<_>>();
+            assert_eq!(addrs, CORE_BIP155.iter().map(|(_, s)| s.to_string()).collect::<Vec<_>>());
+        },
+        msg => panic!("{:?}", msg)
+    }
+
+    // Addresses of unknown networks are skipped, and lengths over 512 bytes are rejected
+    let payload = format!("02{}{}", entry("aa0401020304"), entry("aa00"));
+    let msg = Message::net_decode(&Vec::<u8>::from_hex(&frame(Command::AddrV2, &payload)).unwrap()[..]).unwrap();
+    assert_eq!(msg.network_message().unwrap(), NetworkMessage::AddrV2(vec![]));
+    for address in &["01fd010201020304", "aafe0000000201020304"] {
+        let bytes = Vec::<u8>::from_hex(&frame(Command::AddrV2, &format!("01{}", entry(address)))).unwrap();
+        assert!(Message::net_decode(&bytes[..]).is_err());
+    }
+}
+
+#[test]
+fn inv() {
+    let inv = match roundtrip(INV) {
+        NetworkMessage::Inv(inv) => inv,
+        msg => panic!("{:?}", msg)
+    };
+    assert_eq!(inv.len(), 2);
+    match (&inv[0], &inv[1]) {
+        (Inventory::Block(block), Inventory::Tx(txid)) => {
+            assert_eq!(block.to_string(), "00000000b0c5a240b2a61d2e75692224efd4cbecdf6eaf4cc2cf477ca7c270e7");
+            assert_eq!(txid.to_string(), "a3b0e9e7cddbbe78270fa4182a7675ff00b92872d8df7d14265a2b1e379a9d33");
+        },
+        _ => panic!("{:?}", inv)
+    }
+}
+
+#[test]
+fn headers() {
+    let headers = match roundtrip(HEADERS) {
+        NetworkMessage::Headers(headers) => headers,
+        msg => panic!("{:?}", msg)
+    };
+    assert_eq!(headers[0].hash().to_string(), "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048");
+    assert_eq!(headers[1].hash().to_string(), "000000006a625f06636b8bb6ac7b960a8d03705d1ace08b1a19da3fdcc99ddbd");
+    assert_eq!(headers[0].prev_blockhash, crate::blockdata::BlockHeader::genesis().hash());
+    assert_eq!(headers[1].prev_blockhash, headers[0].hash());
+    assert_eq!(headers[1].time, 1231469744);
+    assert!(headers.iter().all(|h| h.check_pow()));
+}
+
+#[test]
+fn cmpctblock() {
+    let payload = match roundtrip(CMPCTBLOCK) {
+        NetworkMessage::Raw { command: Command::CmpctBlock, payload } => payload,
+        msg => panic!("{:?}", msg)
+    };
+    let cmpct = CompactBlock::net_decode(&payload[..]).unwrap();
+    let mut enc = Vec::new();
+    assert_eq!(cmpct.net_encode(&mut enc), payload.len());
+    assert_eq!(enc, payload);
+
+    assert_eq!(cmpct.header.hash().to_string(), "00000000b0c5a240b2a61d2e75692224efd4cbecdf6eaf4cc2cf477ca7c270e7");
+    assert_eq!(cmpct.nonce, 0x5eed0170);
+    assert_eq!(cmpct.tx_count(), 2);
+    assert_eq!(cmpct.prefilled()[0].index, 0);
+    assert_eq!(cmpct.prefilled()[0].tx.txid().to_string(), "77dfc2fe598419b00641c296181a96cf16943697f573480b023b77cce82ada21");
+
+    // The short id matches the one computed outside of the crate for the second transaction
+    let tx = crate::bitcoin::consensus::deserialize(&Vec::<u8>::from_hex("010000000321f75f3139a013f50f315b23b0c9a2b6eac31e2bec98e5891c924664889942260000000049483045022100cb2c6b346a978ab8c61b18b5e9397755cbd17d6eb2fe0083ef32e067fa6c785a02206ce44e613f31d9a6b0517e46f3db1576e9812cc98d159bfdaf759a5014081b5c01ffffffff79cda0945903627c3da1f85fc95d0b8ee3e76ae0cfdc9a65d09744b1f8fc85430000000049483045022047957cdd957cfd0becd642f6b84d82f49b6cb4c51a91f49246908af7c3cfdf4a022100e96b46621f1bffcf5ea5982f88cef651e9354f5791602369bf5a82a6cd61a62501fffffffffe09f5fe3ffbf5ee97a54eb5e5069e9da6b4856ee86fc52938c2f979b0f38e82000000004847304402204165be9a4cbab8049e1af9723b96199bfd3e85f44c6b4c0177e3962686b26073022028f638da23fc003760861ad481ead4099312c60030d4cb57820ce4d33812a5ce01ffffffff01009d966b01000000434104ea1feff861b51fe3f5f8a3b12d0f4712db80e919548a80839fc47c6a21e66d957e9c5d8cd108c7a2d2324bad71f9904ac0ae7336507d785b17a2c115e427a32fac00000000").unwrap()).unwrap();
+    let tx = Tx::new(tx);
+    assert_eq!(cmpct.short_ids[0].0, [0xb8, 0x94, 0x49, 0x79, 0x9b, 0xf3]);
+    assert_eq!(cmpct.short_id(&tx, 1), cmpct.short_ids[0]);
+
+    let partial = cmpct.reconstruct(std::iter::once(&tx), 1).unwrap();
+    assert!(partial.is_complete());
+    assert!(partial.block().unwrap().check_merkle_root());
+}
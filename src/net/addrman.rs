@@ -0,0 +1,486 @@
+// addrman.rs
+//
+// Address manager for peers learnt through address gossip.
+//
+// Loosely follows the design of the address manager in bitcoin core:
+//    - Addresses which have never been connected to are kept in the "new" table.
+//    - Addresses which a connection was successfully made to are moved to the "tried" table.
+//    - Both tables are split into buckets. The bucket of an address is picked from a keyed
+//      hash of its netgroup (and the netgroup of the peer that sent it for the new table)
+//      so that a single peer or network range cannot fill up the tables.
+//
+
+use crate::{
+    address::{
+        Address,
+        AddressNetwork
+    },
+    msg::network::{
+        NetAddress,
+        TimestampedNetAddress
+    },
+    net::Error
+};
+use rand::{
+    Rng,
+    seq::IteratorRandom
+};
+use sha2::{
+    Sha256,
+    Digest
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::Path,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH
+    }
+};
+
+/// Number of buckets in the new table
+pub const NEW_BUCKETS: usize = 1024;
+
+/// Number of buckets in the tried table
+pub const TRIED_BUCKETS: usize = 256;
+
+/// Maximum number of addresses in a single bucket
+pub const BUCKET_SIZE: usize = 64;
+
+/// Addresses not seen for this long are not handed out
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Addresses that failed this many times without ever succeeding are not handed out
+const MAX_RETRIES: u32 = 3;
+
+/// Get the current time as a duration since the unix epoch
+pub(crate) fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards")
+}
+
+/// Get the netgroup of an IP address.
+/// IPv4 addresses are grouped by /16 and IPv6 addresses are grouped by /32.
+pub fn netgroup(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mut group = vec![4];
+            group.extend_from_slice(&ip.octets()[..2]);
+            group
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ip) if ip.octets()[..3] != [0, 0, 0] => netgroup(&IpAddr::V4(ip)),
+            _ => {
+                let mut group = vec![6];
+                group.extend_from_slice(&ip.octets()[..4]);
+                group
+            }
+        }
+    }
+}
+
+/// Get the group of an address used for bucketing.
+/// IP addresses are grouped by netgroup, other networks by the first 4 bits of their address.
+pub fn address_group(addr: &Address) -> Vec<u8> {
+    match &addr.network {
+        AddressNetwork::Ipv4(ip) => netgroup(&IpAddr::V4(*ip)),
+        AddressNetwork::Ipv6(ip) => netgroup(&IpAddr::V6(*ip)),
+        AddressNetwork::TorV3(key) |
+        AddressNetwork::I2p(key) => vec![addr.network.id(), key[0] >> 4],
+        AddressNetwork::Cjdns(ip) => vec![addr.network.id(), ip.octets()[1] >> 4]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Information kept about an address
+pub struct AddrInfo {
+    /// The address and the services it advertised
+    pub addr: NetAddress,
+
+    /// Time the address was last advertised as seen
+    pub timestamp: Duration,
+
+    /// IP of the peer that sent the address
+    pub source: IpAddr,
+
+    /// Time of the last connection attempt
+    pub last_attempt: Option<Duration>,
+
+    /// Time of the last successful connection
+    pub last_success: Option<Duration>,
+
+    /// Number of failed attempts since the last success
+    pub attempts: u32,
+
+    /// Whether the address is in the tried table
+    pub tried: bool
+}
+
+impl AddrInfo {
+    /// Check if the address is not worth handing out for a new connection
+    pub fn is_terrible(&self, now: Duration) -> bool {
+        // Addresses with timestamps far in the future are not trusted
+        if self.timestamp > now + Duration::from_secs(10 * 60) { return true }
+        if now.saturating_sub(self.timestamp) > MAX_AGE && self.last_success.is_none() { return true }
+        self.last_success.is_none() && self.attempts >= MAX_RETRIES
+    }
+}
+
+/// Address manager with new and tried tables
+#[derive(Clone, Debug)]
+pub struct AddrMan {
+    key: [u8; 32],
+    addrs: HashMap<Address, AddrInfo>,
+    new: Vec<Vec<Address>>,
+    tried: Vec<Vec<Address>>
+}
+
+impl Default for AddrMan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AddrMan {
+    /// Create an empty address manager with a random bucketing key
+    pub fn new() -> Self {
+        Self::with_key(rand::random())
+    }
+
+    /// Create an empty address manager with the given bucketing key
+    pub fn with_key(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            addrs: HashMap::new(),
+            new: vec![Vec::new(); NEW_BUCKETS],
+            tried: vec![Vec::new(); TRIED_BUCKETS]
+        }
+    }
+
+    /// Total number of known addresses
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    /// Check if there are no known addresses
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    /// Number of addresses in the new table
+    pub fn len_new(&self) -> usize {
+        self.new.iter().map(|b| b.len()).sum()
+    }
+
+    /// Number of addresses in the tried table
+    pub fn len_tried(&self) -> usize {
+        self.tried.iter().map(|b| b.len()).sum()
+    }
+
+    /// Get the information for an address
+    pub fn get(&self, addr: &Address) -> Option<&AddrInfo> {
+        self.addrs.get(addr)
+    }
+
+    /// Iterate over all known addresses
+    pub fn iter(&self) -> impl Iterator<Item = &AddrInfo> {
+        self.addrs.values()
+    }
+
+    /// Get the key used to pick buckets
+    pub fn key(&self) -> [u8; 32] {
+        self.key
+    }
+
+    /// Load an address manager from a file written by [`AddrMan::save`]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Self::from_json(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Save the known addresses to a file.
+    /// The file is written next to the destination first so a crash never leaves a partial file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.to_json())?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Insert a previously saved address back into its table
+    pub(crate) fn restore(&mut self, info: AddrInfo) {
+        let key = info.addr.address;
+        if self.addrs.contains_key(&key) { return }
+
+        let tried = info.tried;
+        self.addrs.insert(key, AddrInfo { tried: false, ..info });
+        match tried {
+            true => self.promote(&key),
+            false => {
+                let source = self.addrs[&key].source;
+                let bucket = self.new_bucket(&key, &source);
+                if self.new[bucket].len() >= BUCKET_SIZE {
+                    self.evict_new(bucket);
+                }
+                self.new[bucket].push(key);
+            }
+        }
+    }
+
+    fn bucket(&self, parts: &[&[u8]], buckets: usize) -> usize {
+        let mut hasher = Sha256::new();
+        hasher.update(self.key);
+        parts.iter().for_each(|p| hasher.update(p));
+        let hash = hasher.finalize();
+
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&hash[..8]);
+        (u64::from_le_bytes(bytes) % buckets as u64) as usize
+    }
+
+    fn new_bucket(&self, addr: &Address, source: &IpAddr) -> usize {
+        self.bucket(&[b"new", &address_group(addr), &netgroup(source)], NEW_BUCKETS)
+    }
+
+    fn tried_bucket(&self, addr: &Address) -> usize {
+        self.bucket(&[b"tried", &address_group(addr)], TRIED_BUCKETS)
+    }
+
+    /// Add addresses received from a peer to the new table.
+    /// Returns the number of addresses that were not known before.
+    pub fn add(&mut self, addrs: &[TimestampedNetAddress], source: IpAddr) -> usize {
+        addrs.iter().filter(|a| self.add_one(a, source)).count()
+    }
+
+    fn add_one(&mut self, addr: &TimestampedNetAddress, source: IpAddr) -> bool {
+        let key = addr.netaddress.address;
+        if key.ip().is_some_and(|ip| ip.is_unspecified()) || key.port() == 0 { return false }
+
+        // Known addresses only get their timestamp and services refreshed
+        if let Some(info) = self.addrs.get_mut(&key) {
+            if addr.timestamp > info.timestamp {
+                info.timestamp = addr.timestamp;
+            }
+            info.addr.services = addr.netaddress.services.clone();
+            return false
+        }
+
+        let info = AddrInfo {
+            addr: addr.netaddress.clone(),
+            timestamp: addr.timestamp,
+            source,
+            last_attempt: None,
+            last_success: None,
+            attempts: 0,
+            tried: false
+        };
+        let bucket = self.new_bucket(&key, &source);
+        if self.new[bucket].len() >= BUCKET_SIZE {
+            self.evict_new(bucket);
+        }
+        self.new[bucket].push(key);
+        self.addrs.insert(key, info);
+        true
+    }
+
+    /// Remove the least valuable address from a full new bucket
+    fn evict_new(&mut self, bucket: usize) {
+        let now = now();
+        let addrs = &self.addrs;
+        let worst = self.new[bucket]
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, a)| {
+                let info = &addrs[*a];
+                (!info.is_terrible(now), info.timestamp)
+            })
+            .map(|(i, _)| i);
+
+        if let Some(i) = worst {
+            let addr = self.new[bucket].swap_remove(i);
+            self.addrs.remove(&addr);
+        }
+    }
+
+    /// Record a connection attempt to an address
+    pub fn attempt(&mut self, addr: &Address) {
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.last_attempt = Some(now());
+            info.attempts += 1;
+        }
+    }
+
+    /// Record a successful connection to an address, moving it to the tried table
+    pub fn good(&mut self, addr: &Address) {
+        let now = now();
+        match self.addrs.get_mut(addr) {
+            Some(info) => {
+                info.last_success = Some(now);
+                info.last_attempt = Some(now);
+                info.timestamp = now;
+                info.attempts = 0;
+                if info.tried { return }
+            },
+            None => return
+        };
+
+        let source = self.addrs[addr].source;
+        let bucket = self.new_bucket(addr, &source);
+        self.new[bucket].retain(|a| a != addr);
+        self.promote(addr);
+    }
+
+    /// Put a known address in the tried table.
+    /// A full tried bucket sends the oldest address in it back to the new table.
+    fn promote(&mut self, addr: &Address) {
+        let bucket = self.tried_bucket(addr);
+        if self.tried[bucket].len() >= BUCKET_SIZE {
+            let addrs = &self.addrs;
+            let (i, _) = self.tried[bucket]
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, a)| addrs[*a].last_success)
+                .expect("Bucket is full");
+            let evicted = self.tried[bucket].swap_remove(i);
+            self.demote(evicted);
+        }
+        self.tried[bucket].push(*addr);
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.tried = true
+        }
+    }
+
+    fn demote(&mut self, addr: Address) {
+        let source = match self.addrs.get_mut(&addr) {
+            Some(info) => {
+                info.tried = false;
+                info.source
+            },
+            None => return
+        };
+        let bucket = self.new_bucket(&addr, &source);
+        if self.new[bucket].len() >= BUCKET_SIZE {
+            self.evict_new(bucket);
+        }
+        self.new[bucket].push(addr);
+    }
+
+    /// Remove an address from the tables
+    pub fn remove(&mut self, addr: &Address) -> Option<AddrInfo> {
+        let info = self.addrs.remove(addr)?;
+        match info.tried {
+            true => { let b = self.tried_bucket(addr); self.tried[b].retain(|a| a != addr) },
+            false => { let b = self.new_bucket(addr, &info.source); self.new[b].retain(|a| a != addr) }
+        }
+        Some(info)
+    }
+
+    /// Select a random address to connect to.
+    /// Tried and new addresses are picked with equal chance when both tables have entries.
+    /// If `new_only` is set then only addresses from the new table are selected.
+    pub fn select(&self, new_only: bool) -> Option<NetAddress> {
+        let now = now();
+        let mut rng = rand::thread_rng();
+
+        let use_tried = !new_only && self.len_tried() > 0 && (self.len_new() == 0 || rng.gen_bool(0.5));
+        let table = match use_tried {
+            true => &self.tried,
+            false => &self.new
+        };
+
+        table.iter()
+            .flatten()
+            .map(|a| &self.addrs[a])
+            .filter(|info| !info.is_terrible(now))
+            .choose(&mut rng)
+            .map(|info| info.addr.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::network::ServicesList;
+    use std::net::{
+        Ipv4Addr,
+        SocketAddr
+    };
+
+    fn addr(a: u8, b: u8, c: u8) -> TimestampedNetAddress {
+        TimestampedNetAddress::new(
+            now(),
+            NetAddress::new(ServicesList::default(), SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, 1)), 8333).into())
+        )
+    }
+
+    #[test]
+    fn add_and_promote() {
+        let mut am = AddrMan::with_key([7; 32]);
+        let source = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+        let addrs = vec![addr(20, 0, 0), addr(30, 0, 0), addr(40, 0, 0)];
+
+        assert_eq!(am.add(&addrs, source), 3);
+        assert_eq!(am.add(&addrs, source), 0);
+        assert_eq!((am.len_new(), am.len_tried()), (3, 0));
+
+        let target = addrs[1].netaddress.address;
+        am.attempt(&target);
+        assert_eq!(am.get(&target).unwrap().attempts, 1);
+        am.good(&target);
+        assert_eq!((am.len_new(), am.len_tried()), (2, 1));
+        assert!(am.get(&target).unwrap().tried);
+        assert!(am.select(false).is_some());
+        assert_ne!(am.select(true).unwrap().address, target);
+
+        assert!(am.remove(&target).is_some());
+        assert_eq!(am.len(), 2);
+    }
+
+    #[test]
+    fn netgroup_buckets() {
+        // A single netgroup from a single source fills at most one bucket
+        let mut am = AddrMan::with_key([1; 32]);
+        let source = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+        let addrs = (0..=255).map(|c| addr(50, 50, c)).collect::<Vec<_>>();
+        am.add(&addrs, source);
+        assert_eq!(am.len_new(), BUCKET_SIZE);
+        assert_eq!(am.len(), BUCKET_SIZE);
+
+        assert_eq!(netgroup(&"50.50.1.1".parse().unwrap()), netgroup(&"50.50.200.9".parse().unwrap()));
+        assert_ne!(netgroup(&"50.50.1.1".parse().unwrap()), netgroup(&"50.51.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut am = AddrMan::new();
+        let source = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+        am.add(&[addr(20, 0, 0), addr(30, 0, 0)], source);
+        am.good(&addr(30, 0, 0).netaddress.address);
+
+        let path = std::env::temp_dir().join(format!("btcnetmsg-peers-{}.json", rand::random::<u32>()));
+        am.save(&path).unwrap();
+        let loaded = AddrMan::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.key(), am.key());
+        assert_eq!((loaded.len_new(), loaded.len_tried()), (1, 1));
+        let target = addr(30, 0, 0).netaddress.address;
+        let (a, b) = (loaded.get(&target).unwrap(), am.get(&target).unwrap());
+        assert_eq!(a.addr, b.addr);
+        assert_eq!(a.last_success.map(|t| t.as_secs()), b.last_success.map(|t| t.as_secs()));
+        assert!(a.tried);
+    }
+
+    #[test]
+    fn terrible_addresses() {
+        let mut am = AddrMan::with_key([2; 32]);
+        let a = addr(60, 0, 0);
+        am.add(std::slice::from_ref(&a), IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)));
+        for _ in 0..MAX_RETRIES {
+            am.attempt(&a.netaddress.address);
+        }
+        assert!(am.select(false).is_none());
+    }
+}
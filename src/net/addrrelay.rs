@@ -0,0 +1,221 @@
+// addrrelay.rs
+//
+// Relay of gossiped addresses to other peers.
+//
+// Addresses that were recently announced are forwarded to a few random peers after a
+// random delay, which makes it harder to tell where an address was first announced.
+// Addresses seen within the rolling window are not relayed again, and a peer is never
+// sent an address it sent or was already sent.
+//
+
+use crate::{
+    address::Address,
+    msg::network::TimestampedNetAddress,
+    net::{
+        addrman::now,
+        eventloop::PeerId
+    }
+};
+use rand::{
+    seq::SliceRandom,
+    Rng
+};
+use std::{
+    collections::{
+        HashMap,
+        HashSet
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Addresses older than this when received are not relayed
+pub const MAX_RELAY_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Addresses are only relayed from messages with at most this many entries, as larger
+/// messages are answers to `getaddr` rather than announcements
+pub const MAX_RELAY_ENTRIES: usize = 10;
+
+/// Maximum number of addresses remembered per peer
+const MAX_PEER_KNOWN: usize = 5000;
+
+/// Settings of the address relay
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RelayConfig {
+    /// Number of peers each address is relayed to
+    pub fanout: usize,
+
+    /// Longest random delay before an address is relayed
+    pub max_delay: Duration,
+
+    /// Time an address is remembered for after it was seen
+    pub window: Duration
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            fanout: 2,
+            max_delay: Duration::from_secs(30),
+            window: Duration::from_secs(24 * 60 * 60)
+        }
+    }
+}
+
+/// Address relay state for a set of peers
+#[derive(Clone, Debug)]
+pub struct AddrRelay {
+    config: RelayConfig,
+    seen: HashMap<Address, Instant>,
+    peer_known: HashMap<PeerId, HashSet<Address>>,
+    pending: Vec<(Instant, PeerId, TimestampedNetAddress)>
+}
+
+impl AddrRelay {
+    pub fn new(config: RelayConfig) -> Self {
+        Self {
+            config,
+            seen: HashMap::new(),
+            peer_known: HashMap::new(),
+            pending: Vec::new()
+        }
+    }
+
+    /// Check if an address was seen within the rolling window
+    pub fn is_seen(&self, addr: &Address) -> bool {
+        matches!(self.seen.get(addr), Some(at) if at.elapsed() < self.config.window)
+    }
+
+    /// Number of addresses waiting to be relayed
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Handle addresses received from a peer, scheduling the fresh ones to be relayed to
+    /// some of the other peers.
+    pub fn on_addr(&mut self, from: PeerId, addrs: &[TimestampedNetAddress], peers: &[PeerId]) {
+        self.expire();
+        for addr in addrs {
+            self.know(from, addr.netaddress.address);
+        }
+        if addrs.len() > MAX_RELAY_ENTRIES { return }
+
+        let now_unix = now();
+        let mut rng = rand::thread_rng();
+        for addr in addrs {
+            let key = addr.netaddress.address;
+            if addr.timestamp + MAX_RELAY_AGE < now_unix || self.is_seen(&key) { continue }
+            self.seen.insert(key, Instant::now());
+
+            let candidates = peers
+                .iter()
+                .filter(|p| **p != from && !self.knows(**p, &key))
+                .copied()
+                .collect::<Vec<PeerId>>();
+            for peer in candidates.choose_multiple(&mut rng, self.config.fanout) {
+                let delay = match self.config.max_delay.as_millis() as u64 {
+                    0 => Duration::from_millis(0),
+                    max => Duration::from_millis(rng.gen_range(0..max))
+                };
+                self.pending.push((Instant::now() + delay, *peer, addr.clone()));
+            }
+        }
+    }
+
+    /// Take the addresses that are due to be relayed, grouped by the peer to send them to
+    pub fn due(&mut self) -> Vec<(PeerId, Vec<TimestampedNetAddress>)> {
+        let now = Instant::now();
+        let (due, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(at, _, _)| *at <= now);
+        self.pending = pending;
+
+        let mut by_peer: HashMap<PeerId, Vec<TimestampedNetAddress>> = HashMap::new();
+        for (_, peer, addr) in due {
+            // The peer may have sent the address itself in the meantime
+            if !self.peer_known.contains_key(&peer) || self.knows(peer, &addr.netaddress.address) { continue }
+            self.know(peer, addr.netaddress.address);
+            by_peer.entry(peer).or_default().push(addr);
+        }
+
+        let mut out = by_peer.into_iter().collect::<Vec<(PeerId, Vec<TimestampedNetAddress>)>>();
+        out.sort_by_key(|(peer, _)| *peer);
+        out
+    }
+
+    /// Start tracking a connected peer
+    pub fn add_peer(&mut self, peer: PeerId) {
+        self.peer_known.entry(peer).or_default();
+    }
+
+    /// Forget a disconnected peer and the addresses waiting to be relayed to it
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.peer_known.remove(&peer);
+        self.pending.retain(|(_, p, _)| *p != peer);
+    }
+
+    fn knows(&self, peer: PeerId, addr: &Address) -> bool {
+        matches!(self.peer_known.get(&peer), Some(known) if known.contains(addr))
+    }
+
+    fn know(&mut self, peer: PeerId, addr: Address) {
+        if let Some(known) = self.peer_known.get_mut(&peer) {
+            if known.len() >= MAX_PEER_KNOWN {
+                known.clear();
+            }
+            known.insert(addr);
+        }
+    }
+
+    fn expire(&mut self) {
+        let window = self.config.window;
+        self.seen.retain(|_, at| at.elapsed() < window);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::network::NetAddress;
+
+    fn gossip(addr: &str, age: Duration) -> TimestampedNetAddress {
+        TimestampedNetAddress::new(now() - age, NetAddress::new(Default::default(), addr.parse().unwrap()))
+    }
+
+    #[test]
+    fn relay_without_echo() {
+        let mut relay = AddrRelay::new(RelayConfig { max_delay: Duration::from_millis(0), ..RelayConfig::default() });
+        let peers = (0..4).map(PeerId).collect::<Vec<PeerId>>();
+        for p in &peers {
+            relay.add_peer(*p);
+        }
+
+        let fresh = gossip("20.1.2.3:8333", Duration::from_secs(60));
+        let stale = gossip("20.1.2.4:8333", Duration::from_secs(60 * 60));
+        relay.on_addr(PeerId(0), &[fresh.clone(), stale], &peers);
+
+        let due = relay.due();
+        assert_eq!(due.len(), 2);
+        assert!(due.iter().all(|(p, addrs)| *p != PeerId(0) && addrs == &vec![fresh.clone()]));
+
+        // Seen addresses are not relayed again, not even when they come from another peer
+        relay.on_addr(PeerId(3), &[fresh], &peers);
+        assert!(relay.due().is_empty());
+    }
+
+    #[test]
+    fn getaddr_answers_not_relayed() {
+        let mut relay = AddrRelay::new(RelayConfig::default());
+        let peers = (0..3).map(PeerId).collect::<Vec<PeerId>>();
+        for p in &peers {
+            relay.add_peer(*p);
+        }
+
+        let addrs = (0..20).map(|i| gossip(&format!("20.1.2.{}:8333", i), Duration::from_secs(0))).collect::<Vec<_>>();
+        relay.on_addr(PeerId(0), &addrs, &peers);
+        assert_eq!(relay.pending(), 0);
+    }
+}
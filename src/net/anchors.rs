@@ -0,0 +1,56 @@
+// anchors.rs
+//
+// Anchor peers kept across restarts.
+//
+// On shutdown the most recently used block-relay peers are written to the anchors file,
+// and on startup they are connected to before any other peer. An attacker filling the
+// address manager while the node is down then still cannot pick all of its peers.
+//
+
+use crate::{
+    address::Address,
+    net::Error
+};
+use std::path::Path;
+
+/// Number of anchor peers kept
+pub const MAX_ANCHORS: usize = 2;
+
+/// Peers to connect to first after a restart
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Anchors(pub Vec<Address>);
+
+impl Anchors {
+    /// Load the anchors from a file written by [`Anchors::save`] and delete the file, so a
+    /// node that keeps failing on one of them does not reuse them forever.
+    pub fn take<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let anchors = Self::from_json(&std::fs::read_to_string(&path)?)?;
+        std::fs::remove_file(path)?;
+        Ok(anchors)
+    }
+
+    /// Save the anchors to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.to_json())?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_removes_file() {
+        let path = std::env::temp_dir().join(format!("btcnetmsg-anchors-{}.json", rand::random::<u32>()));
+        let anchors = Anchors(vec!["20.1.2.3:8333".parse().unwrap(), "[2001:db8::1]:8333".parse().unwrap()]);
+        anchors.save(&path).unwrap();
+
+        assert_eq!(Anchors::take(&path).unwrap(), anchors);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,278 @@
+// async.rs
+//
+// Async peer connections built on tokio.
+//
+// Enabled with the `async` feature. Each connection is a pair of halves so that
+// reading messages from a peer and sending messages to it can happen on different tasks.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload,
+            NetworkMessage
+        },
+        header::{
+            MessageHeader,
+            Magic
+        },
+        network::VersionMessage
+    },
+    encode::{
+        Decode,
+        DecodeConfig
+    },
+    net::{
+        handshake,
+        Error
+    }
+};
+use std::{
+    net::SocketAddr,
+    time::{
+        Duration,
+        Instant
+    }
+};
+use tracing::Instrument;
+use tokio::{
+    io::{
+        AsyncReadExt,
+        AsyncWriteExt
+    },
+    net::{
+        TcpStream,
+        tcp::{
+            OwnedReadHalf,
+            OwnedWriteHalf
+        }
+    }
+};
+
+/// Time allowed for the connection and the version handshake to complete
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An async connection to a single peer
+#[derive(Debug)]
+pub struct AsyncPeerConnection {
+    addr: SocketAddr,
+    sender: AsyncPeerSender,
+    stream: AsyncMessageStream,
+    version: Option<VersionMessage>
+}
+
+impl AsyncPeerConnection {
+    /// Open a TCP connection to a peer. The handshake still needs to be done.
+    pub async fn connect(addr: SocketAddr, magic: Magic) -> Result<Self, Error> {
+        let start = Instant::now();
+        let res = tokio::time::timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(addr)).await;
+        tracing::debug!(addr = %addr, elapsed_ms = start.elapsed().as_millis() as u64, ok = matches!(res, Ok(Ok(_))), "connect");
+        match res {
+            Ok(Ok(stream)) => Ok(Self::from_stream(stream, addr, magic)),
+            _ => Err(Error::FailedToConnect(addr.to_string()))
+        }
+    }
+
+    /// Create a connection from an already open TCP stream
+    pub fn from_stream(stream: TcpStream, addr: SocketAddr, magic: Magic) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self {
+            addr,
+            sender: AsyncPeerSender { writer, magic: magic.clone(), buf: Vec::new() },
+            stream: AsyncMessageStream { reader, magic, config: DecodeConfig::default() },
+            version: None
+        }
+    }
+
+    /// Open a connection to a peer and complete the version handshake.
+    pub async fn connect_and_handshake(addr: SocketAddr, magic: Magic, version: VersionMessage) -> Result<Self, Error> {
+        let mut conn = Self::connect(addr, magic).await?;
+        conn.handshake(version).await?;
+        Ok(conn)
+    }
+
+    /// Do the version handshake as the side opening the connection.
+    /// Returns the version message sent by the peer.
+    pub async fn handshake(&mut self, version: VersionMessage) -> Result<VersionMessage, Error> {
+        let span = tracing::debug_span!("handshake", side = "initiate", peer = %self.addr);
+        let start = Instant::now();
+        let res = match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.exchange_versions(version)).instrument(span.clone()).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::HandshakeFailed(String::from("timed out")))
+        };
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+        match &res {
+            Ok(theirs) => tracing::debug!(parent: &span, elapsed_ms, version = theirs.version, "handshake complete"),
+            Err(e) => tracing::debug!(parent: &span, elapsed_ms, error = %e, "handshake failed")
+        }
+        res
+    }
+
+    async fn exchange_versions(&mut self, version: VersionMessage) -> Result<VersionMessage, Error> {
+        self.sender.send(NetworkMessage::Version(version)).await?;
+
+        let mut verack = false;
+        while self.version.is_none() || !verack {
+            let msg = self.stream.recv_network_message().await?;
+            handshake::check_order(&msg.command(), self.version.is_some(), verack).map_err(Error::ProtocolViolation)?;
+            match msg {
+                NetworkMessage::Version(v) => {
+                    self.version = Some(v);
+                    self.sender.send(NetworkMessage::Verack).await?;
+                },
+                NetworkMessage::Verack => verack = true,
+
+                // Feature negotiation messages may be sent before the verack
+                _ => continue
+            }
+        }
+
+        Ok(self.version.clone().expect("Version is set above"))
+    }
+
+    /// The address of the peer
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The version message received from the peer, if the handshake was done.
+    pub fn peer_version(&self) -> Option<&VersionMessage> {
+        self.version.as_ref()
+    }
+
+    /// Set the limits used when decoding messages from the peer
+    pub fn set_config(&mut self, config: DecodeConfig) {
+        self.stream.config = config;
+    }
+
+    /// Send a message to the peer
+    pub async fn send(&mut self, msg: NetworkMessage) -> Result<(), Error> {
+        self.sender.send(msg).await
+    }
+
+    /// Receive the next message from the peer
+    pub async fn recv(&mut self) -> Result<Message, Error> {
+        self.stream.recv().await
+    }
+
+    /// Split the connection into halves that can be moved into different tasks
+    pub fn into_split(self) -> (AsyncPeerSender, AsyncMessageStream) {
+        (self.sender, self.stream)
+    }
+}
+
+/// The sending half of a peer connection
+#[derive(Debug)]
+pub struct AsyncPeerSender {
+    writer: OwnedWriteHalf,
+    magic: Magic,
+
+    /// Frame buffer reused for every message
+    buf: Vec<u8>
+}
+
+impl AsyncPeerSender {
+    /// Send a message to the peer
+    pub async fn send(&mut self, msg: NetworkMessage) -> Result<(), Error> {
+        self.send_message(&Message::from_payload(msg, self.magic.clone())).await
+    }
+
+    /// Send an already framed message to the peer
+    pub async fn send_message(&mut self, msg: &Message) -> Result<(), Error> {
+        let start = Instant::now();
+        msg.encode_into(&mut self.buf);
+        self.writer.write_all(&self.buf).await?;
+        tracing::trace!(
+            command = msg.header().command.to_str(),
+            bytes = self.buf.len(),
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "sent message"
+        );
+        Ok(())
+    }
+}
+
+/// The receiving half of a peer connection, yielding messages as they arrive
+#[derive(Debug)]
+pub struct AsyncMessageStream {
+    reader: OwnedReadHalf,
+    magic: Magic,
+    config: DecodeConfig
+}
+
+impl AsyncMessageStream {
+    /// Receive the next message.
+    /// Messages on a different network are rejected.
+    pub async fn recv(&mut self) -> Result<Message, Error> {
+        let mut buf = [0; 24];
+        self.reader.read_exact(&mut buf).await?;
+        let header = MessageHeader::net_decode(&buf[..])?;
+        if header.magic != self.magic {
+            return Err(Error::Message(crate::encode::Error::BadNetworkMagic(header.magic)))
+        }
+        if header.length as usize > self.config.max_payload {
+            return Err(Error::Message(crate::encode::Error::PayloadTooLarge(header.length)))
+        }
+
+        let start = Instant::now();
+        let mut payload = vec![0; header.length as usize];
+        self.reader.read_exact(&mut payload).await?;
+        let payload = MessagePayload::decode_with_header_config(&header, &payload[..], &self.config)?;
+        tracing::trace!(
+            command = header.command.to_str(),
+            length = header.length,
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "received message"
+        );
+
+        Ok(Message::from_header(header, payload))
+    }
+
+    /// Receive the next message as a typed network message
+    pub async fn recv_network_message(&mut self) -> Result<NetworkMessage, Error> {
+        Ok(self.recv().await?.network_message()?)
+    }
+
+    /// Receive the next message, returning None once the peer closes the connection.
+    pub async fn next(&mut self) -> Option<Result<Message, Error>> {
+        match self.recv().await {
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => None,
+            res => Some(res)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn handshake_and_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Remote side of the connection
+        let remote = tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            let mut conn = AsyncPeerConnection::from_stream(stream, addr, Magic::Main);
+            assert!(matches!(conn.stream.recv_network_message().await.unwrap(), NetworkMessage::Version(_)));
+            conn.send(NetworkMessage::Version(VersionMessage::from(Address::me()))).await.unwrap();
+            conn.send(NetworkMessage::SendHeaders).await.unwrap();
+            conn.send(NetworkMessage::Verack).await.unwrap();
+            assert_eq!(conn.stream.recv_network_message().await.unwrap(), NetworkMessage::Verack);
+            assert_eq!(conn.stream.recv_network_message().await.unwrap(), NetworkMessage::Ping(9));
+        });
+
+        let conn = AsyncPeerConnection::connect_and_handshake(addr, Magic::Main, VersionMessage::from(Address::me())).await.unwrap();
+        assert!(conn.peer_version().is_some());
+
+        let (mut sender, mut stream) = conn.into_split();
+        sender.send(NetworkMessage::Ping(9)).await.unwrap();
+        remote.await.unwrap();
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,213 @@
+// banman.rs
+//
+// Misbehavior scoring and ban list for peers.
+//
+// Every protocol violation adds to the score of the IP it came from. Once the score of an
+// IP reaches the threshold it is banned for a while and its score is reset.
+//
+
+use crate::{
+    encode,
+    net::{
+        addrman::now,
+        Error
+    }
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::Path,
+    time::Duration
+};
+
+/// Score at which a peer is banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// Default time a peer is banned for
+pub const DEFAULT_BAN_TIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// Kinds of protocol violations
+pub enum Misbehavior {
+    /// Message with a checksum that does not match the payload
+    BadChecksum,
+
+    /// Message with a payload or list larger than allowed
+    Oversized,
+
+    /// Message with a payload that could not be decoded
+    InvalidMessage,
+
+    /// Message that was not requested or is not allowed at this point of the connection
+    Unsolicited,
+
+    /// Custom violation with its own score
+    Other(u32)
+}
+
+impl Misbehavior {
+    /// The score added for the violation
+    pub fn score(&self) -> u32 {
+        match self {
+            Self::BadChecksum => 10,
+            Self::Oversized => 20,
+            Self::InvalidMessage => 20,
+            Self::Unsolicited => 10,
+            Self::Other(score) => *score
+        }
+    }
+
+    /// Get the violation caused by a message decoding error.
+    /// Returns `None` for errors that are not the fault of the peer.
+    pub fn from_error(err: &encode::Error) -> Option<Self> {
+        match err {
+            encode::Error::BadChecksum { .. } => Some(Self::BadChecksum),
+            encode::Error::PayloadTooLarge(_) |
+            encode::Error::TooManyItems { .. } => Some(Self::Oversized),
+            encode::Error::Decode { kind, .. } => Some(Self::from_error(kind).unwrap_or(Self::InvalidMessage)),
+            encode::Error::InvalidData |
+            encode::Error::NonCanonicalVarint => Some(Self::InvalidMessage),
+            _ => None
+        }
+    }
+}
+
+/// Misbehavior scores and bans by IP address
+#[derive(Clone, Debug)]
+pub struct BanMan {
+    threshold: u32,
+    ban_time: Duration,
+    scores: HashMap<IpAddr, u32>,
+    bans: HashMap<IpAddr, Duration>
+}
+
+impl Default for BanMan {
+    fn default() -> Self {
+        Self::new(BAN_THRESHOLD, DEFAULT_BAN_TIME)
+    }
+}
+
+impl BanMan {
+    pub fn new(threshold: u32, ban_time: Duration) -> Self {
+        Self {
+            threshold,
+            ban_time,
+            scores: HashMap::new(),
+            bans: HashMap::new()
+        }
+    }
+
+    /// Record a violation from an IP.
+    /// Returns true if the IP got banned because of it.
+    pub fn misbehaving(&mut self, ip: IpAddr, kind: Misbehavior) -> bool {
+        let score = self.scores.entry(ip).or_insert(0);
+        *score = score.saturating_add(kind.score());
+        if *score < self.threshold { return false }
+
+        self.scores.remove(&ip);
+        self.ban(ip, self.ban_time);
+        true
+    }
+
+    /// Get the current misbehavior score of an IP
+    pub fn score(&self, ip: &IpAddr) -> u32 {
+        self.scores.get(ip).copied().unwrap_or(0)
+    }
+
+    /// Ban an IP for the given time
+    pub fn ban(&mut self, ip: IpAddr, time: Duration) {
+        self.bans.insert(ip, now() + time);
+    }
+
+    /// Lift the ban on an IP. Returns true if it was banned.
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.bans.remove(ip).is_some()
+    }
+
+    /// Check if an IP is currently banned
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        matches!(self.bans.get(ip), Some(until) if *until > now())
+    }
+
+    /// Get the banned IPs with the unix time their ban ends, sorted by IP
+    pub fn banned(&self) -> Vec<(IpAddr, Duration)> {
+        let now = now();
+        let mut bans = self.bans
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| (*ip, *until))
+            .collect::<Vec<(IpAddr, Duration)>>();
+        bans.sort();
+        bans
+    }
+
+    /// Remove bans that have ended
+    pub fn sweep(&mut self) {
+        let now = now();
+        self.bans.retain(|_, until| *until > now);
+    }
+
+    /// Load the ban list from a file written by [`BanMan::save`]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Self::from_json(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Save the ban list to a file. Scores are not saved.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.to_json())?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub(crate) fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub(crate) fn ban_time(&self) -> Duration {
+        self.ban_time
+    }
+
+    pub(crate) fn restore(&mut self, ip: IpAddr, until: Duration) {
+        self.bans.insert(ip, until);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_after_threshold() {
+        let mut bm = BanMan::default();
+        let ip: IpAddr = "20.0.0.1".parse().unwrap();
+
+        for _ in 0..9 {
+            assert!(!bm.misbehaving(ip, Misbehavior::BadChecksum));
+        }
+        assert_eq!(bm.score(&ip), 90);
+        assert!(bm.misbehaving(ip, Misbehavior::Unsolicited));
+        assert!(bm.is_banned(&ip));
+        assert_eq!(bm.score(&ip), 0);
+
+        let loaded = BanMan::from_json(&bm.to_json()).unwrap();
+        assert!(loaded.is_banned(&ip));
+        assert_eq!(loaded.banned().len(), 1);
+
+        assert!(bm.unban(&ip));
+        assert!(!bm.is_banned(&ip));
+    }
+
+    #[test]
+    fn error_scores() {
+        let err = encode::Error::Decode {
+            command: crate::msg::header::Command::Addr,
+            offset: 3,
+            kind: Box::new(encode::Error::TooManyItems { count: 2000, max: 1000 })
+        };
+        assert_eq!(Misbehavior::from_error(&err), Some(Misbehavior::Oversized));
+        assert_eq!(Misbehavior::from_error(&encode::Error::Io(std::io::ErrorKind::UnexpectedEof.into())), None);
+    }
+}
@@ -0,0 +1,223 @@
+// blocks.rs
+//
+// Watcher of the new blocks announced by peers.
+//
+// Blocks are announced with an `inv`, or with a `headers` message by peers that were sent
+// `sendheaders`. Each new block is reported once, by the first peer that announced it,
+// along with the time since the previous new block. Heights are only known for headers
+// that follow a block of known height, which can be seeded with the tip of a header chain.
+//
+// When fetching is enabled, the announced blocks are requested from the announcing peer
+// so their size and transaction count can be reported.
+//
+
+use crate::{
+    bitcoin::hash_types::BlockHash,
+    blockdata::{
+        Block,
+        BlockHeader
+    },
+    encode::Encode,
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::Command,
+        inventory::Inventory
+    },
+    net::eventloop::PeerId
+};
+use std::{
+    collections::{
+        HashMap,
+        HashSet
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Number of block hashes and heights remembered
+const MAX_KNOWN: usize = 1000;
+
+/// New block announced by a peer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Announcement {
+    pub hash: BlockHash,
+
+    /// Peer that announced the block first
+    pub peer: PeerId,
+
+    /// The header, if the block was announced with its header
+    pub header: Option<BlockHeader>,
+
+    /// Height of the block, if the height of its parent is known
+    pub height: Option<usize>,
+
+    /// Time since the previous new block was announced
+    pub since_previous: Option<Duration>
+}
+
+/// Size of a received block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockSummary {
+    pub hash: BlockHash,
+    pub tx_count: usize,
+
+    /// Size of the serialized block in bytes
+    pub size: usize
+}
+
+impl BlockSummary {
+    pub fn of(block: &Block) -> Self {
+        Self {
+            hash: block.hash(),
+            tx_count: block.txs.len(),
+            size: block.encoded_size()
+        }
+    }
+}
+
+/// Watcher reporting the new blocks announced by the peers
+#[derive(Clone, Debug, Default)]
+pub struct BlockWatcher {
+    seen: HashSet<BlockHash>,
+    heights: HashMap<BlockHash, usize>,
+    last: Option<Instant>,
+
+    /// Whether announced blocks are requested, and the ones to request
+    fetch: bool,
+    requests: Vec<(PeerId, BlockHash)>
+}
+
+impl BlockWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set whether announced blocks are requested with `getdata`
+    pub fn set_fetch(&mut self, fetch: bool) {
+        self.fetch = fetch;
+        if !fetch {
+            self.requests.clear();
+        }
+    }
+
+    /// Set the height of a known block, ie the tip of a header chain, so the height of the
+    /// blocks announced after it is known
+    pub fn set_height(&mut self, hash: BlockHash, height: usize) {
+        if self.heights.len() >= MAX_KNOWN {
+            self.heights.clear();
+        }
+        self.heights.insert(hash, height);
+    }
+
+    /// Height of an announced or known block
+    pub fn height(&self, hash: &BlockHash) -> Option<usize> {
+        self.heights.get(hash).copied()
+    }
+
+    /// Take the announced blocks to request with `getdata`, by the peer to request them from
+    pub fn take_requests(&mut self) -> Vec<(PeerId, Vec<Inventory>)> {
+        let mut requests: Vec<(PeerId, Vec<Inventory>)> = Vec::new();
+        for (peer, hash) in self.requests.drain(..) {
+            let inv = Inventory::WitnessBlock(hash);
+            match requests.iter_mut().find(|(p, _)| *p == peer) {
+                Some((_, list)) => list.push(inv),
+                None => requests.push((peer, vec![inv]))
+            }
+        }
+        requests
+    }
+
+    /// Handle a message received from a peer.
+    /// Returns the blocks announced for the first time.
+    pub fn on_message(&mut self, peer: PeerId, msg: &Message) -> Vec<Announcement> {
+        match (&msg.header().command, msg.payload()) {
+            (Command::Inv, MessagePayload::InvVect(inv)) => inv
+                .iter()
+                .filter_map(|i| match i {
+                    Inventory::Block(h) |
+                    Inventory::WitnessBlock(h) |
+                    Inventory::CompactBlock(h) => self.announce(peer, *h, None),
+                    _ => None
+                })
+                .collect(),
+
+            // Like for the tip, headers without enough work are not counted
+            (_, MessagePayload::Headers(headers)) => headers
+                .iter()
+                .filter(|h| h.check_pow())
+                .filter_map(|h| self.announce(peer, h.hash(), Some(*h)))
+                .collect(),
+            (_, MessagePayload::Block(block)) if block.header.check_pow() && block.check_merkle_root() => {
+                self.announce(peer, block.hash(), Some(block.header)).into_iter().collect()
+            },
+            _ => Vec::new()
+        }
+    }
+
+    /// Record the announcement of a block, returning it if the block is new
+    fn announce(&mut self, peer: PeerId, hash: BlockHash, header: Option<BlockHeader>) -> Option<Announcement> {
+        // A header may give the height of a block already announced by an inv
+        let height = header.and_then(|h| Some(self.height(&h.prev_blockhash)? + 1));
+        if let Some(height) = height {
+            self.set_height(hash, height);
+        }
+
+        if self.seen.len() >= MAX_KNOWN {
+            self.seen.clear();
+        }
+        if !self.seen.insert(hash) { return None }
+
+        let since_previous = self.last.map(|t| t.elapsed());
+        self.last = Some(Instant::now());
+        if self.fetch {
+            self.requests.push((peer, hash));
+        }
+
+        Some(Announcement {
+            hash,
+            peer,
+            header,
+            height: self.height(&hash),
+            since_previous
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{
+        data::NetworkMessage,
+        header::Magic
+    };
+
+    #[test]
+    fn announcements() {
+        let block = Block::from(crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin));
+        let mut watcher = BlockWatcher::new();
+        watcher.set_fetch(true);
+        watcher.set_height(block.header.prev_blockhash, 41);
+
+        let inv = Message::from_payload(NetworkMessage::Inv(vec![Inventory::Block(block.hash())]), Magic::Main);
+        let announced = watcher.on_message(PeerId(1), &inv);
+        assert_eq!(announced, vec![Announcement { hash: block.hash(), peer: PeerId(1), header: None, height: None, since_previous: None }]);
+        assert!(watcher.on_message(PeerId(2), &inv).is_empty());
+
+        // The header of an announced block still gives its height
+        let headers = Message::from_payload(NetworkMessage::Headers(vec![block.header]), Magic::Main);
+        assert!(watcher.on_message(PeerId(2), &headers).is_empty());
+        assert_eq!(watcher.height(&block.hash()), Some(42));
+
+        assert_eq!(watcher.take_requests(), vec![(PeerId(1), vec![Inventory::WitnessBlock(block.hash())])]);
+        assert!(watcher.take_requests().is_empty());
+
+        let summary = BlockSummary::of(&block);
+        assert_eq!((summary.tx_count, summary.size), (1, 285));
+    }
+}
@@ -0,0 +1,176 @@
+// broadcast.rs
+//
+// Broadcast of a transaction to several peers.
+//
+// The transaction is announced with an `inv` to every peer, and sent to the peers that
+// request it with `getdata`. Peers only relay transactions they accepted to their mempool,
+// so a peer announcing the transaction back shows that it reached the network. Peers are
+// broadcast to in parallel, like the nodes of a crawl.
+//
+
+use crate::{
+    address::Address,
+    bitcoin::{
+        hash_types::Txid,
+        Transaction
+    },
+    msg::{
+        data::NetworkMessage,
+        header::Magic,
+        inventory::Inventory,
+        network::VersionConfig
+    },
+    net::{
+        handshake,
+        stream::PeerStream,
+        Error
+    }
+};
+use rayon::prelude::*;
+use std::{
+    net::TcpStream,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Settings of a broadcast
+#[derive(Clone, Debug)]
+pub struct BroadcastConfig {
+    pub magic: Magic,
+
+    /// Timeout for connecting to a peer
+    pub connect_timeout: Duration,
+
+    /// Time peers have to request the transaction and announce it back
+    pub timeout: Duration,
+
+    /// Fields of the version messages sent to peers. The relay flag is always set.
+    pub version: VersionConfig
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        Self {
+            magic: Magic::Main,
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            version: VersionConfig::default()
+        }
+    }
+}
+
+/// Outcome of the broadcast to a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BroadcastResult {
+    pub addr: Address,
+
+    /// The handshake with the peer completed
+    pub connected: bool,
+
+    /// The peer requested the transaction and it was sent
+    pub requested: bool,
+
+    /// The peer announced the transaction, so it accepted it or got it from another peer
+    pub announced: bool
+}
+
+/// Broadcast a transaction to the given peers.
+/// Returns the outcome for each peer, in the order of the peers.
+pub fn broadcast(tx: &Transaction, peers: &[Address], config: &BroadcastConfig) -> Vec<BroadcastResult> {
+    peers
+        .par_iter()
+        .map(|addr| {
+            let mut result = BroadcastResult { addr: *addr, connected: false, requested: false, announced: false };
+            let _ = send_to(tx, addr, config, &mut result);
+            result
+        })
+        .collect()
+}
+
+/// Connect to a peer, announce the transaction and serve it until the timeout
+fn send_to(tx: &Transaction, addr: &Address, config: &BroadcastConfig, result: &mut BroadcastResult) -> Result<(), Error> {
+    let socket = addr.socket_addr().ok_or_else(|| Error::FailedToConnect(format!("{} cannot be dialled", addr)))?;
+    let stream = TcpStream::connect_timeout(&socket, config.connect_timeout)
+        .map_err(|_| Error::FailedToConnect(addr.to_string()))?;
+    let mut peer = PeerStream::new(stream, config.magic.clone())?;
+
+    // The peer only announces transactions back if asked to relay them
+    let mut ours = config.version.message(*addr);
+    ours.relay = Some(true);
+
+    let start = Instant::now();
+    let res = handshake::initiate(&mut peer, ours, config.timeout)
+        .and_then(|_| {
+            result.connected = true;
+            peer.send(NetworkMessage::Inv(vec![Inventory::Tx(tx.txid())]))?;
+            serve(&mut peer, tx, config, start, result)
+        });
+    let _ = peer.shutdown();
+    res
+}
+
+fn serve(peer: &mut PeerStream, tx: &Transaction, config: &BroadcastConfig, start: Instant, result: &mut BroadcastResult) -> Result<(), Error> {
+    let txid = tx.txid();
+    while !(result.requested && result.announced) {
+        let left = match config.timeout.checked_sub(start.elapsed()).filter(|d| !d.is_zero()) {
+            Some(left) => left,
+            None => return Ok(())
+        };
+        peer.set_read_timeout(Some(left))?;
+
+        match peer.recv_network_message()? {
+            NetworkMessage::GetData(inv) if contains(&inv, &txid) => {
+                peer.send(NetworkMessage::Tx(tx.clone()))?;
+                result.requested = true;
+            },
+            NetworkMessage::Inv(inv) if contains(&inv, &txid) => result.announced = true,
+            NetworkMessage::Ping(nonce) => peer.send(NetworkMessage::Pong(nonce))?,
+            _ => continue
+        }
+    }
+    Ok(())
+}
+
+/// Check if an inventory holds a transaction, by txid
+fn contains(inv: &[Inventory], txid: &Txid) -> bool {
+    inv.iter().any(|i| matches!(i, Inventory::Tx(t) | Inventory::WitnessTx(t) if t == txid))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::listener::Listener;
+
+    #[test]
+    fn broadcast_to_peer() {
+        let tx = crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin).txdata[0].clone();
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), Magic::Main).unwrap();
+        let node = Address::from(listener.local_addr().unwrap());
+        let expected = tx.clone();
+        let server = std::thread::spawn(move || {
+            let peer = listener.accept().unwrap();
+            assert_eq!(peer.version.relay, Some(true));
+            let mut stream = peer.stream;
+            let inv = loop {
+                if let NetworkMessage::Inv(inv) = stream.recv_network_message().unwrap() { break inv }
+            };
+            stream.send(NetworkMessage::GetData(inv.clone())).unwrap();
+            assert_eq!(stream.recv_network_message().unwrap(), NetworkMessage::Tx(expected));
+            stream.send(NetworkMessage::Inv(inv)).unwrap();
+        });
+
+        // The second peer can not be connected to
+        let unreachable = Address::from("127.0.0.1:1".parse::<std::net::SocketAddr>().unwrap());
+        let config = BroadcastConfig { timeout: Duration::from_secs(5), ..BroadcastConfig::default() };
+        let results = broadcast(&tx, &[node, unreachable], &config);
+        server.join().unwrap();
+
+        assert_eq!(results, vec![
+            BroadcastResult { addr: node, connected: true, requested: true, announced: true },
+            BroadcastResult { addr: unreachable, connected: false, requested: false, announced: false }
+        ]);
+    }
+}
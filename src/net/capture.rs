@@ -0,0 +1,341 @@
+// capture.rs
+//
+// Capture of the raw messages exchanged with peers, and replay of captures.
+//
+// A capture file is a sequence of records, each holding the time the message was seen,
+// the peer and direction, and the raw bytes of the message as they were on the wire:
+//      time (u64, microseconds since the unix epoch)
+//      peer id (u64)
+//      direction (u8, 0 for inbound and 1 for outbound)
+//      length (u32)
+//      message bytes
+// Inbound messages are recorded before they are decoded, so messages that fail to decode
+// are captured too. Replaying a capture decodes the messages again, which is useful for
+// offline analysis and to turn traffic that broke the decoder into regression tests.
+//
+// Raw messages can also be shown as annotated hexdumps, with the fields of the header on
+// their own lines and the payload in rows of 16 bytes:
+//      magic     f9beb4d9                  main
+//      command   70696e670000000000000000  ping
+//      length    08000000                  8
+//      checksum  8c820c80                  ok
+//      payload   0000  07 00 00 00 00 00 00 00                          ........
+//
+
+use crate::{
+    encode::{
+        Decode,
+        DecodeConfig,
+        Encode,
+        Error
+    },
+    msg::{
+        header::{
+            sha256d,
+            Magic,
+            MessageHeader
+        },
+        stream::MessageStream
+    },
+    net::eventloop::{
+        Event,
+        PeerId
+    }
+};
+use std::{
+    cell::RefCell,
+    convert::TryFrom,
+    fs::File,
+    io::{
+        BufReader,
+        BufWriter,
+        Read,
+        Write
+    },
+    path::Path,
+    rc::Rc,
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::Duration
+};
+
+/// Direction in which a message was sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Inbound,
+    Outbound
+}
+
+/// Message recorded in a capture
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// Time the message was seen, since the unix epoch
+    pub time: Duration,
+    pub peer: PeerId,
+    pub direction: Direction,
+
+    /// Raw bytes of the message, including its header
+    pub bytes: Vec<u8>
+}
+
+impl Encode for CaptureRecord {
+    fn net_encode<W>(&self, mut w: W) -> usize
+    where W: std::io::Write {
+        (self.time.as_micros() as u64).net_encode(&mut w) +
+        self.peer.0.net_encode(&mut w) +
+        match self.direction {
+            Direction::Inbound => 0u8,
+            Direction::Outbound => 1u8
+        }.net_encode(&mut w) +
+        (self.bytes.len() as u32).net_encode(&mut w) +
+        w.write(&self.bytes).expect("Failed to write")
+    }
+
+    fn encoded_size(&self) -> usize {
+        8 + 8 + 1 + 4 + self.bytes.len()
+    }
+}
+
+impl Decode for CaptureRecord {
+    fn net_decode<R>(mut r: R) -> Result<Self, Error>
+    where R: std::io::Read {
+        let time = Duration::from_micros(u64::net_decode(&mut r)?);
+        let peer = PeerId(u64::net_decode(&mut r)?);
+        let direction = match u8::net_decode(&mut r)? {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            _ => return Err(Error::InvalidData)
+        };
+        let mut bytes = vec![0; u32::net_decode(&mut r)? as usize];
+        r.read_exact(&mut bytes)?;
+
+        Ok(Self { time, peer, direction, bytes })
+    }
+}
+
+/// Writer of a capture file, shared by the threads of the event loop
+#[derive(Clone, Debug)]
+pub struct Capture {
+    file: Arc<Mutex<BufWriter<File>>>
+}
+
+impl Capture {
+    /// Create a capture file, replacing any existing file
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Self {
+            file: Arc::new(Mutex::new(BufWriter::new(File::create(path)?)))
+        })
+    }
+
+    /// Record the raw bytes of a message seen now
+    pub fn record(&self, peer: PeerId, direction: Direction, bytes: &[u8]) -> Result<(), Error> {
+        let record = CaptureRecord {
+            time: crate::net::addrman::now(),
+            peer,
+            direction,
+            bytes: bytes.to_vec()
+        };
+        let mut file = self.file.lock().expect("Capture lock poisoned");
+        record.net_encode(&mut *file);
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Iterator over the records of a capture
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    done: bool
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// Open a capture file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            done: false
+        }
+    }
+
+    /// Decode the messages of the capture again, as the event loop would have reported them.
+    /// Messages that fail to decode are reported as invalid messages.
+    pub fn replay(self, config: DecodeConfig) -> impl Iterator<Item = Result<(CaptureRecord, Event), Error>> {
+        self.map(move |record| {
+            let record = record?;
+            let event = match MessageStream::new(&record.bytes[..], config.clone()).next() {
+                Some(Ok(msg)) => Event::Message(record.peer, msg),
+                Some(Err(e)) => Event::InvalidMessage(record.peer, e),
+                None => Event::InvalidMessage(record.peer, Error::InvalidData)
+            };
+            Ok((record, event))
+        })
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CaptureRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done { return None }
+
+        // Check for the clean end of the capture before the next record
+        let mut first = [0; 1];
+        match self.reader.read(&mut first) {
+            Ok(0) => {
+                self.done = true;
+                return None
+            },
+            Ok(_) => {},
+            Err(e) => {
+                self.done = true;
+                return Some(Err(Error::Io(e)))
+            }
+        }
+
+        let result = CaptureRecord::net_decode((&first[..]).chain(&mut self.reader));
+        if result.is_err() {
+            self.done = true;
+        }
+        Some(result)
+    }
+}
+
+/// Format the raw bytes of a message as an annotated hexdump.
+/// Truncated messages are dumped as far as they go.
+pub fn hexdump(bytes: &[u8]) -> String {
+    let hex = |b: &[u8]| b.iter().map(|x| format!("{:02x}", x)).collect::<String>();
+    let field = |start: usize, end: usize| &bytes[start.min(bytes.len())..end.min(bytes.len())];
+
+    let (magic, command, length, checksum) = (field(0, 4), field(4, 16), field(16, 20), field(20, 24));
+    let payload = field(MessageHeader::SIZE, bytes.len());
+    let magic_note = match Magic::net_decode(magic) {
+        Ok(magic) => magic.to_string(),
+        Err(_) => String::from("unknown")
+    };
+    let command_note = String::from_utf8_lossy(command).trim_end_matches('\0').to_string();
+    let length_note = match <[u8; 4]>::try_from(length) {
+        Ok(len) => match u32::from_le_bytes(len) as usize {
+            len if len == payload.len() => len.to_string(),
+            len => format!("{} ({} bytes follow)", len, payload.len())
+        },
+        Err(_) => String::new()
+    };
+    let checksum_note = match (checksum.len(), &sha256d(payload)[..4]) {
+        (4, expected) if expected == checksum => String::from("ok"),
+        (4, expected) => format!("bad, payload hashes to {}", hex(expected)),
+        _ => String::new()
+    };
+
+    let mut out = String::new();
+    for (name, value, note) in [
+        ("magic", magic, magic_note),
+        ("command", command, command_note),
+        ("length", length, length_note),
+        ("checksum", checksum, checksum_note)
+    ] {
+        out.push_str(format!("{:<9} {:<24}  {}", name, hex(value), note).trim_end());
+        out.push('\n');
+    }
+    for (i, row) in payload.chunks(16).enumerate() {
+        let bytes = row.iter().map(|x| format!("{:02x}", x)).collect::<Vec<String>>().join(" ");
+        let ascii = row.iter().map(|x| if x.is_ascii_graphic() || *x == b' ' { *x as char } else { '.' }).collect::<String>();
+        let name = if i == 0 { "payload" } else { "" };
+        out.push_str(&format!("{:<9} {:04x}  {:<47}  {}\n", name, i * 16, bytes, ascii));
+    }
+    out
+}
+
+/// Reader keeping a copy of the bytes read, used to capture the raw inbound messages
+pub(crate) struct Tee<R: Read> {
+    inner: R,
+    buf: Option<Rc<RefCell<Vec<u8>>>>
+}
+
+impl<R: Read> Tee<R> {
+    pub(crate) fn new(inner: R, buf: Option<Rc<RefCell<Vec<u8>>>>) -> Self {
+        Self { inner, buf }
+    }
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(out)?;
+        if let Some(buf) = &self.buf {
+            buf.borrow_mut().extend_from_slice(&out[..n]);
+        }
+        Ok(n)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{
+        data::{
+            Message,
+            NetworkMessage
+        },
+        header::Magic
+    };
+
+    #[test]
+    fn capture_and_replay() {
+        let path = std::env::temp_dir().join(format!("btcnetmsg-capture-{}.dat", rand::random::<u32>()));
+        let mut ping = Vec::new();
+        Message::from_payload(NetworkMessage::Ping(7), Magic::Main).net_encode(&mut ping);
+        let mut bad = ping.clone();
+        bad[20] ^= 0xFF;
+
+        let capture = Capture::create(&path).unwrap();
+        capture.record(PeerId(1), Direction::Inbound, &ping).unwrap();
+        capture.clone().record(PeerId(2), Direction::Outbound, &bad).unwrap();
+
+        let records = CaptureReader::open(&path).unwrap().collect::<Result<Vec<CaptureRecord>, Error>>().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].bytes, ping);
+        assert_eq!((records[1].peer, records[1].direction), (PeerId(2), Direction::Outbound));
+
+        let events = CaptureReader::open(&path).unwrap().replay(DecodeConfig::default()).collect::<Result<Vec<_>, Error>>().unwrap();
+        assert!(matches!(&events[0].1, Event::Message(PeerId(1), m) if m.network_message().unwrap() == NetworkMessage::Ping(7)));
+        assert!(matches!(&events[1].1, Event::InvalidMessage(PeerId(2), Error::BadChecksum { .. })));
+
+        // A truncated record ends the capture with an error
+        let mut data = std::fs::read(&path).unwrap();
+        data.truncate(data.len() - 1);
+        let mut reader = CaptureReader::new(&data[..]);
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn annotated_hexdump() {
+        let mut ping = Vec::new();
+        Message::from_payload(NetworkMessage::Ping(7), Magic::Main).net_encode(&mut ping);
+        let dump = hexdump(&ping);
+        let lines = dump.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], "magic     f9beb4d9                  main");
+        assert_eq!(lines[1], "command   70696e670000000000000000  ping");
+        assert_eq!(lines[2], "length    08000000                  8");
+        assert_eq!(lines[3], "checksum  8c820c80                  ok");
+        assert_eq!(lines[4], "payload   0000  07 00 00 00 00 00 00 00                          ........");
+
+        // Corrupt and truncated messages are annotated as such
+        let mut bad = ping.clone();
+        bad[20] ^= 0xFF;
+        assert!(hexdump(&bad).lines().nth(3).unwrap().contains("bad, payload hashes to"));
+        assert!(hexdump(&ping[..28]).contains("8 (4 bytes follow)"));
+        assert_eq!(hexdump(&ping[..6]).lines().count(), 4);
+    }
+}
@@ -0,0 +1,234 @@
+// control.rs
+//
+// Control socket of a running connection manager.
+//
+// Clients connect to a unix socket and send one JSON request per line, in the style of
+// JSON-RPC: `{"id": 1, "method": "getpeers", "params": []}`. Each request is answered with
+// one line holding either its result or an error, along with the id of the request:
+//      getpeers                    information about the connected peers
+//      getstats                    session statistics and traffic by command
+//      disconnect <peer>           disconnect a peer by id
+//      sendmsg <peer> <message>    send a message, in the canonical JSON schema, to a peer
+//      stop                        ask the daemon to stop
+// The server is polled from the loop driving the manager, so the running node can be
+// inspected and steered without restarting it:
+//      while !control.stopped() {
+//          manager.poll();
+//          control.poll(&mut manager);
+//      }
+//
+
+use crate::{
+    json::JsonValue,
+    msg::data::Message,
+    net::{
+        eventloop::PeerId,
+        manager::ConnectionManager,
+        Error
+    }
+};
+use serde_json::{
+    json,
+    Value
+};
+use std::{
+    io::{
+        ErrorKind,
+        Read,
+        Write
+    },
+    os::unix::net::{
+        UnixListener,
+        UnixStream
+    },
+    path::{
+        Path,
+        PathBuf
+    }
+};
+
+/// Longest request line accepted from a client
+const MAX_REQUEST: usize = 4 * 1024 * 1024;
+
+/// Client of the control socket
+#[derive(Debug)]
+struct Client {
+    stream: UnixStream,
+    buf: Vec<u8>
+}
+
+/// Server of the control socket
+#[derive(Debug)]
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<Client>,
+    stopped: bool
+}
+
+impl ControlServer {
+    /// Listen on a unix socket, replacing the socket left by a previous run
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path,
+            clients: Vec::new(),
+            stopped: false
+        })
+    }
+
+    /// Path of the socket
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether a client asked the daemon to stop
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Accept new clients and answer their pending requests, without blocking.
+    /// Clients that close the connection or send invalid data are dropped.
+    pub fn poll(&mut self, manager: &mut ConnectionManager) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.clients.push(Client { stream, buf: Vec::new() });
+            }
+        }
+
+        let mut clients = std::mem::take(&mut self.clients);
+        clients.retain_mut(|client| self.serve(client, manager).is_ok());
+        self.clients = clients;
+    }
+
+    /// Answer the complete requests of a client
+    fn serve(&mut self, client: &mut Client, manager: &mut ConnectionManager) -> Result<(), Error> {
+        let mut chunk = [0; 4096];
+        loop {
+            match client.stream.read(&mut chunk) {
+                Ok(0) => return Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+                Ok(n) => client.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Io(e))
+            }
+        }
+
+        while let Some(end) = client.buf.iter().position(|b| *b == b'\n') {
+            let line = client.buf.drain(..=end).collect::<Vec<u8>>();
+            let response = self.handle(&line, manager);
+
+            // Responses are small, so they are written at once
+            client.stream.set_nonblocking(false)?;
+            client.stream.write_all(format!("{}\n", response).as_bytes())?;
+            client.stream.set_nonblocking(true)?;
+        }
+        if client.buf.len() > MAX_REQUEST {
+            return Err(Error::Io(ErrorKind::InvalidData.into()))
+        }
+        Ok(())
+    }
+
+    /// Answer a request line
+    fn handle(&mut self, line: &[u8], manager: &mut ConnectionManager) -> Value {
+        let request = match serde_json::from_slice::<Value>(line) {
+            Ok(request) => request,
+            Err(e) => return json!({ "id": Value::Null, "error": format!("invalid request: {}", e) })
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = request.get("method").and_then(Value::as_str).unwrap_or_default();
+        let params = request.get("params").and_then(Value::as_array).cloned().unwrap_or_default();
+
+        match self.call(method, &params, manager) {
+            Ok(result) => json!({ "id": id, "result": result }),
+            Err(e) => json!({ "id": id, "error": e })
+        }
+    }
+
+    fn call(&mut self, method: &str, params: &[Value], manager: &mut ConnectionManager) -> Result<Value, String> {
+        match method {
+            "getpeers" => Ok(Value::from(manager.peer_infos().iter().map(|p| p.to_value()).collect::<Vec<Value>>())),
+            "getstats" => {
+                let mut stats = manager.session_stats().to_value();
+                stats["traffic"] = manager.eventloop().stats().to_value();
+                Ok(stats)
+            },
+            "disconnect" => {
+                let peer = peer_param(params)?;
+                Ok(Value::from(manager.disconnect(peer).is_some()))
+            },
+            "sendmsg" => {
+                let peer = peer_param(params)?;
+                let msg = params.get(1).ok_or("missing message")?;
+                let msg = Message::from_value(msg)
+                    .and_then(|m| m.network_message())
+                    .map_err(|e| format!("invalid message: {}", e))?;
+                manager.eventloop().send(peer, msg).map_err(|e| e.to_string())?;
+                Ok(Value::from(true))
+            },
+            "stop" => {
+                self.stopped = true;
+                Ok(Value::from(true))
+            },
+            _ => Err(format!("unknown method `{}`", method))
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Get the peer id given as the first parameter
+fn peer_param(params: &[Value]) -> Result<PeerId, String> {
+    params.first().and_then(Value::as_u64).map(PeerId).ok_or_else(|| String::from("missing or invalid peer id"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::manager::ManagerConfig;
+    use std::io::{
+        BufRead,
+        BufReader
+    };
+
+    #[test]
+    fn control_requests() {
+        let path = std::env::temp_dir().join(format!("btcnetmsg-control-{}.sock", rand::random::<u32>()));
+        let mut manager = ConnectionManager::new(ManagerConfig::default()).unwrap();
+        let mut control = ControlServer::bind(&path).unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut lines = BufReader::new(client.try_clone().unwrap()).lines();
+        let mut request = |line: &str| -> Value {
+            client.write_all(line.as_bytes()).unwrap();
+            client.write_all(b"\n").unwrap();
+
+            // Data written to a unix socket can be read at once
+            control.poll(&mut manager);
+            serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap()
+        };
+
+        assert_eq!(request(r#"{"id": 1, "method": "getpeers"}"#), json!({ "id": 1, "result": [] }));
+        assert_eq!(request(r#"{"id": 2, "method": "getstats"}"#)["result"]["connected"], 0);
+        assert_eq!(request(r#"{"id": 3, "method": "disconnect", "params": [7]}"#)["result"], false);
+        assert_eq!(request(r#"{"id": 4, "method": "sendmsg", "params": [7]}"#)["error"], "missing message");
+        assert_eq!(request(r#"{"id": 5, "method": "nope"}"#)["error"], "unknown method `nope`");
+        assert!(request("not json")["error"].is_string());
+        assert_eq!(request(r#"{"id": 6, "method": "stop"}"#)["result"], true);
+        assert!(control.stopped());
+
+        drop(control);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,316 @@
+// crawl.rs
+//
+// Crawler mapping the address space of the network with `getaddr`.
+//
+// Starting from a set of peers, every node is connected to, sent a `getaddr` after the
+// handshake, and the addresses it answers with are queued to be visited as well. Nodes
+// are visited in parallel batches of one node per CPU core, like `Peer::get`.
+//
+// The snapshot of a crawl can be summarized into a census of the user agents, protocol
+// versions and services of the reachable nodes, and saved as JSON for later surveys.
+//
+
+use crate::{
+    address::Address,
+    msg::{
+        data::NetworkMessage,
+        header::Magic,
+        network::{
+            Service,
+            TimestampedNetAddress,
+            VersionConfig,
+            VersionMessage
+        }
+    },
+    net::{
+        handshake,
+        stream::PeerStream,
+        Error
+    }
+};
+use rayon::prelude::*;
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+        VecDeque
+    },
+    net::TcpStream,
+    path::Path,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Settings of a crawl
+#[derive(Clone, Debug)]
+pub struct CrawlConfig {
+    pub magic: Magic,
+
+    /// Maximum number of nodes connected to
+    pub max_nodes: usize,
+
+    /// Timeout for connecting to a node
+    pub connect_timeout: Duration,
+
+    /// Time a node has to complete the handshake and answer the `getaddr`
+    pub timeout: Duration,
+
+    /// Fields of the version messages sent to nodes
+    pub version: VersionConfig
+}
+
+impl Default for CrawlConfig {
+    fn default() -> Self {
+        Self {
+            magic: Magic::Main,
+            max_nodes: 100,
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            version: VersionConfig::default()
+        }
+    }
+}
+
+/// Node that answered the crawler
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrawledNode {
+    pub addr: Address,
+    pub version: VersionMessage,
+
+    /// Number of addresses the node answered with
+    pub addrs: usize
+}
+
+/// Snapshot of the network found by a crawl
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    /// Nodes that completed the handshake
+    pub reachable: Vec<CrawledNode>,
+
+    /// Nodes that could not be connected to or did not complete the handshake
+    pub unreachable: Vec<Address>,
+
+    /// Every address that was announced, once, with the services and timestamp of its most
+    /// recent announcement
+    pub addrs: Vec<TimestampedNetAddress>
+}
+
+impl Snapshot {
+    /// Summarize the reachable nodes
+    pub fn census(&self) -> Census {
+        let mut census = Census::default();
+        for node in self.reachable.iter() {
+            census.add(&node.version);
+        }
+        census.unreachable = self.unreachable.len();
+        census
+    }
+
+    /// Load a snapshot from a file written by [`Snapshot::save`]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Self::from_json(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Save the snapshot and its census to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.to_json())?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Summary of the version messages of reachable nodes, ie of a snapshot or of the peers of
+/// a connection manager.
+/// Counts are sorted from the most common value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Census {
+    pub reachable: usize,
+    pub unreachable: usize,
+    pub user_agents: Vec<(String, usize)>,
+
+    /// Protocol versions
+    pub versions: Vec<(i32, usize)>,
+
+    /// Number of nodes signalling each service
+    pub services: Vec<(Service, usize)>
+}
+
+impl Census {
+    /// Count the version message of a reachable node
+    pub fn add(&mut self, version: &VersionMessage) {
+        self.reachable += 1;
+        count(&mut self.user_agents, version.agent.as_str().to_string());
+        count(&mut self.versions, version.version);
+        for service in version.service.get_flags() {
+            count(&mut self.services, service);
+        }
+    }
+}
+
+impl std::fmt::Display for Census {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} reachable, {} unreachable", self.reachable, self.unreachable)?;
+        writeln!(f, "user agents:")?;
+        for (agent, n) in self.user_agents.iter() {
+            writeln!(f, "  {:>6} {}", n, agent)?;
+        }
+        writeln!(f, "versions:")?;
+        for (version, n) in self.versions.iter() {
+            writeln!(f, "  {:>6} {}", n, version)?;
+        }
+        writeln!(f, "services:")?;
+        for (service, n) in self.services.iter() {
+            writeln!(f, "  {:>6} {}", n, service.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// Count an occurrence of a value, keeping the counts sorted from the most common value.
+/// Values as common as each other are kept in the order they were first seen.
+fn count<T: PartialEq>(counts: &mut Vec<(T, usize)>, value: T) {
+    match counts.iter_mut().find(|(v, _)| *v == value) {
+        Some((_, n)) => *n += 1,
+        None => counts.push((value, 1))
+    }
+    counts.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+}
+
+/// Connect to a node, do the handshake and ask it for addresses.
+/// Single entry `addr` messages are skipped, as they are usually self advertisements.
+pub fn getaddr(addr: &Address, config: &CrawlConfig) -> Result<(VersionMessage, Vec<TimestampedNetAddress>), Error> {
+    let socket = addr.socket_addr().ok_or_else(|| Error::FailedToConnect(format!("{} cannot be dialled", addr)))?;
+    let stream = TcpStream::connect_timeout(&socket, config.connect_timeout)
+        .map_err(|_| Error::FailedToConnect(addr.to_string()))?;
+    let mut peer = PeerStream::new(stream, config.magic.clone())?;
+
+    let start = Instant::now();
+    let res = handshake::initiate(&mut peer, config.version.message(*addr), config.timeout)
+        .and_then(|version| {
+            peer.send(NetworkMessage::GetAddr)?;
+            Ok((version, wait_for_addrs(&mut peer, config, start)?))
+        });
+    let _ = peer.shutdown();
+    res
+}
+
+fn wait_for_addrs(peer: &mut PeerStream, config: &CrawlConfig, start: Instant) -> Result<Vec<TimestampedNetAddress>, Error> {
+    loop {
+        let left = config.timeout.checked_sub(start.elapsed()).filter(|d| !d.is_zero())
+            .ok_or_else(|| Error::FailedToConnect(String::from("timed out waiting for addresses")))?;
+        peer.set_read_timeout(Some(left))?;
+
+        match peer.recv_network_message()? {
+            NetworkMessage::Addr(addrs) |
+            NetworkMessage::AddrV2(addrs) if addrs.len() > 1 => return Ok(addrs),
+            NetworkMessage::Ping(nonce) => peer.send(NetworkMessage::Pong(nonce))?,
+            _ => continue
+        }
+    }
+}
+
+/// Crawl the network starting from the given nodes
+pub fn crawl(start: &[Address], config: &CrawlConfig) -> Snapshot {
+    let mut snapshot = Snapshot::default();
+    let mut seen: HashMap<Address, TimestampedNetAddress> = HashMap::new();
+    let mut visited: HashSet<Address> = HashSet::new();
+    let mut queue: VecDeque<Address> = start.iter().copied().collect();
+
+    while visited.len() < config.max_nodes && !queue.is_empty() {
+        // Only IP addresses can be connected to without a proxy
+        let mut batch = Vec::new();
+        while batch.len() < num_cpus::get() && visited.len() + batch.len() < config.max_nodes {
+            match queue.pop_front() {
+                Some(addr) if addr.socket_addr().is_some() && !visited.contains(&addr) => {
+                    visited.insert(addr);
+                    batch.push(addr);
+                },
+                Some(_) => continue,
+                None => break
+            }
+        }
+
+        let results = batch
+            .par_iter()
+            .map(|addr| (*addr, getaddr(addr, config)))
+            .collect::<Vec<(Address, Result<(VersionMessage, Vec<TimestampedNetAddress>), Error>)>>();
+
+        for (addr, res) in results {
+            let (version, addrs) = match res {
+                Ok(r) => r,
+                Err(_) => { snapshot.unreachable.push(addr); continue }
+            };
+            snapshot.reachable.push(CrawledNode { addr, version, addrs: addrs.len() });
+
+            for entry in addrs {
+                let key = entry.netaddress.address;
+                match seen.get(&key) {
+                    Some(known) if known.timestamp >= entry.timestamp => {},
+                    Some(_) => { seen.insert(key, entry); },
+                    None => {
+                        queue.push_back(key);
+                        seen.insert(key, entry);
+                    }
+                }
+            }
+        }
+    }
+
+    snapshot.addrs = seen.into_values().collect();
+    snapshot.addrs.sort_by_key(|a| a.netaddress.address.to_string());
+    snapshot
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        msg::network::{
+            NetAddress,
+            ServicesList
+        },
+        net::listener::Listener
+    };
+
+    fn entry(addr: &str, secs: u64) -> TimestampedNetAddress {
+        TimestampedNetAddress::new(Duration::from_secs(secs), NetAddress::new(ServicesList::default(), addr.parse().unwrap()))
+    }
+
+    #[test]
+    fn crawl_single_node() {
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), Magic::Main).unwrap();
+        let node = Address::from(listener.local_addr().unwrap());
+        let answer = vec![entry("20.1.2.3:8333", 100), entry("20.1.2.3:8333", 200), entry("203.0.113.1:8333", 100)];
+        let server = std::thread::spawn(move || {
+            let mut peer = listener.accept().unwrap().stream;
+            loop {
+                if let NetworkMessage::GetAddr = peer.recv_network_message().unwrap() { break }
+            }
+            peer.send(NetworkMessage::Addr(vec![entry("20.9.9.9:8333", 1)])).unwrap();
+            peer.send(NetworkMessage::Addr(answer)).unwrap();
+        });
+
+        // The crawled addresses do not exist, so visiting stops at the first node
+        let config = CrawlConfig { max_nodes: 1, timeout: Duration::from_secs(5), ..CrawlConfig::default() };
+        let snapshot = crawl(&[node], &config);
+        server.join().unwrap();
+
+        assert_eq!(snapshot.reachable.len(), 1);
+        assert_eq!(snapshot.reachable[0].addrs, 3);
+        assert_eq!(snapshot.addrs, vec![entry("20.1.2.3:8333", 200), entry("203.0.113.1:8333", 100)]);
+
+        let census = snapshot.census();
+        assert_eq!((census.reachable, census.unreachable), (1, 0));
+        assert_eq!(census.user_agents, vec![(snapshot.reachable[0].version.agent.as_str().to_string(), 1)]);
+
+        let path = std::env::temp_dir().join(format!("btcnetmsg-snapshot-{}.json", rand::random::<u32>()));
+        snapshot.save(&path).unwrap();
+        assert_eq!(Snapshot::load(&path).unwrap(), snapshot);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,787 @@
+// eventloop.rs
+//
+// Event loop driving a set of connected peers.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            NetworkMessage
+        },
+        header::{
+            Command,
+            Magic
+        },
+        stream::MessageStream
+    },
+    encode::{
+        self,
+        DecodeConfig
+    },
+    net::{
+        banman::{
+            BanMan,
+            Misbehavior
+        },
+        capture::{
+            self,
+            Capture,
+            Direction,
+            Tee
+        },
+        handshake::{
+            self,
+            ProtocolViolation
+        },
+        latency::{
+            Latency,
+            LatencyStats
+        },
+        ratelimit::{
+            RateCounters,
+            RateLimiter,
+            RateLimits
+        },
+        stats::{
+            PeerMetrics,
+            Stats
+        },
+        stream::PeerStream,
+        addrman::now,
+        Error
+    }
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io,
+    net::{
+        Shutdown,
+        SocketAddr,
+        TcpStream
+    },
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering
+        },
+        mpsc::{
+            self,
+            Receiver,
+            RecvTimeoutError,
+            Sender,
+            SyncSender,
+            TrySendError
+        },
+        Arc,
+        Mutex
+    },
+    rc::Rc,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Identifier for a peer in the event loop
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(pub u64);
+
+/// Timings used by the event loop
+#[derive(Clone, Debug)]
+pub struct EventLoopConfig {
+    /// Time without any messages from a peer before it is sent a ping
+    pub ping_interval: Duration,
+
+    /// Time without any messages from a peer before it is disconnected.
+    /// Nodes disconnect peers that are inactive for 20 minutes.
+    pub inactivity_timeout: Duration,
+
+    /// Time a ping can go unanswered before the peer is disconnected
+    pub ping_timeout: Duration,
+
+    /// Maximum time a single poll waits for messages
+    pub tick: Duration,
+
+    /// Limits used when decoding messages from peers
+    pub decode: DecodeConfig,
+
+    /// Limits on the traffic received from every peer. Traffic is not limited if unset.
+    pub rate_limits: Option<RateLimits>,
+
+    /// Maximum number of messages waiting to be written to a peer
+    pub send_queue: usize,
+
+    /// Capture recording the messages exchanged with the peers
+    pub capture: Option<Capture>,
+
+    /// Log the raw bytes of every message exchanged with the peers as an annotated hexdump,
+    /// at the debug level of the `btcnetmsg::wire` target
+    pub hexdump: bool
+}
+
+impl Default for EventLoopConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(2 * 60),
+            inactivity_timeout: Duration::from_secs(20 * 60),
+            ping_timeout: Duration::from_secs(60),
+            tick: Duration::from_secs(1),
+            decode: DecodeConfig::default(),
+            rate_limits: Some(RateLimits::default()),
+            send_queue: 100,
+            capture: None,
+            hexdump: false
+        }
+    }
+}
+
+/// Reason for a peer being disconnected
+#[derive(Debug)]
+pub enum DisconnectReason {
+    /// The peer closed the connection or the connection failed
+    Closed(Option<encode::Error>),
+
+    /// No messages were received within the inactivity timeout
+    Inactive,
+
+    /// A ping was not answered within the ping timeout
+    PingTimeout,
+
+    /// The misbehavior score of the peer reached the ban threshold
+    Banned,
+
+    /// The peer was disconnected locally
+    Requested
+}
+
+/// Events produced by the event loop
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Event {
+    /// A message was received from a peer
+    Message(PeerId, Message),
+
+    /// A message from a peer could not be decoded. The peer is still connected.
+    InvalidMessage(PeerId, encode::Error),
+
+    /// A message from a peer exceeded its rate limits and was dropped
+    RateLimited(PeerId, Command),
+
+    /// A message was received out of protocol order and was dropped
+    ProtocolViolation(PeerId, ProtocolViolation),
+
+    /// A peer was disconnected
+    Disconnected(PeerId, DisconnectReason)
+}
+
+/// Messages sent from the reader threads to the event loop
+#[allow(clippy::large_enum_variant)]
+enum ReaderEvent {
+    Message(PeerId, Message),
+    Error(PeerId, encode::Error),
+    Closed(PeerId, Option<encode::Error>)
+}
+
+/// Connection state of a single peer
+#[derive(Debug)]
+struct PeerState {
+    addr: SocketAddr,
+    stream: TcpStream,
+    queue: SyncSender<Message>,
+    queued: Arc<AtomicUsize>,
+    last_seen: Instant,
+    ping: Option<(u64, Instant)>,
+    latency: Latency,
+    limiter: Option<RateLimiter>,
+
+    /// Traffic of the peer, shared with its writer thread
+    traffic: Arc<Mutex<Traffic>>
+}
+
+/// Traffic of a peer and the unix times it was last active
+#[derive(Debug, Default)]
+struct Traffic {
+    stats: Stats,
+    last_recv: Option<Duration>,
+    last_send: Option<Duration>
+}
+
+impl Traffic {
+    fn record(&mut self, direction: Direction, msg: &Message) {
+        self.stats.record_message(direction, msg);
+        match direction {
+            Direction::Inbound => self.last_recv = Some(now()),
+            Direction::Outbound => self.last_send = Some(now())
+        }
+    }
+}
+
+/// Event loop for a set of connected peers
+#[derive(Debug)]
+pub struct EventLoop {
+    magic: Magic,
+    config: EventLoopConfig,
+    peers: HashMap<PeerId, PeerState>,
+    next_id: u64,
+    banman: BanMan,
+    tx: Sender<ReaderEvent>,
+    rx: Receiver<ReaderEvent>,
+
+    /// Traffic of the peers that were disconnected
+    closed: Stats
+}
+
+impl EventLoop {
+    pub fn new(magic: Magic, config: EventLoopConfig) -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            magic,
+            config,
+            peers: HashMap::new(),
+            next_id: 0,
+            banman: BanMan::default(),
+            tx,
+            rx,
+            closed: Stats::new()
+        }
+    }
+
+    /// Replace the ban list used to score peers
+    pub fn set_banman(&mut self, banman: BanMan) {
+        self.banman = banman;
+    }
+
+    pub fn banman(&self) -> &BanMan {
+        &self.banman
+    }
+
+    pub fn banman_mut(&mut self) -> &mut BanMan {
+        &mut self.banman
+    }
+
+    /// Add a connected peer to the loop. The handshake should already be done.
+    /// Peers from banned addresses are rejected.
+    pub fn add_peer(&mut self, stream: PeerStream) -> Result<PeerId, Error> {
+        let addr = stream.get_ref().peer_addr()?;
+        if self.banman.is_banned(&addr.ip()) {
+            let _ = stream.shutdown();
+            return Err(Error::FailedToConnect(format!("{} is banned", addr)))
+        }
+
+        let id = PeerId(self.next_id);
+        self.next_id += 1;
+        let span = tracing::debug_span!("peer", id = id.0, addr = %addr);
+        tracing::debug!(parent: &span, "added peer");
+
+        let mut writer = PeerStream::new(stream.get_ref().try_clone()?, self.magic.clone())?;
+        let (queue, outgoing) = mpsc::sync_channel::<Message>(self.config.send_queue);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let count = queued.clone();
+        let capture = self.config.capture.clone();
+        let hexdump = self.config.hexdump;
+        let traffic = Arc::new(Mutex::new(Traffic::default()));
+        let sent = traffic.clone();
+        let writer_span = span.clone();
+        std::thread::spawn(move || {
+            let _span = writer_span.entered();
+            let mut frame = Vec::new();
+            for msg in outgoing {
+                count.fetch_sub(1, Ordering::Relaxed);
+
+                // The reader notices the broken connection and closes the peer
+                if let Err(e) = writer.send_message(&msg) {
+                    tracing::debug!(command = msg.header().command.to_str(), error = %e, "send failed");
+                    return
+                }
+                // Only messages that were written are recorded, from the payload encoded for the write
+                if capture.is_some() || hexdump {
+                    frame.clear();
+                    frame.extend_from_slice(&msg.header().to_bytes());
+                    frame.extend_from_slice(writer.last_payload());
+                    record_raw(id, Direction::Outbound, &frame, capture.as_ref(), hexdump);
+                }
+                sent.lock().expect("Traffic lock poisoned").record(Direction::Outbound, &msg);
+            }
+        });
+
+        // Bytes read ahead during the handshake stay in the buffer of the reader
+        let socket = stream.get_ref().try_clone()?;
+        let reader = stream.into_reader();
+        let tx = self.tx.clone();
+        let decode = self.config.decode.clone();
+        let capture = self.config.capture.clone();
+        std::thread::spawn(move || {
+            let _span = span.entered();
+            let raw = (capture.is_some() || hexdump).then(|| Rc::new(RefCell::new(Vec::new())));
+            let mut last_err = None;
+            for res in MessageStream::new(Tee::new(reader, raw.clone()), decode) {
+                if let Some(raw) = &raw {
+                    record_raw(id, Direction::Inbound, &raw.borrow(), capture.as_ref(), hexdump);
+                    raw.borrow_mut().clear();
+                }
+                let event = match res {
+                    Ok(msg) => ReaderEvent::Message(id, msg),
+                    Err(e @ encode::Error::Io(_)) |
+                    Err(e @ encode::Error::BadNetworkMagic(_)) => {
+                        last_err = Some(e);
+                        break
+                    },
+                    Err(e) => {
+                        tracing::debug!(error = %e, "invalid message");
+                        ReaderEvent::Error(id, e)
+                    }
+                };
+                if tx.send(event).is_err() { return }
+            }
+            let _ = tx.send(ReaderEvent::Closed(id, last_err));
+        });
+
+        self.peers.insert(id, PeerState {
+            addr,
+            stream: socket,
+            queue,
+            queued,
+            last_seen: Instant::now(),
+            ping: None,
+            latency: Latency::new(),
+            limiter: self.config.rate_limits.map(RateLimiter::new),
+            traffic
+        });
+        Ok(id)
+    }
+
+    /// Get the ids of the connected peers
+    pub fn peers(&self) -> Vec<PeerId> {
+        let mut ids = self.peers.keys().copied().collect::<Vec<PeerId>>();
+        ids.sort();
+        ids
+    }
+
+    /// Get the address of a connected peer
+    pub fn peer_addr(&self, id: PeerId) -> Option<SocketAddr> {
+        self.peers.get(&id).map(|p| p.addr)
+    }
+
+    /// Get the time since a message was last received from a peer
+    pub fn last_seen(&self, id: PeerId) -> Option<Duration> {
+        self.peers.get(&id).map(|p| p.last_seen.elapsed())
+    }
+
+    /// Get the round trip times of the pings answered by a peer
+    pub fn latency(&self, id: PeerId) -> Option<LatencyStats> {
+        self.peers.get(&id)?.latency.stats()
+    }
+
+    /// Get the traffic received from a peer. Returns `None` for unknown peers or if traffic
+    /// is not limited.
+    pub fn rate_counters(&self, id: PeerId) -> Option<RateCounters> {
+        self.peers.get(&id)?.limiter.as_ref().map(|l| l.counters())
+    }
+
+    /// Get the traffic exchanged with a peer
+    pub fn peer_stats(&self, id: PeerId) -> Option<Stats> {
+        Some(self.peers.get(&id)?.traffic.lock().expect("Traffic lock poisoned").stats.clone())
+    }
+
+    /// Get the metrics of a peer
+    pub fn peer_metrics(&self, id: PeerId) -> Option<PeerMetrics> {
+        let peer = self.peers.get(&id)?;
+        let traffic = peer.traffic.lock().expect("Traffic lock poisoned");
+        Some(PeerMetrics {
+            received: traffic.stats.total_received(),
+            sent: traffic.stats.total_sent(),
+            last_recv: traffic.last_recv,
+            last_send: traffic.last_send,
+            ping: peer.latency.stats(),
+            misbehavior: self.banman.score(&peer.addr.ip())
+        })
+    }
+
+    /// Get the traffic exchanged with every peer since the loop started, including the
+    /// peers that were disconnected
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.closed.clone();
+        for peer in self.peers.values() {
+            stats.merge(&peer.traffic.lock().expect("Traffic lock poisoned").stats);
+        }
+        stats
+    }
+
+    /// Get the number of messages waiting to be written to a peer
+    pub fn queued(&self, id: PeerId) -> Option<usize> {
+        self.peers.get(&id).map(|p| p.queued.load(Ordering::Relaxed))
+    }
+
+    /// Queue a message to a peer, waiting for space if the send queue of the peer is full
+    pub fn send(&self, id: PeerId, msg: NetworkMessage) -> Result<(), Error> {
+        let peer = self.peer(id)?;
+        let msg = Message::from_payload(msg, self.magic.clone());
+
+        peer.queued.fetch_add(1, Ordering::Relaxed);
+        peer.queue.send(msg).map_err(|_| {
+            peer.queued.fetch_sub(1, Ordering::Relaxed);
+            Error::Io(io::ErrorKind::BrokenPipe.into())
+        })
+    }
+
+    /// Queue a message to a peer without waiting.
+    /// Fails with [`Error::QueueFull`] if the send queue of the peer is full.
+    pub fn try_send(&self, id: PeerId, msg: NetworkMessage) -> Result<(), Error> {
+        let peer = self.peer(id)?;
+        let msg = Message::from_payload(msg, self.magic.clone());
+
+        peer.queued.fetch_add(1, Ordering::Relaxed);
+        peer.queue.try_send(msg).map_err(|e| {
+            peer.queued.fetch_sub(1, Ordering::Relaxed);
+            match e {
+                TrySendError::Full(_) => Error::QueueFull(peer.addr.to_string()),
+                TrySendError::Disconnected(_) => Error::Io(io::ErrorKind::BrokenPipe.into())
+            }
+        })
+    }
+
+    fn peer(&self, id: PeerId) -> Result<&PeerState, Error> {
+        self.peers.get(&id).ok_or_else(|| Error::FailedToConnect(format!("unknown peer {:?}", id)))
+    }
+
+    /// Disconnect a peer
+    pub fn disconnect(&mut self, id: PeerId) -> Option<Event> {
+        self.remove(id, DisconnectReason::Requested)
+    }
+
+    /// Record a protocol violation by a peer, ie an unsolicited message.
+    /// Returns the disconnection event if the peer got banned.
+    pub fn misbehaving(&mut self, id: PeerId, kind: Misbehavior) -> Option<Event> {
+        let ip = self.peers.get(&id)?.addr.ip();
+        match self.banman.misbehaving(ip, kind) {
+            true => self.remove(id, DisconnectReason::Banned),
+            false => None
+        }
+    }
+
+    fn remove(&mut self, id: PeerId, reason: DisconnectReason) -> Option<Event> {
+        let peer = self.peers.remove(&id)?;
+        tracing::debug!(id = id.0, addr = %peer.addr, reason = ?reason, "disconnected peer");
+        let _ = peer.stream.shutdown(Shutdown::Both);
+        self.closed.merge(&peer.traffic.lock().expect("Traffic lock poisoned").stats);
+        Some(Event::Disconnected(id, reason))
+    }
+
+    /// Wait up to one tick for messages from the peers and handle the keepalive timers.
+    /// Pings are answered automatically and are also returned as events.
+    pub fn poll(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        let mut next = match self.rx.recv_timeout(self.config.tick) {
+            Ok(e) => Some(e),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => unreachable!("The loop holds a sender")
+        };
+        while let Some(reader_event) = next {
+            self.handle(reader_event, &mut events);
+            next = self.rx.try_recv().ok();
+        }
+
+        events.extend(self.check_timers());
+        events
+    }
+
+    /// Poll the loop until there are no peers left, passing every event to the handler.
+    pub fn run<F>(&mut self, mut handler: F)
+    where F: FnMut(&mut Self, Event) {
+        while !self.peers.is_empty() {
+            for event in self.poll() {
+                handler(self, event)
+            }
+        }
+    }
+
+    fn handle(&mut self, reader_event: ReaderEvent, events: &mut Vec<Event>) {
+        let (id, msg) = match reader_event {
+            ReaderEvent::Message(id, msg) => (id, msg),
+
+            // Invalid messages count towards the misbehavior score of the peer
+            ReaderEvent::Error(id, e) => {
+                let peer = match self.peers.get_mut(&id) {
+                    Some(p) => p,
+                    None => return
+                };
+                peer.last_seen = Instant::now();
+                let kind = Misbehavior::from_error(&e);
+                events.push(Event::InvalidMessage(id, e));
+                if let Some(kind) = kind {
+                    events.extend(self.misbehaving(id, kind));
+                }
+                return
+            },
+            ReaderEvent::Closed(id, e) => return events.extend(self.remove(id, DisconnectReason::Closed(e)))
+        };
+
+        let peer = match self.peers.get_mut(&id) {
+            Some(p) => p,
+            None => return
+        };
+        peer.last_seen = Instant::now();
+        tracing::trace!(id = id.0, addr = %peer.addr, command = msg.header().command.to_str(), length = msg.header().length, "received message");
+        peer.traffic.lock().expect("Traffic lock poisoned").record(Direction::Inbound, &msg);
+        if let Some(limiter) = &mut peer.limiter {
+            if !limiter.check(&msg) {
+                return events.push(Event::RateLimited(id, msg.header().command.clone()))
+            }
+        }
+        if let Err(violation) = handshake::check_order(&msg.header().command, true, true) {
+            events.push(Event::ProtocolViolation(id, violation));
+            return events.extend(self.misbehaving(id, Misbehavior::Unsolicited))
+        }
+
+        let reply = match msg.network_message() {
+            Ok(NetworkMessage::Ping(nonce)) => Some(NetworkMessage::Pong(nonce)),
+            Ok(NetworkMessage::Pong(nonce)) => {
+                if let Some((n, sent)) = peer.ping {
+                    if n == nonce {
+                        peer.latency.record(sent.elapsed());
+                        peer.ping = None
+                    }
+                }
+                None
+            },
+            _ => None
+        };
+        // Pongs are skipped rather than waited for if the queue is full
+        if let Some(reply) = reply {
+            if let Err(Error::Io(_)) = self.try_send(id, reply) {
+                return events.extend(self.remove(id, DisconnectReason::Closed(None)))
+            }
+        }
+
+        events.push(Event::Message(id, msg))
+    }
+
+    fn check_timers(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+        for id in self.peers() {
+            let peer = &self.peers[&id];
+            let idle = peer.last_seen.elapsed();
+
+            if idle >= self.config.inactivity_timeout {
+                events.extend(self.remove(id, DisconnectReason::Inactive));
+            } else if matches!(peer.ping, Some((_, sent)) if sent.elapsed() >= self.config.ping_timeout) {
+                events.extend(self.remove(id, DisconnectReason::PingTimeout));
+            } else if peer.ping.is_none() && idle >= self.config.ping_interval {
+                let nonce = rand::random::<u64>();
+                match self.try_send(id, NetworkMessage::Ping(nonce)) {
+                    Ok(_) => self.peers.get_mut(&id).expect("Peer exists").ping = Some((nonce, Instant::now())),
+                    Err(Error::QueueFull(_)) => {},
+                    Err(_) => events.extend(self.remove(id, DisconnectReason::Closed(None)))
+                }
+            }
+        }
+        events
+    }
+}
+
+/// Record the raw bytes of a message to the capture and log them, as configured
+fn record_raw(id: PeerId, direction: Direction, bytes: &[u8], capture: Option<&Capture>, hexdump: bool) {
+    if let Some(capture) = capture {
+        let _ = capture.record(id, direction, bytes);
+    }
+    if hexdump {
+        tracing::debug!(target: "btcnetmsg::wire", peer = id.0, direction = ?direction, "\n{}", capture::hexdump(bytes));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encode::Encode,
+        net::ratelimit::Limit
+    };
+    use std::{
+        io::Write,
+        net::TcpListener
+    };
+
+    fn pair() -> (PeerStream, PeerStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (PeerStream::new(client, Magic::Main).unwrap(), PeerStream::new(server, Magic::Main).unwrap())
+    }
+
+    #[test]
+    fn keepalive() {
+        let (local, mut remote) = pair();
+        let config = EventLoopConfig {
+            ping_interval: Duration::from_millis(50),
+            inactivity_timeout: Duration::from_secs(10),
+            ping_timeout: Duration::from_millis(500),
+            tick: Duration::from_millis(10),
+            ..EventLoopConfig::default()
+        };
+        let mut ev = EventLoop::new(Magic::Main, config);
+        let id = ev.add_peer(local).unwrap();
+
+        // Pings from the peer are answered
+        remote.send(NetworkMessage::Ping(5)).unwrap();
+        let events = (0..20).flat_map(|_| ev.poll()).collect::<Vec<Event>>();
+        assert!(matches!(&events[0], Event::Message(i, _) if *i == id));
+        let pong = remote.recv().unwrap();
+        assert_eq!(pong.network_message().unwrap(), NetworkMessage::Pong(5));
+
+        // A quiet peer is pinged, then disconnected when it does not answer
+        let ping = remote.recv().unwrap();
+        let nonce = match ping.network_message().unwrap() {
+            NetworkMessage::Ping(n) => n,
+            m => panic!("expected a ping, got {:?}", m)
+        };
+
+        // The pong was counted before the ping was written
+        let stats = ev.peer_stats(id).unwrap();
+        assert_eq!(stats.received[&Command::Ping], crate::net::stats::Counter { messages: 1, bytes: 32 });
+        assert_eq!(stats.sent[&Command::Pong], crate::net::stats::Counter { messages: 1, bytes: 32 });
+
+        // Answering it records a round trip time
+        remote.send(NetworkMessage::Pong(nonce)).unwrap();
+        for _ in 0..50 {
+            if ev.latency(id).is_some() { break }
+            ev.poll();
+        }
+        assert_eq!(ev.latency(id).unwrap().samples, 1);
+        let metrics = ev.peer_metrics(id).unwrap();
+        assert_eq!(metrics.received, crate::net::stats::Counter { messages: 2, bytes: 64 });
+        assert!(metrics.last_recv.is_some() && metrics.last_send.is_some());
+        assert_eq!((metrics.ping, metrics.misbehavior), (ev.latency(id), 0));
+
+        // The next ping goes unanswered
+        let mut disconnected = None;
+        for _ in 0..100 {
+            if let Some(Event::Disconnected(i, reason)) = ev.poll().pop() {
+                disconnected = Some((i, reason));
+                break
+            }
+        }
+        assert!(matches!(disconnected, Some((i, DisconnectReason::PingTimeout)) if i == id));
+        assert!(ev.peers().is_empty());
+    }
+
+    #[test]
+    fn ban_on_bad_checksums() {
+        let (local, remote) = pair();
+        let mut ev = EventLoop::new(Magic::Main, EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() });
+        let id = ev.add_peer(local).unwrap();
+
+        let mut buf = Vec::new();
+        Message::from_payload(NetworkMessage::Ping(1), Magic::Main).net_encode(&mut buf);
+        buf[20] ^= 0xFF;
+        let mut raw = remote.get_ref();
+        for _ in 0..10 {
+            raw.write_all(&buf).unwrap();
+        }
+
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            events.extend(ev.poll());
+            if ev.peers().is_empty() { break }
+        }
+        assert_eq!(events.iter().filter(|e| matches!(e, Event::InvalidMessage(..))).count(), 10);
+        assert!(matches!(events.last(), Some(Event::Disconnected(i, DisconnectReason::Banned)) if *i == id));
+        assert!(ev.banman().is_banned(&"127.0.0.1".parse().unwrap()));
+
+        // Reconnections from the banned address are refused
+        let (local, _remote) = pair();
+        assert!(ev.add_peer(local).is_err());
+    }
+
+    #[test]
+    fn full_send_queue() {
+        let (local, mut remote) = pair();
+        let config = EventLoopConfig { send_queue: 2, ..EventLoopConfig::default() };
+        let mut ev = EventLoop::new(Magic::Main, config);
+        let id = ev.add_peer(local).unwrap();
+
+        // Fill the socket buffers of the peer which never reads, until the queue backs up
+        let block = NetworkMessage::Raw { command: Command::Unknown(String::from("junk")), payload: vec![0; 1_000_000] };
+        let mut full = false;
+        for _ in 0..1000 {
+            match ev.try_send(id, block.clone()) {
+                Err(Error::QueueFull(_)) => { full = true; break },
+                res => res.unwrap()
+            }
+        }
+        assert!(full);
+        assert!(ev.queued(id).unwrap() <= 2);
+
+        // Reading from the other end drains the queue
+        let _ = remote.recv().unwrap();
+        ev.send(id, NetworkMessage::Verack).unwrap();
+    }
+
+    #[test]
+    fn message_rate_limit() {
+        let (local, mut remote) = pair();
+        let limits = RateLimits { messages: Limit::new(0.0, 3.0), ..RateLimits::default() };
+        let config = EventLoopConfig { tick: Duration::from_millis(10), rate_limits: Some(limits), ..EventLoopConfig::default() };
+        let mut ev = EventLoop::new(Magic::Main, config);
+        let id = ev.add_peer(local).unwrap();
+
+        for _ in 0..5 {
+            remote.send(NetworkMessage::SendHeaders).unwrap();
+        }
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            events.extend(ev.poll());
+            if events.len() == 5 { break }
+        }
+        assert_eq!(events.iter().filter(|e| matches!(e, Event::Message(..))).count(), 3);
+        assert_eq!(events.iter().filter(|e| matches!(e, Event::RateLimited(_, Command::SendHeaders))).count(), 2);
+
+        let counters = ev.rate_counters(id).unwrap();
+        assert_eq!((counters.messages, counters.dropped_messages, counters.bytes), (3, 2, 3 * 24));
+    }
+
+    #[test]
+    fn version_after_handshake() {
+        let (local, mut remote) = pair();
+        let config = EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() };
+        let mut ev = EventLoop::new(Magic::Main, config);
+        let id = ev.add_peer(local).unwrap();
+
+        remote.send(NetworkMessage::Verack).unwrap();
+        remote.send(NetworkMessage::Ping(1)).unwrap();
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            events.extend(ev.poll());
+            if events.len() == 2 { break }
+        }
+        assert!(matches!(&events[0], Event::ProtocolViolation(i, ProtocolViolation::DuplicateVerack) if *i == id));
+        assert!(matches!(&events[1], Event::Message(_, msg) if msg.header().command == Command::Ping));
+    }
+
+    #[test]
+    fn capture_sent_messages() {
+        let path = std::env::temp_dir().join(format!("btcnetmsg-capture-{}.bin", rand::random::<u32>()));
+        let (local, mut remote) = pair();
+        let config = EventLoopConfig { capture: Some(Capture::create(&path).unwrap()), ..EventLoopConfig::default() };
+        let mut ev = EventLoop::new(Magic::Main, config);
+        let id = ev.add_peer(local).unwrap();
+
+        // The message is recorded once it has been written, as the frame the peer received
+        ev.send(id, NetworkMessage::Ping(7)).unwrap();
+        let ping = remote.recv().unwrap();
+        let mut records = Vec::new();
+        for _ in 0..100 {
+            records = capture::CaptureReader::open(&path).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+            if !records.is_empty() { break }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let mut frame = Vec::new();
+        ping.net_encode(&mut frame);
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].peer, records[0].direction, &records[0].bytes), (id, Direction::Outbound, &frame));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+// handshake.rs
+//
+// Blocking version handshake for both sides of a connection.
+//
+//    Initiator:                     Responder:
+//      -> version
+//                                     <- version
+//                                     <- verack
+//      -> verack
+//
+// Nothing but a version may be sent before the version, and only feature negotiation
+// messages may be sent between the version and the verack. Messages out of this order
+// fail the handshake with a protocol violation.
+//
+// The handshake is done on a `PeerStream`, which is handed on afterwards so that messages
+// read ahead of the verack are not lost.
+//
+
+use crate::{
+    msg::{
+        data::NetworkMessage,
+        header::Command,
+        network::VersionMessage
+    },
+    net::{
+        stream::PeerStream,
+        Error
+    }
+};
+use std::time::{
+    Duration,
+    Instant
+};
+
+/// Default time allowed for a handshake to complete
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Message received out of the order required by the protocol
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProtocolViolation {
+    /// A message other than `version` was received before the version of the peer
+    BeforeVersion(Command),
+
+    /// A second `version` message was received
+    DuplicateVersion,
+
+    /// A second `verack` message was received
+    DuplicateVerack,
+
+    /// A message other than a feature negotiation was received before the verack of the peer
+    BeforeVerack(Command)
+}
+
+impl std::fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BeforeVersion(c) => write!(f, "{} received before version", c),
+            Self::DuplicateVersion => write!(f, "duplicate version message"),
+            Self::DuplicateVerack => write!(f, "duplicate verack message"),
+            Self::BeforeVerack(c) => write!(f, "{} received before verack", c)
+        }
+    }
+}
+
+/// Check that a message may be received, given whether the version and verack of the
+/// peer were already received.
+pub fn check_order(command: &Command, version: bool, verack: bool) -> Result<(), ProtocolViolation> {
+    match command {
+        Command::Version if version => Err(ProtocolViolation::DuplicateVersion),
+        Command::Version => Ok(()),
+        c if !version => Err(ProtocolViolation::BeforeVersion(c.clone())),
+        Command::Verack if verack => Err(ProtocolViolation::DuplicateVerack),
+        _ if verack => Ok(()),
+
+        // Unknown messages are allowed so that future negotiation messages can be added
+        Command::Verack |
+        Command::SendHeaders |
+        Command::WTxIdRelay |
+        Command::SendCmpct |
+        Command::SendAddrV2 |
+        Command::Unknown(_) => Ok(()),
+        c => Err(ProtocolViolation::BeforeVerack(c.clone()))
+    }
+}
+
+/// Do the handshake as the side that opened the connection.
+/// Returns the version message of the peer.
+pub fn initiate(stream: &mut PeerStream, version: VersionMessage, timeout: Duration) -> Result<VersionMessage, Error> {
+    with_timeout(stream, "initiate", timeout, |stream| {
+        stream.send(NetworkMessage::Version(version))?;
+        finish(stream, None)
+    })
+}
+
+/// Do the handshake as the side that accepted the connection.
+/// Returns the version message of the peer.
+pub fn respond(stream: &mut PeerStream, version: VersionMessage, timeout: Duration) -> Result<VersionMessage, Error> {
+    with_timeout(stream, "respond", timeout, |stream| {
+        // The peer has to speak first
+        let theirs = match stream.recv_network_message()? {
+            NetworkMessage::Version(v) => v,
+            msg => return Err(Error::ProtocolViolation(ProtocolViolation::BeforeVersion(msg.command())))
+        };
+        stream.send(NetworkMessage::Version(version))?;
+        stream.send(NetworkMessage::Verack)?;
+        finish(stream, Some(theirs))
+    })
+}
+
+/// Read messages until both the version and verack of the peer were received
+fn finish(stream: &mut PeerStream, mut theirs: Option<VersionMessage>) -> Result<VersionMessage, Error> {
+    let responder = theirs.is_some();
+    let mut verack = false;
+    while theirs.is_none() || !verack {
+        let msg = stream.recv_network_message()?;
+        check_order(&msg.command(), theirs.is_some(), verack).map_err(Error::ProtocolViolation)?;
+        match msg {
+            NetworkMessage::Version(v) => {
+                theirs = Some(v);
+                if !responder {
+                    stream.send(NetworkMessage::Verack)?;
+                }
+            },
+            NetworkMessage::Verack => verack = true,
+
+            // Feature negotiation messages may be sent before the verack
+            _ => continue
+        }
+    }
+
+    Ok(theirs.expect("Version is set above"))
+}
+
+/// Run a function with read and write timeouts set on the stream, in a span of the handshake
+fn with_timeout<F>(stream: &mut PeerStream, side: &'static str, timeout: Duration, f: F) -> Result<VersionMessage, Error>
+where F: FnOnce(&mut PeerStream) -> Result<VersionMessage, Error> {
+    let peer = stream.get_ref().peer_addr().map(|a| a.to_string()).unwrap_or_default();
+    let _span = tracing::debug_span!("handshake", side, peer = %peer).entered();
+    let start = Instant::now();
+
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let res = f(stream);
+    let elapsed_ms = start.elapsed().as_millis() as u64;
+    match &res {
+        Ok(theirs) => tracing::debug!(elapsed_ms, version = theirs.version, "handshake complete"),
+        Err(e) => tracing::debug!(elapsed_ms, error = %e, "handshake failed")
+    }
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    res
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        msg::header::Magic
+    };
+    use std::net::{
+        TcpListener,
+        TcpStream
+    };
+
+    #[test]
+    fn out_of_order_messages() {
+        assert_eq!(check_order(&Command::Ping, false, false), Err(ProtocolViolation::BeforeVersion(Command::Ping)));
+        assert_eq!(check_order(&Command::Version, true, false), Err(ProtocolViolation::DuplicateVersion));
+        assert_eq!(check_order(&Command::Inv, true, false), Err(ProtocolViolation::BeforeVerack(Command::Inv)));
+        assert_eq!(check_order(&Command::WTxIdRelay, true, false), Ok(()));
+        assert_eq!(check_order(&Command::Inv, true, true), Ok(()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = PeerStream::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap(), Magic::Main).unwrap();
+        let mut server = PeerStream::new(listener.accept().unwrap().0, Magic::Main).unwrap();
+        client.send(NetworkMessage::GetAddr).unwrap();
+
+        let res = respond(&mut server, VersionMessage::from(Address::me()), HANDSHAKE_TIMEOUT);
+        assert!(matches!(res, Err(Error::ProtocolViolation(ProtocolViolation::BeforeVersion(Command::GetAddr)))));
+    }
+}
@@ -0,0 +1,85 @@
+// latency.rs
+//
+// Round trip times of pings sent to a peer.
+//
+
+use std::{
+    collections::VecDeque,
+    time::Duration
+};
+
+/// Number of recent samples kept per peer
+pub const MAX_SAMPLES: usize = 32;
+
+/// Summary of the recent round trip times of a peer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub min: Duration,
+    pub avg: Duration,
+    pub p95: Duration,
+    pub samples: usize
+}
+
+/// Recent round trip time samples of a peer
+#[derive(Clone, Debug, Default)]
+pub struct Latency {
+    samples: VecDeque<Duration>
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a round trip time, dropping the oldest sample if there are too many
+    pub fn record(&mut self, rtt: Duration) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    /// Get the most recent round trip time
+    pub fn last(&self) -> Option<Duration> {
+        self.samples.back().copied()
+    }
+
+    /// Summarise the recorded samples. Returns `None` if there are none.
+    pub fn stats(&self) -> Option<LatencyStats> {
+        if self.samples.is_empty() { return None }
+
+        let mut sorted = self.samples.iter().copied().collect::<Vec<Duration>>();
+        sorted.sort();
+
+        // Nearest rank percentile
+        let rank = (sorted.len() * 95).div_ceil(100);
+        Some(LatencyStats {
+            min: sorted[0],
+            avg: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            p95: sorted[rank - 1],
+            samples: sorted.len()
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats() {
+        let mut latency = Latency::new();
+        assert_eq!(latency.stats(), None);
+
+        for ms in (1..=40).rev() {
+            latency.record(Duration::from_millis(ms));
+        }
+        let stats = latency.stats().unwrap();
+        assert_eq!(stats.samples, MAX_SAMPLES);
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.avg, Duration::from_micros(16_500));
+        assert_eq!(stats.p95, Duration::from_millis(31));
+        assert_eq!(latency.last(), Some(Duration::from_millis(1)));
+    }
+}
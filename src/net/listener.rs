@@ -0,0 +1,136 @@
+// listener.rs
+//
+// Listener accepting inbound peer connections.
+//
+
+use crate::{
+    address::Address,
+    msg::{
+        header::Magic,
+        network::{
+            VersionConfig,
+            VersionMessage
+        }
+    },
+    net::{
+        handshake,
+        stream::PeerStream,
+        Error
+    }
+};
+use std::{
+    net::{
+        SocketAddr,
+        TcpListener
+    },
+    sync::mpsc::{
+        self,
+        Receiver
+    },
+    time::Duration
+};
+
+/// Peer that connected to the listener and completed the handshake
+#[derive(Debug)]
+pub struct InboundPeer {
+    pub stream: PeerStream,
+    pub addr: SocketAddr,
+    pub version: VersionMessage
+}
+
+/// Listener for inbound connections
+#[derive(Debug)]
+pub struct Listener {
+    inner: TcpListener,
+    magic: Magic,
+    timeout: Duration,
+    version: VersionConfig
+}
+
+impl Listener {
+    /// Bind the listener to an address. Use port 0 to pick a free port.
+    pub fn bind(addr: SocketAddr, magic: Magic) -> Result<Self, Error> {
+        Ok(Self {
+            inner: TcpListener::bind(addr)?,
+            magic,
+            timeout: handshake::HANDSHAKE_TIMEOUT,
+            version: VersionConfig::default()
+        })
+    }
+
+    /// The address the listener is bound to
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.inner.local_addr()?)
+    }
+
+    /// Set the time allowed for inbound peers to complete the handshake
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set the fields of the version message sent to inbound peers
+    pub fn set_version(&mut self, version: VersionConfig) {
+        self.version = version;
+    }
+
+    /// Wait for the next inbound connection and complete the handshake with it
+    pub fn accept(&self) -> Result<InboundPeer, Error> {
+        let (stream, addr) = self.inner.accept()?;
+        let mut stream = PeerStream::new(stream, self.magic.clone())?;
+        let version = self.version.message(Address::from(addr));
+        match handshake::respond(&mut stream, version, self.timeout) {
+            Ok(version) => Ok(InboundPeer { stream, addr, version }),
+            Err(e) => {
+                let _ = stream.shutdown();
+                Err(e)
+            }
+        }
+    }
+
+    /// Accept connections on a background thread.
+    /// Peers that complete the handshake are sent over the returned channel, the others are dropped.
+    /// The thread stops once the receiver is dropped and another connection comes in.
+    pub fn spawn(self) -> Receiver<InboundPeer> {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || loop {
+            match self.accept() {
+                Ok(peer) => if tx.send(peer).is_err() { return },
+                Err(Error::Io(e)) if e.kind() != std::io::ErrorKind::WouldBlock &&
+                                     e.kind() != std::io::ErrorKind::ConnectionAborted &&
+                                     e.kind() != std::io::ErrorKind::TimedOut &&
+                                     e.kind() != std::io::ErrorKind::UnexpectedEof &&
+                                     e.kind() != std::io::ErrorKind::ConnectionReset => return,
+                Err(_) => continue
+            }
+        });
+        rx
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_handshake() {
+        let mut listener = Listener::bind("127.0.0.1:0".parse().unwrap(), Magic::Main).unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_version(VersionConfig {
+            agent: crate::msg::UserAgent::new(String::from("/listener:0.1/")).unwrap(),
+            start_height: 100,
+            ..VersionConfig::default()
+        });
+
+        let client = std::thread::spawn(move || {
+            let mut stream = PeerStream::new(std::net::TcpStream::connect(addr).unwrap(), Magic::Main).unwrap();
+            handshake::initiate(&mut stream, VersionMessage::from(Address::me()), Duration::from_secs(5)).unwrap()
+        });
+
+        let peer = listener.accept().unwrap();
+        assert_eq!(peer.version.agent.as_str(), "bit-tune-v0.0.1");
+        let theirs = client.join().unwrap();
+        assert_eq!(theirs.addr_recv.address.port(), peer.addr.port());
+        assert_eq!((theirs.agent.as_str(), theirs.start_height, theirs.relay), ("/listener:0.1/", 100, Some(true)));
+    }
+}
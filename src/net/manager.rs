@@ -0,0 +1,1605 @@
+// manager.rs
+//
+// Connection manager owning the state shared between peer connections.
+//
+
+use crate::{
+    address::Address,
+    blockdata::params::ChainParams,
+    encode,
+    msg::{
+        data::{
+            Message,
+            MessagePayload,
+            NetworkMessage
+        },
+        header::Command,
+        inventory::Inventory,
+        network::{
+            NetAddress,
+            ServicesList,
+            TimestampedNetAddress,
+            VersionConfig,
+            VersionMessage
+        }
+    },
+    net::{
+        addrman::{
+            address_group,
+            now,
+            AddrMan
+        },
+        addrrelay::{
+            AddrRelay,
+            RelayConfig
+        },
+        anchors::{
+            Anchors,
+            MAX_ANCHORS
+        },
+        banman::Misbehavior,
+        crawl::Census,
+        eventloop::{
+            DisconnectReason,
+            Event,
+            EventLoop,
+            EventLoopConfig,
+            PeerId
+        },
+        handshake,
+        latency::LatencyStats,
+        listener::{
+            InboundPeer,
+            Listener
+        },
+        stats::PeerMetrics,
+        stream::{
+            socks5_connect,
+            PeerStream,
+            Target
+        },
+        tip::{
+            TipTracker,
+            STALE_TIP_AGE
+        },
+        Error
+    }
+};
+use std::{
+    collections::{
+        HashMap,
+        HashSet
+    },
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        SocketAddr,
+        TcpStream,
+        ToSocketAddrs
+    },
+    path::PathBuf,
+    sync::mpsc::{
+        self,
+        Receiver,
+        Sender
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Configuration for the connection manager
+#[derive(Clone, Debug)]
+pub struct ManagerConfig {
+    /// Parameters of the network, giving the magic of the messages and the default port of
+    /// peers given without one
+    pub network: ChainParams,
+
+    /// File the known peers are saved to. Peers are not persisted if unset.
+    pub peers_file: Option<PathBuf>,
+
+    /// File the anchor peers are saved to on shutdown. No anchors are kept if unset.
+    pub anchors_file: Option<PathBuf>,
+
+    /// How often the known peers are written to the peers file
+    pub flush_interval: Duration,
+
+    /// SOCKS5 proxy all outbound connections are made through, ie Tor
+    pub proxy: Option<SocketAddr>,
+
+    /// Timeout for outbound connections that are not made through the proxy
+    pub connect_timeout: Duration,
+
+    /// Address to accept inbound connections on. Inbound connections are not accepted if unset.
+    pub listen: Option<SocketAddr>,
+
+    /// Maximum number of inbound peers
+    pub max_inbound: usize,
+
+    /// Refuse outbound connections to a netgroup another outbound peer is already in
+    pub diverse_netgroups: bool,
+
+    /// Timings of the event loop driving the peers
+    pub eventloop: EventLoopConfig,
+
+    /// Delays between reconnect attempts. Dropped peers are not reconnected to if unset.
+    pub reconnect: Option<Backoff>,
+
+    /// Time without a new block after which an extra outbound peer is connected to
+    pub stale_tip_age: Duration,
+
+    /// How often a feeler connection is made. No feelers are made if unset.
+    pub feeler_interval: Option<Duration>,
+
+    /// Address the node can be reached at. If unset, the address outbound peers see the
+    /// node at is used when listening.
+    pub external_addr: Option<Address>,
+
+    /// How often the external address is advertised to the peers. The node is not advertised
+    /// if unset, ie for passive listening.
+    pub advertise_interval: Option<Duration>,
+
+    /// Settings for relaying gossiped addresses. Addresses are not relayed if unset.
+    pub addr_relay: Option<RelayConfig>,
+
+    /// Ask peers to announce new blocks with their headers instead of an `inv`
+    pub send_headers: bool,
+
+    /// Fields of the version messages sent to peers
+    pub version: VersionConfig,
+
+    /// Peers connected to on the first poll, ie from `--connect` or `--addnode` of bit-tune.
+    /// They are not subject to the netgroup diversity check, so several peers on a LAN can be used.
+    pub connect: Vec<PeerTarget>,
+
+    /// Only connect to the peers of `connect`. Peers from the address manager and the anchors
+    /// are never connected to, and no feelers are made.
+    pub connect_only: bool
+}
+
+impl Default for ManagerConfig {
+    fn default() -> Self {
+        Self {
+            network: ChainParams::main(),
+            peers_file: None,
+            anchors_file: None,
+            flush_interval: Duration::from_secs(15 * 60),
+            proxy: None,
+            connect_timeout: Duration::from_secs(5),
+            listen: None,
+            max_inbound: 117,
+            diverse_netgroups: true,
+            eventloop: EventLoopConfig::default(),
+            reconnect: Some(Backoff::default()),
+            stale_tip_age: STALE_TIP_AGE,
+            feeler_interval: Some(Duration::from_secs(2 * 60)),
+            external_addr: None,
+            advertise_interval: Some(Duration::from_secs(24 * 60 * 60)),
+            addr_relay: Some(RelayConfig::default()),
+            send_headers: true,
+            version: VersionConfig::default(),
+            connect: Vec::new(),
+            connect_only: false
+        }
+    }
+}
+
+impl ManagerConfig {
+    /// Default configuration for the network of the parameters
+    pub fn for_network(params: &ChainParams) -> Self {
+        Self {
+            network: params.clone(),
+            ..Self::default()
+        }
+    }
+
+    /// Parse a peer, using the default port of the network if none is given
+    pub fn target(&self, s: &str) -> Result<PeerTarget, encode::Error> {
+        PeerTarget::parse(s, self.network.default_port)
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Result<TcpStream, Error> {
+        match self.proxy {
+            Some(proxy) => socks5_connect(proxy, &Target::Ip(addr)),
+            None => TcpStream::connect_timeout(&addr, self.connect_timeout)
+                .map_err(|_| Error::FailedToConnect(addr.to_string()))
+        }
+    }
+
+    fn connect_address(&self, addr: &Address) -> Result<TcpStream, Error> {
+        let start = Instant::now();
+        let res = match (addr.socket_addr(), self.proxy, addr.onion_host()) {
+            (Some(socket), _, _) => self.connect(socket),
+            (None, Some(proxy), Some(host)) => socks5_connect(proxy, &Target::Domain(host, addr.port)),
+            (None, None, Some(_)) => Err(Error::FailedToConnect(format!("{} requires a proxy", addr))),
+            _ => Err(Error::FailedToConnect(format!("{} cannot be dialled", addr)))
+        };
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+        match &res {
+            Ok(_) => tracing::debug!(addr = %addr, elapsed_ms, proxy = self.proxy.is_some(), "connected"),
+            Err(e) => tracing::debug!(addr = %addr, elapsed_ms, error = %e, "connect failed")
+        }
+        res
+    }
+
+    /// Connect to an address, do the handshake and disconnect again
+    fn feel(&self, addr: &Address) -> Result<(), Error> {
+        let mut stream = PeerStream::new(self.connect_address(addr)?, self.network.magic.clone())?;
+        handshake::initiate(&mut stream, self.version.message(*addr), handshake::HANDSHAKE_TIMEOUT)?;
+        let _ = stream.shutdown();
+        Ok(())
+    }
+}
+
+/// Peer given by host name or IP address
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PeerTarget {
+    pub host: String,
+    pub port: u16
+}
+
+impl PeerTarget {
+    /// Parse a "host", "host:port" or "[ipv6]:port" target, using the default port of the
+    /// network if none is given. Bare IPv6 addresses are taken as hosts without a port.
+    pub fn parse(s: &str, default_port: u16) -> Result<Self, encode::Error> {
+        let (host, port) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (host, port) = rest.split_once(']').ok_or(encode::Error::InvalidData)?;
+                match port {
+                    "" => (host, None),
+                    port => (host, Some(port.strip_prefix(':').ok_or(encode::Error::InvalidData)?))
+                }
+            },
+            None => match s.split_once(':') {
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                _ => (s, None)
+            }
+        };
+        if host.is_empty() { return Err(encode::Error::InvalidData) }
+
+        let port = match port {
+            Some(port) => port.parse::<u16>().map_err(|_| encode::Error::InvalidData)?,
+            None => default_port
+        };
+        Ok(Self { host: host.to_string(), port })
+    }
+}
+
+impl std::fmt::Display for PeerTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Exponential backoff with jitter
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// Delay before the first attempt
+    pub initial: Duration,
+
+    /// Longest delay between attempts
+    pub max: Duration,
+
+    /// Failed attempts after which a different peer is tried
+    pub max_attempts: u32
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10 * 60),
+            max_attempts: 5
+        }
+    }
+}
+
+impl Backoff {
+    /// Get the delay before an attempt, which doubles with every failed attempt.
+    /// The delay is randomised between half and all of it so peers are not all retried at once.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.initial
+            .checked_mul(1 << attempt.min(31))
+            .map_or(self.max, |d| d.min(self.max));
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+}
+
+/// Scheduled attempt to reconnect to a dropped peer
+#[derive(Clone, Debug)]
+struct Reconnect {
+    addr: Address,
+    attempts: u32,
+    at: Instant
+}
+
+/// A peer connected through the manager
+#[derive(Clone, Debug)]
+pub struct ConnectedPeer {
+    pub addr: Address,
+    pub inbound: bool,
+    pub version: VersionMessage,
+
+    /// Only blocks and headers are exchanged with the peer
+    pub block_relay_only: bool,
+
+    /// When the peer last announced a new block
+    pub last_block: Option<Instant>,
+
+    /// Unix time the handshake completed
+    pub connected_at: Duration
+}
+
+/// Statistics of the session of a connection manager, also returned on shutdown
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionStats {
+    /// Time since the manager was created
+    pub uptime: Duration,
+
+    /// Number of connected peers, or of the peers connected at shutdown
+    pub connected: usize,
+
+    /// Number of known addresses
+    pub known_addrs: usize,
+
+    /// Census of every peer the handshake completed with
+    pub census: Census
+}
+
+impl std::fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "uptime {}s, {} peers connected, {} known addresses", self.uptime.as_secs(), self.connected, self.known_addrs)?;
+        write!(f, "{}", self.census)
+    }
+}
+
+/// Information about a connected peer, similar to `getpeerinfo` of Bitcoin Core
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub id: PeerId,
+    pub addr: Address,
+
+    /// Our address as seen by the peer
+    pub addr_local: Address,
+    pub inbound: bool,
+
+    /// Protocol version, services, user agent and start height from the peer's version message
+    pub version: i32,
+    pub services: ServicesList,
+    pub user_agent: String,
+    pub start_height: i32,
+
+    /// Whether the peer wants transactions relayed. Peers that do not send the flag do.
+    pub relay: bool,
+
+    /// Only blocks and headers are exchanged with the peer
+    pub block_relay_only: bool,
+
+    /// Unix time the handshake completed
+    pub connected_at: Duration,
+
+    /// Average ping round trip time
+    pub ping: Option<Duration>,
+
+    /// Traffic, activity, latency and misbehavior of the peer
+    pub metrics: PeerMetrics
+}
+
+/// How a message from a block-relay-only peer is handled
+enum BlockRelay {
+    Keep,
+    Ignore,
+    Violation
+}
+
+fn block_relay(msg: &Message) -> BlockRelay {
+    match (&msg.header().command, msg.payload()) {
+        (Command::Tx, _) |
+        (Command::MemPool, _) => BlockRelay::Violation,
+        (_, MessagePayload::InvVect(inv)) => match inv.iter().any(|i| matches!(i, Inventory::Tx(_) | Inventory::WitnessTx(_))) {
+            true => BlockRelay::Violation,
+            false => BlockRelay::Keep
+        },
+        (Command::Version, _) |
+        (Command::Verack, _) |
+        (Command::Ping, _) |
+        (Command::Pong, _) |
+        (Command::SendHeaders, _) |
+        (Command::SendCmpct, _) |
+        (Command::CmpctBlock, _) |
+        (Command::GetBlockTxn, _) |
+        (Command::BlockTxn, _) |
+        (Command::GetBlocks, _) |
+        (Command::GetHeaders, _) |
+        (Command::Headers, _) |
+        (Command::Block, _) => BlockRelay::Keep,
+        _ => BlockRelay::Ignore
+    }
+}
+
+/// Events delivered to the subscriber of a connection manager
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum PeerEvent {
+    /// A connection was opened, before the handshake
+    Connected { addr: Address, inbound: bool },
+
+    /// The handshake with a peer completed and it was added to the event loop
+    HandshakeComplete { peer: PeerId, addr: Address, version: VersionMessage },
+
+    /// A message was received from a peer
+    Message { peer: PeerId, msg: Message },
+
+    /// A peer was disconnected
+    Disconnected { peer: PeerId, reason: DisconnectReason },
+
+    /// A peer violated the protocol and its misbehavior score was raised
+    Misbehaved { peer: PeerId, kind: Misbehavior }
+}
+
+/// Connection manager
+#[derive(Debug)]
+pub struct ConnectionManager {
+    config: ManagerConfig,
+    addrman: AddrMan,
+    last_flush: Instant,
+    eventloop: EventLoop,
+    peers: HashMap<PeerId, ConnectedPeer>,
+    local_addr: Option<SocketAddr>,
+    inbound: Option<Receiver<InboundPeer>>,
+    reconnects: Vec<Reconnect>,
+    subscriber: Option<Sender<PeerEvent>>,
+    tip: TipTracker,
+    extra_peer: Option<PeerId>,
+    last_stale_check: Instant,
+    last_feeler: Instant,
+    feeler: Option<Receiver<(Address, bool)>>,
+    anchors: Vec<Address>,
+    last_advertise: Instant,
+    addr_relay: Option<AddrRelay>,
+    census: Census,
+    started: Instant,
+    stopped: bool,
+
+    /// Addresses of the configured peers, which skip the netgroup check
+    manual: HashSet<Address>,
+    targets: Vec<PeerTarget>
+}
+
+impl ConnectionManager {
+    /// Create a connection manager, loading the known peers from the peers file if it exists.
+    /// Starts listening for inbound connections if a listen address is configured.
+    pub fn new(config: ManagerConfig) -> Result<Self, Error> {
+        let addrman = match &config.peers_file {
+            Some(path) if path.exists() => AddrMan::load(path)?,
+            _ => AddrMan::new()
+        };
+
+        // Anchors that cannot be read are dropped rather than failing the startup
+        let anchors = match &config.anchors_file {
+            Some(path) if path.exists() => Anchors::take(path).map(|a| a.0).unwrap_or_default(),
+            _ => Vec::new()
+        };
+
+        let (local_addr, inbound) = match config.listen {
+            Some(addr) => {
+                let mut listener = Listener::bind(addr, config.network.magic.clone())?;
+                listener.set_version(config.version.clone());
+                (Some(listener.local_addr()?), Some(listener.spawn()))
+            },
+            None => (None, None)
+        };
+
+        Ok(Self {
+            eventloop: EventLoop::new(config.network.magic.clone(), config.eventloop.clone()),
+            addr_relay: config.addr_relay.map(AddrRelay::new),
+            targets: config.connect.clone(),
+            config,
+            addrman,
+            last_flush: Instant::now(),
+            peers: HashMap::new(),
+            local_addr,
+            inbound,
+            reconnects: Vec::new(),
+            subscriber: None,
+            tip: TipTracker::new(),
+            extra_peer: None,
+            last_stale_check: Instant::now(),
+            last_feeler: Instant::now(),
+            feeler: None,
+            anchors,
+            last_advertise: Instant::now(),
+            census: Census::default(),
+            started: Instant::now(),
+            stopped: false,
+            manual: HashSet::new()
+        })
+    }
+
+    /// Subscribe to the events of the manager, replacing any earlier subscription.
+    /// Once subscribed, `poll`, `disconnect` and `misbehaving` deliver their events over the
+    /// channel instead of returning them.
+    pub fn subscribe(&mut self) -> Receiver<PeerEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscriber = Some(tx);
+        rx
+    }
+
+    /// Send an event to the subscriber, dropping the subscription if the receiver is gone
+    fn emit(&mut self, event: PeerEvent) {
+        if let Some(tx) = &self.subscriber {
+            if tx.send(event).is_err() {
+                self.subscriber = None;
+            }
+        }
+    }
+
+    /// The address inbound connections are accepted on
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn eventloop(&self) -> &EventLoop {
+        &self.eventloop
+    }
+
+    pub fn eventloop_mut(&mut self) -> &mut EventLoop {
+        &mut self.eventloop
+    }
+
+    /// Get a connected peer
+    pub fn peer(&self, id: PeerId) -> Option<&ConnectedPeer> {
+        self.peers.get(&id)
+    }
+
+    /// Iterate over the connected peers
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &ConnectedPeer)> {
+        self.peers.iter()
+    }
+
+    /// Get information about a connected peer
+    pub fn peer_info(&self, id: PeerId) -> Option<PeerInfo> {
+        let peer = self.peers.get(&id)?;
+        Some(PeerInfo {
+            id,
+            addr: peer.addr,
+            addr_local: peer.version.addr_recv.address,
+            inbound: peer.inbound,
+            version: peer.version.version,
+            services: peer.version.service.clone(),
+            user_agent: peer.version.agent.as_str().to_string(),
+            start_height: peer.version.start_height,
+            relay: peer.version.relay.unwrap_or(true),
+            block_relay_only: peer.block_relay_only,
+            connected_at: peer.connected_at,
+            ping: self.latency(id).map(|l| l.avg),
+            metrics: self.peer_metrics(id).unwrap_or_default()
+        })
+    }
+
+    /// Get information about all connected peers, sorted by id
+    pub fn peer_infos(&self) -> Vec<PeerInfo> {
+        let mut ids = self.peers.keys().copied().collect::<Vec<PeerId>>();
+        ids.sort();
+        ids.into_iter().filter_map(|id| self.peer_info(id)).collect()
+    }
+
+    /// Census of the version messages of every peer the handshake completed with
+    pub fn census(&self) -> &Census {
+        &self.census
+    }
+
+    /// Get the ping latency of a peer
+    pub fn latency(&self, id: PeerId) -> Option<LatencyStats> {
+        self.eventloop.latency(id)
+    }
+
+    /// Get the metrics of a peer
+    pub fn peer_metrics(&self, id: PeerId) -> Option<PeerMetrics> {
+        self.eventloop.peer_metrics(id)
+    }
+
+    /// Get the connected peer with the lowest average latency.
+    /// Peers without latency samples are only picked if no peer has any.
+    pub fn fastest_peer(&self) -> Option<PeerId> {
+        self.fastest_peer_where(|_| true)
+    }
+
+    fn fastest_peer_where<F>(&self, f: F) -> Option<PeerId>
+    where F: Fn(&ConnectedPeer) -> bool {
+        self.peers
+            .iter()
+            .filter(|(_, p)| f(p))
+            .map(|(id, _)| *id)
+            .min_by_key(|id| match self.latency(*id) {
+                Some(stats) => (false, stats.avg, *id),
+                None => (true, Duration::from_secs(0), *id)
+            })
+    }
+
+    /// Request data from the connected peer with the lowest latency.
+    /// Transactions are not requested from block-relay-only peers.
+    /// Returns the peer the request was sent to.
+    pub fn request_data(&self, inv: Vec<Inventory>) -> Result<PeerId, Error> {
+        let txs = inv.iter().any(|i| matches!(i, Inventory::Tx(_) | Inventory::WitnessTx(_)));
+        let id = self.fastest_peer_where(|p| !(txs && p.block_relay_only))
+            .ok_or_else(|| Error::FailedToConnect(String::from("no connected peers")))?;
+        self.eventloop.send(id, NetworkMessage::GetData(inv))?;
+        Ok(id)
+    }
+
+    /// Get the best tip announced by the peers
+    pub fn tip(&self) -> &TipTracker {
+        &self.tip
+    }
+
+    /// Get the extra outbound peer connected to because of a stale tip
+    pub fn extra_peer(&self) -> Option<PeerId> {
+        self.extra_peer
+    }
+
+    /// Get the netgroups of the connected outbound peers
+    pub fn outbound_groups(&self) -> HashSet<Vec<u8>> {
+        self.peers
+            .values()
+            .filter(|p| !p.inbound)
+            .map(|p| address_group(&p.addr))
+            .collect()
+    }
+
+    /// Select an address from the address manager to make an outbound connection to.
+    /// Addresses that are connected to or in the netgroup of an outbound peer are skipped.
+    /// Returns `None` if only the configured peers are connected to.
+    pub fn select_outbound(&self) -> Option<Address> {
+        if self.config.connect_only { return None }
+        let groups = self.outbound_groups();
+        for _ in 0..50 {
+            let addr = self.addrman.select(false)?.address;
+            if self.peers.values().any(|p| p.addr == addr) { continue }
+            if self.config.diverse_netgroups && groups.contains(&address_group(&addr)) { continue }
+            return Some(addr)
+        }
+        None
+    }
+
+    /// Number of connected inbound peers
+    pub fn inbound_count(&self) -> usize {
+        self.peers.values().filter(|p| p.inbound).count()
+    }
+
+    /// Number of connected outbound peers
+    pub fn outbound_count(&self) -> usize {
+        self.peers.values().filter(|p| !p.inbound).count()
+    }
+
+    /// Connect to a peer, do the handshake and add it to the event loop.
+    /// The result of the attempt is recorded in the address manager.
+    pub fn connect_outbound(&mut self, addr: &Address) -> Result<PeerId, Error> {
+        self.open_outbound(addr, false)
+    }
+
+    /// Connect to a peer that only blocks and headers are exchanged with
+    pub fn connect_block_relay(&mut self, addr: &Address) -> Result<PeerId, Error> {
+        self.open_outbound(addr, true)
+    }
+
+    /// Connect to a peer by host name, resolving it through the proxy if one is configured.
+    /// The peer is reconnected to like other outbound peers.
+    pub fn connect_target(&mut self, target: &PeerTarget) -> Result<PeerId, Error> {
+        let addr = self.resolve_target(target)?;
+        self.manual.insert(addr);
+        self.connect_outbound(&addr)
+    }
+
+    fn resolve_target(&self, target: &PeerTarget) -> Result<Address, Error> {
+        match (target.host.parse::<IpAddr>(), self.config.proxy) {
+            (Ok(ip), _) => Ok(Address::from(SocketAddr::new(ip, target.port))),
+            (Err(_), None) => (target.host.as_str(), target.port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .map(Address::from)
+                .ok_or_else(|| Error::FailedToConnect(target.to_string())),
+
+            // Host names are not resolved locally with a proxy, so only onion hosts work
+            (Err(_), Some(_)) => format!("{}:{}", target.host, target.port).parse::<Address>()
+                .map_err(|_| Error::FailedToConnect(format!("{} can not be resolved through the proxy", target)))
+        }
+    }
+
+    /// Connect to the peers of the configuration.
+    /// Peers that fail to connect are retried like dropped peers, if their host resolved.
+    fn connect_targets(&mut self) {
+        for target in std::mem::take(&mut self.targets) {
+            let addr = match self.resolve_target(&target) {
+                Ok(addr) => addr,
+                Err(_) => continue
+            };
+
+            // Targets resolving to a peer that is already connected are not connected twice
+            if !self.manual.insert(addr) && self.peers.values().any(|p| p.addr == addr) { continue }
+            if self.connect_outbound(&addr).is_err() {
+                self.schedule_reconnect(addr, 1);
+            }
+        }
+    }
+
+    fn open_outbound(&mut self, addr: &Address, block_relay_only: bool) -> Result<PeerId, Error> {
+        let _span = tracing::debug_span!("outbound", addr = %addr, block_relay_only).entered();
+        if self.config.diverse_netgroups && !self.manual.contains(addr) && self.outbound_groups().contains(&address_group(addr)) {
+            return Err(Error::FailedToConnect(format!("{} shares a netgroup with an outbound peer", addr)))
+        }
+        self.addrman.attempt(addr);
+
+        let mut stream = PeerStream::new(self.connect_address(addr)?, self.config.network.magic.clone())?;
+        self.emit(PeerEvent::Connected { addr: *addr, inbound: false });
+        let mut ours = self.config.version.message(*addr);
+        if block_relay_only {
+            ours.relay = Some(false);
+        }
+        let version = handshake::initiate(&mut stream, ours, handshake::HANDSHAKE_TIMEOUT)?;
+        let peer = ConnectedPeer { addr: *addr, inbound: false, version, block_relay_only, last_block: None, connected_at: now() };
+        let id = self.add_peer(stream, peer)?;
+
+        self.addrman.good(addr);
+        Ok(id)
+    }
+
+    /// Disconnect a peer without reconnecting to it
+    pub fn disconnect(&mut self, id: PeerId) -> Option<Event> {
+        self.peers.remove(&id);
+        let event = self.eventloop.disconnect(id)?;
+        self.deliver(event)
+    }
+
+    /// Record a protocol violation by a peer.
+    /// Returns the disconnection event if the peer got banned.
+    pub fn misbehaving(&mut self, id: PeerId, kind: Misbehavior) -> Option<Event> {
+        if !self.peers.contains_key(&id) { return None }
+        self.emit(PeerEvent::Misbehaved { peer: id, kind });
+
+        let event = self.eventloop.misbehaving(id, kind)?;
+        self.peers.remove(&id);
+        self.deliver(event)
+    }
+
+    /// Number of dropped peers waiting to be reconnected to
+    pub fn pending_reconnects(&self) -> usize {
+        self.reconnects.len()
+    }
+
+    /// Add a peer that already completed the handshake to the event loop
+    fn add_peer(&mut self, stream: PeerStream, peer: ConnectedPeer) -> Result<PeerId, Error> {
+        let id = self.eventloop.add_peer(stream)?;
+        self.census.add(&peer.version);
+        if self.config.send_headers {
+            let _ = self.eventloop.try_send(id, NetworkMessage::SendHeaders);
+        }
+        self.tip.on_version(&peer.version);
+        if let Some(relay) = self.addr_relay.as_mut().filter(|_| !peer.block_relay_only) {
+            relay.add_peer(id);
+        }
+        self.emit(PeerEvent::HandshakeComplete { peer: id, addr: peer.addr, version: peer.version.clone() });
+        self.peers.insert(id, peer);
+        Ok(id)
+    }
+
+    /// Add the inbound peers accepted since the last poll, dropping them if there are too many
+    fn accept_inbound(&mut self) {
+        let accepted = match &self.inbound {
+            Some(rx) => rx.try_iter().collect::<Vec<InboundPeer>>(),
+            None => return
+        };
+
+        for peer in accepted {
+            if self.inbound_count() >= self.config.max_inbound {
+                let _ = peer.stream.shutdown();
+                continue
+            }
+            // The listener does the handshake before handing the peer over
+            self.emit(PeerEvent::Connected { addr: Address::from(peer.addr), inbound: true });
+            let _ = self.add_peer(peer.stream, ConnectedPeer {
+                addr: Address::from(peer.addr),
+                inbound: true,
+                version: peer.version,
+                block_relay_only: false,
+                last_block: None,
+                connected_at: now()
+            });
+        }
+    }
+
+    /// Get the routable address the node can be reached at, if any
+    pub fn external_address(&self) -> Option<Address> {
+        if let Some(addr) = self.config.external_addr {
+            return Some(addr).filter(|a| a.is_routable())
+        }
+
+        // Peers only know the address of the connection, not the port being listened on
+        let port = self.local_addr?.port();
+        self.peers
+            .values()
+            .filter(|p| !p.inbound)
+            .map(|p| Address { port, ..p.version.addr_recv.address })
+            .find(|a| a.is_routable())
+    }
+
+    /// Advertise the external address to all peers.
+    /// Returns the number of peers it was sent to.
+    pub fn advertise(&mut self) -> usize {
+        self.last_advertise = Instant::now();
+        let addr = match self.external_address() {
+            Some(a) => a,
+            None => return 0
+        };
+
+        let entry = TimestampedNetAddress::new(now(), NetAddress::new(ServicesList::default(), addr));
+        self.peers
+            .iter()
+            .filter(|(_, p)| !p.block_relay_only)
+            .filter(|(id, _)| self.eventloop.try_send(**id, NetworkMessage::Addr(vec![entry.clone()])).is_ok())
+            .count()
+    }
+
+    /// Get the anchors loaded at startup that were not connected to yet
+    pub fn pending_anchors(&self) -> &[Address] {
+        &self.anchors
+    }
+
+    /// Get the most recently connected block-relay-only peers, which are kept as anchors
+    pub fn anchors(&self) -> Anchors {
+        let mut outbound = self.peers
+            .iter()
+            .filter(|(_, p)| p.block_relay_only)
+            .collect::<Vec<(&PeerId, &ConnectedPeer)>>();
+        outbound.sort_by_key(|(id, _)| std::cmp::Reverse(**id));
+        Anchors(outbound.iter().take(MAX_ANCHORS).map(|(_, p)| p.addr).collect())
+    }
+
+    /// Connect to the anchors loaded at startup. Anchors that fail are not retried.
+    fn connect_anchors(&mut self) {
+        let anchors = std::mem::take(&mut self.anchors);
+        if self.config.connect_only { return }
+        for addr in anchors {
+            let _ = self.connect_block_relay(&addr);
+        }
+    }
+
+    /// Accept inbound peers, poll the event loop and run the periodic tasks.
+    /// The first poll connects to the anchor and configured peers.
+    pub fn poll(&mut self) -> Vec<Event> {
+        self.connect_anchors();
+        self.connect_targets();
+        self.accept_inbound();
+
+        let events = self.eventloop.poll();
+        let mut events = self.filter_block_relay(events);
+        let mut new_block = false;
+        for event in &events {
+            match event {
+                Event::Message(id, msg) => match msg.payload() {
+                    MessagePayload::AddrList(addrs) |
+                    MessagePayload::AddrV2List(addrs) => self.on_addr(*id, addrs),
+                    _ if self.tip.on_message(msg) => {
+                        new_block = true;
+                        if let Some(peer) = self.peers.get_mut(id) {
+                            peer.last_block = Some(Instant::now());
+                        }
+                    },
+                    _ => {}
+                },
+                Event::Disconnected(id, reason) => {
+                    let peer = match self.peers.remove(id) {
+                        Some(p) => p,
+                        None => continue
+                    };
+                    if self.extra_peer == Some(*id) {
+                        self.extra_peer = None;
+                    }
+                    if let Some(relay) = &mut self.addr_relay {
+                        relay.remove_peer(*id);
+                    }
+
+                    // Banned and locally disconnected peers are not reconnected to
+                    if !peer.inbound && !matches!(reason, DisconnectReason::Banned | DisconnectReason::Requested) {
+                        self.schedule_reconnect(peer.addr, 0);
+                    }
+                },
+                _ => {}
+            }
+        }
+        self.relay_addrs();
+        self.reconnect();
+        events.extend(self.check_stale_tip(new_block));
+        self.check_feeler();
+        if matches!(self.config.advertise_interval, Some(interval) if self.last_advertise.elapsed() >= interval) {
+            self.advertise();
+        }
+
+        // Failing to write the peers file should not stop the connections
+        let _ = self.tick();
+
+        events.into_iter().filter_map(|e| self.deliver(e)).collect()
+    }
+
+    /// Drop the messages from block-relay-only peers that are not about blocks.
+    /// Peers that send transactions are scored as misbehaving.
+    fn filter_block_relay(&mut self, events: Vec<Event>) -> Vec<Event> {
+        let mut kept = Vec::with_capacity(events.len());
+        for event in events {
+            let id = match &event {
+                Event::Message(id, msg) if matches!(self.peers.get(id), Some(p) if p.block_relay_only) => match block_relay(msg) {
+                    BlockRelay::Keep => { kept.push(event); continue },
+                    BlockRelay::Ignore => continue,
+                    BlockRelay::Violation => *id
+                },
+                _ => { kept.push(event); continue }
+            };
+            kept.extend(self.misbehaving(id, Misbehavior::Unsolicited));
+        }
+        kept
+    }
+
+    /// Send an event of the loop to the subscriber.
+    /// Returns the event back if there is no subscriber.
+    fn deliver(&mut self, event: Event) -> Option<Event> {
+        if self.subscriber.is_none() { return Some(event) }
+        match event {
+            Event::Message(peer, msg) => self.emit(PeerEvent::Message { peer, msg }),
+            Event::InvalidMessage(peer, e) => {
+                if let Some(kind) = Misbehavior::from_error(&e) {
+                    self.emit(PeerEvent::Misbehaved { peer, kind })
+                }
+            },
+            Event::ProtocolViolation(peer, _) => self.emit(PeerEvent::Misbehaved { peer, kind: Misbehavior::Unsolicited }),
+            Event::Disconnected(peer, reason) => self.emit(PeerEvent::Disconnected { peer, reason }),
+            Event::RateLimited(..) => {}
+        }
+        None
+    }
+
+    /// Start a feeler connection to an address from the new table of the address manager.
+    /// Only one feeler runs at a time. Returns the address being tested.
+    pub fn start_feeler(&mut self) -> Option<Address> {
+        if self.feeler.is_some() { return None }
+        self.last_feeler = Instant::now();
+
+        let addr = self.addrman.select(true)?.address;
+        if self.peers.values().any(|p| p.addr == addr) { return None }
+        self.addrman.attempt(&addr);
+
+        let (tx, rx) = mpsc::channel();
+        let config = self.config.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send((addr, config.feel(&addr).is_ok()));
+        });
+        self.feeler = Some(rx);
+        Some(addr)
+    }
+
+    /// Check if a feeler connection is running
+    pub fn feeler_running(&self) -> bool {
+        self.feeler.is_some()
+    }
+
+    /// Record the result of the running feeler and start a new one when it is due
+    fn check_feeler(&mut self) {
+        if let Some(rx) = &self.feeler {
+            match rx.try_recv() {
+                Ok((addr, success)) => {
+                    if success {
+                        self.addrman.good(&addr);
+                    }
+                    self.feeler = None;
+                },
+                Err(mpsc::TryRecvError::Empty) => return,
+                Err(mpsc::TryRecvError::Disconnected) => self.feeler = None
+            }
+        }
+
+        if self.config.connect_only { return }
+        if matches!(self.config.feeler_interval, Some(interval) if self.last_feeler.elapsed() >= interval) {
+            self.start_feeler();
+        }
+    }
+
+    /// Add gossiped addresses to the address manager and schedule them to be relayed
+    fn on_addr(&mut self, id: PeerId, addrs: &[TimestampedNetAddress]) {
+        let source = match self.peers.get(&id) {
+            Some(p) => p.addr.ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            None => return
+        };
+        self.addrman.add(addrs, source);
+
+        let peers = self.peers.keys().copied().collect::<Vec<PeerId>>();
+        if let Some(relay) = &mut self.addr_relay {
+            relay.on_addr(id, addrs, &peers);
+        }
+    }
+
+    /// Send the addresses that are due to be relayed.
+    /// Only IP addresses are relayed, as `addr` messages cannot hold other networks.
+    fn relay_addrs(&mut self) {
+        let due = match &mut self.addr_relay {
+            Some(relay) => relay.due(),
+            None => return
+        };
+        for (id, addrs) in due {
+            let addrs = addrs
+                .into_iter()
+                .filter(|a| a.netaddress.address.ip().is_some())
+                .collect::<Vec<TimestampedNetAddress>>();
+            if !addrs.is_empty() {
+                let _ = self.eventloop.try_send(id, NetworkMessage::Addr(addrs));
+            }
+        }
+    }
+
+    /// Connect to an extra outbound peer if the tip is stale, and disconnect an outbound
+    /// peer again once a new block was announced.
+    fn check_stale_tip(&mut self, new_block: bool) -> Option<Event> {
+        if self.extra_peer.is_some() {
+            if !new_block { return None }
+            self.extra_peer = None;
+
+            // Peers that never announced a block sort first. The last outbound peer is kept.
+            if self.outbound_count() < 2 { return None }
+            let worst = self.peers
+                .iter()
+                .filter(|(_, p)| !p.inbound)
+                .min_by_key(|(id, p)| (p.last_block, **id))
+                .map(|(id, _)| *id)?;
+            return self.disconnect(worst)
+        }
+
+        let age = self.config.stale_tip_age;
+        if !self.tip.is_stale(age) || self.last_stale_check.elapsed() < age { return None }
+        self.last_stale_check = Instant::now();
+
+        let addr = self.select_outbound()?;
+        self.extra_peer = self.connect_outbound(&addr).ok();
+        None
+    }
+
+    fn schedule_reconnect(&mut self, addr: Address, attempts: u32) {
+        if let Some(backoff) = self.config.reconnect {
+            self.reconnects.push(Reconnect { addr, attempts, at: Instant::now() + backoff.delay(attempts) });
+        }
+    }
+
+    /// Run the reconnect attempts that are due. After too many failed attempts the peer is
+    /// given up on and a fresh peer is selected from the address manager instead.
+    fn reconnect(&mut self) {
+        let backoff = match self.config.reconnect {
+            Some(b) => b,
+            None => return
+        };
+
+        let now = Instant::now();
+        let (due, waiting) = self.reconnects.drain(..).partition(|r| r.at <= now);
+        self.reconnects = waiting;
+
+        for r in due {
+            if self.connect_outbound(&r.addr).is_ok() { continue }
+
+            if r.attempts + 1 < backoff.max_attempts {
+                self.schedule_reconnect(r.addr, r.attempts + 1);
+            } else if let Some(fresh) = self.select_outbound() {
+                self.schedule_reconnect(fresh, 0);
+            }
+        }
+    }
+
+    pub fn config(&self) -> &ManagerConfig {
+        &self.config
+    }
+
+    pub fn addrman(&self) -> &AddrMan {
+        &self.addrman
+    }
+
+    pub fn addrman_mut(&mut self) -> &mut AddrMan {
+        &mut self.addrman
+    }
+
+    /// Open a connection to a peer, through the proxy if one is configured
+    pub fn connect(&self, addr: SocketAddr) -> Result<TcpStream, Error> {
+        self.config.connect(addr)
+    }
+
+    /// Open a connection to an address.
+    /// Onion addresses require a proxy. I2P and CJDNS addresses cannot be dialled.
+    pub fn connect_address(&self, addr: &Address) -> Result<TcpStream, Error> {
+        self.config.connect_address(addr)
+    }
+
+    /// Open a connection to a peer by host name.
+    /// With a proxy the host name is resolved by the proxy so no DNS requests leak.
+    pub fn connect_host(&self, host: &str, port: u16) -> Result<TcpStream, Error> {
+        if let Some(proxy) = self.config.proxy {
+            return socks5_connect(proxy, &Target::Domain(host.to_string(), port))
+        }
+
+        let target = format!("{}:{}", host, port);
+        let addr = target
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or(Error::FailedToConnect(target))?;
+        self.connect(addr)
+    }
+
+    /// Write the known peers to the peers file
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.last_flush = Instant::now();
+        match &self.config.peers_file {
+            Some(path) => self.addrman.save(path),
+            None => Ok(())
+        }
+    }
+
+    /// Shut the manager down: stop accepting and reconnecting to peers, save the anchors and
+    /// known peers, and disconnect every peer.
+    /// Unlike dropping the manager, persistence errors are returned. `bit-tune listen` calls it
+    /// when interrupted by SIGINT or SIGTERM.
+    pub fn shutdown(mut self) -> Result<SessionStats, Error> {
+        self.stopped = true;
+        self.inbound = None;
+        self.reconnects.clear();
+        self.feeler = None;
+
+        // Anchors are taken from the connected peers, so they are saved first
+        let stats = self.session_stats();
+        let anchors = self.config.anchors_file.clone().map(|path| self.anchors().save(path));
+        let ids = self.peers.keys().copied().collect::<Vec<PeerId>>();
+        for id in ids {
+            self.disconnect(id);
+        }
+        anchors.transpose()?;
+        self.flush()?;
+        Ok(stats)
+    }
+
+    /// Statistics of the session so far
+    pub fn session_stats(&self) -> SessionStats {
+        SessionStats {
+            uptime: self.started.elapsed(),
+            connected: self.peers.len(),
+            known_addrs: self.addrman.len(),
+            census: self.census.clone()
+        }
+    }
+
+    /// Run periodic tasks. Should be called regularly, ie once per event loop poll.
+    pub fn tick(&mut self) -> Result<(), Error> {
+        if self.last_flush.elapsed() >= self.config.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionManager {
+    fn drop(&mut self) {
+        if self.stopped { return }
+        let _ = self.flush();
+        if let Some(path) = &self.config.anchors_file {
+            let _ = self.anchors().save(path);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{
+        inventory::Hash,
+        network::{
+            NetAddress,
+            ServicesList,
+            TimestampedNetAddress
+        }
+    };
+    use std::net::SocketAddr;
+
+    #[test]
+    fn inbound_and_outbound() {
+        let config = ManagerConfig { listen: Some("127.0.0.1:0".parse().unwrap()), ..ManagerConfig::default() };
+        let mut server = ConnectionManager::new(config).unwrap();
+        let mut client = ConnectionManager::new(ManagerConfig::default()).unwrap();
+
+        let server_addr = Address::from(server.local_addr().unwrap());
+        let id = client.connect_outbound(&server_addr).unwrap();
+        assert_eq!(client.outbound_count(), 1);
+        assert!(!client.peer(id).unwrap().inbound);
+
+        let info = client.peer_info(id).unwrap();
+        assert_eq!(info.addr, server_addr);
+        assert_eq!(info.user_agent, "bit-tune-v0.0.1");
+        assert_eq!(info.version, 70015);
+        assert!(info.relay);
+        assert!(info.connected_at.as_secs() > 0);
+
+        // Messages queued after the handshake may be written in between
+        let infos = client.peer_infos();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(PeerInfo { metrics: info.metrics, ..infos[0].clone() }, info);
+
+        for _ in 0..50 {
+            if server.inbound_count() > 0 { break }
+            server.poll();
+        }
+        assert_eq!(server.inbound_count(), 1);
+        let info = server.peer_infos().pop().unwrap();
+        assert!(info.inbound);
+        assert_eq!(info.addr_local, server_addr);
+    }
+
+    #[test]
+    fn reconnect_dropped_peer() {
+        let config = ManagerConfig { listen: Some("127.0.0.1:0".parse().unwrap()), ..ManagerConfig::default() };
+        let mut server = ConnectionManager::new(config).unwrap();
+        let config = ManagerConfig {
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            reconnect: Some(Backoff { initial: Duration::from_millis(20), ..Backoff::default() }),
+            ..ManagerConfig::default()
+        };
+        let mut client = ConnectionManager::new(config).unwrap();
+        let first = client.connect_outbound(&Address::from(server.local_addr().unwrap())).unwrap();
+
+        while server.inbound_count() == 0 {
+            server.poll();
+        }
+        let inbound = *server.peers().next().unwrap().0;
+        server.disconnect(inbound);
+
+        for _ in 0..200 {
+            client.poll();
+            if client.outbound_count() == 1 && client.peer(first).is_none() { break }
+        }
+        assert_eq!(client.outbound_count(), 1);
+        assert!(client.peer(first).is_none());
+        assert_eq!(client.pending_reconnects(), 0);
+    }
+
+    #[test]
+    fn subscribe_events() {
+        let config = ManagerConfig {
+            listen: Some("127.0.0.1:0".parse().unwrap()),
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            ..ManagerConfig::default()
+        };
+        let mut server = ConnectionManager::new(config).unwrap();
+        let events = server.subscribe();
+        let mut client = ConnectionManager::new(ManagerConfig::default()).unwrap();
+        let id = client.connect_outbound(&Address::from(server.local_addr().unwrap())).unwrap();
+
+        while server.inbound_count() == 0 {
+            assert!(server.poll().is_empty());
+        }
+        assert!(matches!(events.try_recv(), Ok(PeerEvent::Connected { inbound: true, .. })));
+        let peer = match events.try_recv() {
+            Ok(PeerEvent::HandshakeComplete { peer, .. }) => peer,
+            e => panic!("unexpected event {:?}", e)
+        };
+
+        client.eventloop().send(id, NetworkMessage::Ping(9)).unwrap();
+        while events.try_recv().is_err() {
+            server.poll();
+        }
+
+        assert!(server.misbehaving(peer, Misbehavior::Other(100)).is_none());
+        assert!(matches!(events.try_recv(), Ok(PeerEvent::Misbehaved { kind: Misbehavior::Other(100), .. })));
+        assert!(matches!(events.try_recv(), Ok(PeerEvent::Disconnected { reason: DisconnectReason::Banned, .. })));
+        assert_eq!(server.inbound_count(), 0);
+    }
+
+    #[test]
+    fn extra_peer_on_stale_tip() {
+        let config = ManagerConfig { listen: Some("127.0.0.1:0".parse().unwrap()), ..ManagerConfig::default() };
+        let mut server = ConnectionManager::new(config).unwrap();
+        let config = ManagerConfig {
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            stale_tip_age: Duration::from_millis(50),
+            ..ManagerConfig::default()
+        };
+        let mut client = ConnectionManager::new(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        let gossip = TimestampedNetAddress::new(crate::net::addrman::now(), NetAddress::new(ServicesList::default(), addr.into()));
+        client.addrman_mut().add(&[gossip], "1.1.1.1".parse().unwrap());
+
+        for _ in 0..20 {
+            if client.extra_peer().is_some() { break }
+            client.poll();
+        }
+        assert!(client.extra_peer().is_some());
+
+        // A block announcement ends the stale tip. The extra peer is kept as the only outbound peer.
+        while server.inbound_count() == 0 {
+            server.poll();
+        }
+        let inbound = *server.peers().next().unwrap().0;
+        let inv = vec![Inventory::Block(crate::msg::inventory::BlockHash::from_inner([2; 32]))];
+        server.eventloop().send(inbound, NetworkMessage::Inv(inv)).unwrap();
+        for _ in 0..50 {
+            if client.extra_peer().is_none() { break }
+            client.poll();
+        }
+        assert!(client.extra_peer().is_none());
+        assert_eq!(client.outbound_count(), 1);
+        assert!(!client.tip().is_stale(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn feeler_moves_to_tried() {
+        let config = ManagerConfig { listen: Some("127.0.0.1:0".parse().unwrap()), ..ManagerConfig::default() };
+        let mut server = ConnectionManager::new(config).unwrap();
+        let config = ManagerConfig {
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            feeler_interval: Some(Duration::from_millis(20)),
+            ..ManagerConfig::default()
+        };
+        let mut client = ConnectionManager::new(config).unwrap();
+        let addr = Address::from(server.local_addr().unwrap());
+        let gossip = TimestampedNetAddress::new(crate::net::addrman::now(), NetAddress::new(ServicesList::default(), addr));
+        client.addrman_mut().add(&[gossip], "1.1.1.1".parse().unwrap());
+        assert_eq!(client.addrman().len_new(), 1);
+
+        for _ in 0..100 {
+            server.poll();
+            client.poll();
+            if client.addrman().len_tried() == 1 { break }
+        }
+        assert_eq!(client.addrman().len_tried(), 1);
+        assert!(client.addrman().get(&addr).unwrap().last_success.is_some());
+
+        // Feelers do not become peers
+        assert_eq!(client.outbound_count(), 0);
+    }
+
+    #[test]
+    fn anchors_reconnected_on_restart() {
+        let config = ManagerConfig { listen: Some("127.0.0.1:0".parse().unwrap()), ..ManagerConfig::default() };
+        let server = ConnectionManager::new(config).unwrap();
+        let addr = Address::from(server.local_addr().unwrap());
+
+        let path = std::env::temp_dir().join(format!("btcnetmsg-anchors-{}.json", rand::random::<u32>()));
+        let config = ManagerConfig {
+            anchors_file: Some(path.clone()),
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            ..ManagerConfig::default()
+        };
+        {
+            let mut client = ConnectionManager::new(config.clone()).unwrap();
+            client.connect_block_relay(&addr).unwrap();
+            assert_eq!(client.anchors(), Anchors(vec![addr]));
+        }
+
+        let mut client = ConnectionManager::new(config).unwrap();
+        assert_eq!(client.pending_anchors(), &[addr]);
+        assert!(!path.exists());
+        client.poll();
+        assert!(client.pending_anchors().is_empty());
+        assert!(client.peer_infos()[0].block_relay_only);
+
+        // Shutting down keeps the anchors even though every peer is disconnected
+        let stats = client.shutdown().unwrap();
+        assert_eq!((stats.connected, stats.census.reachable), (1, 1));
+        assert_eq!(Anchors::take(&path).unwrap(), Anchors(vec![addr]));
+    }
+
+    #[test]
+    fn outbound_netgroups() {
+        let listen = |_| {
+            let config = ManagerConfig { listen: Some("127.0.0.1:0".parse().unwrap()), ..ManagerConfig::default() };
+            ConnectionManager::new(config).unwrap()
+        };
+        let servers = (0..2).map(listen).collect::<Vec<ConnectionManager>>();
+        let addrs = servers.iter().map(|s| Address::from(s.local_addr().unwrap())).collect::<Vec<Address>>();
+
+        let mut client = ConnectionManager::new(ManagerConfig::default()).unwrap();
+        client.connect_outbound(&addrs[0]).unwrap();
+        assert!(client.outbound_groups().contains(&address_group(&addrs[1])));
+        assert!(client.connect_outbound(&addrs[1]).is_err());
+
+        let mut client = ConnectionManager::new(ManagerConfig { diverse_netgroups: false, ..ManagerConfig::default() }).unwrap();
+        client.connect_outbound(&addrs[0]).unwrap();
+        client.connect_outbound(&addrs[1]).unwrap();
+        assert_eq!(client.outbound_count(), 2);
+
+        // Configured peers skip the netgroup check
+        let targets = addrs.iter().map(|a| PeerTarget::parse(&a.to_string(), 8333).unwrap()).collect();
+        let mut client = ConnectionManager::new(ManagerConfig { connect: targets, ..ManagerConfig::default() }).unwrap();
+        client.poll();
+        assert_eq!(client.outbound_count(), 2);
+
+        // Only the configured peers are used when connecting to them only
+        let config = ManagerConfig {
+            connect: vec![PeerTarget::parse(&addrs[0].to_string(), 8333).unwrap()],
+            connect_only: true,
+            diverse_netgroups: false,
+            feeler_interval: Some(Duration::ZERO),
+            ..ManagerConfig::default()
+        };
+        let mut client = ConnectionManager::new(config).unwrap();
+        let gossip = TimestampedNetAddress::new(now(), NetAddress::new(ServicesList::default(), addrs[1]));
+        client.addrman_mut().add(&[gossip], IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(client.addrman().len(), 1);
+        client.poll();
+        assert_eq!(client.outbound_count(), 1);
+        assert_eq!(client.select_outbound(), None);
+        assert!(!client.feeler_running());
+    }
+
+    #[test]
+    fn parse_targets() {
+        let target = |host: &str, port| PeerTarget { host: host.to_string(), port };
+        assert_eq!(PeerTarget::parse("node.lan", 18444).unwrap(), target("node.lan", 18444));
+        assert_eq!(PeerTarget::parse("node.lan:8333", 18444).unwrap(), target("node.lan", 8333));
+        assert_eq!(PeerTarget::parse("[::1]:8333", 18444).unwrap(), target("::1", 8333));
+        assert_eq!(PeerTarget::parse("[::1]", 18444).unwrap(), target("::1", 18444));
+        assert_eq!(PeerTarget::parse("2001:db8::1", 8333).unwrap().to_string(), "[2001:db8::1]:8333");
+        assert!(PeerTarget::parse("node.lan:port", 8333).is_err());
+        assert!(PeerTarget::parse(":8333", 8333).is_err());
+
+        // Peers given without a port use the port of the configured network
+        let config = ManagerConfig::for_network(&ChainParams::regtest());
+        assert_eq!(config.network.magic, crate::msg::header::Magic::Test);
+        assert_eq!(config.target("node.lan").unwrap(), target("node.lan", 18444));
+        assert_eq!(ManagerConfig::default().target("node.lan").unwrap(), target("node.lan", 8333));
+    }
+
+    #[test]
+    fn advertise_external_address() {
+        let config = ManagerConfig {
+            listen: Some("127.0.0.1:0".parse().unwrap()),
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            ..ManagerConfig::default()
+        };
+        let mut server = ConnectionManager::new(config).unwrap();
+        let external: Address = "20.1.2.3:8333".parse().unwrap();
+        let config = ManagerConfig {
+            external_addr: Some(external),
+            advertise_interval: Some(Duration::from_millis(20)),
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            ..ManagerConfig::default()
+        };
+        let mut client = ConnectionManager::new(config).unwrap();
+        client.connect_outbound(&Address::from(server.local_addr().unwrap())).unwrap();
+
+        let mut advertised = None;
+        for _ in 0..50 {
+            client.poll();
+            for event in server.poll() {
+                if let Event::Message(_, msg) = event {
+                    if let Ok(NetworkMessage::Addr(addrs)) = msg.network_message() {
+                        advertised = Some(addrs);
+                    }
+                }
+            }
+            if advertised.is_some() { break }
+        }
+        let addrs = advertised.unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].netaddress.address, external);
+
+        // Loopback addresses are never advertised
+        assert_eq!(server.external_address(), None);
+        assert_eq!(server.advertise(), 0);
+    }
+
+    #[test]
+    fn relay_gossip() {
+        let config = ManagerConfig {
+            listen: Some("127.0.0.1:0".parse().unwrap()),
+            diverse_netgroups: false,
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            addr_relay: Some(RelayConfig { max_delay: Duration::from_millis(0), ..RelayConfig::default() }),
+            ..ManagerConfig::default()
+        };
+        let mut hub = ConnectionManager::new(config).unwrap();
+        let hub_addr = Address::from(hub.local_addr().unwrap());
+        let config = ManagerConfig {
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            ..ManagerConfig::default()
+        };
+        let mut a = ConnectionManager::new(config.clone()).unwrap();
+        let mut b = ConnectionManager::new(config).unwrap();
+        let a_id = a.connect_outbound(&hub_addr).unwrap();
+        b.connect_outbound(&hub_addr).unwrap();
+        while hub.inbound_count() < 2 {
+            hub.poll();
+        }
+
+        // Timestamps are sent as whole seconds
+        let timestamp = Duration::from_secs(now().as_secs());
+        let gossip = TimestampedNetAddress::new(timestamp, NetAddress::new(ServicesList::default(), "20.1.2.3:8333".parse().unwrap()));
+        a.eventloop().send(a_id, NetworkMessage::Addr(vec![gossip.clone()])).unwrap();
+        for _ in 0..20 {
+            hub.poll();
+            if hub.addrman().len() == 1 { break }
+        }
+        assert!(hub.addrman().get(&gossip.netaddress.address).is_some());
+
+        // The address reaches b but is not echoed back to a
+        let received = |m: &mut ConnectionManager| (0..20)
+            .flat_map(|_| m.poll())
+            .any(|e| match e {
+                Event::Message(_, msg) => matches!(msg.network_message(), Ok(NetworkMessage::Addr(a)) if a == vec![gossip.clone()]),
+                _ => false
+            });
+        assert!(received(&mut b));
+        assert!(!received(&mut a));
+    }
+
+    #[test]
+    fn block_relay_only() {
+        let config = ManagerConfig {
+            listen: Some("127.0.0.1:0".parse().unwrap()),
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            ..ManagerConfig::default()
+        };
+        let mut server = ConnectionManager::new(config).unwrap();
+        let config = ManagerConfig {
+            eventloop: EventLoopConfig { tick: Duration::from_millis(10), ..EventLoopConfig::default() },
+            ..ManagerConfig::default()
+        };
+        let mut client = ConnectionManager::new(config).unwrap();
+        let id = client.connect_block_relay(&Address::from(server.local_addr().unwrap())).unwrap();
+        assert!(client.request_data(vec![Inventory::Tx(Hash::from_inner([1; 32]))]).is_err());
+
+        while server.inbound_count() == 0 {
+            server.poll();
+        }
+        let inbound = *server.peers().next().unwrap().0;
+        assert!(!server.peer_info(inbound).unwrap().relay);
+        assert_eq!(server.census().user_agents, vec![(String::from("bit-tune-v0.0.1"), 1)]);
+
+        // Addresses are dropped, blocks are kept and transactions are misbehavior
+        let gossip = TimestampedNetAddress::new(now(), NetAddress::new(ServicesList::default(), "20.1.2.3:8333".parse().unwrap()));
+        server.eventloop().send(inbound, NetworkMessage::Addr(vec![gossip])).unwrap();
+        server.eventloop().send(inbound, NetworkMessage::Inv(vec![Inventory::Block(Hash::from_inner([2; 32]))])).unwrap();
+        server.eventloop().send(inbound, NetworkMessage::Inv(vec![Inventory::Tx(Hash::from_inner([3; 32]))])).unwrap();
+        let mut events = Vec::new();
+        for _ in 0..20 {
+            events.extend(client.poll());
+        }
+        assert_eq!(events.iter().filter(|e| matches!(e, Event::Message(_, m) if m.header().command != Command::SendHeaders)).count(), 1);
+        assert!(client.addrman().is_empty());
+        assert_eq!(client.eventloop().banman().score(&"127.0.0.1".parse().unwrap()), Misbehavior::Unsolicited.score());
+        assert!(client.peer(id).is_some());
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let backoff = Backoff { initial: Duration::from_secs(1), max: Duration::from_secs(60), max_attempts: 5 };
+        for attempt in 0..10 {
+            let expected = Duration::from_secs((1 << attempt).min(60));
+            let delay = backoff.delay(attempt);
+            assert!(delay >= expected / 2 && delay <= expected);
+        }
+        assert!(backoff.delay(100) <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn peers_survive_restart() {
+        let path = std::env::temp_dir().join(format!("btcnetmsg-manager-{}.json", rand::random::<u32>()));
+        let config = ManagerConfig { peers_file: Some(path.clone()), ..ManagerConfig::default() };
+        let addr: SocketAddr = "20.1.2.3:8333".parse().unwrap();
+
+        {
+            let mut manager = ConnectionManager::new(config.clone()).unwrap();
+            let gossip = TimestampedNetAddress::new(crate::net::addrman::now(), NetAddress::new(ServicesList::default(), addr.into()));
+            manager.addrman_mut().add(&[gossip], "1.1.1.1".parse().unwrap());
+        }
+
+        let manager = ConnectionManager::new(config).unwrap();
+        assert!(manager.addrman().get(&addr.into()).is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
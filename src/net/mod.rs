@@ -5,8 +5,74 @@
 
 pub mod peer;
 pub mod stream;
+pub mod eventloop;
+pub mod addrman;
+pub mod manager;
+pub mod banman;
+pub mod handshake;
+pub mod listener;
+pub mod ratelimit;
+pub mod stats;
+pub mod latency;
+pub mod tip;
+pub mod blocks;
+pub mod anchors;
+pub mod addrrelay;
+pub mod crawl;
+pub mod capture;
+pub mod broadcast;
+#[cfg(unix)]
+pub mod control;
+pub mod websocket;
+pub mod testing;
+#[cfg(feature = "async")]
+pub mod r#async;
+#[cfg(feature = "sonify")]
+pub mod sonify;
 
 #[derive(Debug)]
 pub enum Error {
     FailedToConnect(String),
-}
\ No newline at end of file
+    HandshakeFailed(String),
+    Proxy(String),
+    QueueFull(String),
+    ProtocolViolation(handshake::ProtocolViolation),
+    Chain(crate::blockdata::chain::Error),
+    Message(crate::encode::Error),
+    Io(std::io::Error)
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FailedToConnect(p) => write!(f, "failed to connect to {}", p),
+            Self::HandshakeFailed(r) => write!(f, "handshake failed: {}", r),
+            Self::Proxy(r) => write!(f, "proxy error: {}", r),
+            Self::QueueFull(p) => write!(f, "send queue of {} is full", p),
+            Self::ProtocolViolation(v) => write!(f, "protocol violation: {}", v),
+            Self::Chain(e) => write!(f, "invalid headers: {}", e),
+            Self::Message(e) => write!(f, "message error: {}", e),
+            Self::Io(e) => write!(f, "io error: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<crate::encode::Error> for Error {
+    fn from(e: crate::encode::Error) -> Self {
+        Self::Message(e)
+    }
+}
+
+impl From<crate::blockdata::chain::Error> for Error {
+    fn from(e: crate::blockdata::chain::Error) -> Self {
+        Self::Chain(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
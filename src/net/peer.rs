@@ -4,22 +4,53 @@
 //
 
 use crate::{
-    msg::network::NetAddress
+    msg::network::{
+        NetAddress,
+        ServicesList
+    },
+    address::{
+        Address,
+        Port
+    },
+    encode
+};
+use crate::net::{
+    addrman::netgroup,
+    Error
 };
-use crate::net::Error;
 use rayon::prelude::*;
-use std::net::{
-    Ipv4Addr,
-    TcpStream
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        SocketAddr,
+        TcpStream
+    }
 };
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+/// IPv4 or IPv6 peer that can be connected to directly
 pub struct Peer {
-    pub addr: Ipv4Addr,
-    pub port: Port
+    pub addr: SocketAddr
 }
 
 impl Peer {
+    pub fn new(ip: IpAddr, port: u16) -> Self {
+        Self {
+            addr: SocketAddr::new(ip, port)
+        }
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    pub fn port(&self) -> Port {
+        Port::from(self.addr.port())
+    }
+
     /// Get a list of working peers
     pub fn get(min: usize, peerlist: &[[u8; 6]]) -> Result<Vec<Self>, Error> {
         // Get a list of potential peers from the seeds module
@@ -31,21 +62,18 @@ impl Peer {
         // While the minium number of peers is not met and there
         // are peers to test remaining, paralell test if a peer
         // is active or not.
+        // Only one working peer per netgroup is kept so the peers span distinct networks
+        let mut groups = HashSet::new();
         let mut peers: Vec<Peer> = vec![];
-        while peers.len() < min || ut_peers.len() < min {
-            peers.extend_from_slice(
-                &ut_peers
-                    .par_iter()                                            // Paralell test
-                    .take(num_cpus::get())                            // 1 peer per CPU core (Rayon spawns 1 thread per core.)
-                    .map(|x| {
-                        if x.test_conn() { return Some(*x) }                         // If peer works, save it as Some
-                        None                                                         // else dont save it
-                    })
-                    .filter(|x| x.is_some()) // Remove None peers from the list
-                    .map(|x| x.unwrap())
-                    .collect::<Vec<Peer>>()                                          // Collect working peers to push
-            );
-            ut_peers.drain(0..min);                                            // Remove tested peers
+        while peers.len() < min && !ut_peers.is_empty() {
+            let tested = ut_peers.len().min(num_cpus::get());                // 1 peer per CPU core (Rayon spawns 1 thread per core.)
+            let working = ut_peers[..tested]
+                .par_iter()                                                // Paralell test
+                .filter(|x| x.test_conn())                                 // Keep the peers that work
+                .copied()
+                .collect::<Vec<Peer>>();                                   // Collect working peers to push
+            peers.extend(working.into_iter().filter(|x| groups.insert(netgroup(&x.ip()))));
+            ut_peers.drain(..tested);                                      // Remove tested peers
         }
 
         // If the minimum amount of connections could not be made, return an error.
@@ -58,33 +86,55 @@ impl Peer {
     
     /// Test if a peer is accepting TCP connections
     fn test_conn(&self) -> bool {
-        let peer: String = format!("{}:{}", self.addr.to_string(), self.port.to_u16());
+        let ok = TcpStream::connect(self.addr).is_ok();
+        tracing::debug!(addr = %self.addr, ok, "test connection");
+        ok
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Peer {
+        Peer { addr }
+    }
+}
 
-        if let Ok(_) = TcpStream::connect(&peer) {
-            println!("Connection established to {}", peer);
-            return true
+impl TryFrom<Address> for Peer {
+    type Error = encode::Error;
+
+    /// Fails for addresses that are not IPv4 or IPv6, which can only be reached through a proxy
+    fn try_from(addr: Address) -> Result<Peer, Self::Error> {
+        match addr.socket_addr() {
+            Some(addr) => Ok(Peer { addr }),
+            None => Err(encode::Error::InvalidData)
         }
+    }
+}
+
+impl TryFrom<NetAddress> for Peer {
+    type Error = encode::Error;
 
-        println!("Failed to connect to {}", peer);
-        false
+    fn try_from(netaddr: NetAddress) -> Result<Peer, Self::Error> {
+        Peer::try_from(netaddr.address)
     }
 }
 
-impl From<NetAddress> for Peer {
-    fn from(netaddr: NetAddress) -> Peer {
-        Peer {
-            addr: match netaddr.address.ip() {
-                std::net::IpAddr::V4(x) => x,
-                std::net::IpAddr::V6(_) => panic!("Peer struct does not support IPv6")
-            },
-            port: Port::from(netaddr.address.port())
-        }
+impl From<Peer> for Address {
+    fn from(peer: Peer) -> Address {
+        Address::from(peer.addr)
+    }
+}
+
+impl From<Peer> for NetAddress {
+    /// Net address for relaying the peer. The services of the peer are unknown.
+    fn from(peer: Peer) -> NetAddress {
+        NetAddress::new(ServicesList::default(), Address::from(peer))
     }
 }
 
-impl std::string::ToString for Peer {
-    fn to_string(&self) -> String {
-        format!("{}:{}", self.addr.to_string(), self.port.to_u16())
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // IPv6 addresses are shown in brackets
+        write!(f, "{}", self.addr)
     }
 }
 
@@ -93,32 +143,47 @@ pub type UntestedPeer = Peer;
 
 impl From<[u8; 6]> for UntestedPeer {
     fn from(seed: [u8; 6]) -> Self {
-        Self {
-            addr: Ipv4Addr::from([seed[0], seed[1], seed[2], seed[3]]),
-            port: Port::from([seed[4], seed[5]])
-        }
+        Self::new(
+            IpAddr::V4(Ipv4Addr::from([seed[0], seed[1], seed[2], seed[3]])),
+            Port::from([seed[4], seed[5]]).to_u16()
+        )
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-/// TCP/IP Port stored as big endian bytes
-//  Hence the use of [u8; 2] instead of u16.
-pub struct Port(pub [u8; 2]);
 
-impl From<u16> for Port {
-    fn from(port: u16) -> Port {
-        Port(port.to_be_bytes())
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl From<[u8; 2]> for Port {
-    fn from(port: [u8; 2]) -> Port {
-        Port(port)
+    #[test]
+    fn ipv6_from_netaddress() {
+        let addr = Address::new("2001:db8::1".parse().unwrap(), 8333);
+        let peer = Peer::try_from(NetAddress::new(ServicesList::default(), addr)).unwrap();
+        assert_eq!(peer.to_string(), "[2001:db8::1]:8333");
+        assert_eq!(Address::from(peer), addr);
+
+        let onion = Address::onion([7; 32], 8333);
+        assert!(Peer::try_from(onion).is_err());
     }
-}
 
-impl Port {
-    pub fn to_u16(&self) -> u16 {
-        ((self.0[0] as u16) << 8) | (self.0[1] as u16)
+    #[test]
+    fn get_fewer_peers_than_min() {
+        let seed = |port: u16| {
+            let [a, b] = port.to_be_bytes();
+            [127, 0, 0, 1, a, b]
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        // A seed that is down does not hide the working seeds of its netgroup
+        let working = vec![Peer::from(listener.local_addr().unwrap())];
+        assert_eq!(Peer::get(1, &[seed(closed), seed(open)]).unwrap(), working);
+        assert_eq!(Peer::get(1, &[seed(open), seed(closed)]).unwrap(), working);
+
+        // Only one working peer per netgroup is kept
+        assert!(Peer::get(2, &[seed(open), seed(open)]).is_err());
+        assert!(Peer::get(3, &[seed(closed), seed(open)]).is_err());
+        assert!(Peer::get(1, &[]).is_err());
     }
-}
\ No newline at end of file
+}
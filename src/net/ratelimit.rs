@@ -0,0 +1,223 @@
+// ratelimit.rs
+//
+// Token bucket rate limiting of the traffic received from a peer.
+//
+// Every connection has buckets for bytes and messages, plus buckets for the number of
+// entries in addr and inv messages. Messages that do not fit in their buckets are
+// dropped instead of being handed to the caller.
+//
+
+use crate::msg::{
+    data::{
+        Message,
+        MessagePayload
+    },
+    header::{
+        Command,
+        MessageHeader
+    }
+};
+use std::time::Instant;
+
+/// Sustained rate and burst size of a token bucket
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Limit {
+    /// Tokens added per second
+    pub rate: f64,
+
+    /// Maximum number of tokens the bucket holds
+    pub burst: f64
+}
+
+impl Limit {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self { rate, burst }
+    }
+}
+
+/// Limits applied to every connection
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RateLimits {
+    pub bytes: Limit,
+    pub messages: Limit,
+
+    /// Addresses in `addr` and `addrv2` messages
+    pub addr: Limit,
+
+    /// Entries in `inv` messages
+    pub inv: Limit
+}
+
+impl Default for RateLimits {
+    /// Address limits match the ones used by Bitcoin Core. The burst of the byte limit fits
+    /// the largest allowed payload.
+    fn default() -> Self {
+        Self {
+            bytes: Limit::new(1_000_000.0, 8_000_000.0),
+            messages: Limit::new(100.0, 1000.0),
+            addr: Limit::new(0.1, 1000.0),
+            inv: Limit::new(7000.0, 50_000.0)
+        }
+    }
+}
+
+/// Bucket refilled at a constant rate up to its burst size
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    last: Instant
+}
+
+impl TokenBucket {
+    /// Create a full bucket
+    pub fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            last: Instant::now()
+        }
+    }
+
+    /// Get the number of tokens currently in the bucket
+    pub fn tokens(&mut self) -> f64 {
+        self.refill();
+        self.tokens
+    }
+
+    /// Check if `n` tokens can be taken. Amounts larger than the burst are allowed when the
+    /// bucket is full and leave it in debt.
+    pub fn has(&mut self, n: f64) -> bool {
+        self.refill();
+        self.tokens >= n.min(self.limit.burst)
+    }
+
+    /// Take tokens out of the bucket without checking if they are available
+    pub fn take(&mut self, n: f64) {
+        self.refill();
+        self.tokens -= n;
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.last = now;
+    }
+}
+
+/// Traffic received from a peer
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RateCounters {
+    pub bytes: u64,
+    pub messages: u64,
+    pub addrs: u64,
+    pub invs: u64,
+
+    /// Messages dropped for exceeding a limit, and their size
+    pub dropped_messages: u64,
+    pub dropped_bytes: u64
+}
+
+/// Rate limiter for a single connection
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bytes: TokenBucket,
+    messages: TokenBucket,
+    addr: TokenBucket,
+    inv: TokenBucket,
+    counters: RateCounters
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            bytes: TokenBucket::new(limits.bytes),
+            messages: TokenBucket::new(limits.messages),
+            addr: TokenBucket::new(limits.addr),
+            inv: TokenBucket::new(limits.inv),
+            counters: RateCounters::default()
+        }
+    }
+
+    /// Count a received message against the limits.
+    /// Returns false if the message exceeds a limit and should be dropped.
+    pub fn check(&mut self, msg: &Message) -> bool {
+        let size = (MessageHeader::SIZE + msg.header().length as usize) as u64;
+        let (addrs, invs) = match (&msg.header().command, msg.payload()) {
+            (_, MessagePayload::AddrList(a)) |
+            (_, MessagePayload::AddrV2List(a)) => (a.len() as u64, 0),
+            (Command::Inv, MessagePayload::InvVect(i)) => (0, i.len() as u64),
+            _ => (0, 0)
+        };
+
+        let allowed = self.bytes.has(size as f64) &&
+            self.messages.has(1.0) &&
+            self.addr.has(addrs as f64) &&
+            self.inv.has(invs as f64);
+        if !allowed {
+            self.counters.dropped_messages += 1;
+            self.counters.dropped_bytes += size;
+            return false
+        }
+
+        self.bytes.take(size as f64);
+        self.messages.take(1.0);
+        self.addr.take(addrs as f64);
+        self.inv.take(invs as f64);
+
+        self.counters.bytes += size;
+        self.counters.messages += 1;
+        self.counters.addrs += addrs;
+        self.counters.invs += invs;
+        true
+    }
+
+    pub fn counters(&self) -> RateCounters {
+        self.counters
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{
+        data::NetworkMessage,
+        header::Magic,
+        network::{
+            NetAddress,
+            TimestampedNetAddress
+        }
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn addr_flood_dropped() {
+        let limits = RateLimits { addr: Limit::new(0.0, 10.0), ..RateLimits::default() };
+        let mut limiter = RateLimiter::new(limits);
+
+        let addrs = vec![TimestampedNetAddress::new(Duration::from_secs(0), NetAddress::default()); 6];
+        let msg = Message::from_payload(NetworkMessage::Addr(addrs), Magic::Main);
+        assert!(limiter.check(&msg));
+        assert!(!limiter.check(&msg));
+
+        // Other messages are not affected by the address limit
+        assert!(limiter.check(&Message::from_payload(NetworkMessage::Ping(1), Magic::Main)));
+
+        let counters = limiter.counters();
+        assert_eq!(counters.messages, 2);
+        assert_eq!(counters.addrs, 6);
+        assert_eq!(counters.dropped_messages, 1);
+        assert_eq!(counters.bytes + counters.dropped_bytes, 2 * msg.header().length as u64 + 24 * 3 + 8);
+    }
+
+    #[test]
+    fn bucket_debt() {
+        let mut bucket = TokenBucket::new(Limit::new(0.0, 100.0));
+        assert!(bucket.has(500.0));
+        bucket.take(500.0);
+        assert!(!bucket.has(1.0));
+        assert!(bucket.tokens() < 0.0);
+    }
+}
@@ -0,0 +1,215 @@
+// sonify.rs
+//
+// Sonification of the events of a connection manager.
+//
+// Enabled with the `sonify` feature. Events are mapped to short sounds, by default a tick
+// for announced transactions, a chime for new blocks and a tone for new peers, and any
+// command can be given its own sound. Sounds are synthesized as decaying sine waves and
+// written as raw 16 bit little endian mono samples, so any writer works as the backend,
+// ie a pipe to `aplay -f S16_LE -r 22050 -c 1`.
+//
+
+use crate::{
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::Command,
+        inventory::Inventory
+    },
+    net::manager::PeerEvent
+};
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::Duration
+};
+
+/// Number of samples per second
+pub const SAMPLE_RATE: u32 = 22050;
+
+/// Short decaying sine wave
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sound {
+    /// Frequency in hertz
+    pub frequency: f32,
+    pub duration: Duration,
+
+    /// Volume between 0 and 1
+    pub volume: f32
+}
+
+impl Sound {
+    /// Short high click
+    pub fn tick() -> Self {
+        Self { frequency: 2000.0, duration: Duration::from_millis(15), volume: 0.3 }
+    }
+
+    /// Long ringing note
+    pub fn chime() -> Self {
+        Self { frequency: 880.0, duration: Duration::from_millis(800), volume: 0.8 }
+    }
+
+    /// Plain low note
+    pub fn tone() -> Self {
+        Self { frequency: 440.0, duration: Duration::from_millis(200), volume: 0.5 }
+    }
+
+    /// Samples of the sound
+    pub fn render(&self) -> Vec<i16> {
+        let len = (self.duration.as_secs_f32() * SAMPLE_RATE as f32) as usize;
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let envelope = 1.0 - i as f32 / len as f32;
+                let sample = (2.0 * std::f32::consts::PI * self.frequency * t).sin() * envelope * self.volume;
+                (sample * i16::MAX as f32) as i16
+            })
+            .collect()
+    }
+}
+
+/// Kind of event a sound is played for
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Cue {
+    /// Inventory announcing transactions
+    TxAnnouncement,
+
+    /// Inventory, headers or block announcing a block
+    BlockAnnouncement,
+
+    /// Handshake completed with a peer
+    NewPeer,
+
+    /// Peer disconnected
+    Disconnected,
+
+    /// Any other message, by command
+    Message(Command)
+}
+
+impl Cue {
+    /// Get the cue of an event, if any
+    pub fn of(event: &PeerEvent) -> Option<Cue> {
+        match event {
+            PeerEvent::HandshakeComplete { .. } => Some(Cue::NewPeer),
+            PeerEvent::Disconnected { .. } => Some(Cue::Disconnected),
+            PeerEvent::Message { msg, .. } => Some(Self::of_message(msg)),
+            _ => None
+        }
+    }
+
+    fn of_message(msg: &Message) -> Cue {
+        match msg.payload() {
+            MessagePayload::InvVect(inv) if msg.header().command == Command::Inv => {
+                if inv.iter().any(|i| matches!(i, Inventory::Block(_) | Inventory::WitnessBlock(_) | Inventory::CompactBlock(_))) {
+                    Cue::BlockAnnouncement
+                } else if inv.iter().any(|i| matches!(i, Inventory::Tx(_) | Inventory::WitnessTx(_))) {
+                    Cue::TxAnnouncement
+                } else {
+                    Cue::Message(Command::Inv)
+                }
+            },
+            MessagePayload::Headers(headers) if !headers.is_empty() => Cue::BlockAnnouncement,
+            MessagePayload::Block(_) => Cue::BlockAnnouncement,
+            _ => Cue::Message(msg.header().command.clone())
+        }
+    }
+}
+
+/// Sounds played for each cue. Cues without a sound are silent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoundMap(pub HashMap<Cue, Sound>);
+
+impl Default for SoundMap {
+    fn default() -> Self {
+        let mut map = HashMap::new();
+        map.insert(Cue::TxAnnouncement, Sound::tick());
+        map.insert(Cue::BlockAnnouncement, Sound::chime());
+        map.insert(Cue::NewPeer, Sound::tone());
+        Self(map)
+    }
+}
+
+impl SoundMap {
+    /// Set or remove the sound of a cue
+    pub fn set(&mut self, cue: Cue, sound: Option<Sound>) {
+        match sound {
+            Some(sound) => self.0.insert(cue, sound),
+            None => self.0.remove(&cue)
+        };
+    }
+
+    pub fn get(&self, cue: &Cue) -> Option<&Sound> {
+        self.0.get(cue)
+    }
+}
+
+/// Player writing the sounds of events as samples
+#[derive(Debug)]
+pub struct Sonifier<W: Write> {
+    out: W,
+    sounds: SoundMap
+}
+
+impl<W: Write> Sonifier<W> {
+    pub fn new(out: W, sounds: SoundMap) -> Self {
+        Self { out, sounds }
+    }
+
+    pub fn sounds_mut(&mut self) -> &mut SoundMap {
+        &mut self.sounds
+    }
+
+    /// Play the sound of an event, if it has one.
+    /// Returns whether a sound was played.
+    pub fn play(&mut self, event: &PeerEvent) -> std::io::Result<bool> {
+        let sound = match Cue::of(event).and_then(|c| self.sounds.get(&c)) {
+            Some(sound) => *sound,
+            None => return Ok(false)
+        };
+        let bytes = sound.render().iter().flat_map(|s| s.to_le_bytes()).collect::<Vec<u8>>();
+        self.out.write_all(&bytes)?;
+        self.out.flush()?;
+        Ok(true)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitcoin::hash_types::Txid,
+        msg::{
+            data::NetworkMessage,
+            header::Magic
+        },
+        net::eventloop::PeerId
+    };
+
+    fn message(msg: NetworkMessage) -> PeerEvent {
+        PeerEvent::Message { peer: PeerId(1), msg: Message::from_payload(msg, Magic::Main) }
+    }
+
+    #[test]
+    fn play_cues() {
+        let tx = message(NetworkMessage::Inv(vec![Inventory::Tx(Txid::default())]));
+        let ping = message(NetworkMessage::Ping(1));
+        assert_eq!(Cue::of(&tx), Some(Cue::TxAnnouncement));
+        assert_eq!(Cue::of(&ping), Some(Cue::Message(Command::Ping)));
+
+        let mut sonifier = Sonifier::new(Vec::new(), SoundMap::default());
+        assert!(sonifier.play(&tx).unwrap());
+        assert!(!sonifier.play(&ping).unwrap());
+        assert_eq!(sonifier.out.len(), Sound::tick().render().len() * 2);
+
+        // Commands can be given their own sound
+        sonifier.sounds_mut().set(Cue::Message(Command::Ping), Some(Sound::tone()));
+        sonifier.sounds_mut().set(Cue::TxAnnouncement, None);
+        assert!(sonifier.play(&ping).unwrap());
+        assert!(!sonifier.play(&tx).unwrap());
+        assert_eq!(Sound::tone().render().len(), 4410);
+    }
+}
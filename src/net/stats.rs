@@ -0,0 +1,171 @@
+// stats.rs
+//
+// Statistics of the traffic exchanged with peers.
+//
+// Messages and bytes are counted by command and direction, for every peer and for the whole
+// event loop, so the composition of the network chatter can be seen at a glance. Bytes
+// include the 24 byte header of each message.
+//
+// `PeerMetrics` sums up a single connection: its traffic, when it was last active, how fast
+// it answers pings and how badly it behaved.
+//
+
+use crate::{
+    msg::{
+        data::Message,
+        header::{
+            Command,
+            MessageHeader
+        }
+    },
+    net::{
+        capture::Direction,
+        latency::LatencyStats
+    }
+};
+use std::{
+    collections::HashMap,
+    time::Duration
+};
+
+/// Number of messages and bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counter {
+    pub messages: u64,
+    pub bytes: u64
+}
+
+impl Counter {
+    fn add(&mut self, other: Counter) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Traffic by command in each direction
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub received: HashMap<Command, Counter>,
+    pub sent: HashMap<Command, Counter>
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a message of the given size, including its header
+    pub fn record(&mut self, direction: Direction, command: &Command, bytes: usize) {
+        let counters = match direction {
+            Direction::Inbound => &mut self.received,
+            Direction::Outbound => &mut self.sent
+        };
+        counters.entry(command.clone()).or_default().add(Counter { messages: 1, bytes: bytes as u64 });
+    }
+
+    /// Count a message with its header
+    pub fn record_message(&mut self, direction: Direction, msg: &Message) {
+        self.record(direction, &msg.header().command, MessageHeader::SIZE + msg.header().length as usize);
+    }
+
+    /// Add the traffic of other statistics, ie of another peer
+    pub fn merge(&mut self, other: &Stats) {
+        for (command, counter) in other.received.iter() {
+            self.received.entry(command.clone()).or_default().add(*counter);
+        }
+        for (command, counter) in other.sent.iter() {
+            self.sent.entry(command.clone()).or_default().add(*counter);
+        }
+    }
+
+    /// Total traffic received
+    pub fn total_received(&self) -> Counter {
+        total(&self.received)
+    }
+
+    /// Total traffic sent
+    pub fn total_sent(&self) -> Counter {
+        total(&self.sent)
+    }
+
+    /// Traffic of each command in both directions, with the most bytes first
+    pub fn by_command(&self) -> Vec<(Command, Counter, Counter)> {
+        let mut commands = self.received.keys().chain(self.sent.keys()).cloned().collect::<Vec<Command>>();
+        commands.sort_by(|a, b| a.to_str().cmp(b.to_str()));
+        commands.dedup();
+
+        let mut rows = commands
+            .into_iter()
+            .map(|c| {
+                let received = self.received.get(&c).copied().unwrap_or_default();
+                let sent = self.sent.get(&c).copied().unwrap_or_default();
+                (c, received, sent)
+            })
+            .collect::<Vec<(Command, Counter, Counter)>>();
+        rows.sort_by_key(|(_, r, s)| std::cmp::Reverse(r.bytes + s.bytes));
+        rows
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<12} {:>10} {:>14} {:>10} {:>14}", "command", "recv msgs", "recv bytes", "sent msgs", "sent bytes")?;
+        for (command, received, sent) in self.by_command() {
+            writeln!(f, "{:<12} {:>10} {:>14} {:>10} {:>14}", command.to_str(), received.messages, received.bytes, sent.messages, sent.bytes)?;
+        }
+        let (received, sent) = (self.total_received(), self.total_sent());
+        write!(f, "{:<12} {:>10} {:>14} {:>10} {:>14}", "total", received.messages, received.bytes, sent.messages, sent.bytes)
+    }
+}
+
+/// Metrics of a connected peer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerMetrics {
+    pub received: Counter,
+    pub sent: Counter,
+
+    /// Unix time a message was last received from the peer
+    pub last_recv: Option<Duration>,
+
+    /// Unix time a message was last sent to the peer
+    pub last_send: Option<Duration>,
+
+    /// Round trip times of the recent pings answered by the peer
+    pub ping: Option<LatencyStats>,
+
+    /// Misbehavior score of the IP of the peer
+    pub misbehavior: u32
+}
+
+fn total(counters: &HashMap<Command, Counter>) -> Counter {
+    let mut sum = Counter::default();
+    for counter in counters.values() {
+        sum.add(*counter);
+    }
+    sum
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_by_command() {
+        let mut peer = Stats::new();
+        peer.record(Direction::Inbound, &Command::Inv, 61);
+        peer.record(Direction::Inbound, &Command::Inv, 61);
+        peer.record(Direction::Outbound, &Command::Ping, 32);
+
+        let mut global = Stats::new();
+        global.record(Direction::Inbound, &Command::Ping, 32);
+        global.merge(&peer);
+        assert_eq!(global.total_received(), Counter { messages: 3, bytes: 154 });
+        assert_eq!(global.total_sent(), Counter { messages: 1, bytes: 32 });
+        assert_eq!(global.by_command(), vec![
+            (Command::Inv, Counter { messages: 2, bytes: 122 }, Counter::default()),
+            (Command::Ping, Counter { messages: 1, bytes: 32 }, Counter { messages: 1, bytes: 32 })
+        ]);
+        assert!(global.to_string().lines().nth(1).unwrap().starts_with("inv"));
+    }
+}
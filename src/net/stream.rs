@@ -2,26 +2,309 @@
 //
 // Module for TCP related code.
 //
+// `PeerStream` sends and receives whole messages over a connection, from the handshake
+// on. Reads are buffered so that decoding a message does not take a system call per
+// field, which means bytes may be read ahead of the last message: hand the `PeerStream`
+// or its reader over rather than the underlying stream.
+//
 
-use crate::net::{
-    peer::{
-        Peer
+use crate::{
+    encode::{
+        self,
+        DecodeConfig
+    },
+    msg::{
+        data::{
+            Message,
+            NetworkMessage
+        },
+        header::Magic
     },
-    Error
+    net::{
+        peer::{
+            Peer
+        },
+        Error
+    }
 };
 use std::{
-    net::TcpStream,
     io::{
-        Write,
+        BufReader,
         Read,
-        BufReader
+        Write
+    },
+    net::{
+        Shutdown,
+        SocketAddr,
+        TcpStream
+    },
+    time::{
+        Duration,
+        Instant
     }
 };
 
 /// Create a tcp stream from a peer
 pub fn stream_from(peer: Peer) -> Result<TcpStream, Error> {
-    match TcpStream::connect(peer.to_string()) {
+    match TcpStream::connect(peer.addr) {
         Ok(x) => Ok(x),
         Err(_) => Err(Error::FailedToConnect(peer.to_string()))
     }
-}
\ No newline at end of file
+}
+
+/// Create a tcp stream to a peer through a SOCKS5 proxy
+pub fn stream_from_via_proxy(peer: Peer, proxy: SocketAddr) -> Result<TcpStream, Error> {
+    socks5_connect(proxy, &Target::Ip(peer.addr))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Destination of a connection made through a proxy
+pub enum Target {
+    Ip(SocketAddr),
+
+    // Host names are resolved by the proxy, which is required for .onion hosts
+    Domain(String, u16)
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{}", addr),
+            Self::Domain(host, port) => write!(f, "{}:{}", host, port)
+        }
+    }
+}
+
+/// Open a connection to the target through a SOCKS5 proxy without authentication (RFC 1928)
+pub fn socks5_connect(proxy: SocketAddr, target: &Target) -> Result<TcpStream, Error> {
+    let mut stream = TcpStream::connect(proxy).map_err(|_| Error::FailedToConnect(proxy.to_string()))?;
+
+    // Greeting offering only the "no authentication" method
+    stream.write_all(&[0x05, 0x01, 0x00])?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [0x05, 0x00] {
+        return Err(Error::Proxy(String::from("proxy requires unsupported authentication")))
+    }
+
+    // Connect request
+    let mut req = vec![0x05, 0x01, 0x00];
+    match target {
+        Target::Ip(SocketAddr::V4(addr)) => {
+            req.push(0x01);
+            req.extend_from_slice(&addr.ip().octets());
+        },
+        Target::Ip(SocketAddr::V6(addr)) => {
+            req.push(0x04);
+            req.extend_from_slice(&addr.ip().octets());
+        },
+        Target::Domain(host, _) => {
+            if host.len() > 255 {
+                return Err(Error::Proxy(format!("host name too long: {}", host)))
+            }
+            req.push(0x03);
+            req.push(host.len() as u8);
+            req.extend_from_slice(host.as_bytes());
+        }
+    }
+    let port = match target {
+        Target::Ip(addr) => addr.port(),
+        Target::Domain(_, port) => *port
+    };
+    req.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&req)?;
+
+    // Reply with the address bound by the proxy, which is read and discarded
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 0x05 {
+        return Err(Error::Proxy(String::from("invalid reply version")))
+    }
+    if reply[1] != 0x00 {
+        return Err(Error::FailedToConnect(format!("{} (proxy reply {:#04x})", target, reply[1])))
+    }
+    let bound = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        },
+        x => return Err(Error::Proxy(format!("invalid address type {:#04x}", x)))
+    };
+    let mut bound_addr = vec![0; bound + 2];
+    stream.read_exact(&mut bound_addr)?;
+
+    Ok(stream)
+}
+
+/// Connection to a peer exchanging whole messages
+#[derive(Debug)]
+pub struct PeerStream {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    magic: Magic,
+    config: DecodeConfig,
+
+    /// Frame buffer reused for every message
+    buf: Vec<u8>
+}
+
+impl PeerStream {
+    pub fn new(stream: TcpStream, magic: Magic) -> Result<Self, Error> {
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            magic,
+            config: DecodeConfig::default(),
+            buf: Vec::new()
+        })
+    }
+
+    /// Set the limits used when decoding messages from the peer
+    pub fn set_config(&mut self, config: DecodeConfig) {
+        self.config = config;
+    }
+
+    /// Set the time a message can take to be received, or none to wait forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        Ok(self.writer.set_read_timeout(timeout)?)
+    }
+
+    /// Set the time a message can take to be sent, or none to wait forever
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        Ok(self.writer.set_write_timeout(timeout)?)
+    }
+
+    /// The underlying stream, ie to get the address of the peer
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.writer
+    }
+
+    /// Send a message to the peer
+    pub fn send(&mut self, msg: NetworkMessage) -> Result<(), Error> {
+        self.send_message(&Message::from_payload(msg, self.magic.clone()))
+    }
+
+    /// Send an already framed message to the peer. The header and the payload are written
+    /// with vectored writes, so large payloads are not copied into a frame.
+    pub fn send_message(&mut self, msg: &Message) -> Result<(), Error> {
+        let start = Instant::now();
+        let bytes = msg.write_vectored(&mut self.writer, &mut self.buf)?;
+        self.writer.flush()?;
+        tracing::trace!(
+            command = msg.header().command.to_str(),
+            bytes,
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "sent message"
+        );
+        Ok(())
+    }
+
+    /// Payload of the last message sent, as it was encoded for the write
+    pub fn last_payload(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Receive the next message.
+    /// Messages on a different network are rejected.
+    pub fn recv(&mut self) -> Result<Message, Error> {
+        let msg = Message::net_decode_with_config(&mut self.reader, &self.config)?;
+        if msg.header().magic != self.magic {
+            return Err(Error::Message(encode::Error::BadNetworkMagic(msg.header().magic.clone())))
+        }
+        tracing::trace!(command = msg.header().command.to_str(), length = msg.header().length, "received message");
+        Ok(msg)
+    }
+
+    /// Receive the next message as a typed network message
+    pub fn recv_network_message(&mut self) -> Result<NetworkMessage, Error> {
+        Ok(self.recv()?.network_message()?)
+    }
+
+    /// Take the buffered reader, with the bytes read ahead of the last message
+    pub fn into_reader(self) -> BufReader<TcpStream> {
+        self.reader
+    }
+
+    /// Close both directions of the connection
+    pub fn shutdown(&self) -> Result<(), Error> {
+        Ok(self.writer.shutdown(Shutdown::Both)?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::Decode;
+    use std::net::TcpListener;
+
+    #[test]
+    fn peer_stream_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = PeerStream::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap(), Magic::Main).unwrap();
+        let mut server = PeerStream::new(listener.accept().unwrap().0, Magic::Main).unwrap();
+
+        client.send(NetworkMessage::Ping(1)).unwrap();
+        client.send(NetworkMessage::GetAddr).unwrap();
+        assert_eq!(server.recv_network_message().unwrap(), NetworkMessage::Ping(1));
+        assert_eq!(server.recv().unwrap().header().command, crate::msg::header::Command::GetAddr);
+
+        // Messages from other networks and above the limits are rejected
+        client.send_message(&Message::from_payload(NetworkMessage::Verack, Magic::Testnet)).unwrap();
+        assert!(matches!(server.recv(), Err(Error::Message(encode::Error::BadNetworkMagic(Magic::Testnet)))));
+        server.set_config(DecodeConfig { max_payload: 4, ..DecodeConfig::default() });
+        client.send(NetworkMessage::Ping(2)).unwrap();
+        assert!(matches!(server.recv(), Err(Error::Message(encode::Error::PayloadTooLarge(8)))));
+
+        server.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert!(server.recv().is_err());
+    }
+
+    #[test]
+    fn reader_keeps_read_ahead() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = PeerStream::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap(), Magic::Main).unwrap();
+        let mut server = PeerStream::new(listener.accept().unwrap().0, Magic::Main).unwrap();
+
+        // Both messages arrive before the first is received, so the second is buffered
+        client.send(NetworkMessage::Ping(1)).unwrap();
+        client.send(NetworkMessage::Ping(2)).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(server.recv_network_message().unwrap(), NetworkMessage::Ping(1));
+
+        let reader = server.into_reader();
+        assert_eq!(reader.buffer().len(), 32);
+        assert_eq!(Message::net_decode(reader).unwrap().network_message().unwrap(), NetworkMessage::Ping(2));
+    }
+
+    #[test]
+    fn socks5_domain_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+            s.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            s.write_all(&[5, 0]).unwrap();
+
+            let mut req = [0; 5 + 11 + 2];
+            s.read_exact(&mut req).unwrap();
+            assert_eq!(&req[..5], &[5, 1, 0, 3, 11]);
+            assert_eq!(&req[5..16], b"example.com");
+            assert_eq!(&req[16..], &8333u16.to_be_bytes());
+            s.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            s.write_all(b"hi").unwrap();
+        });
+
+        let mut stream = socks5_connect(proxy, &Target::Domain(String::from("example.com"), 8333)).unwrap();
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,614 @@
+// testing.rs
+//
+// In-memory peer for unit tests of code talking to peers, without sockets.
+//
+// `MockPeer` is a byte stream (`Read` and `Write`) playing a script. Frames written to it
+// are decoded and matched against the script, and the replies of the script are queued to
+// be read back. Every message written is recorded, so tests can check what was sent.
+//
+//    let peer = MockPeer::new(Magic::Main)
+//        .handshake(version)                          // answer the version handshake
+//        .expect(Command::GetHeaders, vec![headers])  // answer getheaders with headers
+//        .send(inv);                                  // then announce an inventory
+//
+// Messages that do not match the next step of the script are recorded and otherwise
+// ignored. Once the script has nothing left to send, reads return the end of the stream,
+// like a peer closing the connection.
+//
+// `FaultyStream` wraps a stream and delays, truncates, duplicates, reorders or corrupts the
+// frames read from it, at random but reproducibly from a seed. It is used to check that
+// readers of a stream recover from bad messages, or fail cleanly, under adverse network
+// conditions. Writes go to the wrapped stream untouched.
+//
+
+use crate::{
+    encode::{
+        Decode,
+        Encode
+    },
+    msg::{
+        data::{
+            Message,
+            NetworkMessage
+        },
+        header::{
+            Command,
+            Magic
+        },
+        network::VersionMessage
+    }
+};
+use rand::{
+    rngs::StdRng,
+    Rng,
+    SeedableRng
+};
+use std::{
+    collections::VecDeque,
+    io::{
+        self,
+        Read,
+        Write
+    },
+    time::Duration
+};
+
+/// Size of a message header in bytes
+const HEADER_SIZE: usize = 24;
+
+/// Step of the script of a mock peer
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::large_enum_variant)]
+enum Step {
+    /// Send a message as soon as the previous steps are done
+    Send(NetworkMessage),
+
+    /// Wait for a message with the command, then send the replies
+    Expect(Command, Vec<NetworkMessage>)
+}
+
+/// Scripted peer exchanging messages in memory
+#[derive(Clone, Debug)]
+pub struct MockPeer {
+    magic: Magic,
+    script: VecDeque<Step>,
+    answer_pings: bool,
+
+    /// Encoded messages waiting to be read
+    outgoing: VecDeque<u8>,
+
+    /// Bytes written that do not make a whole message yet
+    incoming: Vec<u8>,
+    received: Vec<NetworkMessage>
+}
+
+impl MockPeer {
+    pub fn new(magic: Magic) -> Self {
+        Self {
+            magic,
+            script: VecDeque::new(),
+            answer_pings: false,
+            outgoing: VecDeque::new(),
+            incoming: Vec::new(),
+            received: Vec::new()
+        }
+    }
+
+    /// Send a message once the previous steps of the script are done
+    pub fn send(mut self, msg: NetworkMessage) -> Self {
+        self.script.push_back(Step::Send(msg));
+        self.run_sends();
+        self
+    }
+
+    /// Wait for a message with the command, then send the replies
+    pub fn expect(mut self, command: Command, replies: Vec<NetworkMessage>) -> Self {
+        self.script.push_back(Step::Expect(command, replies));
+        self
+    }
+
+    /// Answer the handshake of a peer opening the connection: reply to its version with
+    /// our version and a verack, then wait for its verack
+    pub fn handshake(self, version: VersionMessage) -> Self {
+        self.expect(Command::Version, vec![NetworkMessage::Version(version), NetworkMessage::Verack])
+            .expect(Command::Verack, vec![])
+    }
+
+    /// Answer every ping with a pong, at any point of the script
+    pub fn answer_pings(mut self) -> Self {
+        self.answer_pings = true;
+        self
+    }
+
+    /// Messages written to the peer so far, in order
+    pub fn received(&self) -> &[NetworkMessage] {
+        &self.received
+    }
+
+    /// Check that every step of the script was played and every message sent was read
+    pub fn is_done(&self) -> bool {
+        self.script.is_empty() && self.outgoing.is_empty()
+    }
+
+    /// Queue a message to be read
+    fn queue(&mut self, msg: NetworkMessage) {
+        let msg = Message::from_payload(msg, self.magic.clone());
+        let mut buf = Vec::with_capacity(msg.encoded_size());
+        msg.net_encode(&mut buf);
+        self.outgoing.extend(buf);
+    }
+
+    /// Play the sending steps at the front of the script
+    fn run_sends(&mut self) {
+        while let Some(Step::Send(_)) = self.script.front() {
+            if let Some(Step::Send(msg)) = self.script.pop_front() {
+                self.queue(msg);
+            }
+        }
+    }
+
+    /// Handle a message written to the peer
+    fn on_message(&mut self, msg: NetworkMessage) {
+        if let (true, NetworkMessage::Ping(nonce)) = (self.answer_pings, &msg) {
+            self.queue(NetworkMessage::Pong(*nonce));
+        }
+
+        let command = msg.command();
+        self.received.push(msg);
+        if let Some(Step::Expect(expected, _)) = self.script.front() {
+            if *expected == command {
+                if let Some(Step::Expect(_, replies)) = self.script.pop_front() {
+                    replies.into_iter().for_each(|msg| self.queue(msg));
+                }
+                self.run_sends();
+            }
+        }
+    }
+}
+
+impl Read for MockPeer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.outgoing.len());
+        for (b, byte) in buf.iter_mut().zip(self.outgoing.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MockPeer {
+    /// Decode the messages written so far. Messages on another network or which can not be
+    /// decoded fail the write.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.incoming.extend_from_slice(buf);
+        while self.incoming.len() >= HEADER_SIZE {
+            let mut length = [0; 4];
+            length.copy_from_slice(&self.incoming[16..20]);
+            let end = HEADER_SIZE + u32::from_le_bytes(length) as usize;
+            if self.incoming.len() < end { break }
+
+            let frame = self.incoming.drain(..end).collect::<Vec<u8>>();
+            let msg = Message::net_decode(&frame[..])
+                .and_then(|msg| match msg.header().magic == self.magic {
+                    true => msg.network_message(),
+                    false => Err(crate::encode::Error::BadNetworkMagic(msg.header().magic.clone()))
+                })
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            self.on_message(msg);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Probabilities of the faults injected into each frame read through a `FaultyStream`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultConfig {
+    pub delay: f64,           // Wait before a frame is read
+    pub max_delay: Duration,  // Longest wait before a frame, the waits are uniform up to it
+    pub truncate: f64,        // Cut a frame short and end the stream, like a dropped connection
+    pub duplicate: f64,       // Read a frame twice
+    pub reorder: f64,         // Swap a frame with the one after it
+    pub corrupt: f64          // Flip a bit in a frame, either in its header or its payload
+}
+
+impl Default for FaultConfig {
+    /// No faults
+    fn default() -> Self {
+        Self {
+            delay: 0.0,
+            max_delay: Duration::from_millis(10),
+            truncate: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            corrupt: 0.0
+        }
+    }
+}
+
+/// Fault injected into a frame, with the index of the frame in the wrapped stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    Delayed(usize, Duration),
+    Truncated(usize, usize),    // Length the frame was cut to
+    Duplicated(usize),
+    Reordered(usize),
+    Corrupted(usize, usize)     // Offset of the flipped bit in the frame, in bits
+}
+
+/// Stream injecting faults into the frames read from another stream
+#[derive(Debug)]
+pub struct FaultyStream<S> {
+    inner: S,
+    config: FaultConfig,
+    rng: StdRng,
+
+    /// Index of the next frame read from the inner stream
+    frame: usize,
+
+    /// Frame held back to be read after the next one
+    held: Option<Vec<u8>>,
+
+    /// Bytes of the current frames left to read
+    pending: VecDeque<u8>,
+    ended: bool,
+    faults: Vec<Fault>
+}
+
+impl<S> FaultyStream<S> {
+    pub fn new(inner: S, config: FaultConfig, seed: u64) -> Self {
+        Self {
+            inner,
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            frame: 0,
+            held: None,
+            pending: VecDeque::new(),
+            ended: false,
+            faults: Vec::new()
+        }
+    }
+
+    /// Faults injected so far, in the order of the frames of the wrapped stream
+    pub fn faults(&self) -> &[Fault] {
+        &self.faults
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn happens(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen_bool(probability.min(1.0))
+    }
+}
+
+impl<S: Read> FaultyStream<S> {
+    /// Read the next frame of the inner stream, as many bytes as its header announces.
+    /// Returns `None` at the end of the inner stream.
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut frame = Vec::with_capacity(HEADER_SIZE);
+        (&mut self.inner).take(HEADER_SIZE as u64).read_to_end(&mut frame)?;
+        if frame.len() < HEADER_SIZE {
+            return Ok(if frame.is_empty() { None } else { Some(frame) })
+        }
+
+        let mut length = [0; 4];
+        length.copy_from_slice(&frame[16..20]);
+        (&mut self.inner).take(u32::from_le_bytes(length) as u64).read_to_end(&mut frame)?;
+        Ok(Some(frame))
+    }
+
+    /// Queue the next frames to be read, with faults injected.
+    /// Returns false once there is nothing left to read.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.ended {
+            return Ok(false)
+        }
+        let index = self.frame;
+        let mut frame = match self.next_frame()? {
+            Some(frame) => frame,
+            None => {
+                self.ended = true;
+                return Ok(match self.held.take() {
+                    Some(held) => {
+                        self.pending.extend(held);
+                        true
+                    },
+                    None => false
+                })
+            }
+        };
+        self.frame += 1;
+
+        if self.happens(self.config.delay) {
+            let delay = self.config.max_delay.mul_f64(self.rng.gen::<f64>());
+            std::thread::sleep(delay);
+            self.faults.push(Fault::Delayed(index, delay));
+        }
+        if self.happens(self.config.corrupt) {
+            let bit = self.rng.gen_range(0..frame.len() * 8);
+            frame[bit / 8] ^= 1 << (bit % 8);
+            self.faults.push(Fault::Corrupted(index, bit));
+        }
+        if self.happens(self.config.truncate) {
+            let len = self.rng.gen_range(0..frame.len());
+            frame.truncate(len);
+            self.faults.push(Fault::Truncated(index, len));
+            self.ended = true;
+            self.held = None;
+            self.pending.extend(frame);
+            return Ok(true)
+        }
+        if self.happens(self.config.duplicate) {
+            self.faults.push(Fault::Duplicated(index));
+            self.pending.extend(frame.iter().copied());
+        }
+        if self.held.is_none() && self.happens(self.config.reorder) {
+            self.faults.push(Fault::Reordered(index));
+            self.held = Some(frame);
+            return self.fill()
+        }
+
+        self.pending.extend(frame);
+        if let Some(held) = self.held.take() {
+            self.pending.extend(held);
+        }
+        Ok(true)
+    }
+}
+
+impl<S: Read> Read for FaultyStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && !self.fill()? {
+            return Ok(0)
+        }
+        let n = buf.len().min(self.pending.len());
+        for (b, byte) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for FaultyStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        encode::DecodeConfig,
+        msg::{
+            inventory::Inventory,
+            network::VersionConfig,
+            stream::MessageStream
+        }
+    };
+
+    fn version() -> VersionMessage {
+        VersionConfig::default().message_at(Address::me(), 1_640_995_200, 0x5eed)
+    }
+
+    fn write_message<W: Write>(w: &mut W, magic: &Magic, msg: NetworkMessage) -> io::Result<()> {
+        let mut buf = Vec::new();
+        Message::from_payload(msg, magic.clone()).net_encode(&mut buf);
+        w.write_all(&buf)
+    }
+
+    fn read_message<R: Read>(r: &mut R) -> Result<NetworkMessage, crate::encode::Error> {
+        Message::net_decode(r)?.network_message()
+    }
+
+    #[test]
+    fn scripted_peer() {
+        let inv = NetworkMessage::Inv(vec![Inventory::from_id_and_hash(1, [7; 32])]);
+        let mut peer = MockPeer::new(Magic::Main)
+            .handshake(version())
+            .send(inv.clone())
+            .expect(Command::GetAddr, vec![NetworkMessage::Addr(vec![])])
+            .answer_pings();
+
+        // Nothing is sent before the version
+        assert!(read_message(&mut peer).is_err());
+
+        write_message(&mut peer, &Magic::Main, NetworkMessage::Version(version())).unwrap();
+        assert_eq!(read_message(&mut peer).unwrap(), NetworkMessage::Version(version()));
+        assert_eq!(read_message(&mut peer).unwrap(), NetworkMessage::Verack);
+
+        // Messages out of the script are recorded but do not advance it
+        write_message(&mut peer, &Magic::Main, NetworkMessage::SendHeaders).unwrap();
+        write_message(&mut peer, &Magic::Main, NetworkMessage::Ping(7)).unwrap();
+        assert_eq!(read_message(&mut peer).unwrap(), NetworkMessage::Pong(7));
+        write_message(&mut peer, &Magic::Main, NetworkMessage::Verack).unwrap();
+        assert_eq!(read_message(&mut peer).unwrap(), inv);
+
+        // Frames written in pieces are put back together
+        let mut getaddr = Vec::new();
+        Message::from_payload(NetworkMessage::GetAddr, Magic::Main).net_encode(&mut getaddr);
+        for byte in getaddr {
+            peer.write_all(&[byte]).unwrap();
+        }
+        assert!(!peer.is_done());
+        let replies = MessageStream::new(&mut peer, DecodeConfig::default()).collect::<Result<Vec<Message>, _>>().unwrap();
+        assert_eq!(replies, vec![Message::from_payload(NetworkMessage::Addr(vec![]), Magic::Main)]);
+        assert!(peer.is_done());
+
+        assert_eq!(peer.received(), &[
+            NetworkMessage::Version(version()),
+            NetworkMessage::SendHeaders,
+            NetworkMessage::Ping(7),
+            NetworkMessage::Verack,
+            NetworkMessage::GetAddr
+        ]);
+
+        // Messages of other networks are rejected
+        assert!(write_message(&mut peer, &Magic::Test, NetworkMessage::GetAddr).is_err());
+    }
+
+    /// Frames of `inv` messages, mostly made of payload, each filled with its index
+    fn frames(n: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for i in 0..n {
+            let inv = vec![Inventory::from_id_and_hash(1, [i; 32]); 30];
+            Message::from_payload(NetworkMessage::Inv(inv), Magic::Main).net_encode(&mut buf);
+        }
+        buf
+    }
+
+    fn read_all(stream: &mut FaultyStream<&[u8]>) -> Vec<Result<Message, crate::encode::Error>> {
+        MessageStream::new(stream, DecodeConfig::default()).collect()
+    }
+
+    /// Indexes of the frames read whole
+    fn indexes(msgs: &[Result<Message, crate::encode::Error>]) -> Vec<u8> {
+        msgs.iter().filter_map(|m| match m.as_ref().ok().map(|m| m.network_message()) {
+            Some(Ok(NetworkMessage::Inv(inv))) => {
+                let i = inv[0].inner()[0];
+                assert!(inv.iter().all(|entry| entry.inner() == [i; 32]));
+                Some(i)
+            },
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn reproducible_faults() {
+        let frames = frames(100);
+        let config = FaultConfig {
+            delay: 0.1,
+            max_delay: Duration::from_micros(100),
+            truncate: 0.01,
+            duplicate: 0.1,
+            reorder: 0.1,
+            corrupt: 0.1
+        };
+        let read = |seed| {
+            let mut stream = FaultyStream::new(&frames[..], config.clone(), seed);
+            let mut bytes = Vec::new();
+            stream.read_to_end(&mut bytes).unwrap();
+            (bytes, stream.faults().to_vec())
+        };
+        assert_eq!(read(1), read(1));
+        assert_ne!(read(1).1, read(2).1);
+
+        let mut clean = FaultyStream::new(&frames[..], FaultConfig::default(), 1);
+        let mut bytes = Vec::new();
+        clean.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, frames);
+        assert!(clean.faults().is_empty());
+    }
+
+    #[test]
+    fn duplicated_and_reordered_frames() {
+        let config = FaultConfig { duplicate: 0.2, reorder: 0.2, ..FaultConfig::default() };
+        let frames = frames(100);
+        for seed in 0..16 {
+            let mut stream = FaultyStream::new(&frames[..], config.clone(), seed);
+            let msgs = read_all(&mut stream);
+            assert!(msgs.iter().all(|m| m.is_ok()));
+
+            // Every frame is still read whole, some of them twice
+            let mut indexes = indexes(&msgs);
+            let duplicated = stream.faults().iter().filter(|f| matches!(f, Fault::Duplicated(_))).count();
+            assert_eq!(indexes.len(), 100 + duplicated);
+            indexes.dedup();
+            indexes.sort_unstable();
+            indexes.dedup();
+            assert_eq!(indexes, (0..100).collect::<Vec<u8>>());
+        }
+    }
+
+    #[test]
+    fn corrupted_frames() {
+        let config = FaultConfig { corrupt: 0.05, ..FaultConfig::default() };
+        let frames = frames(100);
+        let mut payloads_only = 0;
+        for seed in 0..16 {
+            let mut stream = FaultyStream::new(&frames[..], config.clone(), seed);
+            let msgs = read_all(&mut stream);
+
+            // Corrupted payloads fail their checksum, and a corrupted header can end the
+            // stream or swallow the frames after it, but no frame is read with wrong contents
+            let indexes = indexes(&msgs);
+            assert!(indexes.windows(2).all(|w| w[0] < w[1]));
+
+            // Bits flipped in payloads only cost the frames they hit
+            let corrupted = stream.faults().iter().map(|f| match f {
+                Fault::Corrupted(_, bit) => *bit,
+                f => panic!("{:?}", f)
+            }).collect::<Vec<usize>>();
+            if !corrupted.is_empty() && corrupted.iter().all(|bit| *bit >= HEADER_SIZE * 8) {
+                payloads_only += 1;
+                assert_eq!(indexes.len(), 100 - corrupted.len());
+                assert_eq!(msgs.iter().filter(|m| matches!(m, Err(crate::encode::Error::BadChecksum { .. }))).count(), corrupted.len());
+            }
+        }
+        assert!(payloads_only > 0);
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let config = FaultConfig { truncate: 0.05, ..FaultConfig::default() };
+        let frames = frames(100);
+        for seed in 0..16 {
+            let mut stream = FaultyStream::new(&frames[..], config.clone(), seed);
+            let msgs = read_all(&mut stream);
+            let (index, len) = match stream.faults() {
+                [Fault::Truncated(index, len)] => (*index, *len),
+                [] => (100, 0),
+                faults => panic!("{:?}", faults)
+            };
+
+            // The frames before the cut are read, then the stream ends, with an error if the
+            // cut was in the middle of a frame
+            assert_eq!(indexes(&msgs), (0..index as u8).collect::<Vec<u8>>());
+            assert_eq!(msgs.len(), if len == 0 { index } else { index + 1 });
+            if len > 0 {
+                assert!(matches!(msgs.last(), Some(Err(crate::encode::Error::Io(_)))));
+            }
+        }
+    }
+
+    #[test]
+    fn delayed_peer() {
+        let mut peer = FaultyStream::new(
+            MockPeer::new(Magic::Main).handshake(version()),
+            FaultConfig { delay: 1.0, max_delay: Duration::from_millis(5), ..FaultConfig::default() },
+            7
+        );
+        let start = std::time::Instant::now();
+        write_message(&mut peer, &Magic::Main, NetworkMessage::Version(version())).unwrap();
+        assert_eq!(read_message(&mut peer).unwrap(), NetworkMessage::Version(version()));
+        assert_eq!(read_message(&mut peer).unwrap(), NetworkMessage::Verack);
+        write_message(&mut peer, &Magic::Main, NetworkMessage::Verack).unwrap();
+        assert!(peer.get_ref().is_done());
+
+        let delays = peer.faults().iter().map(|f| match f {
+            Fault::Delayed(_, delay) => *delay,
+            f => panic!("{:?}", f)
+        }).collect::<Vec<Duration>>();
+        assert_eq!(delays.len(), 2);
+        assert!(start.elapsed() >= delays.iter().sum());
+    }
+}
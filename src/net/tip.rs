@@ -0,0 +1,151 @@
+// tip.rs
+//
+// Tracking of the best chain tip announced by peers.
+//
+// Blocks are found every 10 minutes on average, so not hearing about a new block for
+// much longer suggests the connected peers are stuck on a stale tip or are not relaying.
+// Like Bitcoin Core, the tip is considered stale after 30 minutes without a new block.
+//
+
+use crate::{
+    bitcoin::hash_types::BlockHash,
+    msg::{
+        data::{
+            Message,
+            MessagePayload
+        },
+        header::Command,
+        inventory::Inventory,
+        network::VersionMessage
+    }
+};
+use std::{
+    collections::HashSet,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Time without a new block after which the tip is considered stale
+pub const STALE_TIP_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// Number of announced block hashes remembered to tell new blocks apart from relayed ones
+const MAX_SEEN: usize = 1000;
+
+/// Best tip announced by the connected peers
+#[derive(Clone, Debug)]
+pub struct TipTracker {
+    best_height: i32,
+    last_block: Instant,
+    seen: HashSet<BlockHash>
+}
+
+impl Default for TipTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TipTracker {
+    pub fn new() -> Self {
+        Self {
+            best_height: 0,
+            last_block: Instant::now(),
+            seen: HashSet::new()
+        }
+    }
+
+    /// Highest start height announced by a peer
+    pub fn best_height(&self) -> i32 {
+        self.best_height
+    }
+
+    /// Time since a new block was last announced, or since tracking started
+    pub fn since_last_block(&self) -> Duration {
+        self.last_block.elapsed()
+    }
+
+    /// Check if no new block was announced within the given time
+    pub fn is_stale(&self, age: Duration) -> bool {
+        self.since_last_block() >= age
+    }
+
+    /// Record the start height of a peer
+    pub fn on_version(&mut self, version: &VersionMessage) {
+        self.best_height = self.best_height.max(version.start_height);
+    }
+
+    /// Record the blocks announced in a message.
+    /// Returns true if the message announced a block that was not seen before.
+    pub fn on_message(&mut self, msg: &Message) -> bool {
+        let hashes = match (&msg.header().command, msg.payload()) {
+            (Command::Inv, MessagePayload::InvVect(inv)) => inv
+                .iter()
+                .filter_map(|i| match i {
+                    Inventory::Block(h) |
+                    Inventory::WitnessBlock(h) |
+                    Inventory::CompactBlock(h) => Some(*h),
+                    _ => None
+                })
+                .collect::<Vec<BlockHash>>(),
+
+            // Headers without enough work and blocks whose transactions do not match their
+            // header are not counted
+            (_, MessagePayload::Headers(headers)) => headers.iter().filter(|h| h.check_pow()).map(|h| h.hash()).collect(),
+            (_, MessagePayload::Block(block)) if block.header.check_pow() && block.check_merkle_root() => vec![block.hash()],
+            _ => return false
+        };
+
+        let mut new = false;
+        for hash in hashes {
+            if self.seen.len() >= MAX_SEEN {
+                self.seen.clear();
+            }
+            new |= self.seen.insert(hash);
+        }
+        if new {
+            self.last_block = Instant::now();
+        }
+        new
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Address,
+        bitcoin::hashes::Hash,
+        msg::{
+            data::NetworkMessage,
+            header::Magic
+        }
+    };
+
+    #[test]
+    fn new_blocks_reset_staleness() {
+        let mut tip = TipTracker::new();
+        let mut version = VersionMessage::from(Address::me());
+        version.start_height = 700_000;
+        tip.on_version(&version);
+        assert_eq!(tip.best_height(), 700_000);
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(tip.is_stale(Duration::from_millis(20)));
+
+        let inv = vec![Inventory::Block(BlockHash::from_inner([1; 32]))];
+        let msg = Message::from_payload(NetworkMessage::Inv(inv), Magic::Main);
+        assert!(tip.on_message(&msg));
+        assert!(!tip.is_stale(Duration::from_millis(20)));
+
+        // Relays of the same block are not new
+        assert!(!tip.on_message(&msg));
+        assert!(!tip.on_message(&Message::from_payload(NetworkMessage::Ping(1), Magic::Main)));
+
+        let mut block = crate::blockdata::Block::from(crate::bitcoin::blockdata::constants::genesis_block(crate::bitcoin::Network::Bitcoin));
+        block.txs.clear();
+        assert!(!tip.on_message(&Message::from_payload(NetworkMessage::Block(block), Magic::Main)));
+    }
+}
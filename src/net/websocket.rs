@@ -0,0 +1,266 @@
+// websocket.rs
+//
+// WebSocket server streaming the events of the event loop.
+//
+// Each event is pushed to the connected clients as a text frame holding its JSON
+// representation, so browser dashboards and other programs can follow the traffic without
+// linking against the crate. Only what is needed for pushing events is implemented: the
+// opening handshake and unfragmented, unmasked text frames. Frames sent by the clients are
+// ignored, and clients are dropped when they close the connection.
+//
+// Like the control socket, the server is polled from the loop driving the event loop:
+//      for event in manager.poll() {
+//          server.publish(&event);
+//      }
+//      server.poll();
+//
+
+use crate::{
+    bitcoin::hashes::{
+        sha1,
+        Hash
+    },
+    net::{
+        eventloop::Event,
+        Error
+    }
+};
+use std::{
+    io::{
+        ErrorKind,
+        Read,
+        Write
+    },
+    net::{
+        SocketAddr,
+        TcpListener,
+        TcpStream
+    },
+    time::Duration
+};
+
+/// GUID appended to the key of a client to accept its handshake
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest handshake request accepted from a client
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// Time a client has to take a frame before it is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Client of the server, before or after its handshake
+#[derive(Debug)]
+struct Client {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    open: bool
+}
+
+/// WebSocket server pushing events to its clients
+#[derive(Debug)]
+pub struct EventServer {
+    listener: TcpListener,
+    clients: Vec<Client>
+}
+
+impl EventServer {
+    /// Listen for clients on an address
+    pub fn bind(addr: SocketAddr) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            clients: Vec::new()
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Number of clients the handshake completed with
+    pub fn clients(&self) -> usize {
+        self.clients.iter().filter(|c| c.open).count()
+    }
+
+    /// Accept new clients and complete their handshakes, without blocking.
+    /// Clients that close the connection or send invalid handshakes are dropped.
+    pub fn poll(&mut self) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() && stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok() {
+                self.clients.push(Client { stream, buf: Vec::new(), open: false });
+            }
+        }
+        self.clients.retain_mut(|client| serve(client).is_ok());
+    }
+
+    /// Push an event to every client
+    pub fn publish(&mut self, event: &Event) {
+        self.send(event.to_json().as_bytes());
+    }
+
+    /// Push a text frame to every client
+    fn send(&mut self, text: &[u8]) {
+        let frame = frame(text);
+        self.clients.retain_mut(|client| !client.open || write(&mut client.stream, &frame).is_ok());
+    }
+}
+
+/// Read from a client, answering its handshake once it is complete
+fn serve(client: &mut Client) -> Result<(), Error> {
+    let mut chunk = [0; 4096];
+    loop {
+        match client.stream.read(&mut chunk) {
+            Ok(0) => return Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+
+            // Frames from open clients are not used
+            Ok(_) if client.open => continue,
+            Ok(n) => client.buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Io(e))
+        }
+    }
+    if client.open { return Ok(()) }
+
+    if !client.buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if client.buf.len() > MAX_REQUEST {
+            return Err(Error::Io(ErrorKind::InvalidData.into()))
+        }
+        return Ok(())
+    }
+
+    let request = String::from_utf8_lossy(&client.buf).to_string();
+    let key = request
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-key"))
+        .map(|(_, value)| value.trim().to_string());
+    let key = match key {
+        Some(key) if request.starts_with("GET ") => key,
+        _ => {
+            let _ = write(&mut client.stream, b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+            return Err(Error::Io(ErrorKind::InvalidData.into()))
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+    write(&mut client.stream, response.as_bytes())?;
+    client.buf.clear();
+    client.open = true;
+    Ok(())
+}
+
+/// Write to a client in full, waiting at most for the write timeout
+fn write(stream: &mut TcpStream, data: &[u8]) -> Result<(), Error> {
+    stream.set_nonblocking(false)?;
+    stream.write_all(data)?;
+    stream.set_nonblocking(true)?;
+    Ok(())
+}
+
+/// Value of the `Sec-WebSocket-Accept` header answering a key
+fn accept_key(key: &str) -> String {
+    base64(&sha1::Hash::hash(format!("{}{}", key, GUID).as_bytes())[..])
+}
+
+/// Build an unmasked text frame
+fn frame(text: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match text.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        },
+        n => {
+            frame.push(127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(text);
+    frame
+}
+
+/// Encode bytes to padded base64
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char),
+                false => out.push('=')
+            }
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        msg::{
+            data::{
+                Message,
+                NetworkMessage
+            },
+            header::Magic
+        },
+        net::eventloop::PeerId
+    };
+
+    #[test]
+    fn push_events() {
+        // Example of the handshake from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let mut server = EventServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        for _ in 0..100 {
+            server.poll();
+            if server.clients() == 1 { break }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.clients(), 1);
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            client.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let event = Event::Message(PeerId(3), Message::from_payload(NetworkMessage::Ping(9), Magic::Main));
+        server.publish(&event);
+        let mut header = [0; 2];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 0x81);
+        let len = match header[1] {
+            126 => {
+                let mut len = [0; 2];
+                client.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            },
+            n => n as usize
+        };
+        let mut text = vec![0; len];
+        client.read_exact(&mut text).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&text).unwrap();
+        assert_eq!((&value["event"], &value["peer"]), (&serde_json::json!("message"), &serde_json::json!(3)));
+        assert_eq!(value["message"]["command"], "ping");
+
+        assert_eq!(frame(&[0; 300])[..4], [0x81, 126, 1, 44]);
+    }
+}
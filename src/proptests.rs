@@ -0,0 +1,328 @@
+// proptests.rs
+//
+// Property tests of the encoding of every message type.
+//
+// Values are generated by proptest strategies and must encode to `encoded_size()` bytes,
+// decode back to the same value and encode again to the same bytes. Failing values are
+// shrunk by proptest, so a failure points at the smallest value that breaks the encoding.
+//
+// Like the `Arbitrary` implementations used for fuzzing, the strategies only generate
+// canonical values: known magics, transactions with at least one input, IP addresses in
+// the legacy address encoding and addresses of any network in `addrv2`.
+//
+
+use crate::{
+    address::{
+        Address,
+        AddressNetwork
+    },
+    bitcoin::{
+        hashes::Hash,
+        BlockHash,
+        OutPoint,
+        Script,
+        Transaction,
+        TxIn,
+        TxMerkleNode,
+        TxOut,
+        Txid
+    },
+    blockdata::{
+        Block,
+        BlockHeader
+    },
+    encode::{
+        Decode,
+        DecodeConfig,
+        Encode
+    },
+    msg::{
+        data::{
+            Message,
+            MessagePayload,
+            NetworkMessage
+        },
+        header::{
+            Command,
+            Magic,
+            MessageHeader,
+            COMMANDS
+        },
+        inventory::{
+            BlockdataLocatorInfo,
+            Inventory
+        },
+        network::{
+            NetAddress,
+            ServicesList,
+            TimestampedNetAddress,
+            VersionMessage
+        },
+        UserAgent,
+        VariableInteger
+    }
+};
+use proptest::{
+    collection::vec,
+    prelude::*
+};
+use std::{
+    net::{
+        IpAddr,
+        Ipv6Addr
+    },
+    time::Duration
+};
+
+/// Encode a value, decode it and encode it again
+fn roundtrip<T>(value: &T) -> Result<(), TestCaseError>
+where T: Encode + Decode + PartialEq + std::fmt::Debug {
+    let mut enc = Vec::new();
+    let size = value.net_encode(&mut enc);
+    prop_assert_eq!(size, enc.len());
+    prop_assert_eq!(value.encoded_size(), enc.len());
+
+    let dec = T::net_decode(&enc[..]).map_err(|e| TestCaseError::fail(e.to_string()))?;
+    prop_assert_eq!(&dec, value);
+
+    let mut again = Vec::new();
+    dec.net_encode(&mut again);
+    prop_assert_eq!(again, enc);
+    Ok(())
+}
+
+fn hash() -> impl Strategy<Value = [u8; 32]> {
+    any::<[u8; 32]>()
+}
+
+fn varint() -> impl Strategy<Value = VariableInteger> {
+    // Weighted towards the boundaries of the 1, 3, 5 and 9 byte encodings
+    prop_oneof![
+        any::<u64>(),
+        0xFCu64..=0xFD,
+        0xFFFFu64..=0x1_0000,
+        0xFFFF_FFFFu64..=0x1_0000_0000
+    ].prop_map(VariableInteger)
+}
+
+fn magic() -> impl Strategy<Value = Magic> {
+    prop_oneof![Just(Magic::Main), Just(Magic::Test), Just(Magic::Testnet), Just(Magic::Signet)]
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        proptest::sample::select(COMMANDS.iter().map(|(c, _, _)| c.clone()).collect::<Vec<_>>()),
+        "[a-z]{1,12}".prop_map(|s| Command::from_str(s.clone()).unwrap_or(Command::Unknown(s)))
+    ]
+}
+
+fn services() -> impl Strategy<Value = ServicesList> {
+    any::<u64>().prop_map(ServicesList::from_bits)
+}
+
+fn ip_address() -> impl Strategy<Value = Address> {
+    (any::<IpAddr>(), any::<u16>()).prop_map(|(ip, port)| Address::new(ip, port))
+}
+
+fn address() -> impl Strategy<Value = Address> {
+    let network = prop_oneof![
+        any::<std::net::Ipv4Addr>().prop_map(AddressNetwork::Ipv4),
+        any::<Ipv6Addr>().prop_map(AddressNetwork::Ipv6),
+        hash().prop_map(AddressNetwork::TorV3),
+        hash().prop_map(AddressNetwork::I2p),
+        any::<[u8; 16]>().prop_map(|mut octets| {
+            octets[0] = 0xFC;
+            AddressNetwork::Cjdns(Ipv6Addr::from(octets))
+        })
+    ];
+    (network, any::<u16>()).prop_map(|(network, port)| Address { network, port })
+}
+
+fn net_address() -> impl Strategy<Value = NetAddress> {
+    (services(), ip_address()).prop_map(|(services, address)| NetAddress::new(services, address))
+}
+
+fn timestamped(address: impl Strategy<Value = Address>) -> impl Strategy<Value = TimestampedNetAddress> {
+    (any::<u32>(), services(), address).prop_map(|(time, services, address)| {
+        TimestampedNetAddress::new(Duration::from_secs(time as u64), NetAddress::new(services, address))
+    })
+}
+
+fn user_agent() -> impl Strategy<Value = UserAgent> {
+    ".{0,64}".prop_map(|s| UserAgent::new(s).expect("Short user agent"))
+}
+
+prop_compose! {
+    fn version()(
+        version in any::<i32>(),
+        service in services(),
+        timestamp in any::<i64>(),
+        addr_recv in net_address(),
+        addr_from in net_address(),
+        nonce in any::<u64>(),
+        agent in user_agent(),
+        start_height in any::<i32>(),
+        relay in any::<Option<bool>>()
+    ) -> VersionMessage {
+        VersionMessage::new(version, service, timestamp, addr_recv, addr_from, nonce, agent, start_height, relay)
+    }
+}
+
+fn inventory() -> impl Strategy<Value = Inventory> {
+    // Mostly known types, which are decoded into their own variant
+    let id = prop_oneof![
+        proptest::sample::select(vec![0u32, 1, 2, 3, 4, 0x4000_0001, 0x4000_0002, 0x4000_0003]),
+        any::<u32>()
+    ];
+    (id, hash()).prop_map(|(id, hash)| Inventory::from_id_and_hash(id, hash))
+}
+
+fn locator() -> impl Strategy<Value = BlockdataLocatorInfo> {
+    (any::<u32>(), vec(hash(), 0..8), hash()).prop_map(|(version, hashes, stop)| {
+        BlockdataLocatorInfo::new(version, hashes.into_iter().map(BlockHash::from_inner).collect(), BlockHash::from_inner(stop))
+    })
+}
+
+prop_compose! {
+    fn block_header()(
+        version in any::<i32>(),
+        prev in hash(),
+        root in hash(),
+        time in any::<u32>(),
+        bits in any::<u32>(),
+        nonce in any::<u32>()
+    ) -> BlockHeader {
+        BlockHeader::new(version, BlockHash::from_inner(prev), TxMerkleNode::from_inner(root), time, bits, nonce)
+    }
+}
+
+fn transaction() -> impl Strategy<Value = Transaction> {
+    let txin = (hash(), any::<u32>(), vec(any::<u8>(), 0..64), any::<u32>(), vec(vec(any::<u8>(), 0..32), 0..3))
+        .prop_map(|(txid, vout, script, sequence, witness)| TxIn {
+            previous_output: OutPoint::new(Txid::from_inner(txid), vout),
+            script_sig: Script::from(script),
+            sequence,
+            witness
+        });
+    let txout = (any::<u64>(), vec(any::<u8>(), 0..64))
+        .prop_map(|(value, script)| TxOut { value, script_pubkey: Script::from(script) });
+
+    // A transaction without inputs is read back as a witness transaction
+    (any::<i32>(), any::<u32>(), vec(txin, 1..4), vec(txout, 0..4))
+        .prop_map(|(version, lock_time, input, output)| Transaction { version, lock_time, input, output })
+}
+
+fn block() -> impl Strategy<Value = Block> {
+    (block_header(), vec(transaction(), 0..4)).prop_map(|(header, txs)| Block::new(header, txs))
+}
+
+fn network_message() -> impl Strategy<Value = NetworkMessage> {
+    let raw = COMMANDS.iter().map(|(c, _, _)| c.clone()).filter(|c| c.has_raw_payload()).collect::<Vec<_>>();
+    prop_oneof![
+        version().prop_map(NetworkMessage::Version),
+        Just(NetworkMessage::Verack),
+        Just(NetworkMessage::SendHeaders),
+        Just(NetworkMessage::WTxIdRelay),
+        any::<u64>().prop_map(NetworkMessage::Ping),
+        any::<u64>().prop_map(NetworkMessage::Pong),
+        vec(timestamped(ip_address()), 0..8).prop_map(NetworkMessage::Addr),
+        Just(NetworkMessage::GetAddr),
+        vec(inventory(), 0..8).prop_map(NetworkMessage::Inv),
+        vec(inventory(), 0..8).prop_map(NetworkMessage::GetData),
+        vec(inventory(), 0..8).prop_map(NetworkMessage::NotFound),
+        transaction().prop_map(NetworkMessage::Tx),
+        locator().prop_map(NetworkMessage::GetBlocks),
+        locator().prop_map(NetworkMessage::GetHeaders),
+        block().prop_map(NetworkMessage::Block),
+        vec(block_header(), 0..8).prop_map(NetworkMessage::Headers),
+        Just(NetworkMessage::MemPool),
+        Just(NetworkMessage::FilterClear),
+        Just(NetworkMessage::SendAddrV2),
+        vec(timestamped(address()), 0..8).prop_map(NetworkMessage::AddrV2),
+        (proptest::sample::select(raw), vec(any::<u8>(), 0..64))
+            .prop_map(|(command, payload)| NetworkMessage::Raw { command, payload }),
+        ("[A-Z]{1,12}", vec(any::<u8>(), 0..64))
+            .prop_map(|(command, payload)| NetworkMessage::Unknown { command, payload })
+    ]
+}
+
+fn message() -> impl Strategy<Value = Message> {
+    (network_message(), magic()).prop_map(|(msg, magic)| Message::from_payload(msg, magic))
+}
+
+proptest! {
+    #[test]
+    fn varints(value in varint()) {
+        roundtrip(&value)?;
+        let size = match value.0 {
+            0..=0xFC => 1,
+            0xFD..=0xFFFF => 3,
+            0x1_0000..=0xFFFF_FFFF => 5,
+            _ => 9
+        };
+        prop_assert_eq!(value.encoded_size(), size);
+    }
+
+    #[test]
+    fn addresses(legacy in timestamped(ip_address()), any_network in timestamped(address())) {
+        roundtrip(&legacy)?;
+        roundtrip(&legacy.netaddress)?;
+        roundtrip(&legacy.netaddress.address)?;
+
+        // Addresses of other networks only have an encoding inside `addrv2` messages
+        roundtrip(&Message::from_payload(NetworkMessage::AddrV2(vec![any_network]), Magic::Main))?;
+    }
+
+    #[test]
+    fn services_lists(services in services()) {
+        roundtrip(&services)?;
+    }
+
+    #[test]
+    fn versions(version in version()) {
+        roundtrip(&version)?;
+    }
+
+    #[test]
+    fn inventories(inv in inventory(), locator in locator()) {
+        roundtrip(&inv)?;
+        roundtrip(&locator)?;
+    }
+
+    #[test]
+    fn blocks(header in block_header(), block in block()) {
+        roundtrip(&header)?;
+        roundtrip(&block)?;
+    }
+
+    #[test]
+    fn headers(magic in magic(), command in command(), length in any::<u32>(), checksum in any::<[u8; 4]>()) {
+        roundtrip(&MessageHeader::new(magic, command, length as usize, checksum))?;
+    }
+
+    #[test]
+    fn messages(msg in message()) {
+        roundtrip(&msg)?;
+        prop_assert_eq!(msg.header().length as usize, msg.payload().encoded_size());
+
+        // The payload alone also round trips through its typed message
+        let typed = msg.network_message().map_err(|e| TestCaseError::fail(e.to_string()))?;
+        prop_assert_eq!(&typed.clone().into_payload(), msg.payload());
+        prop_assert_eq!(Message::from_payload(typed, msg.header().magic.clone()), msg);
+    }
+
+    #[test]
+    fn decoded_bytes_are_stable(bytes in vec(any::<u8>(), 0..256), command in command()) {
+        // Whatever decodes from arbitrary payload bytes encodes to a frame that decodes to the
+        // same message. Trailing bytes after a payload are ignored, so such frames are skipped,
+        // and user agents that are not UTF-8 are rejected as they are decoded lossily.
+        let mut enc = Vec::new();
+        Message::new(MessagePayload::Dump(bytes), Magic::Main, command).net_encode(&mut enc);
+        let config = DecodeConfig { strict_utf8: true, ..DecodeConfig::default() };
+        let msg = match Message::net_decode_with_config(&enc[..], &config) {
+            Ok(msg) if msg.payload().encoded_size() == msg.header().length as usize => msg,
+            _ => return Ok(())
+        };
+        roundtrip(&msg)?;
+    }
+}
@@ -0,0 +1,272 @@
+// python.rs
+//
+// Python module exposing the codec, enabled with the `python` feature.
+//
+// `Message.decode(bytes)` decodes a whole message and `Message.encode()` encodes it back.
+// Payloads are read through accessors that return `None` when the message has another
+// command, or as a whole through the canonical JSON representation of the message.
+//
+// The extension module is built with maturin, which enables `pyo3/extension-module`:
+//   maturin build --release
+//
+
+// The wrappers generated by pyo3 convert errors that already are `PyErr`
+#![allow(clippy::useless_conversion)]
+
+use crate::{
+    blockdata::HexHash,
+    encode::{
+        Encode,
+        Decode,
+        Error
+    },
+    msg::{
+        data::{
+            Message,
+            MessagePayload,
+            NetworkMessage
+        },
+        network::VersionMessage
+    }
+};
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::PyBytes
+};
+
+impl From<Error> for PyErr {
+    fn from(err: Error) -> PyErr {
+        PyValueError::new_err(err.to_string())
+    }
+}
+
+/// Network message
+#[pyclass(name = "Message", module = "btcnetmsg")]
+#[derive(Clone)]
+pub struct PyMessage(Message);
+
+#[pymethods]
+impl PyMessage {
+    /// Decode a message from bytes, raising `ValueError` if they are not a valid message
+    #[staticmethod]
+    fn decode(data: &[u8]) -> PyResult<Self> {
+        Ok(Self(Message::net_decode(data)?))
+    }
+
+    /// Decode a message from its canonical JSON representation
+    #[staticmethod]
+    fn from_json(json: &str) -> PyResult<Self> {
+        Ok(Self(Message::from_json(json)?))
+    }
+
+    /// Encode the message into bytes
+    fn encode<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        let mut buf = Vec::with_capacity(self.0.encoded_size());
+        self.0.net_encode(&mut buf);
+        PyBytes::new_bound(py, &buf)
+    }
+
+    /// Canonical JSON representation of the message
+    fn to_json(&self) -> String {
+        self.0.to_json()
+    }
+
+    /// Network magic as the little endian integer of its wire bytes
+    #[getter]
+    fn magic(&self) -> u32 {
+        self.0.header().magic.bytes()
+    }
+
+    #[getter]
+    fn command(&self) -> &str {
+        self.0.header().command.to_str()
+    }
+
+    /// Length of the payload in bytes
+    #[getter]
+    fn length(&self) -> u32 {
+        self.0.header().length
+    }
+
+    #[getter]
+    fn checksum<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.0.header().checksum)
+    }
+
+    /// Encoded payload
+    #[getter]
+    fn payload<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        let mut buf = Vec::with_capacity(self.0.payload().encoded_size());
+        self.0.payload().net_encode(&mut buf);
+        PyBytes::new_bound(py, &buf)
+    }
+
+    /// Fields of a `version` message
+    #[getter]
+    fn version(&self) -> Option<PyVersion> {
+        match self.network_message() {
+            Some(NetworkMessage::Version(v)) => Some(PyVersion::from(&v)),
+            _ => None
+        }
+    }
+
+    /// Nonce of a `ping` or `pong` message
+    #[getter]
+    fn nonce(&self) -> Option<u64> {
+        match self.network_message() {
+            Some(NetworkMessage::Ping(n)) |
+            Some(NetworkMessage::Pong(n)) => Some(n),
+            _ => None
+        }
+    }
+
+    /// Entries of an `inv`, `getdata` or `notfound` message as (type, hex hash) tuples
+    #[getter]
+    fn inventory(&self) -> Option<Vec<(u32, String)>> {
+        match &self.0.payload() {
+            MessagePayload::InvVect(inv) => Some(inv.iter().map(|i| (i.identifier(), i.inner().to_be_hex())).collect()),
+            _ => None
+        }
+    }
+
+    /// Entries of an `addr` or `addrv2` message as (unix time, address, services) tuples
+    #[getter]
+    fn addresses(&self) -> Option<Vec<(u64, String, u64)>> {
+        match &self.0.payload() {
+            MessagePayload::AddrList(addrs) |
+            MessagePayload::AddrV2List(addrs) => Some(addrs.iter().map(|a| (a.timestamp.as_secs(), a.netaddress.address.to_string(), a.netaddress.services.bits())).collect()),
+            _ => None
+        }
+    }
+
+    /// Hashes of the headers of a `headers` message, or of the block of a `block` message
+    #[getter]
+    fn block_hashes(&self) -> Option<Vec<String>> {
+        match &self.0.payload() {
+            MessagePayload::Headers(headers) => Some(headers.iter().map(|h| h.hash().to_string()).collect()),
+            MessagePayload::Block(block) => Some(vec![block.hash().to_string()]),
+            _ => None
+        }
+    }
+
+    /// Txids of the transactions of a `tx` or `block` message
+    #[getter]
+    fn txids(&self) -> Option<Vec<String>> {
+        match &self.0.payload() {
+            MessagePayload::Transction(tx) => Some(vec![tx.txid().to_string()]),
+            MessagePayload::Block(block) => Some(block.iter().map(|tx| tx.txid().to_string()).collect()),
+            _ => None
+        }
+    }
+
+    fn __bytes__<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        self.encode(py)
+    }
+
+    fn __eq__(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Message({})", self.0.header())
+    }
+}
+
+impl PyMessage {
+    fn network_message(&self) -> Option<NetworkMessage> {
+        self.0.network_message().ok()
+    }
+}
+
+/// Fields of a `version` message
+#[pyclass(name = "Version", module = "btcnetmsg", get_all)]
+#[derive(Clone)]
+pub struct PyVersion {
+    version: i32,
+    services: u64,
+    timestamp: i64,
+    addr_recv: String,
+    addr_from: String,
+    nonce: u64,
+    agent: String,
+    start_height: i32,
+    relay: Option<bool>
+}
+
+impl From<&VersionMessage> for PyVersion {
+    fn from(v: &VersionMessage) -> Self {
+        Self {
+            version: v.version,
+            services: v.service.bits(),
+            timestamp: v.timestamp,
+            addr_recv: v.addr_recv.address.to_string(),
+            addr_from: v.addr_from.address.to_string(),
+            nonce: v.nonce,
+            agent: v.agent.as_str().to_string(),
+            start_height: v.start_height,
+            relay: v.relay
+        }
+    }
+}
+
+#[pymethods]
+impl PyVersion {
+    fn __repr__(&self) -> String {
+        format!("Version(version={}, agent={:?}, start_height={})", self.version, self.agent, self.start_height)
+    }
+}
+
+#[pymodule]
+fn btcnetmsg(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyMessage>()?;
+    m.add_class::<PyVersion>()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{
+        header::Magic,
+        network::VersionConfig
+    };
+
+    #[test]
+    fn python_module() {
+        let version = VersionConfig::default().message_at(crate::address::Address::me(), 1_640_995_200, 0x5eed);
+        let mut enc = Vec::new();
+        Message::from_payload(NetworkMessage::Version(version), Magic::Main).net_encode(&mut enc);
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new_bound(py, "btcnetmsg").unwrap();
+            btcnetmsg(&module).unwrap();
+            let locals = pyo3::types::PyDict::new_bound(py);
+            locals.set_item("btcnetmsg", module).unwrap();
+            locals.set_item("data", PyBytes::new_bound(py, &enc)).unwrap();
+            py.run_bound(r#"
+msg = btcnetmsg.Message.decode(data)
+assert msg.command == "version"
+assert msg.magic == 0xD9B4BEF9
+assert msg.encode() == data and bytes(msg) == data
+assert msg.length == len(msg.payload) == len(data) - 24
+assert msg.version.nonce == 0x5eed and msg.version.agent == "bit-tune-v0.0.1"
+assert msg.version.relay is True
+assert msg.nonce is None and msg.inventory is None
+assert btcnetmsg.Message.from_json(msg.to_json()) == msg
+
+try:
+    btcnetmsg.Message.decode(data[:-1])
+    assert False
+except ValueError:
+    pass
+"#, None, Some(&locals)).unwrap();
+        });
+    }
+}
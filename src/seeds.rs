@@ -6,6 +6,36 @@
 //      Port: 2 bytes
 //
 // Only contains IPv4 seeds.
+//
+// Peers of a network are found by resolving its DNS seeds, falling back to the fixed seeds
+// if none of them answer. Resolving needs the `net` feature.
+
+#[cfg(feature = "net")]
+use crate::{
+    address::Address,
+    blockdata::params::ChainParams,
+    net::peer::UntestedPeer
+};
+#[cfg(feature = "net")]
+use std::net::ToSocketAddrs;
+
+/// Resolve the DNS seeds of a network into addresses of its default port.
+/// Returns the fixed seeds of the network if no DNS seed resolved.
+#[cfg(feature = "net")]
+pub fn resolve(params: &ChainParams) -> Vec<Address> {
+    let mut addrs: Vec<Address> = params.dns_seeds
+        .iter()
+        .filter_map(|host| (*host, params.default_port).to_socket_addrs().ok())
+        .flatten()
+        .map(Address::from)
+        .collect();
+    if addrs.is_empty() {
+        addrs = params.fixed_seeds.iter().map(|seed| Address::from(UntestedPeer::from(*seed))).collect();
+    }
+    addrs.sort_by_key(|a| a.to_string());
+    addrs.dedup();
+    addrs
+}
 
 pub const MAIN_SEEDS: [[u8; 6]; 512] = [
     [0x02, 0x27, 0xad, 0x7e, 0x20, 0x8d],
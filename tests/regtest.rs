@@ -0,0 +1,244 @@
+// regtest.rs
+//
+// End to end tests against a Bitcoin Core node on regtest.
+//
+// The tests are ignored by default, as they need bitcoind and bitcoin-cli. Run them with:
+//   cargo test --test regtest -- --ignored
+//
+// By default a node is spawned in a temporary data directory, with `bitcoind` and
+// `bitcoin-cli` taken from the PATH or from the `BITCOIND` and `BITCOIN_CLI` variables.
+// To attach to a running regtest node instead, set `REGTEST_P2P` to its P2P address and
+// `REGTEST_CLI_ARGS` to the bitcoin-cli options reaching its RPC server, for example:
+//   REGTEST_P2P=127.0.0.1:18444 REGTEST_CLI_ARGS="-rpcport=18443 -rpcuser=u -rpcpassword=p"
+//
+// Blocks mined by the tests stay in the chain of an attached node.
+//
+
+#![cfg(feature = "net")]
+
+use btcnetmsg::{
+    blockdata::{
+        chain::HeaderChain,
+        params::ChainParams,
+        HexHash
+    },
+    msg::network::VersionConfig,
+    net::{
+        handshake,
+        stream::PeerStream,
+        tip::TipTracker
+    },
+    Address,
+    Magic,
+    Message,
+    MessagePayload,
+    NetworkMessage
+};
+use std::{
+    env,
+    net::{
+        SocketAddr,
+        TcpListener,
+        TcpStream
+    },
+    path::PathBuf,
+    process::{
+        Child,
+        Command,
+        Stdio
+    },
+    sync::atomic::{
+        AtomicUsize,
+        Ordering
+    },
+    time::Duration
+};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of nodes spawned by this process, to give each its own data directory
+static SPAWNED: AtomicUsize = AtomicUsize::new(0);
+
+/// Regtest node, killed and removed on drop if it was spawned by the test
+struct Node {
+    p2p: SocketAddr,
+    cli_args: Vec<String>,
+    process: Option<(Child, PathBuf)>
+}
+
+impl Node {
+    fn start() -> Self {
+        if let Ok(p2p) = env::var("REGTEST_P2P") {
+            let cli_args = env::var("REGTEST_CLI_ARGS").unwrap_or_default();
+            return Self {
+                p2p: p2p.parse().expect("REGTEST_P2P is not a socket address"),
+                cli_args: cli_args.split_whitespace().map(String::from).collect(),
+                process: None
+            }
+        }
+
+        let datadir = env::temp_dir().join(format!("btcnetmsg-regtest-{}-{}", std::process::id(), SPAWNED.fetch_add(1, Ordering::SeqCst)));
+        std::fs::create_dir_all(&datadir).unwrap();
+        let (p2p, rpc) = (free_port(), free_port());
+        let cli_args = vec![
+            format!("-datadir={}", datadir.display()),
+            format!("-rpcport={}", rpc),
+            String::from("-rpcuser=btcnetmsg"),
+            String::from("-rpcpassword=btcnetmsg")
+        ];
+
+        let bitcoind = env::var("BITCOIND").unwrap_or_else(|_| String::from("bitcoind"));
+        let child = Command::new(&bitcoind)
+            .arg("-regtest")
+            .args(&cli_args)
+            .arg(format!("-bind=127.0.0.1:{}", p2p))
+            .args(["-listen=1", "-server=1", "-daemon=0", "-printtoconsole=0", "-dnsseed=0", "-fixedseeds=0"])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| {
+                let _ = std::fs::remove_dir_all(&datadir);
+                panic!("failed to spawn {}: {}, see tests/regtest.rs", bitcoind, e)
+            });
+
+        let node = Self {
+            p2p: SocketAddr::from(([127, 0, 0, 1], p2p)),
+            cli_args,
+            process: Some((child, datadir))
+        };
+        node.rpc(&["-rpcwait", "getblockcount"]);
+        node
+    }
+
+    /// Run bitcoin-cli and return its trimmed output
+    fn rpc(&self, args: &[&str]) -> String {
+        let cli = env::var("BITCOIN_CLI").unwrap_or_else(|_| String::from("bitcoin-cli"));
+        let output = Command::new(&cli)
+            .arg("-regtest")
+            .args(&self.cli_args)
+            .args(args)
+            .output()
+            .unwrap_or_else(|e| panic!("failed to run {}: {}", cli, e));
+        assert!(output.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// Mine blocks paying to an anyone-can-spend script, returning their hashes
+    fn mine(&self, n: usize) -> Vec<String> {
+        let hashes = self.rpc(&["generatetodescriptor", &n.to_string(), "raw(51)"]);
+        serde_json::from_str(&hashes).unwrap()
+    }
+
+    fn height(&self) -> usize {
+        self.rpc(&["getblockcount"]).parse().unwrap()
+    }
+
+    /// Connect to the node and do the handshake
+    fn connect(&self) -> (PeerStream, btcnetmsg::VersionMessage) {
+        let mut stream = PeerStream::new(TcpStream::connect_timeout(&self.p2p, TIMEOUT).unwrap(), Magic::Test).unwrap();
+        let version = VersionConfig::default().message(Address::from(self.p2p));
+        let theirs = handshake::initiate(&mut stream, version, TIMEOUT).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        (stream, theirs)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Some((mut child, datadir)) = self.process.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+            let _ = std::fs::remove_dir_all(datadir);
+        }
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Read the next message, answering the pings of the node on the way
+fn next_message(stream: &mut PeerStream) -> Message {
+    loop {
+        let msg = stream.recv().expect("Failed to read a message from the node");
+        match msg.network_message() {
+            Ok(NetworkMessage::Ping(nonce)) => stream.send(NetworkMessage::Pong(nonce)).unwrap(),
+            Ok(_) => return msg,
+            Err(e) => panic!("Failed to decode a {} message from the node: {}", msg.header().command.to_str(), e)
+        }
+    }
+}
+
+/// Ping the node and wait for the matching pong. Other messages are returned.
+fn ping(stream: &mut PeerStream, nonce: u64) -> Vec<Message> {
+    stream.send(NetworkMessage::Ping(nonce)).unwrap();
+    let mut others = Vec::new();
+    loop {
+        let msg = next_message(stream);
+        match msg.network_message() {
+            Ok(NetworkMessage::Pong(n)) if n == nonce => return others,
+            _ => others.push(msg)
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs a regtest bitcoind, see tests/regtest.rs"]
+fn handshake_and_ping() {
+    let node = Node::start();
+    let (mut stream, theirs) = node.connect();
+    assert!(theirs.version >= 70015);
+    assert!(theirs.agent.as_str().starts_with("/Satoshi:"), "{}", theirs.agent);
+    assert_eq!(theirs.start_height as usize, node.height());
+
+    for nonce in 0..3 {
+        ping(&mut stream, 0x5eed + nonce);
+    }
+
+    // The node only answers getaddr once and not at all with an empty address manager, but
+    // it must not drop the connection for it
+    stream.send(NetworkMessage::GetAddr).unwrap();
+    for msg in ping(&mut stream, 1) {
+        if let MessagePayload::AddrList(addrs) = msg.payload() {
+            assert!(addrs.len() <= 1000);
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs a regtest bitcoind, see tests/regtest.rs"]
+fn headers_and_block_announcement() {
+    let node = Node::start();
+    node.mine(5);
+    let (mut stream, theirs) = node.connect();
+    let mut tip = TipTracker::new();
+    tip.on_version(&theirs);
+
+    // Sync every header of the node from the genesis block
+    let mut chain = HeaderChain::new(ChainParams::regtest());
+    chain.sync(&mut stream, TIMEOUT).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    assert_eq!(chain.best_height(), node.height());
+    assert_eq!(chain.tip().to_string(), node.rpc(&["getbestblockhash"]));
+    assert_eq!(tip.best_height() as usize, chain.best_height());
+
+    // Ask for new blocks to be announced with their headers, and make sure the node has
+    // processed the request before mining
+    stream.send(NetworkMessage::SendHeaders).unwrap();
+    ping(&mut stream, 2);
+
+    let mined = node.mine(1).remove(0);
+    loop {
+        let msg = next_message(&mut stream);
+        if !tip.on_message(&msg) { continue }
+
+        match msg.network_message().unwrap() {
+            NetworkMessage::Headers(headers) => {
+                assert_eq!(chain.extend(&headers), Ok(1));
+                assert_eq!(chain.tip().to_string(), mined);
+            },
+            NetworkMessage::Inv(inv) => assert!(inv.iter().any(|i| i.inner().to_be_hex() == mined), "{:?}", inv),
+            msg => panic!("unexpected announcement {:?}", msg)
+        }
+        break
+    }
+}